@@ -36,7 +36,9 @@ struct TasksParam {
 enum TaskCommands {
     AlbumOfWeek,
     ChartsOfWeek,
-    SyncFromSpotify
+    SyncFromSpotify,
+    FetchArtwork,
+    AnalyzeLoudness
 }
 
 
@@ -52,7 +54,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             match &params.subcommand {
                 TaskCommands::ChartsOfWeek => tasks::do_fetch_charts_of_week(server_url).await,
                 TaskCommands::AlbumOfWeek => tasks::do_fetch_album_of_week(server_url).await,
-                TaskCommands::SyncFromSpotify => tasks::do_sync_from_spotify(server_url).await
+                TaskCommands::SyncFromSpotify => tasks::do_sync_from_spotify(server_url).await,
+                TaskCommands::FetchArtwork => tasks::do_fetch_artwork(server_url).await,
+                TaskCommands::AnalyzeLoudness => tasks::do_analyze_loudness(server_url).await
             }
         }
     }
@@ -152,4 +156,18 @@ mod tasks {
             Request::new(super::services::TasksBlank{})).await?;
         Ok(())
     }
+
+    pub async fn do_fetch_artwork(server : String) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tasks_client = super::services::tasks_client::TasksClient::connect(server).await?;
+        let _ = tasks_client.fetch_artwork(
+            Request::new(super::services::TasksBlank{})).await?;
+        Ok(())
+    }
+
+    pub async fn do_analyze_loudness(server : String) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tasks_client = super::services::tasks_client::TasksClient::connect(server).await?;
+        let _ = tasks_client.analyze_loudness(
+            Request::new(super::services::TasksBlank{})).await?;
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -111,6 +111,7 @@ async fn handle_page_request<CB>(
             entity: super::services::LibraryEntities::from(entity) as i32,
             offset,
             limit,
+            order: super::services::ListOrders::Unspecified as i32,
         }))
         .await;
 
@@ -237,9 +237,13 @@ pub async fn queue_load(
                 let msg = result_stream.message().await;
                 match msg {
                     Ok(m) => match m {
-                        Some(track) => glib_tx
-                            .send(Ok(PlaybackResponse::QueueTrack(track)))
-                            .expect("Failed to send to GLib!"),
+                        Some(item) => {
+                            if let Some(track) = item.track {
+                                glib_tx
+                                    .send(Ok(PlaybackResponse::QueueTrack(track)))
+                                    .expect("Failed to send to GLib!")
+                            }
+                        }
                         None => {
                             log::info!("Reached end of Queue load stream!");
                             break;
@@ -5,5 +5,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>>{
             &["../proto/"]
         )
         .unwrap();
+
+    println!("cargo:rerun-if-changed=../proto");
+
+    //the newest migrations known to this binary; used to detect databases migrated by a newer release
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=migrations_sqlite");
+    println!("cargo:rustc-env=SOUNDBASE_PG_SCHEMA_VERSION={}", latest_version("migrations")?);
+    println!("cargo:rustc-env=SOUNDBASE_SQLITE_SCHEMA_VERSION={}", latest_version("migrations_sqlite")?);
     Ok(())
-}
\ No newline at end of file
+}
+
+fn latest_version(dir: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let latest = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_str().map(migration_version))
+        .max()
+        .unwrap_or_default();
+    Ok(latest)
+}
+
+//same version format diesel uses for its __diesel_schema_migrations table
+fn migration_version(dir_name: &str) -> String {
+    dir_name.split('_').next().unwrap_or_default().replace('-', "")
+}
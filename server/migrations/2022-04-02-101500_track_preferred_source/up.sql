@@ -0,0 +1,4 @@
+-- per track override of the configured playback source
+-- null => use configured default, 1 => local, 2 => spotify
+alter table tracks
+    add column preferred_source integer;
@@ -0,0 +1,18 @@
+-- artwork per album; size 0 is the original image, all other sizes are
+-- resized variants. A row without file_path is still waiting to be fetched
+create table album_art
+(
+    art_id       serial
+        primary key,
+    album_id     integer      not null
+        references albums (album_id)
+            on delete cascade,
+    size         integer      not null default 0,
+    source       VARCHAR(16)  not null,
+    source_url   VARCHAR(1024),
+    file_path    VARCHAR(1024),
+    content_type VARCHAR(32)
+);
+
+create unique index album_art_album_size_uindex
+    on album_art (album_id, size);
@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+update albums
+set album_type = case album_type
+                     when 1 then 1
+                     when 2 then 0
+                     when 3 then 2
+                     when 4 then 3
+                     when 5 then 1
+                     when 6 then 0
+                     else 0
+    end;
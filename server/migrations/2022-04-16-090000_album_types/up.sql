@@ -0,0 +1,26 @@
+-- album types now share the numbering of the proto AlbumTypes enum:
+-- 0 unknown, 1 single, 2 album, 3 compilation, 4 appears on, 5 ep, 6 live
+update albums
+set album_type = case album_type
+                     when 0 then 2
+                     when 1 then 1
+                     when 2 then 3
+                     when 3 then 4
+                     else 0
+    end;
+
+-- singles with 4 to 6 tracks are EPs
+update albums
+set album_type = 5
+where album_type = 1
+  and total_tracks between 4 and 6;
+
+update albums
+set album_type = 6
+where album_type = 2
+  and (lower(name) like '%(live%'
+    or lower(name) like '%[live%'
+    or lower(name) like '% live at %'
+    or lower(name) like '% live in %'
+    or lower(name) like 'live at %'
+    or lower(name) like 'live in %');
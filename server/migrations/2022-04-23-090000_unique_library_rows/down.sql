@@ -0,0 +1,5 @@
+drop index artist_genre_artist_genre_uindex;
+drop index track_artist_track_artist_uindex;
+drop index album_artists_album_artist_uindex;
+drop index tracks_spot_id_uindex;
+drop index albums_spot_id_uindex;
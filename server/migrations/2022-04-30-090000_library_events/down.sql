@@ -0,0 +1,21 @@
+drop table library_events;
+
+drop trigger tracks_updated_at on tracks;
+drop trigger albums_updated_at on albums;
+drop trigger artists_updated_at on artists;
+drop function soundbase_set_updated_at();
+
+alter table tracks
+    drop column created_at,
+    drop column updated_at,
+    drop column faved_at;
+
+alter table albums
+    drop column created_at,
+    drop column updated_at,
+    drop column faved_at;
+
+alter table artists
+    drop column created_at,
+    drop column updated_at,
+    drop column faved_at;
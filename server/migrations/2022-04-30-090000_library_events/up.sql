@@ -0,0 +1,60 @@
+-- all timestamps are stored as utc
+alter table artists
+    add column created_at timestamp not null default (now() at time zone 'utc'),
+    add column updated_at timestamp not null default (now() at time zone 'utc'),
+    add column faved_at   timestamp;
+
+alter table albums
+    add column created_at timestamp not null default (now() at time zone 'utc'),
+    add column updated_at timestamp not null default (now() at time zone 'utc'),
+    add column faved_at   timestamp;
+
+alter table tracks
+    add column created_at timestamp not null default (now() at time zone 'utc'),
+    add column updated_at timestamp not null default (now() at time zone 'utc'),
+    add column faved_at   timestamp;
+
+create function soundbase_set_updated_at() returns trigger as
+$$
+begin
+    if (new is distinct from old) then
+        new.updated_at := now() at time zone 'utc';
+    end if;
+    return new;
+end;
+$$ language plpgsql;
+
+create trigger artists_updated_at
+    before update on artists
+    for each row execute procedure soundbase_set_updated_at();
+create trigger albums_updated_at
+    before update on albums
+    for each row execute procedure soundbase_set_updated_at();
+create trigger tracks_updated_at
+    before update on tracks
+    for each row execute procedure soundbase_set_updated_at();
+
+-- append only log of library changes; entity follows the proto LibraryEntities,
+-- kind the proto LibraryEventKinds numbering
+create table library_events
+(
+    event_id   serial
+        primary key,
+    entity     integer     not null,
+    entity_id  integer     not null,
+    kind       integer     not null,
+    source     VARCHAR(32) not null,
+    created_at timestamp   not null default (now() at time zone 'utc')
+);
+
+create index library_events_created_at_index
+    on library_events (created_at);
+
+-- the library that exists already shows up as added, so clients replaying
+-- the log from the start see every row
+insert into library_events (entity, entity_id, kind, source)
+select 1, artist_id, 1, 'migration' from artists order by artist_id;
+insert into library_events (entity, entity_id, kind, source)
+select 2, album_id, 1, 'migration' from albums order by album_id;
+insert into library_events (entity, entity_id, kind, source)
+select 3, track_id, 1, 'migration' from tracks order by track_id;
@@ -0,0 +1,20 @@
+-- one row per (partial) playback of a track; source follows the proto
+-- PlaybackSources numbering
+create table plays
+(
+    play_id    serial
+        primary key,
+    track_id   integer   not null
+        references tracks (track_id)
+            on delete cascade,
+    started_at timestamp not null,
+    played_ms  bigint    not null,
+    completed  boolean   not null,
+    source     integer   not null
+);
+
+create index plays_track_id_index
+    on plays (track_id);
+
+create index plays_started_at_index
+    on plays (started_at);
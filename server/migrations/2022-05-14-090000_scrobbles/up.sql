@@ -0,0 +1,17 @@
+-- listens waiting to be submitted to the scrobbling service; rows carry their
+-- own copy of the track metadata and are removed once submitted
+create table scrobbles
+(
+    scrobble_id  serial
+        primary key,
+    artist_name  VARCHAR(1024) not null,
+    track_name   VARCHAR(1024) not null,
+    release_name VARCHAR(1024),
+    duration_ms  bigint        not null,
+    listened_at  timestamp     not null,
+    attempts     integer       not null default 0,
+    last_error   text
+);
+
+create index scrobbles_listened_at_index
+    on scrobbles (listened_at);
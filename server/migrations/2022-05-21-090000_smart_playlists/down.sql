@@ -0,0 +1 @@
+drop table smart_playlists;
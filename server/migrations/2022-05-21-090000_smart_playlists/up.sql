@@ -0,0 +1,17 @@
+-- rule holds the json encoded filter tree, sort follows the proto PlaylistSorts numbering
+create table smart_playlists
+(
+    playlist_id serial
+        primary key,
+    name        VARCHAR(255) not null,
+    rule        text         not null,
+    sort        integer      not null default 0,
+    descending  boolean      not null default false,
+    max_tracks  integer,
+    created_at  timestamp    not null default (now() at time zone 'utc'),
+    updated_at  timestamp    not null default (now() at time zone 'utc')
+);
+
+create trigger smart_playlists_updated_at
+    before update on smart_playlists
+    for each row execute procedure soundbase_set_updated_at();
@@ -0,0 +1,11 @@
+-- local files which couldn't be decoded for the loudness analysis; they are
+-- tried again once the track points to another file
+create table loudness_failures
+(
+    track_id   integer       not null
+        primary key
+        references tracks (track_id)
+            on delete cascade,
+    local_file VARCHAR(1024) not null,
+    failed_at  timestamp     not null default (now() at time zone 'utc')
+);
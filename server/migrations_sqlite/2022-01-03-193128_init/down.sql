@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+drop table track_fav_proposals;
+drop table charts_of_week;
+drop table albums_of_week;
+drop table track_artist;
+drop table tracks;
+drop table album_artists;
+drop table albums;
+drop table artist_genre;
+drop table artists;
+drop table genre;
\ No newline at end of file
@@ -0,0 +1,71 @@
+-- merge albums sharing a spotify id into the oldest one
+create temporary table album_dupes as
+select a.album_id as dupe_id, k.keep_id
+from albums a
+    join (select spot_id, min(album_id) as keep_id
+          from albums
+          where spot_id is not null
+          group by spot_id
+          having count(*) > 1) k on a.spot_id = k.spot_id and a.album_id <> k.keep_id;
+
+update albums set is_faved = true
+where album_id in (select d.keep_id from album_dupes d join albums a on a.album_id = d.dupe_id where a.is_faved);
+update albums set was_aow = true
+where album_id in (select d.keep_id from album_dupes d join albums a on a.album_id = d.dupe_id where a.was_aow);
+
+update tracks set album_id = (select keep_id from album_dupes where dupe_id = tracks.album_id)
+where album_id in (select dupe_id from album_dupes);
+update album_artists set album_id = (select keep_id from album_dupes where dupe_id = album_artists.album_id)
+where album_id in (select dupe_id from album_dupes);
+update albums_of_week set album_id = (select keep_id from album_dupes where dupe_id = albums_of_week.album_id)
+where album_id in (select dupe_id from album_dupes);
+
+-- artwork of the duplicates is dropped, it can be fetched again for the kept album
+delete from album_art where album_id in (select dupe_id from album_dupes);
+delete from albums where album_id in (select dupe_id from album_dupes);
+drop table album_dupes;
+
+-- merge tracks sharing a spotify id into the oldest one
+create temporary table track_dupes as
+select t.track_id as dupe_id, k.keep_id
+from tracks t
+    join (select spot_id, min(track_id) as keep_id
+          from tracks
+          where spot_id is not null
+          group by spot_id
+          having count(*) > 1) k on t.spot_id = k.spot_id and t.track_id <> k.keep_id;
+
+update tracks set is_faved = true
+where track_id in (select d.keep_id from track_dupes d join tracks t on t.track_id = d.dupe_id where t.is_faved);
+update tracks set local_file = (select min(t.local_file) from track_dupes d join tracks t on t.track_id = d.dupe_id
+                                where d.keep_id = tracks.track_id)
+where local_file is null and track_id in (select keep_id from track_dupes);
+
+update track_artist set track_id = (select keep_id from track_dupes where dupe_id = track_artist.track_id)
+where track_id in (select dupe_id from track_dupes);
+update charts_of_week set track_id = (select keep_id from track_dupes where dupe_id = charts_of_week.track_id)
+where track_id in (select dupe_id from track_dupes);
+update track_fav_proposals set track_id = (select keep_id from track_dupes where dupe_id = track_fav_proposals.track_id)
+where track_id in (select dupe_id from track_dupes);
+
+delete from tracks where track_id in (select dupe_id from track_dupes);
+drop table track_dupes;
+
+-- drop duplicated links, including those created by the merges above
+delete from album_artists
+where id not in (select min(id) from album_artists group by album_id, artist_id);
+delete from track_artist
+where id not in (select min(id) from track_artist group by track_id, artist_id);
+delete from artist_genre
+where id not in (select min(id) from artist_genre group by artist_id, genre_id);
+
+create unique index albums_spot_id_uindex
+    on albums (spot_id);
+create unique index tracks_spot_id_uindex
+    on tracks (spot_id);
+create unique index album_artists_album_artist_uindex
+    on album_artists (album_id, artist_id);
+create unique index track_artist_track_artist_uindex
+    on track_artist (track_id, artist_id);
+create unique index artist_genre_artist_genre_uindex
+    on artist_genre (artist_id, genre_id);
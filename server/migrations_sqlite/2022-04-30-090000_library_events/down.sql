@@ -0,0 +1,19 @@
+drop table library_events;
+
+drop trigger tracks_updated_at;
+drop trigger tracks_created_at;
+alter table tracks drop column faved_at;
+alter table tracks drop column updated_at;
+alter table tracks drop column created_at;
+
+drop trigger albums_updated_at;
+drop trigger albums_created_at;
+alter table albums drop column faved_at;
+alter table albums drop column updated_at;
+alter table albums drop column created_at;
+
+drop trigger artists_updated_at;
+drop trigger artists_created_at;
+alter table artists drop column faved_at;
+alter table artists drop column updated_at;
+alter table artists drop column created_at;
@@ -0,0 +1,78 @@
+-- all timestamps are stored as utc. sqlite can't add columns with a
+-- non-constant default, so triggers fill them in instead
+
+alter table artists add column created_at timestamp not null default '1970-01-01 00:00:00';
+alter table artists add column updated_at timestamp not null default '1970-01-01 00:00:00';
+alter table artists add column faved_at timestamp;
+update artists set created_at = strftime('%Y-%m-%d %H:%M:%f', 'now'), updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now');
+
+create trigger artists_created_at
+    after insert on artists
+begin
+    update artists set created_at = strftime('%Y-%m-%d %H:%M:%f', 'now'), updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') where artist_id = new.artist_id;
+end;
+
+create trigger artists_updated_at
+    after update on artists
+begin
+    update artists set updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') where artist_id = new.artist_id;
+end;
+
+alter table albums add column created_at timestamp not null default '1970-01-01 00:00:00';
+alter table albums add column updated_at timestamp not null default '1970-01-01 00:00:00';
+alter table albums add column faved_at timestamp;
+update albums set created_at = strftime('%Y-%m-%d %H:%M:%f', 'now'), updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now');
+
+create trigger albums_created_at
+    after insert on albums
+begin
+    update albums set created_at = strftime('%Y-%m-%d %H:%M:%f', 'now'), updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') where album_id = new.album_id;
+end;
+
+create trigger albums_updated_at
+    after update on albums
+begin
+    update albums set updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') where album_id = new.album_id;
+end;
+
+alter table tracks add column created_at timestamp not null default '1970-01-01 00:00:00';
+alter table tracks add column updated_at timestamp not null default '1970-01-01 00:00:00';
+alter table tracks add column faved_at timestamp;
+update tracks set created_at = strftime('%Y-%m-%d %H:%M:%f', 'now'), updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now');
+
+create trigger tracks_created_at
+    after insert on tracks
+begin
+    update tracks set created_at = strftime('%Y-%m-%d %H:%M:%f', 'now'), updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') where track_id = new.track_id;
+end;
+
+create trigger tracks_updated_at
+    after update on tracks
+begin
+    update tracks set updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') where track_id = new.track_id;
+end;
+
+-- append only log of library changes; entity follows the proto LibraryEntities,
+-- kind the proto LibraryEventKinds numbering
+create table library_events
+(
+    event_id   integer not null
+        primary key,
+    entity     integer     not null,
+    entity_id  integer     not null,
+    kind       integer     not null,
+    source     VARCHAR(32) not null,
+    created_at timestamp   not null default (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+);
+
+create index library_events_created_at_index
+    on library_events (created_at);
+
+-- the library that exists already shows up as added, so clients replaying
+-- the log from the start see every row
+insert into library_events (entity, entity_id, kind, source)
+select 1, artist_id, 1, 'migration' from artists order by artist_id;
+insert into library_events (entity, entity_id, kind, source)
+select 2, album_id, 1, 'migration' from albums order by album_id;
+insert into library_events (entity, entity_id, kind, source)
+select 3, track_id, 1, 'migration' from tracks order by track_id;
@@ -0,0 +1,19 @@
+-- rule holds the json encoded filter tree, sort follows the proto PlaylistSorts numbering
+create table smart_playlists
+(
+    playlist_id integer not null
+        primary key,
+    name        VARCHAR(255) not null,
+    rule        text         not null,
+    sort        integer      not null default 0,
+    descending  boolean      not null default false,
+    max_tracks  integer,
+    created_at  timestamp    not null default (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    updated_at  timestamp    not null default (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+);
+
+create trigger smart_playlists_updated_at
+    after update on smart_playlists
+begin
+    update smart_playlists set updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') where playlist_id = new.playlist_id;
+end;
@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+alter table tracks
+    drop column track_gain;
+alter table tracks
+    drop column track_peak;
+alter table tracks
+    drop column album_gain;
+alter table tracks
+    drop column album_peak;
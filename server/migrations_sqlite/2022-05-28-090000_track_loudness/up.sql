@@ -0,0 +1,10 @@
+-- replay gain of local files measured per EBU R128, null until analysed
+-- gains are dB relative to -18 LUFS, peaks are linear sample values
+alter table tracks
+    add column track_gain real;
+alter table tracks
+    add column track_peak real;
+alter table tracks
+    add column album_gain real;
+alter table tracks
+    add column album_peak real;
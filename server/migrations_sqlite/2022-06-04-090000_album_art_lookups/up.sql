@@ -0,0 +1,11 @@
+-- albums whose artwork couldn't be found or downloaded, so the artwork task
+-- moves on to other albums until it is time to try them again
+create table album_art_lookups
+(
+    album_id integer   not null
+        primary key
+        references albums (album_id)
+            on delete cascade,
+    attempts integer   not null default 1,
+    tried_at timestamp not null default (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+);
@@ -0,0 +1,368 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use chrono::{Duration, Utc};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::db_new::album::AlbumDb;
+use crate::db_new::album_art::AlbumArtDb;
+use crate::db_new::models::{AlbumArt, NewAlbumArt};
+use crate::db_new::track::TrackDb;
+use crate::db_new::DbApi;
+
+type Result<T> = std::result::Result<T, ArtworkError>;
+
+//size 0 always refers to the original image
+pub const ORIGINAL_SIZE: i32 = 0;
+pub const ARTWORK_SIZES: [i32; 3] = [64, 300, 640];
+
+//albums fetched per run of the artwork task
+const FETCH_BATCH_SIZE: i64 = 500;
+//albums without artwork are looked up again after this long, e.g. for new cover files
+const LOOKUP_RETRY_DAYS: i64 = 7;
+
+const COVER_FILE_NAMES: [&str; 4] = ["cover.jpg", "cover.png", "folder.jpg", "front.jpg"];
+
+#[derive(Error, Debug)]
+pub enum ArtworkError {
+    #[error("internal error: {0}")]
+    Internal(String),
+
+    #[error("database error: {0}")]
+    Database(#[from] crate::db_new::DbError),
+
+    #[error("Reqwest error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("image error: {0}")]
+    Image(#[from] image::ImageError),
+
+    #[error("tag error: {0}")]
+    Tag(#[from] lofty::LoftyError),
+
+    #[error("join error: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+pub struct Artwork {
+    pub data: Vec<u8>,
+    pub content_type: String,
+}
+
+#[derive(Clone)]
+pub struct ArtworkStore {
+    db: DbApi,
+    base_dir: PathBuf,
+}
+
+impl ArtworkStore {
+    pub fn new(db: DbApi) -> Self {
+        let base_dir = match dotenv::var("ARTWORK_DIR") {
+            Ok(dir) => dir,
+            Err(_) => "./.artwork".to_string()
+        };
+        Self { db, base_dir: PathBuf::from(base_dir) }
+    }
+
+    pub async fn get_artwork(&self, album_id: i32, requested_size: i32) -> Result<Option<Artwork>> {
+        let size = normalize_size(requested_size);
+        let api: &dyn AlbumArtDb = &self.db;
+        if let Some(art) = api.find_album_art(album_id, size)? {
+            if let Some(artwork) = read_art(&art).await? {
+                return Ok(Some(artwork));
+            }
+        }
+
+        //nothing cached for the requested size, so make sure the original is there
+        let original = match self.cache_original(album_id).await? {
+            Some(original) => original,
+            None => return Ok(None)
+        };
+
+        if size == ORIGINAL_SIZE {
+            return read_art(&original).await;
+        }
+
+        let variant = self.create_variant(&original, size).await?;
+        read_art(&variant).await
+    }
+
+    pub async fn fetch_missing(&self) -> Result<()> {
+        //albums that failed recently are skipped, so they can't hold up the others
+        let tried_before = Utc::now().naive_utc() - Duration::days(LOOKUP_RETRY_DAYS);
+
+        //1. download all registered but not yet cached remote images
+        let api: &dyn AlbumArtDb = &self.db;
+        for art in api.load_pending_album_art(tried_before, FETCH_BATCH_SIZE)? {
+            let result = match self.download(&art).await {
+                Ok(original) => self.create_variants(&original).await,
+                Err(e) => {
+                    self.record_failed_lookup(art.album_id);
+                    Err(e)
+                }
+            };
+            if let Err(e) = result {
+                log::warn!("Failed to download artwork for album {} => {:?}", art.album_id, e);
+            }
+        }
+
+        //2. search local files for artwork of albums without any
+        let albums = api.load_local_tracks_without_art(tried_before, FETCH_BATCH_SIZE)?
+            .into_iter()
+            .group_by(|t| t.album_id)
+            .into_iter()
+            .map(|(album_id, tracks)| (album_id, tracks.filter_map(|t| t.local_file).collect_vec()))
+            .collect_vec();
+        for (album_id, files) in albums {
+            let result = match self.import_local(album_id, files, None).await {
+                Ok(Some(original)) => self.create_variants(&original).await,
+                Ok(None) => {
+                    self.record_failed_lookup(album_id);
+                    Ok(())
+                }
+                Err(e) => {
+                    self.record_failed_lookup(album_id);
+                    Err(e)
+                }
+            };
+            if let Err(e) = result {
+                log::warn!("Failed to import local artwork for album {} => {:?}", album_id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record_failed_lookup(&self, album_id: i32) {
+        let api: &dyn AlbumArtDb = &self.db;
+        if let Err(e) = api.record_failed_album_art_lookup(album_id) {
+            log::error!("Failed to record artwork lookup of album {} => {:?}", album_id, e);
+        }
+    }
+
+    async fn create_variants(&self, original: &AlbumArt) -> Result<()> {
+        for size in ARTWORK_SIZES {
+            let _ = self.create_variant(original, size).await?;
+        }
+        Ok(())
+    }
+
+    async fn cache_original(&self, album_id: i32) -> Result<Option<AlbumArt>> {
+        let api: &dyn AlbumArtDb = &self.db;
+        match api.find_album_art(album_id, ORIGINAL_SIZE)? {
+            Some(art) if is_cached(&art) => Ok(Some(art)),
+            //not downloaded yet or the file got lost
+            Some(art) if art.source_url.is_some() => Ok(Some(self.download(&art).await?)),
+            known => {
+                let api: &dyn AlbumDb = &self.db;
+                let files = match api.find_by_id(album_id)? {
+                    Some(album) => self.db.load_tracks_for_album(&album)?
+                        .into_iter()
+                        .filter_map(|t| t.local_file)
+                        .collect_vec(),
+                    None => return Ok(None)
+                };
+                self.import_local(album_id, files, known.as_ref()).await
+            }
+        }
+    }
+
+    async fn download(&self, art: &AlbumArt) -> Result<AlbumArt> {
+        let url = match &art.source_url {
+            Some(url) => url,
+            None => return Err(ArtworkError::Internal(format!("Album art {} has no source url!", art.art_id)))
+        };
+
+        let data = reqwest::get(url).await?
+            .error_for_status()?
+            .bytes().await?
+            .to_vec();
+        let (file_path, content_type) = self.store_original(art.album_id, &data).await?;
+
+        let api: &dyn AlbumArtDb = &self.db;
+        api.set_album_art_file(art.art_id, &file_path, content_type)?;
+        Ok(AlbumArt {
+            file_path: Some(file_path),
+            content_type: Some(content_type.to_string()),
+            ..art.clone()
+        })
+    }
+
+    /// Stores the artwork found in or next to the files as the original; known is
+    /// the registered original whose file got lost, if any
+    async fn import_local(&self, album_id: i32, files: Vec<String>, known: Option<&AlbumArt>) -> Result<Option<AlbumArt>> {
+        let found = tokio::task::spawn_blocking(move || find_local_artwork(&files)).await??;
+
+        match (found, known) {
+            (Some((_, data)), Some(art)) => {
+                let (file_path, content_type) = self.store_original(album_id, &data).await?;
+                let api: &dyn AlbumArtDb = &self.db;
+                api.set_album_art_file(art.art_id, &file_path, content_type)?;
+                Ok(Some(AlbumArt {
+                    file_path: Some(file_path),
+                    content_type: Some(content_type.to_string()),
+                    ..art.clone()
+                }))
+            }
+            (Some((source, data)), None) => {
+                let (file_path, content_type) = self.store_original(album_id, &data).await?;
+                let api: &dyn AlbumArtDb = &self.db;
+                let art = api.new_album_art(NewAlbumArt {
+                    album_id,
+                    size: ORIGINAL_SIZE,
+                    source,
+                    source_url: None,
+                    file_path: Some(&*file_path),
+                    content_type: Some(content_type)
+                })?;
+                Ok(Some(art))
+            }
+            (None, _) => Ok(None)
+        }
+    }
+
+    async fn create_variant(&self, original: &AlbumArt, size: i32) -> Result<AlbumArt> {
+        let original_path = original.file_path.as_ref()
+            .ok_or_else(|| ArtworkError::Internal(format!("Album art {} isn't cached yet!", original.art_id)))?;
+        let data = tokio::fs::read(original_path).await?;
+        let resized = tokio::task::spawn_blocking(move || resize(&data, size as u32)).await??;
+
+        let file_path = self.album_dir(original.album_id).join(format!("{}.jpg", size));
+        tokio::fs::write(&file_path, resized).await?;
+
+        let api: &dyn AlbumArtDb = &self.db;
+        let file_path = file_path.to_string_lossy();
+        let content_type = ImageFormat::Jpeg.to_mime_type();
+        match api.find_album_art(original.album_id, size)? {
+            //the variant was known but its file got lost, just point to the new one
+            Some(art) => {
+                api.set_album_art_file(art.art_id, &file_path, content_type)?;
+                Ok(AlbumArt {
+                    file_path: Some(file_path.to_string()),
+                    content_type: Some(content_type.to_string()),
+                    ..art
+                })
+            }
+            None => Ok(api.new_album_art(NewAlbumArt {
+                album_id: original.album_id,
+                size,
+                source: &original.source,
+                source_url: original.source_url.as_deref(),
+                file_path: Some(&*file_path),
+                content_type: Some(content_type)
+            })?)
+        }
+    }
+
+    async fn store_original(&self, album_id: i32, data: &[u8]) -> Result<(String, &'static str)> {
+        let format = image::guess_format(data)?;
+        let extension = format.extensions_str().first().unwrap_or(&"img");
+
+        let dir = self.album_dir(album_id);
+        tokio::fs::create_dir_all(&dir).await?;
+        let file_path = dir.join(format!("original.{}", extension));
+        tokio::fs::write(&file_path, data).await?;
+
+        Ok((file_path.to_string_lossy().to_string(), format.to_mime_type()))
+    }
+
+    fn album_dir(&self, album_id: i32) -> PathBuf {
+        self.base_dir.join(album_id.to_string())
+    }
+}
+
+fn normalize_size(requested: i32) -> i32 {
+    if requested <= 0 {
+        return ORIGINAL_SIZE;
+    }
+    //serve the smallest variant which is at least as large as requested
+    ARTWORK_SIZES.iter()
+        .find(|&&size| size >= requested)
+        .cloned()
+        .unwrap_or(ARTWORK_SIZES[ARTWORK_SIZES.len() - 1])
+}
+
+fn is_cached(art: &AlbumArt) -> bool {
+    art.file_path.as_ref().is_some_and(|file_path| Path::new(file_path).is_file())
+}
+
+async fn read_art(art: &AlbumArt) -> Result<Option<Artwork>> {
+    match &art.file_path {
+        Some(file_path) if Path::new(file_path).is_file() => {
+            let data = tokio::fs::read(file_path).await?;
+            let content_type = art.content_type.clone()
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            Ok(Some(Artwork { data, content_type }))
+        }
+        _ => Ok(None)
+    }
+}
+
+fn resize(data: &[u8], size: u32) -> Result<Vec<u8>> {
+    let img = image::load_from_memory(data)?;
+    let resized = img.resize(size, size, FilterType::Lanczos3);
+
+    //jpeg can't carry an alpha channel
+    let rgb = DynamicImage::ImageRgb8(resized.to_rgb8());
+    let mut out = Cursor::new(Vec::new());
+    rgb.write_to(&mut out, ImageOutputFormat::Jpeg(85))?;
+    Ok(out.into_inner())
+}
+
+fn find_local_artwork(files: &[String]) -> Result<Option<(&'static str, Vec<u8>)>> {
+    //1. prefer pictures embedded into the tracks
+    for file in files {
+        let tagged = match lofty::read_from_path(file) {
+            Ok(tagged) => tagged,
+            Err(e) => {
+                log::warn!("Failed to read tags of {} => {:?}", file, e);
+                continue;
+            }
+        };
+
+        let pictures = tagged.tags().iter().flat_map(|tag| tag.pictures()).collect_vec();
+        let picture = pictures.iter()
+            .find(|pic| pic.pic_type() == lofty::PictureType::CoverFront)
+            .or_else(|| pictures.first());
+        if let Some(picture) = picture {
+            return Ok(Some(("embedded", picture.data().to_vec())));
+        }
+    }
+
+    //2. fall back to a cover image next to the files
+    let dirs = files.iter()
+        .filter_map(|file| Path::new(file).parent())
+        .unique();
+    for dir in dirs {
+        for name in COVER_FILE_NAMES {
+            let candidate = dir.join(name);
+            if candidate.is_file() {
+                return Ok(Some(("cover_file", std::fs::read(candidate)?)));
+            }
+        }
+    }
+
+    Ok(None)
+}
@@ -17,52 +17,71 @@
 use diesel::prelude::*;
 use itertools::Itertools;
 
-use crate::db_new::{DbApi, DbError, DbPool, Result, SetFavedState};
+use crate::db_new::{lower, faved_at_for, DbApi, DbError, DbPool, Result, SetFavedState};
 use crate::db_new::{FindByFavedStatus, FindById};
 use crate::db_new::models::{Album, AlbumArtists, AlbumOfWeek, Artist, NewAlbum, Track};
+use crate::db_new::library_event::LibraryEventDb;
 use crate::db_new::schema::*;
-use crate::model::{RequestPage, UniversalId};
+use crate::model::{LibraryEntity, LibraryEventKind, ListOrder, RequestPage, UniversalId};
 
 pub trait AlbumDb: FindById<Album> + FindByFavedStatus<Album> + SetFavedState<Album> + Sync {
     fn new_full_album(&self, new_album: NewAlbum) -> Result<Album>;
+    fn new_full_album_if_missing(&self, new_album: NewAlbum) -> Result<Album>;
     fn find_by_artist_and_name(&self, artist: &Artist, name: &str) -> Result<Option<Album>>;
     fn find_by_universal_id(&self, id : &UniversalId) -> Result<Option<Album>>;
     fn load_album_for_track(&self, track: &Track) -> Result<Album>;
     fn load_albums_for_tracks(&self, tracks : &[Track]) -> Result<Vec<Album>>;
     fn load_album_for_aow(&self, aow: &AlbumOfWeek) -> Result<Album>;
-    fn load_albums(&self, page : &RequestPage) -> Result<Vec<Album>>;
+    fn load_albums(&self, page : &RequestPage, order : ListOrder) -> Result<Vec<Album>>;
     fn set_was_aow(&self, album: &Album, was_aow: bool) -> Result<Album>;
 }
 
 impl AlbumDb for DbApi {
     fn new_full_album(&self, new_album: NewAlbum) -> Result<Album> {
-        let conn = self.0.get()?;
-        let result = diesel::insert_into(albums::table)
-            .values(&new_album)
-            .get_result(&conn);
-        Ok(result?)
+        self.transaction(|tx| {
+            let album: Album = insert_returning!(tx.0, albums::table, &new_album)?;
+            tx.new_library_event(LibraryEntity::Album, album.album_id, LibraryEventKind::Add)?;
+            Ok(album)
+        })
+    }
+
+    fn new_full_album_if_missing(&self, new_album: NewAlbum) -> Result<Album> {
+        //only the spotify id is unique, albums without one can't collide
+        let spot_id = match &new_album.spot_id {
+            Some(spot_id) => spot_id.clone(),
+            None => return self.new_full_album(new_album)
+        };
+        self.transaction(|tx| {
+            let inserted = insert_if_missing!(tx.0, albums::table, &new_album, albums::spot_id)?;
+            let album: Album = with_conn!(tx.0, conn => albums::table
+                .filter(albums::spot_id.eq(&spot_id))
+                .first(conn))?;
+            if inserted == 1 {
+                tx.new_library_event(LibraryEntity::Album, album.album_id, LibraryEventKind::Add)?;
+            }
+            Ok(album)
+        })
     }
 
     fn find_by_artist_and_name(&self, artist: &Artist, name: &str) -> Result<Option<Album>> {
-        use diesel::dsl::any;
-        let conn = self.0.get()?;
-        let album_ids = AlbumArtists::belonging_to(artist).select(album_artists::album_id);
-        let result = albums::table
-            .filter(albums::album_id.eq(any(album_ids)))
-            .filter(albums::name.ilike(name))
-            .first(&conn)
-            .optional();
-        Ok(result?)
+        with_conn!(self.0, conn => {
+            let album_ids = AlbumArtists::belonging_to(artist).select(album_artists::album_id);
+            let result = albums::table
+                .filter(albums::album_id.eq_any(album_ids))
+                .filter(lower(albums::name).eq(lower(name)))
+                .first(conn)
+                .optional();
+            Ok(result?)
+        })
     }
 
     fn find_by_universal_id(&self, id: &UniversalId) -> Result<Option<Album>> {
         match id {
             UniversalId::Spotify(spot_id) => {
-                let conn = self.0.get()?;
-                let result = albums::table
-                    .filter(albums::spot_id.like(spot_id))
-                    .first(&conn)
-                    .optional();
+                let result = with_conn!(self.0, conn => albums::table
+                    .filter(albums::spot_id.eq(spot_id))
+                    .first(conn)
+                    .optional());
                 Ok(result?)
             },
             UniversalId::Database(album_id) => self.find_by_id(*album_id)
@@ -83,42 +102,51 @@ impl AlbumDb for DbApi {
         self.find_by_ids(ids)
     }
 
-    fn load_albums(&self, page: &RequestPage) -> Result<Vec<Album>> {
-        let conn = self.0.get()?;
-        let results = albums::table
-            .offset(page.offset())
-            .limit(page.limit())
-            .load::<Album>(&conn);
-        Ok(results?)
+    fn load_albums(&self, page: &RequestPage, order: ListOrder) -> Result<Vec<Album>> {
+        let result = with_conn!(self.0, conn => {
+            let query = match order {
+                ListOrder::Default => albums::table.into_boxed(),
+                ListOrder::RecentlyAdded => albums::table
+                    .order_by(albums::created_at.desc())
+                    .into_boxed(),
+                ListOrder::RecentlyFaved => albums::table
+                    .filter(albums::faved_at.is_not_null())
+                    .order_by(albums::faved_at.desc())
+                    .into_boxed()
+            };
+            query
+                .offset(page.offset())
+                .limit(page.limit())
+                .load::<Album>(conn)
+        });
+        Ok(result?)
     }
 
     fn set_was_aow(&self, album: &Album, was_aow: bool) -> Result<Album> {
-        let conn = self.0.get()?;
-        let result = diesel::update(
-            albums::table.filter(albums::album_id.eq(album.album_id))
-        ).set(albums::was_aow.eq(was_aow))
-            .get_result(&conn);
-        Ok(result?)
+        with_conn!(self.0, conn => {
+            diesel::update(
+                albums::table.filter(albums::album_id.eq(album.album_id))
+            ).set(albums::was_aow.eq(was_aow))
+                .execute(conn)?;
+            Ok(albums::table.find(album.album_id).first(conn)?)
+        })
     }
 }
 
 impl FindById<Album> for DbApi {
     fn find_by_id(&self, id: i32) -> Result<Option<Album>> {
         use crate::db_new::schema::albums::dsl::*;
-        let conn = self.0.get()?;
-        let result = albums
+        let result = with_conn!(self.0, conn => albums
             .find(id)
-            .first(&conn)
-            .optional();
+            .first(conn)
+            .optional());
         Ok(result?)
     }
 
     fn find_by_ids(&self, ids : Vec<i32>) -> Result<Vec<Album>> {
-        use diesel::dsl::any;
-        let conn = self.0.get()?;
-        let result = albums::table
-            .filter(albums::album_id.eq(any(ids)))
-            .load::<Album>(&conn);
+        let result = with_conn!(self.0, conn => albums::table
+            .filter(albums::album_id.eq_any(ids))
+            .load::<Album>(conn));
         Ok(result?)
     }
 }
@@ -135,26 +163,32 @@ impl FindByFavedStatus<Album> for DbApi {
 
 impl SetFavedState<Album> for DbApi {
     fn set_faved_state(&self, album_id: i32, now_faved: bool) -> Result<()> {
-        let conn = self.0.get()?;
-        let updated = diesel::update(
-            albums::table.filter(albums::album_id.eq(album_id))
-        ).set(albums::is_faved.eq(now_faved))
-            .execute(&conn)?;
-        if updated == 1 {
-            Ok(())
-        }else{
-            Err(DbError::Update(format!("Failed to set fav state on album {} to {}!", album_id, now_faved)))
-        }
+        self.transaction(|tx| {
+            let updated = with_conn!(tx.0, conn => diesel::update(
+                albums::table
+                    .filter(albums::album_id.eq(album_id))
+                    .filter(albums::is_faved.ne(now_faved))
+            ).set((albums::is_faved.eq(now_faved), albums::faved_at.eq(faved_at_for(now_faved))))
+                .execute(conn))?;
+
+            if updated == 1 {
+                tx.new_library_event(LibraryEntity::Album, album_id, LibraryEventKind::for_fav_state(now_faved))
+            }else if FindById::<Album>::find_by_id(tx, album_id)?.is_some() {
+                //already in the requested state, nothing changed
+                Ok(())
+            }else{
+                Err(DbError::Update(format!("Failed to set fav state on album {} to {}!", album_id, now_faved)))
+            }
+        })
     }
 }
 
 fn _find_by_fav_status(pool: &DbPool, faved: bool, offset: i64, limit: i64) -> Result<Vec<Album>> {
     use crate::db_new::schema::albums::dsl::*;
-    let conn = pool.get()?;
-    let results = albums
+    let results = with_conn!(*pool, conn => albums
         .filter(is_faved.eq(faved))
         .limit(limit)
         .offset(offset)
-        .load::<Album>(&conn);
+        .load::<Album>(conn));
     Ok(results?)
 }
\ No newline at end of file
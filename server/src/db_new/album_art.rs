@@ -0,0 +1,125 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+
+use crate::db_new::{DbApi, DbError, Result};
+use crate::db_new::models::{AlbumArt, NewAlbumArt, NewAlbumArtLookup, Track};
+use crate::db_new::schema::*;
+
+pub trait AlbumArtDb: Sync {
+    fn new_album_art(&self, new_art: NewAlbumArt) -> Result<AlbumArt>;
+    fn find_album_art(&self, album_id: i32, size: i32) -> Result<Option<AlbumArt>>;
+    /// Originals still to be downloaded, skipping albums whose lookup failed after tried_before
+    fn load_pending_album_art(&self, tried_before: NaiveDateTime, limit: i64) -> Result<Vec<AlbumArt>>;
+    /// Local tracks of albums without any artwork, skipping albums whose lookup failed after tried_before
+    fn load_local_tracks_without_art(&self, tried_before: NaiveDateTime, limit: i64) -> Result<Vec<Track>>;
+    fn set_album_art_file(&self, art_id: i32, file_path: &str, content_type: &str) -> Result<()>;
+    /// Remembers that no artwork could be found or downloaded for the album
+    fn record_failed_album_art_lookup(&self, album_id: i32) -> Result<()>;
+}
+
+impl AlbumArtDb for DbApi {
+    fn new_album_art(&self, new_art: NewAlbumArt) -> Result<AlbumArt> {
+        let result = insert_returning!(self.0, album_art::table, &new_art);
+        Ok(result?)
+    }
+
+    fn find_album_art(&self, album_id: i32, size: i32) -> Result<Option<AlbumArt>> {
+        let result = with_conn!(self.0, conn => album_art::table
+            .filter(album_art::album_id.eq(album_id))
+            .filter(album_art::size.eq(size))
+            .first(conn)
+            .optional());
+        Ok(result?)
+    }
+
+    fn load_pending_album_art(&self, tried_before: NaiveDateTime, limit: i64) -> Result<Vec<AlbumArt>> {
+        let result = with_conn!(self.0, conn => {
+            let recently_tried = album_art_lookups::table
+                .filter(album_art_lookups::tried_at.gt(tried_before))
+                .select(album_art_lookups::album_id);
+            album_art::table
+                .filter(album_art::size.eq(0))
+                .filter(album_art::file_path.is_null())
+                .filter(album_art::source_url.is_not_null())
+                .filter(album_art::album_id.ne_all(recently_tried))
+                .order_by(album_art::album_id.asc())
+                .limit(limit)
+                .load::<AlbumArt>(conn)
+        });
+        Ok(result?)
+    }
+
+    fn load_local_tracks_without_art(&self, tried_before: NaiveDateTime, limit: i64) -> Result<Vec<Track>> {
+        with_conn!(self.0, conn => {
+            let albums_with_art = album_art::table.select(album_art::album_id);
+            let recently_tried = album_art_lookups::table
+                .filter(album_art_lookups::tried_at.gt(tried_before))
+                .select(album_art_lookups::album_id);
+            let album_ids = tracks::table
+                .filter(tracks::local_file.is_not_null())
+                .filter(tracks::album_id.ne_all(albums_with_art))
+                .filter(tracks::album_id.ne_all(recently_tried))
+                .select(tracks::album_id)
+                .distinct()
+                .order_by(tracks::album_id.asc())
+                .limit(limit)
+                .load::<i32>(conn)?;
+
+            let result = tracks::table
+                .filter(tracks::local_file.is_not_null())
+                .filter(tracks::album_id.eq_any(album_ids))
+                .order((tracks::album_id.asc(), tracks::disc_number.asc(), tracks::track_number.asc()))
+                .load::<Track>(conn);
+            Ok(result?)
+        })
+    }
+
+    fn set_album_art_file(&self, art_id: i32, file_path: &str, content_type: &str) -> Result<()> {
+        with_conn!(self.0, conn => {
+            let updated = diesel::update(
+                album_art::table.filter(album_art::art_id.eq(art_id))
+            ).set((
+                album_art::file_path.eq(file_path),
+                album_art::content_type.eq(content_type)
+            )).execute(conn)?;
+
+            if updated == 1 {
+                Ok(())
+            }else{
+                Err(DbError::Update(format!("Failed to set file of album art {} to {}", art_id, file_path)))
+            }
+        })
+    }
+
+    fn record_failed_album_art_lookup(&self, album_id: i32) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        let result = with_conn!(self.0, conn => conn.transaction::<_, diesel::result::Error, _>(|| {
+            let updated = diesel::update(album_art_lookups::table.find(album_id))
+                .set((album_art_lookups::attempts.eq(album_art_lookups::attempts + 1), album_art_lookups::tried_at.eq(now)))
+                .execute(conn)?;
+            if updated == 0 {
+                diesel::insert_into(album_art_lookups::table)
+                    .values(&NewAlbumArtLookup { album_id, tried_at: now })
+                    .execute(conn)?;
+            }
+            Ok(())
+        }));
+        Ok(result?)
+    }
+}
@@ -26,78 +26,95 @@ pub trait AlbumArtistsDb : Sync {
     fn new_album_artist(&self, artist_id: i32, album_id: i32) -> Result<AlbumArtists>;
     fn new_album_artist_if_missing(&self, artist_id: i32, album_id: i32) -> Result<AlbumArtists>;
     fn load_albums_for_artist(&self, artist: &Artist) -> Result<Vec<Album>>;
+    fn load_appears_on_albums_for_artist(&self, artist: &Artist) -> Result<Vec<Album>>;
     fn load_artists_for_album(&self, album: &Album) -> Result<Vec<Artist>>;
     fn load_artist_ids_for_albums(&self, albums : &[Album]) -> Result<HashMap<i32, Vec<i32>>>;
 }
 
 impl AlbumArtistsDb for DbApi {
     fn new_album_artist(&self, artist_id: i32, album_id: i32) -> Result<AlbumArtists> {
-        let conn = self.0.get()?;
-        let result = diesel::insert_into(album_artists::table)
-            .values(&NewAlbumArtists {
-                album_id,
-                artist_id,
-            })
-            .get_result(&conn);
+        let result = insert_returning!(self.0, album_artists::table, &NewAlbumArtists {
+            album_id,
+            artist_id,
+        });
         Ok(result?)
     }
 
     fn new_album_artist_if_missing(&self, artist_id: i32, album_id: i32) -> Result<AlbumArtists> {
-        let conn = self.0.get()?;
-        let result = album_artists::table
+        insert_if_missing!(self.0, album_artists::table, &NewAlbumArtists {
+            artist_id,
+            album_id,
+        }, (album_artists::artist_id, album_artists::album_id))?;
+
+        let result = with_conn!(self.0, conn => album_artists::table
             .filter(album_artists::artist_id.eq(artist_id))
             .filter(album_artists::album_id.eq(album_id))
-            .first(&conn)
-            .optional()?;
-
-        match result {
-            Some(link) => Ok(link),
-            None => self.new_album_artist(artist_id, album_id)
-        }
+            .first(conn));
+        Ok(result?)
     }
 
     fn load_albums_for_artist(&self, artist: &Artist) -> Result<Vec<Album>> {
         use crate::db_new::schema::album_artists::dsl::*;
-        use diesel::dsl::any;
 
-        let conn = self.0.get()?;
-        let album_ids = AlbumArtists::belonging_to(artist).select(album_id);
-        let result = albums::table
-            .filter(albums::album_id.eq(any(album_ids)))
-            .load::<Album>(&conn);
-        Ok(result?)
+        with_conn!(self.0, conn => {
+            let album_ids = AlbumArtists::belonging_to(artist).select(album_id);
+            let result = albums::table
+                .filter(albums::album_id.eq_any(album_ids))
+                .order_by(albums::year.desc())
+                .load::<Album>(conn);
+            Ok(result?)
+        })
+    }
+
+    fn load_appears_on_albums_for_artist(&self, artist: &Artist) -> Result<Vec<Album>> {
+        //albums with tracks featuring the artist without the artist being an album artist
+        with_conn!(self.0, conn => {
+            let own_album_ids = AlbumArtists::belonging_to(artist).select(album_artists::album_id);
+            let track_ids = track_artist::table
+                .filter(track_artist::artist_id.eq(artist.artist_id))
+                .select(track_artist::track_id);
+            let album_ids = tracks::table
+                .filter(tracks::track_id.eq_any(track_ids))
+                .select(tracks::album_id);
+            let result = albums::table
+                .filter(albums::album_id.eq_any(album_ids))
+                .filter(albums::album_id.ne_all(own_album_ids))
+                .order_by(albums::year.desc())
+                .load::<Album>(conn);
+            Ok(result?)
+        })
     }
 
     fn load_artists_for_album(&self, album: &Album) -> Result<Vec<Artist>> {
         use crate::db_new::schema::album_artists::dsl::*;
-        use diesel::dsl::any;
 
-        let conn = self.0.get()?;
-        let artist_ids = AlbumArtists::belonging_to(album).select(artist_id);
-        let result = artists::table
-            .filter(artists::artist_id.eq(any(artist_ids)))
-            .load::<Artist>(&conn);
-        Ok(result?)
+        with_conn!(self.0, conn => {
+            let artist_ids = AlbumArtists::belonging_to(album).select(artist_id);
+            let result = artists::table
+                .filter(artists::artist_id.eq_any(artist_ids))
+                .load::<Artist>(conn);
+            Ok(result?)
+        })
     }
 
     fn load_artist_ids_for_albums(&self, albums: &[Album]) -> Result<HashMap<i32, Vec<i32>>> {
-        use diesel::dsl::any;
         let album_ids = albums.iter().map(|a| a.album_id).unique().collect_vec();
-        let conn = self.0.get()?;
-        let album_artists = track_artist::table
-            .filter(track_artist::track_id.eq(any(album_ids)))
-            .load::<AlbumArtists>(&conn)?;
+        with_conn!(self.0, conn => {
+            let album_artists = track_artist::table
+                .filter(track_artist::track_id.eq_any(album_ids))
+                .load::<AlbumArtists>(conn)?;
 
-        let mut map : HashMap<i32, Vec<i32>> = HashMap::new();
-        for album_artist in &album_artists {
-            match map.get_mut(&album_artist.album_id) {
-                Some(artists) => artists.insert(0, album_artist.artist_id),
-                None => {
-                    map.insert(album_artist.album_id, vec![album_artist.artist_id]);
+            let mut map : HashMap<i32, Vec<i32>> = HashMap::new();
+            for album_artist in &album_artists {
+                match map.get_mut(&album_artist.album_id) {
+                    Some(artists) => artists.insert(0, album_artist.artist_id),
+                    None => {
+                        map.insert(album_artist.album_id, vec![album_artist.artist_id]);
+                    }
                 }
             }
-        }
 
-        Ok(map)
+            Ok(map)
+        })
     }
 }
\ No newline at end of file
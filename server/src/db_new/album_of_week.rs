@@ -15,54 +15,59 @@
  */
 use diesel::prelude::*;
 
-use crate::db_new::{DbApi, Result};
+use crate::db_new::{lower, DbApi, Result};
 use crate::db_new::FindById;
-use crate::db_new::models::{AlbumOfWeek, NewAlbumOfWeek};
+use crate::db_new::models::{AlbumArtists, AlbumOfWeek, Artist, NewAlbumOfWeek};
 use crate::db_new::schema::*;
 
 pub trait AlbumOfWeekDb : FindById<AlbumOfWeek> {
     fn new_album_of_week(&self, new_aow : NewAlbumOfWeek) -> Result<AlbumOfWeek>;
     fn find_by_source_and_week(&self, source : &str, year : i32, week : i32) -> Result<Option<AlbumOfWeek>>;
+    fn load_albums_of_week_for_artist(&self, artist : &Artist) -> Result<Vec<AlbumOfWeek>>;
 }
 
 impl AlbumOfWeekDb for DbApi {
     fn new_album_of_week(&self, new_aow: NewAlbumOfWeek) -> Result<AlbumOfWeek> {
-        let conn = self.0.get()?;
-        let result = diesel::insert_into(albums_of_week::table)
-            .values(&new_aow)
-            .get_result(&conn);
+        let result = insert_returning!(self.0, albums_of_week::table, &new_aow);
         Ok(result?)
     }
 
     fn find_by_source_and_week(&self, source: &str, year: i32, week: i32) -> Result<Option<AlbumOfWeek>> {
-        let conn = self.0.get()?;
-        let result = albums_of_week::table
+        let result = with_conn!(self.0, conn => albums_of_week::table
             .filter(albums_of_week::year.eq(year))
             .filter(albums_of_week::week.eq(week))
-            .filter(albums_of_week::source_name.ilike(source))
-            .first(&conn)
-            .optional();
+            .filter(lower(albums_of_week::source_name).eq(lower(source)))
+            .first(conn)
+            .optional());
         Ok(result?)
     }
+
+    fn load_albums_of_week_for_artist(&self, artist: &Artist) -> Result<Vec<AlbumOfWeek>> {
+        with_conn!(self.0, conn => {
+            let album_ids = AlbumArtists::belonging_to(artist).select(album_artists::album_id);
+            let result = albums_of_week::table
+                .filter(albums_of_week::album_id.eq_any(album_ids))
+                .order_by((albums_of_week::year.desc(), albums_of_week::week.desc()))
+                .load::<AlbumOfWeek>(conn);
+            Ok(result?)
+        })
+    }
 }
 
 impl FindById<AlbumOfWeek> for DbApi {
     fn find_by_id(&self, id: i32) -> Result<Option<AlbumOfWeek>> {
         use crate::db_new::schema::albums_of_week::dsl::*;
-        let conn = self.0.get()?;
-        let result = albums_of_week
+        let result = with_conn!(self.0, conn => albums_of_week
             .find(id)
-            .first(&conn)
-            .optional();
+            .first(conn)
+            .optional());
         Ok(result?)
     }
 
     fn find_by_ids(&self, ids: Vec<i32>) -> Result<Vec<AlbumOfWeek>> {
-        use diesel::dsl::any;
-        let conn = self.0.get()?;
-        let result = albums_of_week::table
-            .filter(albums_of_week::aow_id.eq(any(ids)))
-            .load::<AlbumOfWeek>(&conn);
+        let result = with_conn!(self.0, conn => albums_of_week::table
+            .filter(albums_of_week::aow_id.eq_any(ids))
+            .load::<AlbumOfWeek>(conn));
         Ok(result?)
     }
 }
\ No newline at end of file
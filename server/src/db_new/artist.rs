@@ -14,79 +14,145 @@
  * limitations under the License.
  */
 
+use std::collections::HashMap;
+
 use diesel::prelude::*;
+use itertools::Itertools;
 
-use crate::db_new::{DbApi, DbError, DbPool, Result, SetFavedState};
+use crate::db_new::{faved_at_for, DbApi, DbError, DbPool, Result, SetFavedState};
 use crate::db_new::{FindByFavedStatus, FindById};
 use crate::db_new::models::{Artist, NewArtist};
+use crate::db_new::library_event::LibraryEventDb;
 use crate::db_new::schema::*;
-use crate::model::{RequestPage, UniversalId};
+use crate::model::{LibraryEntity, LibraryEventKind, ListOrder, RequestPage, UniversalId};
 
 pub trait ArtistDb: FindById<Artist> + FindByFavedStatus<Artist> + SetFavedState<Artist> {
     fn new_full_artist(&self, new_artist: NewArtist) -> Result<Artist>;
     fn find_artist_by_name(&self, name: &str) -> Result<Option<Artist>>;
     fn find_artist_by_universal_id(&self, id : &UniversalId) -> Result<Option<Artist>>;
-    fn load_artists(&self, page : &RequestPage) -> Result<Vec<Artist>>;
+    fn load_artists(&self, page : &RequestPage, order : ListOrder) -> Result<Vec<Artist>>;
+    fn load_related_artists(&self, artist : &Artist, limit : usize) -> Result<Vec<Artist>>;
 }
 
 impl ArtistDb for DbApi {
 
     fn new_full_artist(&self, new_artist: NewArtist) -> Result<Artist> {
-        let conn = self.0.get()?;
-        let result = diesel::insert_into(artists::table)
-            .values(&new_artist)
-            .get_result(&conn);
-        Ok(result?)
+        self.transaction(|tx| {
+            let artist: Artist = insert_returning!(tx.0, artists::table, &new_artist)?;
+            tx.new_library_event(LibraryEntity::Artist, artist.artist_id, LibraryEventKind::Add)?;
+            Ok(artist)
+        })
     }
 
 
     fn find_artist_by_name(&self, name: &str) -> Result<Option<Artist>> {
-        let conn = self.0.get()?;
-        let result = artists::table
+        let result = with_conn!(self.0, conn => artists::table
             .filter(artists::name.eq(name))
-            .first(&conn)
-            .optional();
+            .first(conn)
+            .optional());
         Ok(result?)
     }
 
     fn find_artist_by_universal_id(&self, id: &UniversalId) -> Result<Option<Artist>> {
         match id {
             UniversalId::Spotify(spot_id) => {
-                let conn = self.0.get()?;
-                let result = artists::table
-                    .filter(artists::spot_id.like(spot_id))
-                    .first(&conn)
-                    .optional();
+                let result = with_conn!(self.0, conn => artists::table
+                    .filter(artists::spot_id.eq(spot_id))
+                    .first(conn)
+                    .optional());
                 Ok(result?)
             },
             UniversalId::Database(artist_id) => self.find_by_id(*artist_id)
         }
     }
 
-    fn load_artists(&self, page: &RequestPage) -> Result<Vec<Artist>> {
-        let conn = self.0.get()?;
-        let result = artists::table.offset(page.offset()).limit(page.limit()).load::<Artist>(&conn);
+    fn load_artists(&self, page: &RequestPage, order: ListOrder) -> Result<Vec<Artist>> {
+        let result = with_conn!(self.0, conn => {
+            let query = match order {
+                ListOrder::Default => artists::table.into_boxed(),
+                ListOrder::RecentlyAdded => artists::table
+                    .order_by(artists::created_at.desc())
+                    .into_boxed(),
+                ListOrder::RecentlyFaved => artists::table
+                    .filter(artists::faved_at.is_not_null())
+                    .order_by(artists::faved_at.desc())
+                    .into_boxed()
+            };
+            query
+                .offset(page.offset())
+                .limit(page.limit())
+                .load::<Artist>(conn)
+        });
         Ok(result?)
     }
+
+    fn load_related_artists(&self, artist: &Artist, limit: usize) -> Result<Vec<Artist>> {
+        with_conn!(self.0, conn => {
+
+            //1. artists sharing genres with the given one
+            let genre_ids = artist_genre::table
+                .filter(artist_genre::artist_id.eq(artist.artist_id))
+                .select(artist_genre::genre_id)
+                .load::<i32>(conn)?;
+            let genre_peers = artist_genre::table
+                .filter(artist_genre::genre_id.eq_any(genre_ids))
+                .filter(artist_genre::artist_id.ne(artist.artist_id))
+                .select(artist_genre::artist_id)
+                .load::<i32>(conn)?;
+
+            //2. artists credited on the same tracks
+            let track_ids = track_artist::table
+                .filter(track_artist::artist_id.eq(artist.artist_id))
+                .select(track_artist::track_id)
+                .load::<i32>(conn)?;
+            let co_credited = track_artist::table
+                .filter(track_artist::track_id.eq_any(track_ids))
+                .filter(track_artist::artist_id.ne(artist.artist_id))
+                .select(track_artist::artist_id)
+                .load::<i32>(conn)?;
+
+            //3. rank by shared genres, a shared track weighs twice
+            let mut scores: HashMap<i32, usize> = HashMap::new();
+            for artist_id in genre_peers {
+                *scores.entry(artist_id).or_insert(0) += 1;
+            }
+            for artist_id in co_credited {
+                *scores.entry(artist_id).or_insert(0) += 2;
+            }
+            let ranked_ids = scores.into_iter()
+                .sorted_by(|(id_a, score_a), (id_b, score_b)| score_b.cmp(score_a).then(id_a.cmp(id_b)))
+                .map(|(artist_id, _)| artist_id)
+                .take(limit)
+                .collect_vec();
+
+            let related = artists::table
+                .filter(artists::artist_id.eq_any(ranked_ids.clone()))
+                .load::<Artist>(conn)?;
+            let mut related: HashMap<i32, Artist> = related
+                .into_iter()
+                .map(|a| (a.artist_id, a))
+                .collect();
+            Ok(ranked_ids.iter()
+                .filter_map(|id| related.remove(id))
+                .collect_vec())
+        })
+    }
 }
 
 impl FindById<Artist> for DbApi {
     fn find_by_id(&self, id: i32) -> Result<Option<Artist>> {
         use crate::db_new::schema::artists::dsl::*;
-        let conn = self.0.get()?;
-        let result = artists
+        let result = with_conn!(self.0, conn => artists
             .find(id)
-            .first(&conn)
-            .optional();
+            .first(conn)
+            .optional());
         Ok(result?)
     }
 
     fn find_by_ids(&self, ids : Vec<i32>) -> Result<Vec<Artist>> {
-        use diesel::dsl::any;
-        let conn = self.0.get()?;
-        let result = artists::table
-            .filter(artists::artist_id.eq(any(ids)))
-            .load::<Artist>(&conn);
+        let result = with_conn!(self.0, conn => artists::table
+            .filter(artists::artist_id.eq_any(ids))
+            .load::<Artist>(conn));
         Ok(result?)
     }
 }
@@ -103,26 +169,32 @@ impl FindByFavedStatus<Artist> for DbApi {
 
 impl SetFavedState<Artist> for DbApi {
     fn set_faved_state(&self, artist_id: i32, now_faved: bool) -> Result<()> {
-        let conn = self.0.get()?;
-        let updated = diesel::update(
-            artists::table.filter(artists::artist_id.eq(artist_id))
-        ).set(artists::is_faved.eq(now_faved))
-            .execute(&conn)?;
-        if updated == 1 {
-            Ok(())
-        }else{
-            Err(DbError::Update(format!("Failed to set artist {} to fav state {}!", artist_id, now_faved)))
-        }
+        self.transaction(|tx| {
+            let updated = with_conn!(tx.0, conn => diesel::update(
+                artists::table
+                    .filter(artists::artist_id.eq(artist_id))
+                    .filter(artists::is_faved.ne(now_faved))
+            ).set((artists::is_faved.eq(now_faved), artists::faved_at.eq(faved_at_for(now_faved))))
+                .execute(conn))?;
+
+            if updated == 1 {
+                tx.new_library_event(LibraryEntity::Artist, artist_id, LibraryEventKind::for_fav_state(now_faved))
+            }else if FindById::<Artist>::find_by_id(tx, artist_id)?.is_some() {
+                //already in the requested state, nothing changed
+                Ok(())
+            }else{
+                Err(DbError::Update(format!("Failed to set artist {} to fav state {}!", artist_id, now_faved)))
+            }
+        })
     }
 }
 
 fn _find_by_fav_status(pool: &DbPool, faved: bool, offset: i64, limit: i64) -> Result<Vec<Artist>> {
     use crate::db_new::schema::artists::dsl::*;
-    let conn = pool.get()?;
-    let results = artists
+    let results = with_conn!(*pool, conn => artists
         .filter(is_faved.eq(faved))
         .limit(limit)
         .offset(offset)
-        .load::<Artist>(&conn);
+        .load::<Artist>(conn));
     Ok(results?)
 }
\ No newline at end of file
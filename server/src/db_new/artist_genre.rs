@@ -17,50 +17,90 @@
 use diesel::prelude::*;
 
 use crate::db_new::{DbApi, Result};
-use crate::db_new::models::{Artist, ArtistGenre, Genre, NewArtistGenre};
+use crate::db_new::models::{Album, Artist, ArtistGenre, Genre, NewArtistGenre, Track};
 use crate::db_new::schema::*;
+use crate::model::RequestPage;
 
 pub trait ArtistGenreDb: Sync {
-    fn new_artist_genre(&self, artist_id: i32, genre_id: i32) -> Result<ArtistGenre>;
+    fn new_artist_genre_if_missing(&self, artist_id: i32, genre_id: i32) -> Result<ArtistGenre>;
     fn load_artists_for_genre(&self, genre: &Genre, offset: i64, limit: i64) -> Result<Vec<Artist>>;
+    fn load_albums_for_genre(&self, genre: &Genre, page: &RequestPage) -> Result<Vec<Album>>;
+    fn load_tracks_for_genre(&self, genre: &Genre, page: &RequestPage) -> Result<Vec<Track>>;
     fn load_genres_for_artist(&self, artist: &Artist) -> Result<Vec<Genre>>;
 }
 
 impl ArtistGenreDb for DbApi {
-    fn new_artist_genre(&self, artist_id: i32, genre_id: i32) -> Result<ArtistGenre> {
-        let conn = self.0.get()?;
-        let result = diesel::insert_into(artist_genre::table)
-            .values(&NewArtistGenre {
-                artist_id,
-                genre_id,
-            })
-            .get_result(&conn);
+    fn new_artist_genre_if_missing(&self, artist_id: i32, genre_id: i32) -> Result<ArtistGenre> {
+        insert_if_missing!(self.0, artist_genre::table, &NewArtistGenre {
+            artist_id,
+            genre_id,
+        }, (artist_genre::artist_id, artist_genre::genre_id))?;
+
+        let result = with_conn!(self.0, conn => artist_genre::table
+            .filter(artist_genre::artist_id.eq(artist_id))
+            .filter(artist_genre::genre_id.eq(genre_id))
+            .first(conn));
         Ok(result?)
     }
 
     fn load_artists_for_genre(&self, genre: &Genre, offset: i64, limit: i64) -> Result<Vec<Artist>> {
         use crate::db_new::schema::artist_genre::dsl::*;
-        use diesel::dsl::any;
 
-        let conn = self.0.get()?;
-        let genre_artist_ids = ArtistGenre::belonging_to(genre).select(artist_id);
-        let result = artists::table
-            .filter(artists::artist_id.eq(any(genre_artist_ids)))
-            .limit(limit)
-            .offset(offset)
-            .load::<Artist>(&conn);
-        Ok(result?)
+        with_conn!(self.0, conn => {
+            let genre_artist_ids = ArtistGenre::belonging_to(genre).select(artist_id);
+            let result = artists::table
+                .filter(artists::artist_id.eq_any(genre_artist_ids))
+                .limit(limit)
+                .offset(offset)
+                .load::<Artist>(conn);
+            Ok(result?)
+        })
+    }
+
+    fn load_albums_for_genre(&self, genre: &Genre, page: &RequestPage) -> Result<Vec<Album>> {
+        //an album belongs to a genre as soon as one of its artists does
+        with_conn!(self.0, conn => {
+            let genre_artist_ids = ArtistGenre::belonging_to(genre).select(artist_genre::artist_id);
+            let album_ids = album_artists::table
+                .filter(album_artists::artist_id.eq_any(genre_artist_ids))
+                .select(album_artists::album_id);
+            let result = albums::table
+                .filter(albums::album_id.eq_any(album_ids))
+                .order_by(albums::name.asc())
+                .limit(page.limit())
+                .offset(page.offset())
+                .load::<Album>(conn);
+            Ok(result?)
+        })
+    }
+
+    fn load_tracks_for_genre(&self, genre: &Genre, page: &RequestPage) -> Result<Vec<Track>> {
+
+        with_conn!(self.0, conn => {
+            let genre_artist_ids = ArtistGenre::belonging_to(genre).select(artist_genre::artist_id);
+            let track_ids = track_artist::table
+                .filter(track_artist::artist_id.eq_any(genre_artist_ids))
+                .select(track_artist::track_id);
+            let result = tracks::table
+                .filter(tracks::track_id.eq_any(track_ids))
+                .order_by(tracks::title.asc())
+                .limit(page.limit())
+                .offset(page.offset())
+                .load::<Track>(conn);
+            Ok(result?)
+        })
     }
 
     fn load_genres_for_artist(&self, artist: &Artist) -> Result<Vec<Genre>> {
         use crate::db_new::schema::artist_genre::dsl::*;
-        use diesel::dsl::any;
 
-        let conn = self.0.get()?;
-        let genre_artist_ids = ArtistGenre::belonging_to(artist).select(genre_id);
-        let result = genre::table
-            .filter(genre::genre_id.eq(any(genre_artist_ids)))
-            .load::<Genre>(&conn);
-        Ok(result?)
+        with_conn!(self.0, conn => {
+            let genre_artist_ids = ArtistGenre::belonging_to(artist).select(genre_id);
+            let result = genre::table
+                .filter(genre::genre_id.eq_any(genre_artist_ids))
+                .order_by(genre::name.asc())
+                .load::<Genre>(conn);
+            Ok(result?)
+        })
     }
 }
\ No newline at end of file
@@ -0,0 +1,144 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{HashMap, HashSet};
+
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::Integer;
+use itertools::Itertools;
+
+use crate::db_new::{DbApi, Result};
+use crate::db_new::models::Track;
+use crate::db_new::schema::*;
+
+//how far apart release years may be to still count as similar
+const SIMILAR_YEAR_RANGE: i32 = 2;
+
+/// A playable library track sharing artists, genres or the release era with a seed track
+#[derive(Debug)]
+pub struct SimilarTrack {
+    pub track: Track,
+    pub shared_artists: usize,
+    pub shared_genres: usize,
+    //None if the release year isn't close to the one of the seed
+    pub year_distance: Option<i32>,
+}
+
+pub trait AutoplayDb : Sync {
+    /// Loads up to pool_size random candidates per kind of similarity; the seed itself is excluded
+    fn load_similar_tracks(&self, seed_track_id: i32, pool_size: i64) -> Result<Vec<SimilarTrack>>;
+}
+
+impl AutoplayDb for DbApi {
+    fn load_similar_tracks(&self, seed_track_id: i32, pool_size: i64) -> Result<Vec<SimilarTrack>> {
+        let result = with_conn!(self.0, conn => {
+            let seed_artists = track_artist::table
+                .filter(track_artist::track_id.eq(seed_track_id))
+                .select(track_artist::artist_id)
+                .load::<i32>(conn)?;
+            let seed_genres = artist_genre::table
+                .filter(artist_genre::artist_id.eq_any(&seed_artists))
+                .select(artist_genre::genre_id)
+                .load::<i32>(conn)?
+                .into_iter()
+                .collect::<HashSet<i32>>();
+            let seed_year = albums::table
+                .filter(albums::album_id.nullable().eq(tracks::table
+                    .filter(tracks::track_id.eq(seed_track_id))
+                    .select(tracks::album_id)
+                    .single_value()))
+                .select(albums::year)
+                .first::<i32>(conn)
+                .optional()?;
+
+            let playable = tracks::local_file.is_not_null().or(tracks::spot_id.is_not_null());
+            let mut candidates = tracks::table
+                .filter(tracks::track_id.ne(seed_track_id))
+                .filter(playable)
+                .filter(tracks::track_id.eq_any(track_artist::table
+                    .filter(track_artist::artist_id.eq_any(&seed_artists))
+                    .select(track_artist::track_id)))
+                .order_by(sql::<Integer>("random()"))
+                .limit(pool_size)
+                .load::<Track>(conn)?;
+            candidates.extend(tracks::table
+                .filter(tracks::track_id.ne(seed_track_id))
+                .filter(playable)
+                .filter(tracks::track_id.eq_any(track_artist::table
+                    .filter(track_artist::artist_id.eq_any(artist_genre::table
+                        .filter(artist_genre::genre_id.eq_any(seed_genres.iter().cloned().collect_vec()))
+                        .select(artist_genre::artist_id)))
+                    .select(track_artist::track_id)))
+                .order_by(sql::<Integer>("random()"))
+                .limit(pool_size)
+                .load::<Track>(conn)?);
+            if let Some(year) = seed_year {
+                candidates.extend(tracks::table
+                    .filter(tracks::track_id.ne(seed_track_id))
+                    .filter(playable)
+                    .filter(tracks::album_id.eq_any(albums::table
+                        .filter(albums::year.between(year - SIMILAR_YEAR_RANGE, year + SIMILAR_YEAR_RANGE))
+                        .select(albums::album_id)))
+                    .order_by(sql::<Integer>("random()"))
+                    .limit(pool_size)
+                    .load::<Track>(conn)?);
+            }
+            let candidates = candidates.into_iter()
+                .unique_by(|track| track.track_id)
+                .collect_vec();
+
+            //now gather what the candidates have in common with the seed
+            let track_artists = track_artist::table
+                .filter(track_artist::track_id.eq_any(candidates.iter().map(|t| t.track_id).collect_vec()))
+                .select((track_artist::track_id, track_artist::artist_id))
+                .load::<(i32, i32)>(conn)?;
+            let artist_genres = artist_genre::table
+                .filter(artist_genre::artist_id.eq_any(track_artists.iter().map(|(_, a)| *a).unique().collect_vec()))
+                .select((artist_genre::artist_id, artist_genre::genre_id))
+                .load::<(i32, i32)>(conn)?
+                .into_iter()
+                .into_group_map();
+            let album_years = albums::table
+                .filter(albums::album_id.eq_any(candidates.iter().map(|t| t.album_id).unique().collect_vec()))
+                .select((albums::album_id, albums::year))
+                .load::<(i32, i32)>(conn)?
+                .into_iter()
+                .collect::<HashMap<i32, i32>>();
+            let track_artists = track_artists.into_iter().into_group_map();
+
+            let similar = candidates.into_iter().map(|track| {
+                let artist_ids = track_artists.get(&track.track_id).cloned().unwrap_or_default();
+                let shared_artists = artist_ids.iter()
+                    .filter(|artist_id| seed_artists.contains(artist_id))
+                    .count();
+                let shared_genres = artist_ids.iter()
+                    .filter_map(|artist_id| artist_genres.get(artist_id))
+                    .flatten()
+                    .unique()
+                    .filter(|genre_id| seed_genres.contains(genre_id))
+                    .count();
+                let year_distance = match (seed_year, album_years.get(&track.album_id)) {
+                    (Some(seed), Some(year)) if (seed - year).abs() <= SIMILAR_YEAR_RANGE => Some((seed - year).abs()),
+                    _ => None
+                };
+                SimilarTrack { track, shared_artists, shared_genres, year_distance }
+            }).collect_vec();
+            Ok::<_, diesel::result::Error>(similar)
+        });
+        Ok(result?)
+    }
+}
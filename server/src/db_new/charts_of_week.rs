@@ -17,52 +17,40 @@ use diesel::prelude::*;
 
 use crate::db_new::{DbApi, Result};
 use crate::db_new::FindById;
-use crate::db_new::models::{ChartsOfWeekEntry, NewChartsOfWeek};
+use crate::db_new::models::{Artist, ChartsOfWeekEntry, TrackArtists};
 use crate::db_new::schema::*;
 
 pub trait ChartsOfWeekDb: FindById<ChartsOfWeekEntry> + Sync {
-    fn new_charts_of_week_entry(&self, new_charts_entry: NewChartsOfWeek) -> Result<ChartsOfWeekEntry>;
-    fn find_by_source_and_week(&self, source: &str, year: i32, week: i32) -> Result<Option<Vec<ChartsOfWeekEntry>>>;
+    fn load_chart_entries_for_artist(&self, artist: &Artist) -> Result<Vec<ChartsOfWeekEntry>>;
 }
 
 impl ChartsOfWeekDb for DbApi {
-    fn new_charts_of_week_entry(&self, new_charts_entry: NewChartsOfWeek) -> Result<ChartsOfWeekEntry> {
-        let conn = self.0.get()?;
-        let result = diesel::insert_into(charts_of_week::table)
-            .values(&new_charts_entry)
-            .get_result(&conn);
-        Ok(result?)
-    }
-
-    fn find_by_source_and_week(&self, source: &str, year: i32, week: i32) -> Result<Option<Vec<ChartsOfWeekEntry>>> {
-        let conn = self.0.get()?;
-        let result = charts_of_week::table
-            .filter(charts_of_week::year.eq(year))
-            .filter(charts_of_week::calendar_week.eq(week))
-            .filter(charts_of_week::source_name.ilike(source))
-            .load::<ChartsOfWeekEntry>(&conn)
-            .optional();
-        Ok(result?)
+    fn load_chart_entries_for_artist(&self, artist: &Artist) -> Result<Vec<ChartsOfWeekEntry>> {
+        with_conn!(self.0, conn => {
+            let track_ids = TrackArtists::belonging_to(artist).select(track_artist::track_id);
+            let result = charts_of_week::table
+                .filter(charts_of_week::track_id.eq_any(track_ids))
+                .order_by((charts_of_week::year.desc(), charts_of_week::calendar_week.desc(), charts_of_week::chart_position.asc()))
+                .load::<ChartsOfWeekEntry>(conn);
+            Ok(result?)
+        })
     }
 }
 
 impl FindById<ChartsOfWeekEntry> for DbApi {
     fn find_by_id(&self, id: i32) -> Result<Option<ChartsOfWeekEntry>> {
         use crate::db_new::schema::charts_of_week::dsl::*;
-        let conn = self.0.get()?;
-        let result = charts_of_week
+        let result = with_conn!(self.0, conn => charts_of_week
             .find(id)
-            .first(&conn)
-            .optional();
+            .first(conn)
+            .optional());
         Ok(result?)
     }
 
     fn find_by_ids(&self, ids: Vec<i32>) -> Result<Vec<ChartsOfWeekEntry>> {
-        use diesel::dsl::any;
-        let conn = self.0.get()?;
-        let result = charts_of_week::table
-            .filter(charts_of_week::chart_id.eq(any(ids)))
-            .load::<ChartsOfWeekEntry>(&conn);
+        let result = with_conn!(self.0, conn => charts_of_week::table
+            .filter(charts_of_week::chart_id.eq_any(ids))
+            .load::<ChartsOfWeekEntry>(conn));
         Ok(result?)
     }
 }
\ No newline at end of file
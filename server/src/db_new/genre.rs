@@ -20,29 +20,31 @@ use crate::db_new::{DbApi, Result};
 use crate::db_new::FindById;
 use crate::db_new::models::{Genre, NewGenre};
 use crate::db_new::schema::*;
+use crate::model::RequestPage;
 
 pub trait GenreDb: FindById<Genre> + Sync {
-    fn new_genre(&self, name: &str) -> Result<Genre>;
-    fn find_genre_by_name(&self, name: &str) -> Result<Genre>;
+    fn get_or_create_genre(&self, name: &str) -> Result<Genre>;
+    fn load_genres(&self, page: &RequestPage) -> Result<Vec<Genre>>;
 }
 
 impl GenreDb for DbApi {
-    fn new_genre(&self, name: &str) -> Result<Genre> {
-        let conn = self.0.get()?;
-        let result = diesel::insert_into(genre::table)
-            .values(&NewGenre {
-                name
-            })
-            .get_result(&conn);
+    fn get_or_create_genre(&self, name: &str) -> Result<Genre> {
+        insert_if_missing!(self.0, genre::table, &NewGenre {
+            name
+        }, genre::name)?;
+
+        let result = with_conn!(self.0, conn => genre::table
+            .filter(genre::name.eq(name))
+            .first(conn));
         Ok(result?)
     }
 
-    fn find_genre_by_name(&self, genre_name: &str) -> Result<Genre> {
-        use crate::db_new::schema::genre::dsl::*;
-        let conn = self.0.get()?;
-        let result = genre
-            .filter(name.eq(genre_name))
-            .first(&conn);
+    fn load_genres(&self, page: &RequestPage) -> Result<Vec<Genre>> {
+        let result = with_conn!(self.0, conn => genre::table
+            .order_by(genre::name.asc())
+            .limit(page.limit())
+            .offset(page.offset())
+            .load::<Genre>(conn));
         Ok(result?)
     }
 }
@@ -50,20 +52,17 @@ impl GenreDb for DbApi {
 impl FindById<Genre> for DbApi {
     fn find_by_id(&self, id: i32) -> Result<Option<Genre>> {
         use crate::db_new::schema::genre::dsl::*;
-        let conn = self.0.get()?;
-        let result = genre
+        let result = with_conn!(self.0, conn => genre
             .find(id)
-            .first(&conn)
-            .optional();
+            .first(conn)
+            .optional());
         Ok(result?)
     }
 
     fn find_by_ids(&self, ids: Vec<i32>) -> Result<Vec<Genre>> {
-        use diesel::dsl::any;
-        let conn = self.0.get()?;
-        let result = genre::table
-            .filter(genre::genre_id.eq(any(ids)))
-            .load::<Genre>(&conn);
+        let result = with_conn!(self.0, conn => genre::table
+            .filter(genre::genre_id.eq_any(ids))
+            .load::<Genre>(conn));
         Ok(result?)
     }
 }
\ No newline at end of file
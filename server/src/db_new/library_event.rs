@@ -0,0 +1,52 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+use crate::db_new::{DbApi, Result};
+use crate::db_new::models::{LibraryEvent, NewLibraryEvent};
+use crate::db_new::schema::*;
+use crate::model::{LibraryEntity, LibraryEventKind, RequestPage};
+
+pub trait LibraryEventDb : Sync {
+    fn new_library_event(&self, entity: LibraryEntity, entity_id: i32, kind: LibraryEventKind) -> Result<()>;
+    fn load_library_events_since(&self, since: NaiveDateTime, page: &RequestPage) -> Result<Vec<LibraryEvent>>;
+}
+
+impl LibraryEventDb for DbApi {
+    fn new_library_event(&self, entity: LibraryEntity, entity_id: i32, kind: LibraryEventKind) -> Result<()> {
+        let _ = with_conn!(self.0, conn => diesel::insert_into(library_events::table)
+            .values(&NewLibraryEvent {
+                entity: entity.into(),
+                entity_id,
+                kind: kind.into(),
+                source: self.1.as_str()
+            })
+            .execute(conn))?;
+        Ok(())
+    }
+
+    fn load_library_events_since(&self, since: NaiveDateTime, page: &RequestPage) -> Result<Vec<LibraryEvent>> {
+        let result = with_conn!(self.0, conn => library_events::table
+            .filter(library_events::created_at.gt(since))
+            .order_by((library_events::created_at.asc(), library_events::event_id.asc()))
+            .offset(page.offset())
+            .limit(page.limit())
+            .load::<LibraryEvent>(conn));
+        Ok(result?)
+    }
+}
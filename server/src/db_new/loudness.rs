@@ -0,0 +1,103 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use chrono::Utc;
+use diesel::prelude::*;
+
+use crate::db_new::{DbApi, DbError, Result};
+use crate::db_new::models::{NewLoudnessFailure, Track};
+use crate::db_new::schema::*;
+use crate::model::ReplayGain;
+
+pub trait LoudnessDb: Sync {
+    /// Albums with at least one local track which wasn't analysed yet,
+    /// files that failed to decode before are skipped
+    fn load_album_ids_missing_loudness(&self) -> Result<Vec<i32>>;
+    fn load_local_tracks_for_album(&self, album_id: i32) -> Result<Vec<Track>>;
+    fn set_replay_gain(&self, track_id: i32, gain: &ReplayGain) -> Result<()>;
+    fn record_failed_loudness(&self, track_id: i32, local_file: &str) -> Result<()>;
+}
+
+impl Track {
+    pub fn replay_gain(&self) -> ReplayGain {
+        ReplayGain {
+            track_gain: self.track_gain,
+            track_peak: self.track_peak,
+            album_gain: self.album_gain,
+            album_peak: self.album_peak,
+        }
+    }
+}
+
+impl LoudnessDb for DbApi {
+    fn load_album_ids_missing_loudness(&self) -> Result<Vec<i32>> {
+        let result = with_conn!(self.0, conn => tracks::table
+            .left_join(loudness_failures::table.on(loudness_failures::track_id.eq(tracks::track_id)
+                .and(loudness_failures::local_file.nullable().eq(tracks::local_file))))
+            .filter(tracks::local_file.is_not_null())
+            .filter(tracks::track_gain.is_null())
+            .filter(loudness_failures::track_id.is_null())
+            .select(tracks::album_id)
+            .distinct()
+            .order_by(tracks::album_id.asc())
+            .load::<i32>(conn));
+        Ok(result?)
+    }
+
+    fn load_local_tracks_for_album(&self, album_id: i32) -> Result<Vec<Track>> {
+        let result = with_conn!(self.0, conn => tracks::table
+            .filter(tracks::album_id.eq(album_id))
+            .filter(tracks::local_file.is_not_null())
+            .order_by(tracks::track_id.asc())
+            .load::<Track>(conn));
+        Ok(result?)
+    }
+
+    fn set_replay_gain(&self, track_id: i32, gain: &ReplayGain) -> Result<()> {
+        with_conn!(self.0, conn => {
+            let updated = diesel::update(
+                tracks::table.filter(tracks::track_id.eq(track_id))
+            ).set((
+                tracks::track_gain.eq(gain.track_gain),
+                tracks::track_peak.eq(gain.track_peak),
+                tracks::album_gain.eq(gain.album_gain),
+                tracks::album_peak.eq(gain.album_peak)
+            )).execute(conn)?;
+
+            if updated == 1 {
+                Ok(())
+            }else{
+                Err(DbError::Update(format!("Failed to set replay gain of track {}", track_id)))
+            }
+        })
+    }
+
+    fn record_failed_loudness(&self, track_id: i32, local_file: &str) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        let result = with_conn!(self.0, conn => conn.transaction::<_, diesel::result::Error, _>(|| {
+            let updated = diesel::update(loudness_failures::table.find(track_id))
+                .set((loudness_failures::local_file.eq(local_file), loudness_failures::failed_at.eq(now)))
+                .execute(conn)?;
+            if updated == 0 {
+                diesel::insert_into(loudness_failures::table)
+                    .values(&NewLoudnessFailure { track_id, local_file, failed_at: now })
+                    .execute(conn)?;
+            }
+            Ok(())
+        }));
+        Ok(result?)
+    }
+}
@@ -14,9 +14,16 @@
  * limitations under the License.
  */
 
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::connection::{SimpleConnection, TransactionManager};
+use diesel::Connection;
 use diesel::pg::PgConnection;
-use diesel::r2d2::ConnectionManager;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, PooledConnection};
 use diesel::r2d2::Pool;
+use diesel::sqlite::SqliteConnection;
+use diesel_migrations::MigrationConnection;
 use thiserror::Error;
 use url::Url;
 
@@ -25,6 +32,64 @@ use crate::db_new::album_artist::AlbumArtistsDb;
 use crate::db_new::artist::ArtistDb;
 use crate::db_new::models::{Album, Artist, NewAlbum, NewArtist, NewTrack, Track};
 use crate::db_new::track::TrackDb;
+use crate::model::ChangeSource;
+
+//runs the given body with a connection of the configured backend; inside of a transaction
+//this is the pinned connection, so the body must not call back into the DbApi.
+//the body is compiled once per backend so it has to stick to portable dsl
+macro_rules! with_conn {
+    ($pool:expr, $conn:ident => $body:expr) => {
+        with_conn!($pool, $conn => $body, $body)
+    };
+    ($pool:expr, $conn:ident => $pg_body:expr, $sqlite_body:expr) => {
+        match &$pool {
+            $crate::db_new::DbPool::Postgres(pool) => {
+                let pooled = pool.get()?;
+                let $conn = &*pooled;
+                $pg_body
+            }
+            $crate::db_new::DbPool::PostgresTx(tx) => {
+                let pinned = $crate::db_new::pin(tx);
+                let $conn = &**pinned;
+                $pg_body
+            }
+            $crate::db_new::DbPool::Sqlite(pool) => {
+                let pooled = pool.get()?;
+                let $conn = &*pooled;
+                $sqlite_body
+            }
+            $crate::db_new::DbPool::SqliteTx(tx) => {
+                let pinned = $crate::db_new::pin(tx);
+                let $conn = &**pinned;
+                $sqlite_body
+            }
+        }
+    };
+}
+
+//sqlite has no RETURNING clause, so the inserted row is loaded by its rowid
+macro_rules! insert_returning {
+    ($pool:expr, $table:expr, $values:expr) => {
+        with_conn!($pool, conn =>
+            diesel::insert_into($table).values($values).get_result(conn),
+            diesel::Connection::transaction(conn, || {
+                diesel::insert_into($table).values($values).execute(conn)?;
+                let row_id = diesel::select($crate::db_new::last_insert_rowid).get_result::<i64>(conn)?;
+                $table.find(row_id as i32).first(conn)
+            })
+        )
+    };
+}
+
+//inserts the given row unless it collides with the unique index on the conflict target
+macro_rules! insert_if_missing {
+    ($pool:expr, $table:expr, $values:expr, $target:expr) => {
+        with_conn!($pool, conn =>
+            diesel::insert_into($table).values($values).on_conflict($target).do_nothing().execute(conn),
+            diesel::insert_or_ignore_into($table).values($values).execute(conn)
+        )
+    };
+}
 
 mod schema;
 pub mod models;
@@ -32,14 +97,40 @@ pub mod genre;
 pub mod artist;
 pub mod artist_genre;
 pub mod album;
+pub mod album_art;
 pub mod album_artist;
 pub mod track;
 pub mod track_artist;
 pub mod album_of_week;
 pub mod charts_of_week;
 pub mod track_fav_proposal;
+pub mod library_event;
+pub mod play;
+pub mod stats;
+pub mod scrobble;
+pub mod smart_playlist;
+pub mod autoplay;
+pub mod loudness;
+pub mod search;
 
-type DbPool = Pool<ConnectionManager<PgConnection>>;
+sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+//diesel refuses to mix its aggregates with the grouped column in one select,
+//these plain function declarations render the same sql without that check
+sql_function!(fn count(x: diesel::sql_types::Integer) -> diesel::sql_types::BigInt);
+sql_function!(fn min(x: diesel::sql_types::Timestamp) -> diesel::sql_types::Nullable<diesel::sql_types::Timestamp>);
+sql_function!(fn max(x: diesel::sql_types::Timestamp) -> diesel::sql_types::Nullable<diesel::sql_types::Timestamp>);
+no_arg_sql_function!(last_insert_rowid, diesel::sql_types::BigInt);
+
+type PinnedConnection<C> = Arc<Mutex<PooledConnection<ConnectionManager<C>>>>;
+
+#[derive(Clone)]
+pub enum DbPool {
+    Postgres(Pool<ConnectionManager<PgConnection>>),
+    Sqlite(Pool<ConnectionManager<SqliteConnection>>),
+    //connection of a running transaction, see DbApi::transaction
+    PostgresTx(PinnedConnection<PgConnection>),
+    SqliteTx(PinnedConnection<SqliteConnection>),
+}
 
 type Result<R> = std::result::Result<R, DbError>;
 
@@ -55,7 +146,16 @@ pub enum DbError{
     Update(String),
     
     #[error("DB delete failed: {0}")]
-    Delete(String)
+    Delete(String),
+
+    #[error("DB migration failed: {0}")]
+    Migration(#[from] diesel_migrations::RunMigrationsError),
+
+    #[error("DB schema error: {0}")]
+    Schema(String),
+
+    #[error("DB value not decodable: {0}")]
+    Decode(#[from] serde_json::Error)
 }
 
 pub trait FindById<T> {
@@ -75,13 +175,51 @@ pub trait UpdateSingle<T> {
     fn update(&self, to_update: &T) -> Result<()>;
 }
 
+mod pg_migrations {
+    embed_migrations!("migrations");
+    pub(super) use self::embedded_migrations::run_with_output;
+}
+
+mod sqlite_migrations {
+    embed_migrations!("migrations_sqlite");
+    pub(super) use self::embedded_migrations::run_with_output;
+}
+
+//newest migrations embedded into this binary, see build.rs
+const PG_SCHEMA_VERSION: &str = env!("SOUNDBASE_PG_SCHEMA_VERSION");
+const SQLITE_SCHEMA_VERSION: &str = env!("SOUNDBASE_SQLITE_SCHEMA_VERSION");
+
+//besides the pool every api carries the source its library changes get recorded with
 #[derive(Clone)]
-pub struct DbApi(DbPool);
+pub struct DbApi(DbPool, ChangeSource);
 impl DbApi {
 
-    pub fn new(url: Url) -> Self {
-        let pool = init_db(url);
-        DbApi(pool)
+    pub fn new(url: Url, run_migrations: bool) -> Result<Self> {
+        let pool = init_db(url, run_migrations)?;
+        Ok(DbApi(pool, ChangeSource::Api))
+    }
+
+    pub fn with_source(&self, source: ChangeSource) -> Self {
+        DbApi(self.0.clone(), source)
+    }
+
+    //runs the given closure as one unit of work; all writes are committed once it returns Ok
+    //and rolled back if it fails. nested calls are run within a savepoint of the outer one
+    pub fn transaction<T, E, F>(&self, work: F) -> std::result::Result<T, E>
+        where F: FnOnce(&DbApi) -> std::result::Result<T, E>,
+              E: From<DbError> {
+        match &self.0 {
+            DbPool::Postgres(pool) => {
+                let pinned = Arc::new(Mutex::new(pool.get().map_err(DbError::from)?));
+                run_transaction(pinned, DbPool::PostgresTx, self.1, work)
+            }
+            DbPool::Sqlite(pool) => {
+                let pinned = Arc::new(Mutex::new(pool.get().map_err(DbError::from)?));
+                run_transaction(pinned, DbPool::SqliteTx, self.1, work)
+            }
+            DbPool::PostgresTx(pinned) => run_transaction(pinned.clone(), DbPool::PostgresTx, self.1, work),
+            DbPool::SqliteTx(pinned) => run_transaction(pinned.clone(), DbPool::SqliteTx, self.1, work),
+        }
     }
 
     pub fn get_or_create_artist<'a, Fn>(&self, name: &str, create_fn: Fn) -> Result<Artist>
@@ -126,14 +264,108 @@ impl DbApi {
     }
 }
 
-fn init_db(url: Url) -> DbPool {
-    let manager = ConnectionManager::<PgConnection>::new(url);
-    let pool = Pool::builder()
-        .max_size(5)
-        .build(manager)
-        .expect("DB connection failed!");
+fn run_transaction<C, T, E, F>(pinned: PinnedConnection<C>, wrap: fn(PinnedConnection<C>) -> DbPool, source: ChangeSource, work: F) -> std::result::Result<T, E>
+    where C: Connection + Send + 'static,
+          F: FnOnce(&DbApi) -> std::result::Result<T, E>,
+          E: From<DbError> {
+    {
+        let conn = pin(&pinned);
+        conn.transaction_manager().begin_transaction(&**conn).map_err(DbError::from)?;
+    }
+
+    //the connection must not stay locked while the work runs as every db call locks it again
+    let result = work(&DbApi(wrap(pinned.clone()), source));
+
+    let conn = pin(&pinned);
+    let manager = conn.transaction_manager();
+    match result {
+        Ok(value) => {
+            if let Err(e) = manager.commit_transaction(&**conn) {
+                let _ = manager.rollback_transaction(&**conn);
+                return Err(DbError::from(e).into());
+            }
+            Ok(value)
+        }
+        Err(e) => {
+            if let Err(rollback_error) = manager.rollback_transaction(&**conn) {
+                log::warn!("Failed to roll back transaction => {:?}", rollback_error);
+            }
+            Err(e)
+        }
+    }
+}
+
+//faved_at keeps the time of the latest fav, an unfav clears it
+fn faved_at_for(now_faved: bool) -> Option<NaiveDateTime> {
+    if now_faved {
+        Some(Utc::now().naive_utc())
+    }else{
+        None
+    }
+}
+
+fn pin<C>(pinned: &Mutex<C>) -> MutexGuard<'_, C> {
+    //a panic while holding the lock does not leave the connection itself in a broken state
+    pinned.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn init_db(url: Url, run_migrations: bool) -> Result<DbPool> {
+    match url.scheme() {
+        "postgres" | "postgresql" => {
+            let manager = ConnectionManager::<PgConnection>::new(url);
+            let pool = Pool::builder()
+                .max_size(5)
+                .build(manager)?;
 
-    //TODO run migrations
+            let conn = pool.get()?;
+            if prepare_schema(&conn, PG_SCHEMA_VERSION, run_migrations)? {
+                pg_migrations::run_with_output(&conn, &mut std::io::stdout())?;
+            }
+            Ok(DbPool::Postgres(pool))
+        }
+        "sqlite" => {
+            //sqlite://relative/file.db or sqlite:///absolute/file.db
+            let file = url.as_str().trim_start_matches("sqlite://").to_string();
+            let manager = ConnectionManager::<SqliteConnection>::new(file);
+            let pool = Pool::builder()
+                .max_size(5)
+                .connection_customizer(Box::new(SqliteCustomizer))
+                .build(manager)?;
+
+            let conn = pool.get()?;
+            if prepare_schema(&conn, SQLITE_SCHEMA_VERSION, run_migrations)? {
+                sqlite_migrations::run_with_output(&conn, &mut std::io::stdout())?;
+            }
+            Ok(DbPool::Sqlite(pool))
+        }
+        other => Err(DbError::Schema(format!("Unsupported database scheme '{}'!", other)))
+    }
+}
 
-    pool
-}
\ No newline at end of file
+//checks the schema against the version known to the binary; true if migrations should run
+fn prepare_schema<Conn: MigrationConnection>(conn: &Conn, known_version: &str, run_migrations: bool) -> Result<bool> {
+    diesel_migrations::setup_database(conn)?;
+    let db_version = conn.latest_run_migration_version()?.unwrap_or_default();
+    if db_version.as_str() > known_version {
+        return Err(DbError::Schema(format!(
+            "Database schema version {} is newer than the latest known version {}; refusing to start!",
+            db_version, known_version
+        )));
+    }
+
+    if !run_migrations && db_version.as_str() < known_version {
+        log::warn!("Database has pending migrations, but migrations are disabled!");
+    }
+    Ok(run_migrations)
+}
+
+#[derive(Debug)]
+struct SqliteCustomizer;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for SqliteCustomizer {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> std::result::Result<(), diesel::r2d2::Error> {
+        //foreign keys are off by default and concurrent writers should wait instead of failing
+        conn.batch_execute("PRAGMA foreign_keys = ON; PRAGMA busy_timeout = 5000;")
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
@@ -15,6 +15,7 @@
  */
 
 use crate::db_new::schema::*;
+use chrono::NaiveDateTime;
 use serde::{Serialize, Deserialize};
 
 #[derive(Queryable, Identifiable, Associations, AsChangeset, Serialize, PartialEq, Debug)]
@@ -41,7 +42,10 @@ pub struct Artist {
     pub is_faved : bool,
     pub is_known_spot : bool,
     pub is_known_local: bool,
-    pub spot_id : Option<String>
+    pub spot_id : Option<String>,
+    pub created_at : NaiveDateTime,
+    pub updated_at : NaiveDateTime,
+    pub faved_at : Option<NaiveDateTime>
 }
 
 #[derive(Insertable, PartialEq, Debug)]
@@ -85,7 +89,10 @@ pub struct Album {
     pub is_known_spot : bool,
     pub is_known_local : bool,
     pub was_aow : bool,
-    pub spot_id : Option<String>
+    pub spot_id : Option<String>,
+    pub created_at : NaiveDateTime,
+    pub updated_at : NaiveDateTime,
+    pub faved_at : Option<NaiveDateTime>
 }
 
 #[derive(Insertable, PartialEq, Debug)]
@@ -102,6 +109,47 @@ pub struct NewAlbum<'a> {
     pub spot_id : Option<String>
 }
 
+#[derive(Queryable, Identifiable, Associations, AsChangeset, PartialEq, Debug, Clone)]
+#[belongs_to(Album)]
+#[table_name = "album_art"]
+#[primary_key(art_id)]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct AlbumArt {
+    pub art_id : i32,
+    pub album_id : i32,
+    pub size : i32,
+    pub source : String,
+    pub source_url : Option<String>,
+    pub file_path : Option<String>,
+    pub content_type : Option<String>
+}
+
+#[derive(Insertable)]
+#[table_name = "album_art"]
+pub struct NewAlbumArt<'a> {
+    pub album_id : i32,
+    pub size : i32,
+    pub source : &'a str,
+    pub source_url : Option<&'a str>,
+    pub file_path : Option<&'a str>,
+    pub content_type : Option<&'a str>
+}
+
+#[derive(Insertable)]
+#[table_name = "album_art_lookups"]
+pub struct NewAlbumArtLookup {
+    pub album_id : i32,
+    pub tried_at : NaiveDateTime
+}
+
+#[derive(Insertable)]
+#[table_name = "loudness_failures"]
+pub struct NewLoudnessFailure<'a> {
+    pub track_id : i32,
+    pub local_file : &'a str,
+    pub failed_at : NaiveDateTime
+}
+
 #[derive(Queryable, Identifiable, Associations, AsChangeset, PartialEq, Debug)]
 #[belongs_to(Album)]
 #[belongs_to(Artist)]
@@ -162,7 +210,15 @@ pub struct Track {
     pub duration_ms : i64,
     pub is_faved : bool,
     pub local_file : Option<String>,
-    pub spot_id : Option<String>
+    pub spot_id : Option<String>,
+    pub preferred_source : Option<i32>,
+    pub created_at : NaiveDateTime,
+    pub updated_at : NaiveDateTime,
+    pub faved_at : Option<NaiveDateTime>,
+    pub track_gain : Option<f32>,
+    pub track_peak : Option<f32>,
+    pub album_gain : Option<f32>,
+    pub album_peak : Option<f32>
 }
 
 #[derive(Queryable, Identifiable, Associations, AsChangeset, PartialEq, Debug)]
@@ -242,4 +298,96 @@ pub struct NewTrackFavProposal {
     pub ext_track_title : String,
     pub ext_artist_name : String,
     pub ext_album_name : Option<String>
-}
\ No newline at end of file
+}
+#[derive(Queryable, Identifiable, PartialEq, Debug)]
+#[table_name = "library_events"]
+#[primary_key(event_id)]
+pub struct LibraryEvent {
+    pub event_id : i32,
+    pub entity : i32,
+    pub entity_id : i32,
+    pub kind : i32,
+    pub source : String,
+    pub created_at : NaiveDateTime
+}
+
+#[derive(Insertable, PartialEq, Debug)]
+#[table_name = "library_events"]
+pub struct NewLibraryEvent<'a> {
+    pub entity : i32,
+    pub entity_id : i32,
+    pub kind : i32,
+    pub source : &'a str
+}
+
+#[derive(Queryable, Identifiable, Associations, PartialEq, Debug)]
+#[belongs_to(Track)]
+#[table_name = "plays"]
+#[primary_key(play_id)]
+pub struct Play {
+    pub play_id : i32,
+    pub track_id : i32,
+    pub started_at : NaiveDateTime,
+    pub played_ms : i64,
+    pub completed : bool,
+    pub source : i32
+}
+
+#[derive(Insertable, PartialEq, Debug)]
+#[table_name = "plays"]
+pub struct NewPlay {
+    pub track_id : i32,
+    pub started_at : NaiveDateTime,
+    pub played_ms : i64,
+    pub completed : bool,
+    pub source : i32
+}
+
+#[derive(Queryable, Identifiable, PartialEq, Debug)]
+#[table_name = "scrobbles"]
+#[primary_key(scrobble_id)]
+pub struct Scrobble {
+    pub scrobble_id : i32,
+    pub artist_name : String,
+    pub track_name : String,
+    pub release_name : Option<String>,
+    pub duration_ms : i64,
+    pub listened_at : NaiveDateTime,
+    pub attempts : i32,
+    pub last_error : Option<String>
+}
+
+#[derive(Insertable, PartialEq, Debug)]
+#[table_name = "scrobbles"]
+pub struct NewScrobble {
+    pub artist_name : String,
+    pub track_name : String,
+    pub release_name : Option<String>,
+    pub duration_ms : i64,
+    pub listened_at : NaiveDateTime
+}
+
+#[derive(Queryable, Identifiable, PartialEq, Debug)]
+#[table_name = "smart_playlists"]
+#[primary_key(playlist_id)]
+pub struct SmartPlaylist {
+    pub playlist_id : i32,
+    pub name : String,
+    pub rule : String,
+    pub sort : i32,
+    pub descending : bool,
+    pub max_tracks : Option<i32>,
+    pub created_at : NaiveDateTime,
+    pub updated_at : NaiveDateTime
+}
+
+#[derive(Insertable, AsChangeset, PartialEq, Debug)]
+#[table_name = "smart_playlists"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct NewSmartPlaylist<'a> {
+    pub name : &'a str,
+    pub rule : String,
+    pub sort : i32,
+    pub descending : bool,
+    pub max_tracks : Option<i32>
+}
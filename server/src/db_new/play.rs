@@ -0,0 +1,81 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::query_dsl::GroupByDsl;
+
+use crate::db_new::{count, max, DbApi, Result};
+use crate::db_new::models::{NewPlay, Play};
+use crate::db_new::schema::*;
+use crate::model::RequestPage;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PlayStats {
+    //only plays which ran until the end of the track count
+    pub play_count : i64,
+    pub last_played : Option<NaiveDateTime>
+}
+
+pub trait PlayDb : Sync {
+    fn new_play(&self, new_play: NewPlay) -> Result<Play>;
+    fn load_plays(&self, page: &RequestPage) -> Result<Vec<Play>>;
+    fn load_play_stats_for_tracks(&self, track_ids: &[i32]) -> Result<HashMap<i32, PlayStats>>;
+}
+
+impl PlayDb for DbApi {
+    fn new_play(&self, new_play: NewPlay) -> Result<Play> {
+        let result = insert_returning!(self.0, plays::table, &new_play);
+        Ok(result?)
+    }
+
+    fn load_plays(&self, page: &RequestPage) -> Result<Vec<Play>> {
+        let result = with_conn!(self.0, conn => plays::table
+            .order_by((plays::started_at.desc(), plays::play_id.desc()))
+            .offset(page.offset())
+            .limit(page.limit())
+            .load::<Play>(conn));
+        Ok(result?)
+    }
+
+    fn load_play_stats_for_tracks(&self, track_ids: &[i32]) -> Result<HashMap<i32, PlayStats>> {
+        let (counts, last_played) = with_conn!(self.0, conn => {
+            let counts = plays::table
+                .filter(plays::track_id.eq_any(track_ids))
+                .filter(plays::completed.eq(true))
+                .group_by(plays::track_id)
+                .select((plays::track_id, count(plays::play_id)))
+                .load::<(i32, i64)>(conn)?;
+            let last_played = plays::table
+                .filter(plays::track_id.eq_any(track_ids))
+                .group_by(plays::track_id)
+                .select((plays::track_id, max(plays::started_at)))
+                .load::<(i32, Option<NaiveDateTime>)>(conn)?;
+            Ok::<_, diesel::result::Error>((counts, last_played))
+        })?;
+
+        let mut stats : HashMap<i32, PlayStats> = HashMap::new();
+        for (track_id, last) in last_played {
+            stats.entry(track_id).or_default().last_played = last;
+        }
+        for (track_id, count) in counts {
+            stats.entry(track_id).or_default().play_count = count;
+        }
+        Ok(stats)
+    }
+}
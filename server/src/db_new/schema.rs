@@ -1,3 +1,23 @@
+table! {
+    album_art (art_id) {
+        art_id -> Int4,
+        album_id -> Int4,
+        size -> Int4,
+        source -> Varchar,
+        source_url -> Nullable<Varchar>,
+        file_path -> Nullable<Varchar>,
+        content_type -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    album_art_lookups (album_id) {
+        album_id -> Int4,
+        attempts -> Int4,
+        tried_at -> Timestamp,
+    }
+}
+
 table! {
     album_artists (id) {
         id -> Int4,
@@ -18,6 +38,9 @@ table! {
         is_known_local -> Bool,
         was_aow -> Bool,
         spot_id -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        faved_at -> Nullable<Timestamp>,
     }
 }
 
@@ -50,6 +73,9 @@ table! {
         is_known_spot -> Bool,
         is_known_local -> Bool,
         spot_id -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        faved_at -> Nullable<Timestamp>,
     }
 }
 
@@ -71,6 +97,62 @@ table! {
     }
 }
 
+table! {
+    library_events (event_id) {
+        event_id -> Int4,
+        entity -> Int4,
+        entity_id -> Int4,
+        kind -> Int4,
+        source -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    loudness_failures (track_id) {
+        track_id -> Int4,
+        local_file -> Varchar,
+        failed_at -> Timestamp,
+    }
+}
+
+table! {
+    plays (play_id) {
+        play_id -> Int4,
+        track_id -> Int4,
+        started_at -> Timestamp,
+        played_ms -> Int8,
+        completed -> Bool,
+        source -> Int4,
+    }
+}
+
+table! {
+    scrobbles (scrobble_id) {
+        scrobble_id -> Int4,
+        artist_name -> Varchar,
+        track_name -> Varchar,
+        release_name -> Nullable<Varchar>,
+        duration_ms -> Int8,
+        listened_at -> Timestamp,
+        attempts -> Int4,
+        last_error -> Nullable<Text>,
+    }
+}
+
+table! {
+    smart_playlists (playlist_id) {
+        playlist_id -> Int4,
+        name -> Varchar,
+        rule -> Text,
+        sort -> Int4,
+        descending -> Bool,
+        max_tracks -> Nullable<Int4>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 table! {
     track_artist (id) {
         id -> Int4,
@@ -102,21 +184,35 @@ table! {
         is_faved -> Bool,
         local_file -> Nullable<Varchar>,
         spot_id -> Nullable<Varchar>,
+        preferred_source -> Nullable<Int4>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        faved_at -> Nullable<Timestamp>,
+        track_gain -> Nullable<Float4>,
+        track_peak -> Nullable<Float4>,
+        album_gain -> Nullable<Float4>,
+        album_peak -> Nullable<Float4>,
     }
 }
 
+joinable!(album_art -> albums (album_id));
+joinable!(album_art_lookups -> albums (album_id));
 joinable!(album_artists -> albums (album_id));
 joinable!(album_artists -> artists (artist_id));
 joinable!(albums_of_week -> albums (album_id));
 joinable!(artist_genre -> artists (artist_id));
 joinable!(artist_genre -> genre (genre_id));
 joinable!(charts_of_week -> tracks (track_id));
+joinable!(loudness_failures -> tracks (track_id));
+joinable!(plays -> tracks (track_id));
 joinable!(track_artist -> artists (artist_id));
 joinable!(track_artist -> tracks (track_id));
 joinable!(track_fav_proposals -> tracks (track_id));
 joinable!(tracks -> albums (album_id));
 
 allow_tables_to_appear_in_same_query!(
+    album_art,
+    album_art_lookups,
     album_artists,
     albums,
     albums_of_week,
@@ -124,6 +220,11 @@ allow_tables_to_appear_in_same_query!(
     artists,
     charts_of_week,
     genre,
+    library_events,
+    loudness_failures,
+    plays,
+    scrobbles,
+    smart_playlists,
     track_artist,
     track_fav_proposals,
     tracks,
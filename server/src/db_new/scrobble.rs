@@ -0,0 +1,58 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use diesel::prelude::*;
+
+use crate::db_new::{DbApi, Result};
+use crate::db_new::models::{NewScrobble, Scrobble};
+use crate::db_new::schema::*;
+
+pub trait ScrobbleDb : Sync {
+    fn new_scrobble(&self, new_scrobble: NewScrobble) -> Result<Scrobble>;
+    fn load_pending_scrobbles(&self, limit: i64) -> Result<Vec<Scrobble>>;
+    fn remove_scrobbles(&self, scrobble_ids: &[i32]) -> Result<()>;
+    fn mark_scrobbles_failed(&self, scrobble_ids: &[i32], error: &str) -> Result<()>;
+}
+
+impl ScrobbleDb for DbApi {
+    fn new_scrobble(&self, new_scrobble: NewScrobble) -> Result<Scrobble> {
+        let result = insert_returning!(self.0, scrobbles::table, &new_scrobble);
+        Ok(result?)
+    }
+
+    fn load_pending_scrobbles(&self, limit: i64) -> Result<Vec<Scrobble>> {
+        let result = with_conn!(self.0, conn => scrobbles::table
+            .order_by((scrobbles::listened_at.asc(), scrobbles::scrobble_id.asc()))
+            .limit(limit)
+            .load::<Scrobble>(conn));
+        Ok(result?)
+    }
+
+    fn remove_scrobbles(&self, scrobble_ids: &[i32]) -> Result<()> {
+        let _ = with_conn!(self.0, conn => diesel::delete(
+            scrobbles::table.filter(scrobbles::scrobble_id.eq_any(scrobble_ids))
+        ).execute(conn))?;
+        Ok(())
+    }
+
+    fn mark_scrobbles_failed(&self, scrobble_ids: &[i32], error: &str) -> Result<()> {
+        let _ = with_conn!(self.0, conn => diesel::update(
+            scrobbles::table.filter(scrobbles::scrobble_id.eq_any(scrobble_ids))
+        ).set((scrobbles::attempts.eq(scrobbles::attempts + 1), scrobbles::last_error.eq(error)))
+            .execute(conn))?;
+        Ok(())
+    }
+}
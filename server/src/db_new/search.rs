@@ -0,0 +1,197 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use diesel::expression::BoxableExpression;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+
+use crate::db_new::{lower, DbApi, Result};
+use crate::db_new::models::Track;
+use crate::db_new::schema::*;
+
+/// Tags of a track the library can be searched by
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SearchTag {
+    Title,
+    Artist,
+    Album,
+    //genres of the track artists
+    Genre,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TagFilter {
+    //None matches any of the tags
+    pub tag: Option<SearchTag>,
+    pub value: String,
+    //the whole tag has to match instead of a part of it
+    pub exact: bool,
+}
+
+pub trait SearchDb {
+    /// Tracks matching all filters ignoring case, in album, disc and track order
+    fn search_tracks(&self, filters: &[TagFilter], limit: i64) -> Result<Vec<Track>>;
+    /// The distinct values of the tag among the tracks matching all filters
+    fn load_tag_values(&self, tag: SearchTag, filters: &[TagFilter], limit: i64) -> Result<Vec<String>>;
+}
+
+impl SearchDb for DbApi {
+    fn search_tracks(&self, filters: &[TagFilter], limit: i64) -> Result<Vec<Track>> {
+        let result = with_conn!(self.0, conn => tracks::table
+            .filter(compile_pg_filters(filters))
+            .order_by((tracks::album_id.asc(),
+                       tracks::disc_number.is_null(), tracks::disc_number.asc(),
+                       tracks::track_number.is_null(), tracks::track_number.asc(), tracks::track_id.asc()))
+            .limit(limit)
+            .load::<Track>(conn),
+        tracks::table
+            .filter(compile_sqlite_filters(filters))
+            .order_by((tracks::album_id.asc(),
+                       tracks::disc_number.is_null(), tracks::disc_number.asc(),
+                       tracks::track_number.is_null(), tracks::track_number.asc(), tracks::track_id.asc()))
+            .limit(limit)
+            .load::<Track>(conn));
+        Ok(result?)
+    }
+
+    fn load_tag_values(&self, tag: SearchTag, filters: &[TagFilter], limit: i64) -> Result<Vec<String>> {
+        let result = with_conn!(self.0, conn =>
+            tag_values!(conn, tag, compile_pg_filters(filters), limit, diesel::pg::Pg),
+            tag_values!(conn, tag, compile_sqlite_filters(filters), limit, diesel::sqlite::Sqlite));
+        Ok(result?)
+    }
+}
+
+//like patterns escape their wildcards with a backslash
+fn contains_pattern(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+//narrows the boxed select down to rows whose text column matches the filter
+macro_rules! matching_text {
+    ($query:expr, $column:expr, $filter:expr) => {
+        if $filter.exact {
+            $query.filter(lower($column).eq(lower($filter.value.clone())))
+        } else {
+            $query.filter(lower($column).like(lower(contains_pattern(&$filter.value))).escape('\\'))
+        }
+    };
+}
+
+//loads the sorted values of the tag among the tracks the matching expression selects;
+//the tracks are boxed selects since the expression only appears on the tracks table
+macro_rules! tag_values {
+    ($conn:expr, $tag:expr, $matching:expr, $limit:expr, $db:ty) => {
+        match $tag {
+            SearchTag::Title => tracks::table
+                .filter($matching)
+                .select(tracks::title)
+                .distinct()
+                .order_by(tracks::title.asc())
+                .limit($limit)
+                .load::<String>($conn),
+            SearchTag::Album => albums::table
+                .filter(albums::album_id.eq_any(tracks::table
+                    .filter($matching)
+                    .select(tracks::album_id)
+                    .into_boxed::<$db>()))
+                .select(albums::name)
+                .distinct()
+                .order_by(albums::name.asc())
+                .limit($limit)
+                .load::<String>($conn),
+            SearchTag::Artist => artists::table
+                .filter(artists::artist_id.eq_any(track_artist::table
+                    .filter(track_artist::track_id.eq_any(tracks::table
+                        .filter($matching)
+                        .select(tracks::track_id)
+                        .into_boxed::<$db>()))
+                    .select(track_artist::artist_id)))
+                .select(artists::name)
+                .distinct()
+                .order_by(artists::name.asc())
+                .limit($limit)
+                .load::<String>($conn),
+            SearchTag::Genre => genre::table
+                .filter(genre::genre_id.eq_any(artist_genre::table
+                    .filter(artist_genre::artist_id.eq_any(track_artist::table
+                        .filter(track_artist::track_id.eq_any(tracks::table
+                            .filter($matching)
+                            .select(tracks::track_id)
+                            .into_boxed::<$db>()))
+                        .select(track_artist::artist_id)))
+                    .select(artist_genre::genre_id)))
+                .select(genre::name)
+                .distinct()
+                .order_by(genre::name.asc())
+                .limit($limit)
+                .load::<String>($conn),
+        }
+    };
+}
+use tag_values;
+
+//like the smart playlist rules every filter is compiled into a subselect on
+//the tracks table, once per backend since boxed expressions are bound to one
+macro_rules! filter_compiler {
+    ($name:ident, $filter_name:ident, $db:ty) => {
+        fn $name(filters: &[TagFilter]) -> Box<dyn BoxableExpression<tracks::table, $db, SqlType = Bool>> {
+            filters.iter()
+                .map($filter_name)
+                .fold(Box::new(tracks::track_id.is_not_null()), |all, filter| Box::new(all.and(filter)))
+        }
+
+        fn $filter_name(filter: &TagFilter) -> Box<dyn BoxableExpression<tracks::table, $db, SqlType = Bool>> {
+            match filter.tag {
+                Some(SearchTag::Title) => {
+                    let track_ids = matching_text!(tracks::table.select(tracks::track_id).into_boxed::<$db>(), tracks::title, filter);
+                    Box::new(tracks::track_id.eq_any(track_ids))
+                }
+                Some(SearchTag::Album) => {
+                    let album_ids = matching_text!(albums::table.select(albums::album_id).into_boxed::<$db>(), albums::name, filter);
+                    Box::new(tracks::album_id.eq_any(album_ids))
+                }
+                Some(SearchTag::Artist) => {
+                    let artist_ids = matching_text!(artists::table.select(artists::artist_id).into_boxed::<$db>(), artists::name, filter);
+                    let track_ids = track_artist::table
+                        .filter(track_artist::artist_id.eq_any(artist_ids))
+                        .select(track_artist::track_id);
+                    Box::new(tracks::track_id.eq_any(track_ids))
+                }
+                Some(SearchTag::Genre) => {
+                    let genre_ids = matching_text!(genre::table.select(genre::genre_id).into_boxed::<$db>(), genre::name, filter);
+                    let artist_ids = artist_genre::table
+                        .filter(artist_genre::genre_id.eq_any(genre_ids))
+                        .select(artist_genre::artist_id);
+                    let track_ids = track_artist::table
+                        .filter(track_artist::artist_id.eq_any(artist_ids))
+                        .select(track_artist::track_id);
+                    Box::new(tracks::track_id.eq_any(track_ids))
+                }
+                None => [SearchTag::Title, SearchTag::Artist, SearchTag::Album, SearchTag::Genre].iter()
+                    .map(|tag| $filter_name(&TagFilter { tag: Some(*tag), ..filter.clone() }))
+                    .fold(Box::new(tracks::track_id.is_null()), |any, filter| Box::new(any.or(filter))),
+            }
+        }
+    };
+}
+
+filter_compiler!(compile_pg_filters, compile_pg_filter, diesel::pg::Pg);
+filter_compiler!(compile_sqlite_filters, compile_sqlite_filter, diesel::sqlite::Sqlite);
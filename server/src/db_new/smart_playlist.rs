@@ -0,0 +1,261 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::dsl::{not, sql};
+use diesel::expression::BoxableExpression;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Integer};
+
+use crate::db_new::{lower, DbApi, DbError, FindById, Result};
+use crate::db_new::models::{NewSmartPlaylist, SmartPlaylist, Track};
+use crate::db_new::schema::*;
+use crate::model::RequestPage;
+use crate::model::smart_playlist::{PlaylistRule, PlaylistSort, SmartPlaylistQuery};
+
+pub trait SmartPlaylistDb : FindById<SmartPlaylist> + Sync {
+    fn new_smart_playlist(&self, name: &str, query: &SmartPlaylistQuery) -> Result<SmartPlaylist>;
+    fn update_smart_playlist(&self, playlist_id: i32, name: &str, query: &SmartPlaylistQuery) -> Result<SmartPlaylist>;
+    fn delete_smart_playlist(&self, playlist_id: i32) -> Result<()>;
+    fn load_smart_playlists(&self, page: &RequestPage) -> Result<Vec<SmartPlaylist>>;
+    fn evaluate_smart_playlist(&self, query: &SmartPlaylistQuery) -> Result<Vec<Track>>;
+}
+
+impl SmartPlaylist {
+    pub fn query(&self) -> Result<SmartPlaylistQuery> {
+        Ok(SmartPlaylistQuery {
+            rule: serde_json::from_str(&self.rule)?,
+            sort: PlaylistSort::from(self.sort),
+            descending: self.descending,
+            limit: self.max_tracks.map(i64::from),
+        })
+    }
+}
+
+impl SmartPlaylistDb for DbApi {
+    fn new_smart_playlist(&self, name: &str, query: &SmartPlaylistQuery) -> Result<SmartPlaylist> {
+        let new_playlist = new_smart_playlist(name, query)?;
+        let result = insert_returning!(self.0, smart_playlists::table, &new_playlist);
+        Ok(result?)
+    }
+
+    fn update_smart_playlist(&self, playlist_id: i32, name: &str, query: &SmartPlaylistQuery) -> Result<SmartPlaylist> {
+        let changes = new_smart_playlist(name, query)?;
+        with_conn!(self.0, conn => {
+            let updated = diesel::update(smart_playlists::table.find(playlist_id))
+                .set(&changes)
+                .execute(conn)?;
+            if updated == 1 {
+                Ok(smart_playlists::table.find(playlist_id).first(conn)?)
+            }else{
+                Err(DbError::Update(format!("Smart playlist {} not found", playlist_id)))
+            }
+        })
+    }
+
+    fn delete_smart_playlist(&self, playlist_id: i32) -> Result<()> {
+        let deleted = with_conn!(self.0, conn => diesel::delete(smart_playlists::table.find(playlist_id))
+            .execute(conn))?;
+        if deleted == 1 {
+            Ok(())
+        }else{
+            Err(DbError::Delete(format!("Smart playlist {} not found", playlist_id)))
+        }
+    }
+
+    fn load_smart_playlists(&self, page: &RequestPage) -> Result<Vec<SmartPlaylist>> {
+        let result = with_conn!(self.0, conn => smart_playlists::table
+            .order_by(smart_playlists::name.asc())
+            .offset(page.offset())
+            .limit(page.limit())
+            .load::<SmartPlaylist>(conn));
+        Ok(result?)
+    }
+
+    fn evaluate_smart_playlist(&self, query: &SmartPlaylistQuery) -> Result<Vec<Track>> {
+        let now = Utc::now().naive_utc();
+        let result = with_conn!(self.0, conn => {
+            let mut tracks = tracks::table
+                .filter(compile_pg_rule(&query.rule, now))
+                .into_boxed();
+            tracks = sort_tracks!(tracks, query);
+            if let Some(limit) = query.limit {
+                tracks = tracks.limit(limit);
+            }
+            tracks.load::<Track>(conn)
+        }, {
+            let mut tracks = tracks::table
+                .filter(compile_sqlite_rule(&query.rule, now))
+                .into_boxed();
+            tracks = sort_tracks!(tracks, query);
+            if let Some(limit) = query.limit {
+                tracks = tracks.limit(limit);
+            }
+            tracks.load::<Track>(conn)
+        });
+        Ok(result?)
+    }
+}
+
+impl FindById<SmartPlaylist> for DbApi {
+    fn find_by_id(&self, id: i32) -> Result<Option<SmartPlaylist>> {
+        let result = with_conn!(self.0, conn => smart_playlists::table
+            .find(id)
+            .first(conn)
+            .optional());
+        Ok(result?)
+    }
+
+    fn find_by_ids(&self, ids: Vec<i32>) -> Result<Vec<SmartPlaylist>> {
+        let result = with_conn!(self.0, conn => smart_playlists::table
+            .filter(smart_playlists::playlist_id.eq_any(ids))
+            .load::<SmartPlaylist>(conn));
+        Ok(result?)
+    }
+}
+
+fn new_smart_playlist<'a>(name: &'a str, query: &SmartPlaylistQuery) -> Result<NewSmartPlaylist<'a>> {
+    Ok(NewSmartPlaylist {
+        name,
+        rule: serde_json::to_string(&query.rule)?,
+        sort: query.sort.into(),
+        descending: query.descending,
+        max_tracks: query.limit.map(|l| l as i32),
+    })
+}
+
+macro_rules! sort_tracks {
+    ($tracks:expr, $query:expr) => {
+        match ($query.sort, $query.descending) {
+            (PlaylistSort::Default, false) => $tracks.order_by(tracks::track_id.asc()),
+            (PlaylistSort::Default, true) => $tracks.order_by(tracks::track_id.desc()),
+            (PlaylistSort::Title, false) => $tracks.order_by(tracks::title.asc()),
+            (PlaylistSort::Title, true) => $tracks.order_by(tracks::title.desc()),
+            (PlaylistSort::RecentlyAdded, false) => $tracks.order_by(tracks::created_at.desc()),
+            (PlaylistSort::RecentlyAdded, true) => $tracks.order_by(tracks::created_at.asc()),
+            //unfaved tracks go last either way
+            (PlaylistSort::RecentlyFaved, false) => $tracks.order_by((tracks::faved_at.is_null(), tracks::faved_at.desc())),
+            (PlaylistSort::RecentlyFaved, true) => $tracks.order_by((tracks::faved_at.is_null(), tracks::faved_at.asc())),
+            (PlaylistSort::Duration, false) => $tracks.order_by(tracks::duration_ms.asc()),
+            (PlaylistSort::Duration, true) => $tracks.order_by(tracks::duration_ms.desc()),
+            (PlaylistSort::Random, _) => $tracks.order_by(sql::<Integer>("random()")),
+        }
+    };
+}
+use sort_tracks;
+
+//the rule tree is compiled into a single filter on the tracks table; every
+//leaf is a subselect so the rules can be nested in any way. The compiler is
+//generated per backend since boxed expressions are bound to one
+macro_rules! rule_compiler {
+    ($name:ident, $db:ty) => {
+        fn $name(rule: &PlaylistRule, now: NaiveDateTime) -> Box<dyn BoxableExpression<tracks::table, $db, SqlType = Bool>> {
+            match rule {
+                PlaylistRule::All(rules) => rules.iter()
+                    .map(|rule| $name(rule, now))
+                    .fold(Box::new(tracks::track_id.is_not_null()), |all, rule| Box::new(all.and(rule))),
+                PlaylistRule::Any(rules) => rules.iter()
+                    .map(|rule| $name(rule, now))
+                    .fold(Box::new(tracks::track_id.is_null()), |any, rule| Box::new(any.or(rule))),
+                PlaylistRule::Not(rule) => Box::new(not($name(rule, now))),
+                PlaylistRule::Faved(faved) => Box::new(tracks::is_faved.eq(*faved)),
+                PlaylistRule::AlbumOfWeek { since_year, source_name } => {
+                    let mut picks = albums_of_week::table
+                        .select(albums_of_week::album_id)
+                        .into_boxed::<$db>();
+                    if let Some(since_year) = since_year {
+                        picks = picks.filter(albums_of_week::year.ge(*since_year));
+                    }
+                    if let Some(source_name) = source_name {
+                        picks = picks.filter(lower(albums_of_week::source_name).eq(lower(source_name.clone())));
+                    }
+                    Box::new(tracks::album_id.eq_any(picks))
+                }
+                PlaylistRule::ReleaseYear { from, to } => {
+                    let mut albums = albums::table
+                        .select(albums::album_id)
+                        .into_boxed::<$db>();
+                    if let Some(from) = from {
+                        albums = albums.filter(albums::year.ge(*from));
+                    }
+                    if let Some(to) = to {
+                        albums = albums.filter(albums::year.le(*to));
+                    }
+                    Box::new(tracks::album_id.eq_any(albums))
+                }
+                PlaylistRule::Charted { source_name, max_position, since_year } => {
+                    let mut entries = charts_of_week::table
+                        .select(charts_of_week::track_id)
+                        .into_boxed::<$db>();
+                    if let Some(source_name) = source_name {
+                        entries = entries.filter(lower(charts_of_week::source_name).eq(lower(source_name.clone())));
+                    }
+                    if let Some(max_position) = max_position {
+                        entries = entries.filter(charts_of_week::chart_position.le(*max_position));
+                    }
+                    if let Some(since_year) = since_year {
+                        entries = entries.filter(charts_of_week::year.ge(*since_year));
+                    }
+                    Box::new(tracks::track_id.eq_any(entries))
+                }
+                PlaylistRule::Genre(name) => {
+                    let genre_ids = genre::table
+                        .filter(lower(genre::name).eq(lower(name.clone())))
+                        .select(genre::genre_id);
+                    let artist_ids = artist_genre::table
+                        .filter(artist_genre::genre_id.eq_any(genre_ids))
+                        .select(artist_genre::artist_id);
+                    let track_ids = track_artist::table
+                        .filter(track_artist::artist_id.eq_any(artist_ids))
+                        .select(track_artist::track_id);
+                    Box::new(tracks::track_id.eq_any(track_ids))
+                }
+                PlaylistRule::Artist(artist_id) => {
+                    let track_ids = track_artist::table
+                        .filter(track_artist::artist_id.eq(*artist_id))
+                        .select(track_artist::track_id);
+                    Box::new(tracks::track_id.eq_any(track_ids))
+                }
+                PlaylistRule::NotPlayedForDays(days) => {
+                    let mut played = plays::table
+                        .select(plays::track_id)
+                        .into_boxed::<$db>();
+                    if let Some(since) = days_before(now, *days) {
+                        played = played.filter(plays::started_at.ge(since));
+                    }
+                    Box::new(tracks::track_id.ne_all(played))
+                }
+                PlaylistRule::PlayedWithinDays(days) => {
+                    let mut played = plays::table
+                        .select(plays::track_id)
+                        .into_boxed::<$db>();
+                    if let Some(since) = days_before(now, *days) {
+                        played = played.filter(plays::started_at.ge(since));
+                    }
+                    Box::new(tracks::track_id.eq_any(played))
+                }
+            }
+        }
+    };
+}
+
+//None if the days reach back further than chrono can represent, which means no lower bound
+fn days_before(now: NaiveDateTime, days: i64) -> Option<NaiveDateTime> {
+    now.checked_sub_signed(Duration::try_days(days)?)
+}
+
+rule_compiler!(compile_pg_rule, diesel::pg::Pg);
+rule_compiler!(compile_sqlite_rule, diesel::sqlite::Sqlite);
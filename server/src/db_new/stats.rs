@@ -0,0 +1,234 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::query_dsl::GroupByDsl;
+use diesel::sql_types::{BigInt, Text};
+
+use crate::db_new::{count, min, DbApi, DbError, DbPool, Result};
+use crate::db_new::schema::*;
+use crate::model::{LibraryEntity, StatsBucket, StatsRange};
+
+//everything ranked by the number of plays which ran until the end of the track,
+//the same way the play count of a track is computed
+pub trait StatsDb : Sync {
+    fn top_tracks(&self, range: &StatsRange, limit: i64) -> Result<Vec<(i32, i64)>>;
+    fn top_artists(&self, range: &StatsRange, limit: i64) -> Result<Vec<(i32, i64)>>;
+    fn top_albums(&self, range: &StatsRange, limit: i64) -> Result<Vec<(i32, i64)>>;
+    fn top_genres(&self, range: &StatsRange, limit: i64) -> Result<Vec<(i32, i64)>>;
+    fn listening_time(&self, range: &StatsRange, bucket: StatsBucket) -> Result<Vec<(NaiveDate, i64)>>;
+    fn first_listened(&self, entity: LibraryEntity, ids: &[i32]) -> Result<HashMap<i32, NaiveDateTime>>;
+    fn load_year_stats(&self, range: &StatsRange, year: i32) -> Result<YearStats>;
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct YearStats {
+    pub listened_ms : i64,
+    pub play_count : i64,
+    pub distinct_tracks : i64,
+    pub albums_of_week : i64,
+    //album ids of the year's albums of the week with at least one play in the range
+    pub listened_albums_of_week : Vec<i32>,
+    pub chart_tracks : i64,
+    pub listened_chart_tracks : i64,
+}
+
+impl StatsDb for DbApi {
+    fn top_tracks(&self, range: &StatsRange, limit: i64) -> Result<Vec<(i32, i64)>> {
+        let result = with_conn!(self.0, conn => plays::table
+            .filter(plays::completed.eq(true))
+            .filter(plays::started_at.ge(range.from))
+            .filter(plays::started_at.lt(range.to))
+            .group_by(plays::track_id)
+            .select((plays::track_id, count(plays::play_id)))
+            .order_by((count(plays::play_id).desc(), plays::track_id.asc()))
+            .limit(limit)
+            .load::<(i32, i64)>(conn));
+        Ok(result?)
+    }
+
+    fn top_artists(&self, range: &StatsRange, limit: i64) -> Result<Vec<(i32, i64)>> {
+        let result = with_conn!(self.0, conn => plays::table
+            .inner_join(track_artist::table.on(track_artist::track_id.eq(plays::track_id)))
+            .filter(plays::completed.eq(true))
+            .filter(plays::started_at.ge(range.from))
+            .filter(plays::started_at.lt(range.to))
+            .group_by(track_artist::artist_id)
+            .select((track_artist::artist_id, count(plays::play_id)))
+            .order_by((count(plays::play_id).desc(), track_artist::artist_id.asc()))
+            .limit(limit)
+            .load::<(i32, i64)>(conn));
+        Ok(result?)
+    }
+
+    fn top_albums(&self, range: &StatsRange, limit: i64) -> Result<Vec<(i32, i64)>> {
+        let result = with_conn!(self.0, conn => plays::table
+            .inner_join(tracks::table)
+            .filter(plays::completed.eq(true))
+            .filter(plays::started_at.ge(range.from))
+            .filter(plays::started_at.lt(range.to))
+            .group_by(tracks::album_id)
+            .select((tracks::album_id, count(plays::play_id)))
+            .order_by((count(plays::play_id).desc(), tracks::album_id.asc()))
+            .limit(limit)
+            .load::<(i32, i64)>(conn));
+        Ok(result?)
+    }
+
+    fn top_genres(&self, range: &StatsRange, limit: i64) -> Result<Vec<(i32, i64)>> {
+        //a track by several artists of the same genre still is a single play of it
+        let result = with_conn!(self.0, conn => plays::table
+            .inner_join(track_artist::table.on(track_artist::track_id.eq(plays::track_id)))
+            .inner_join(artist_genre::table.on(artist_genre::artist_id.eq(track_artist::artist_id)))
+            .filter(plays::completed.eq(true))
+            .filter(plays::started_at.ge(range.from))
+            .filter(plays::started_at.lt(range.to))
+            .group_by(artist_genre::genre_id)
+            .select((artist_genre::genre_id, sql::<BigInt>("count(distinct plays.play_id)")))
+            .order_by((sql::<BigInt>("count(distinct plays.play_id)").desc(), artist_genre::genre_id.asc()))
+            .limit(limit)
+            .load::<(i32, i64)>(conn));
+        Ok(result?)
+    }
+
+    fn listening_time(&self, range: &StatsRange, bucket: StatsBucket) -> Result<Vec<(NaiveDate, i64)>> {
+        //skipped plays were still listened to, so all of them add up
+        let bucket_sql = bucket_start_sql(&self.0, bucket);
+        let result = with_conn!(self.0, conn => plays::table
+            .filter(plays::started_at.ge(range.from))
+            .filter(plays::started_at.lt(range.to))
+            .group_by(sql::<Text>(bucket_sql))
+            .select((sql::<Text>(bucket_sql), sql::<BigInt>("cast(sum(played_ms) as bigint)")))
+            .order_by(sql::<Text>(bucket_sql).asc())
+            .load::<(String, i64)>(conn))?;
+
+        result.into_iter()
+            .map(|(day, listened_ms)| NaiveDate::parse_from_str(&day, "%Y-%m-%d")
+                .map(|day| (day, listened_ms))
+                .map_err(|e| DbError::Sql(diesel::result::Error::DeserializationError(Box::new(e)))))
+            .collect()
+    }
+
+    fn first_listened(&self, entity: LibraryEntity, ids: &[i32]) -> Result<HashMap<i32, NaiveDateTime>> {
+        let result = match entity {
+            LibraryEntity::Track => with_conn!(self.0, conn => plays::table
+                .filter(plays::track_id.eq_any(ids))
+                .group_by(plays::track_id)
+                .select((plays::track_id, min(plays::started_at)))
+                .load::<(i32, Option<NaiveDateTime>)>(conn)),
+            LibraryEntity::Album => with_conn!(self.0, conn => plays::table
+                .inner_join(tracks::table)
+                .filter(tracks::album_id.eq_any(ids))
+                .group_by(tracks::album_id)
+                .select((tracks::album_id, min(plays::started_at)))
+                .load::<(i32, Option<NaiveDateTime>)>(conn)),
+            LibraryEntity::Artist => with_conn!(self.0, conn => plays::table
+                .inner_join(track_artist::table.on(track_artist::track_id.eq(plays::track_id)))
+                .filter(track_artist::artist_id.eq_any(ids))
+                .group_by(track_artist::artist_id)
+                .select((track_artist::artist_id, min(plays::started_at)))
+                .load::<(i32, Option<NaiveDateTime>)>(conn)),
+            LibraryEntity::Unknown => Ok(vec![])
+        }?;
+
+        Ok(result.into_iter()
+            .filter_map(|(id, first)| first.map(|first| (id, first)))
+            .collect())
+    }
+
+    fn load_year_stats(&self, range: &StatsRange, year: i32) -> Result<YearStats> {
+        let result = with_conn!(self.0, conn => {
+            let in_range = plays::started_at.ge(range.from).and(plays::started_at.lt(range.to));
+
+            let (play_count, listened_ms) = plays::table
+                .filter(in_range)
+                .select((
+                    sql::<BigInt>("count(case when completed then 1 end)"),
+                    sql::<BigInt>("cast(coalesce(sum(played_ms), 0) as bigint)")
+                ))
+                .first::<(i64, i64)>(conn)?;
+
+            let played_track_ids = plays::table
+                .filter(in_range)
+                .select(plays::track_id);
+            let distinct_tracks = tracks::table
+                .filter(tracks::track_id.eq_any(played_track_ids))
+                .count()
+                .get_result::<i64>(conn)?;
+
+            //an album of the week counts as listened as soon as one of its tracks was played
+            let albums_of_week = albums_of_week::table
+                .filter(albums_of_week::year.eq(year))
+                .count()
+                .get_result::<i64>(conn)?;
+            let played_album_ids = plays::table
+                .inner_join(tracks::table)
+                .filter(in_range)
+                .select(tracks::album_id);
+            let listened_albums_of_week = albums_of_week::table
+                .filter(albums_of_week::year.eq(year))
+                .filter(albums_of_week::album_id.eq_any(played_album_ids))
+                .select(albums_of_week::album_id)
+                .distinct()
+                .load::<i32>(conn)?;
+
+            //a track may chart in several weeks, so count the tracks instead of the entries
+            let chart_track_ids = charts_of_week::table
+                .filter(charts_of_week::year.eq(year))
+                .select(charts_of_week::track_id);
+            let chart_tracks = tracks::table
+                .filter(tracks::track_id.eq_any(chart_track_ids))
+                .count()
+                .get_result::<i64>(conn)?;
+            let chart_track_ids = charts_of_week::table
+                .filter(charts_of_week::year.eq(year))
+                .select(charts_of_week::track_id);
+            let played_track_ids = plays::table
+                .filter(in_range)
+                .select(plays::track_id);
+            let listened_chart_tracks = tracks::table
+                .filter(tracks::track_id.eq_any(chart_track_ids))
+                .filter(tracks::track_id.eq_any(played_track_ids))
+                .count()
+                .get_result::<i64>(conn)?;
+
+            Ok::<_, diesel::result::Error>(YearStats {
+                listened_ms,
+                play_count,
+                distinct_tracks,
+                albums_of_week,
+                listened_albums_of_week,
+                chart_tracks,
+                listened_chart_tracks,
+            })
+        });
+        Ok(result?)
+    }
+}
+
+//renders the utc date a play falls into as yyyy-mm-dd; weeks are named after their monday
+fn bucket_start_sql(pool: &DbPool, bucket: StatsBucket) -> &'static str {
+    match (pool, bucket) {
+        (DbPool::Postgres(_) | DbPool::PostgresTx(_), StatsBucket::Day) => "to_char(started_at, 'YYYY-MM-DD')",
+        (DbPool::Postgres(_) | DbPool::PostgresTx(_), StatsBucket::Week) => "to_char(date_trunc('week', started_at), 'YYYY-MM-DD')",
+        (DbPool::Sqlite(_) | DbPool::SqliteTx(_), StatsBucket::Day) => "date(started_at)",
+        (DbPool::Sqlite(_) | DbPool::SqliteTx(_), StatsBucket::Week) => "date(started_at, 'weekday 0', '-6 days')",
+    }
+}
@@ -15,48 +15,71 @@
  */
 use diesel::prelude::*;
 
-use crate::db_new::{DbApi, DbError, DbPool, Result, SetFavedState};
+use crate::db_new::{lower, faved_at_for, DbApi, DbError, DbPool, Result, SetFavedState};
 use crate::db_new::{FindByFavedStatus, FindById};
 use crate::db_new::models::{Track, NewTrack, Album, Artist, TrackArtists};
+use crate::db_new::library_event::LibraryEventDb;
 use crate::db_new::schema::*;
-use crate::model::{RequestPage, UniversalId};
+use crate::model::{LibraryEntity, LibraryEventKind, ListOrder, PlaybackSource, RequestPage, UniversalId};
 
 pub trait TrackDb: FindById<Track> + FindByFavedStatus<Track> + SetFavedState<Track> + Sync {
     fn new_full_track(&self, new_track: NewTrack) -> Result<Track>;
+    fn new_full_track_if_missing(&self, new_track: NewTrack) -> Result<Track>;
     fn find_track_by_album(&self, album : &Album, name : &str) -> Result<Option<Track>>;
     fn find_track_by_universal_id(&self, uni_id : &UniversalId) -> Result<Option<Track>>;
     fn load_tracks_for_album(&self, album : &Album) -> Result<Vec<Track>>;
     fn load_fav_tracks_for_artist(&self, artist : &Artist, page : &RequestPage) -> Result<Vec<Track>>;
-    fn load_tracks(&self, page : &RequestPage) -> Result<Vec<Track>>;
+    /// All track ids of the album in disc and track order
+    fn load_track_ids_for_album(&self, album_id : i32) -> Result<Vec<i32>>;
+    /// All faved track ids of the artist ordered by release, disc and track
+    fn load_fav_track_ids_for_artist(&self, artist_id : i32) -> Result<Vec<i32>>;
+    fn load_tracks(&self, page : &RequestPage, order : ListOrder) -> Result<Vec<Track>>;
+    fn set_preferred_source(&self, track_id : i32, source : Option<PlaybackSource>) -> Result<()>;
 }
 
 impl TrackDb for DbApi {
 
     fn new_full_track(&self, new_track: NewTrack) -> Result<Track> {
-        let conn = self.0.get()?;
-        let result = diesel::insert_into(tracks::table)
-            .values(&new_track)
-            .get_result(&conn);
-        Ok(result?)
+        self.transaction(|tx| {
+            let track: Track = insert_returning!(tx.0, tracks::table, &new_track)?;
+            tx.new_library_event(LibraryEntity::Track, track.track_id, LibraryEventKind::Add)?;
+            Ok(track)
+        })
+    }
+
+    fn new_full_track_if_missing(&self, new_track: NewTrack) -> Result<Track> {
+        //only the spotify id is unique, tracks without one can't collide
+        let spot_id = match &new_track.spot_id {
+            Some(spot_id) => spot_id.clone(),
+            None => return self.new_full_track(new_track)
+        };
+        self.transaction(|tx| {
+            let inserted = insert_if_missing!(tx.0, tracks::table, &new_track, tracks::spot_id)?;
+            let track: Track = with_conn!(tx.0, conn => tracks::table
+                .filter(tracks::spot_id.eq(&spot_id))
+                .first(conn))?;
+            if inserted == 1 {
+                tx.new_library_event(LibraryEntity::Track, track.track_id, LibraryEventKind::Add)?;
+            }
+            Ok(track)
+        })
     }
 
     fn find_track_by_album(&self, album: &Album, name: &str) -> Result<Option<Track>> {
-        let conn = self.0.get()?;
-        let result = Track::belonging_to(album)
-            .filter(tracks::title.ilike(name))
-            .first(&conn)
-            .optional();
+        let result = with_conn!(self.0, conn => Track::belonging_to(album)
+            .filter(lower(tracks::title).eq(lower(name)))
+            .first(conn)
+            .optional());
         Ok(result?)
     }
 
     fn find_track_by_universal_id(&self, uni_id: &UniversalId) -> Result<Option<Track>> {
         match uni_id {
             UniversalId::Spotify(spot) => {
-                let conn = self.0.get()?;
-                let result = tracks::table
-                    .filter(tracks::spot_id.like(spot))
-                    .first(&conn)
-                    .optional();
+                let result = with_conn!(self.0, conn => tracks::table
+                    .filter(tracks::spot_id.eq(spot))
+                    .first(conn)
+                    .optional());
                 Ok(result?)
             },
             UniversalId::Database(id) => self.find_by_id(*id)
@@ -64,51 +87,100 @@ impl TrackDb for DbApi {
     }
     
     fn load_tracks_for_album(&self, album: &Album) -> Result<Vec<Track>> {
-        let conn = self.0.get()?;
-        let result = Track::belonging_to(album)
-            .load::<Track>(&conn);
+        let result = with_conn!(self.0, conn => Track::belonging_to(album)
+            .load::<Track>(conn));
         Ok(result?)
     }
 
     fn load_fav_tracks_for_artist(&self, artist: &Artist, page: &RequestPage) -> Result<Vec<Track>> {
-        let conn = self.0.get()?;
-        let tracks = TrackArtists::belonging_to(artist).select(track_artist::track_id);
-        let results = tracks::table
-            .filter(tracks::track_id.eq(diesel::dsl::any(tracks)))
+        with_conn!(self.0, conn => {
+            let tracks = TrackArtists::belonging_to(artist).select(track_artist::track_id);
+            let results = tracks::table
+                .filter(tracks::track_id.eq_any(tracks))
+                .filter(tracks::is_faved.eq(true))
+                .offset(page.offset())
+                .limit(page.limit())
+                .load::<Track>(conn);
+            Ok(results?)
+        })
+    }
+
+    fn load_track_ids_for_album(&self, album_id: i32) -> Result<Vec<i32>> {
+        let result = with_conn!(self.0, conn => tracks::table
+            .filter(tracks::album_id.eq(album_id))
+            //tracks without numbers go last on both backends
+            .order_by((tracks::disc_number.is_null(), tracks::disc_number.asc(),
+                       tracks::track_number.is_null(), tracks::track_number.asc(), tracks::track_id.asc()))
+            .select(tracks::track_id)
+            .load::<i32>(conn));
+        Ok(result?)
+    }
+
+    fn load_fav_track_ids_for_artist(&self, artist_id: i32) -> Result<Vec<i32>> {
+        let result = with_conn!(self.0, conn => tracks::table
+            .inner_join(albums::table)
+            .filter(tracks::track_id.eq_any(track_artist::table
+                .filter(track_artist::artist_id.eq(artist_id))
+                .select(track_artist::track_id)))
             .filter(tracks::is_faved.eq(true))
-            .offset(page.offset())
-            .limit(page.limit())
-            .load::<Track>(&conn);
-        Ok(results?)
+            .order_by((albums::year.asc(), albums::album_id.asc(),
+                       tracks::disc_number.is_null(), tracks::disc_number.asc(),
+                       tracks::track_number.is_null(), tracks::track_number.asc(), tracks::track_id.asc()))
+            .select(tracks::track_id)
+            .load::<i32>(conn));
+        Ok(result?)
     }
 
-    fn load_tracks(&self, page : &RequestPage) -> Result<Vec<Track>> {
-        let conn = self.0.get()?;
-        let result = tracks::table
-            .offset(page.offset())
-            .limit(page.limit())
-            .load::<Track>(&conn);
+    fn load_tracks(&self, page: &RequestPage, order: ListOrder) -> Result<Vec<Track>> {
+        let result = with_conn!(self.0, conn => {
+            let query = match order {
+                ListOrder::Default => tracks::table.into_boxed(),
+                ListOrder::RecentlyAdded => tracks::table
+                    .order_by(tracks::created_at.desc())
+                    .into_boxed(),
+                ListOrder::RecentlyFaved => tracks::table
+                    .filter(tracks::faved_at.is_not_null())
+                    .order_by(tracks::faved_at.desc())
+                    .into_boxed()
+            };
+            query
+                .offset(page.offset())
+                .limit(page.limit())
+                .load::<Track>(conn)
+        });
         Ok(result?)
     }
+
+    fn set_preferred_source(&self, track_id: i32, source: Option<PlaybackSource>) -> Result<()> {
+        with_conn!(self.0, conn => {
+            let updated = diesel::update(
+                tracks::table.filter(tracks::track_id.eq(track_id))
+            ).set(tracks::preferred_source.eq(source.map(i32::from)))
+                .execute(conn)?;
+
+            if updated == 1 {
+                Ok(())
+            }else{
+                Err(DbError::Update(format!("Failed to set preferred source of track {} to {:?}", track_id, source)))
+            }
+        })
+    }
 }
 
 impl FindById<Track> for DbApi {
     fn find_by_id(&self, id: i32) -> Result<Option<Track>> {
         use crate::db_new::schema::tracks::dsl::*;
-        let conn = self.0.get()?;
-        let result = tracks
+        let result = with_conn!(self.0, conn => tracks
             .find(id)
-            .first(&conn)
-            .optional();
+            .first(conn)
+            .optional());
         Ok(result?)
     }
 
     fn find_by_ids(&self, ids: Vec<i32>) -> Result<Vec<Track>> {
-        use diesel::dsl::any;
-        let conn = self.0.get()?;
-        let result = tracks::table
-            .filter(tracks::track_id.eq(any(ids)))
-            .load::<Track>(&conn);
+        let result = with_conn!(self.0, conn => tracks::table
+            .filter(tracks::track_id.eq_any(ids))
+            .load::<Track>(conn));
         Ok(result?)
     }
 }
@@ -125,27 +197,32 @@ impl FindByFavedStatus<Track> for DbApi {
 
 impl SetFavedState<Track> for DbApi {
     fn set_faved_state(&self, id: i32, now_faved: bool) -> Result<()> {
-        let conn = self.0.get()?;
-        let updated = diesel::update(
-            tracks::table.filter(tracks::track_id.eq(id))
-        ).set(tracks::is_faved.eq(now_faved))
-            .execute(&conn)?;
-
-        if updated == 1 {
-            Ok(())
-        }else{
-            Err(DbError::Update(format!("Failed to set track {} to fav state {}", id, now_faved)))
-        }
+        self.transaction(|tx| {
+            let updated = with_conn!(tx.0, conn => diesel::update(
+                tracks::table
+                    .filter(tracks::track_id.eq(id))
+                    .filter(tracks::is_faved.ne(now_faved))
+            ).set((tracks::is_faved.eq(now_faved), tracks::faved_at.eq(faved_at_for(now_faved))))
+                .execute(conn))?;
+
+            if updated == 1 {
+                tx.new_library_event(LibraryEntity::Track, id, LibraryEventKind::for_fav_state(now_faved))
+            }else if FindById::<Track>::find_by_id(tx, id)?.is_some() {
+                //already in the requested state, nothing changed
+                Ok(())
+            }else{
+                Err(DbError::Update(format!("Failed to set track {} to fav state {}", id, now_faved)))
+            }
+        })
     }
 }
 
 fn _find_by_fav_status(pool : &DbPool, faved : bool, offset : i64, limit : i64) -> Result<Vec<Track>> {
     use crate::db_new::schema::tracks::dsl::*;
-    let conn = pool.get()?;
-    let results = tracks
+    let results = with_conn!(*pool, conn => tracks
         .filter(is_faved.eq(faved))
         .limit(limit)
         .offset(offset)
-        .load::<Track>(&conn);
+        .load::<Track>(conn));
     Ok(results?)
 }
\ No newline at end of file
@@ -25,7 +25,6 @@ use crate::db_new::schema::*;
 use crate::model::RequestPage;
 
 pub trait TrackArtistsDb {
-    fn new_track_artist(&self, track_id : i32, artist_id : i32) -> Result<TrackArtists>;
     fn new_track_artist_if_missing(&self, track_id : i32, artist_id : i32) -> Result<TrackArtists>;
     fn load_track_for_artist(&self, artist: &Artist, page : RequestPage) -> Result<Vec<Track>>;
     fn load_artists_for_track(&self, track : &Track) -> Result<Vec<Artist>>;
@@ -33,75 +32,63 @@ pub trait TrackArtistsDb {
 }
 
 impl TrackArtistsDb for DbApi {
-    fn new_track_artist(&self, track_id: i32, artist_id: i32) -> Result<TrackArtists> {
-        let conn = self.0.get()?;
-        let result = diesel::insert_into(track_artist::table)
-            .values(&NewTrackArtists{
-                track_id,
-                artist_id
-            })
-            .get_result(&conn);
-        Ok(result?)
-    }
-
     fn new_track_artist_if_missing(&self, track_id: i32, artist_id: i32) -> Result<TrackArtists> {
-        let conn = self.0.get()?;
-        let option = track_artist::table
-                    .filter(track_artist::track_id.eq(track_id))
-                    .filter(track_artist::artist_id.eq(artist_id))
-                    .first(&conn)
-                    .optional()?;
+        insert_if_missing!(self.0, track_artist::table, &NewTrackArtists {
+            track_id,
+            artist_id,
+        }, (track_artist::track_id, track_artist::artist_id))?;
 
-        match option {
-            Some(link) => Ok(link),
-            None => self.new_track_artist(track_id, artist_id)
-        }
+        let result = with_conn!(self.0, conn => track_artist::table
+            .filter(track_artist::track_id.eq(track_id))
+            .filter(track_artist::artist_id.eq(artist_id))
+            .first(conn));
+        Ok(result?)
     }
     
     fn load_track_for_artist(&self, artist: &Artist, page: RequestPage) -> Result<Vec<Track>> {
         use crate::db_new::schema::track_artist::dsl::*;
-        use diesel::dsl::any;
 
-        let conn = self.0.get()?;
-        let track_ids = TrackArtists::belonging_to(artist).select(track_id);
-        let result = tracks::table
-            .filter(tracks::track_id.eq(any(track_ids)))
-            .limit(page.limit())
-            .offset(page.offset())
-            .load::<Track>(&conn);
-        Ok(result?)
+        with_conn!(self.0, conn => {
+            let track_ids = TrackArtists::belonging_to(artist).select(track_id);
+            let result = tracks::table
+                .filter(tracks::track_id.eq_any(track_ids))
+                .limit(page.limit())
+                .offset(page.offset())
+                .load::<Track>(conn);
+            Ok(result?)
+        })
     }
 
     fn load_artists_for_track(&self, track: &Track) -> Result<Vec<Artist>> {
         use crate::db_new::schema::track_artist::dsl::*;
-        use diesel::dsl::any;
 
-        let conn = self.0.get()?;
-        let artist_ids = TrackArtists::belonging_to(track).select(artist_id);
-        let result = artists::table
-            .filter(artists::artist_id.eq(any(artist_ids)))
-            .load::<Artist>(&conn);
-        Ok(result?)
+        with_conn!(self.0, conn => {
+            let artist_ids = TrackArtists::belonging_to(track).select(artist_id);
+            let result = artists::table
+                .filter(artists::artist_id.eq_any(artist_ids))
+                .load::<Artist>(conn);
+            Ok(result?)
+        })
     }
 
     fn load_artist_ids_for_tracks(&self, tracks: &[Track]) -> Result<HashMap<i32, Vec<i32>>> {
-        use diesel::dsl::any;
         let track_ids = tracks.iter().map(|t| t.track_id).unique().collect_vec();
-        let conn = self.0.get()?;
-        let track_artists = track_artist::table
-            .filter(track_artist::track_id.eq(any(track_ids)))
-            .load::<TrackArtists>(&conn)?;
+        with_conn!(self.0, conn => {
+            let track_artists = track_artist::table
+                .filter(track_artist::track_id.eq_any(track_ids))
+                .load::<TrackArtists>(conn)?;
 
-        let mut map : HashMap<i32, Vec<i32>> = HashMap::new();
-        for track_artist in &track_artists {
-            match map.get_mut(&track_artist.track_id) {
-                Some(artists) => artists.insert(0, track_artist.artist_id),
-                None => {
-                    map.insert(track_artist.track_id, vec![track_artist.artist_id]);
+            let mut map : HashMap<i32, Vec<i32>> = HashMap::new();
+            for track_artist in &track_artists {
+                match map.get_mut(&track_artist.track_id) {
+                    Some(artists) => artists.insert(0, track_artist.artist_id),
+                    None => {
+                        map.insert(track_artist.track_id, vec![track_artist.artist_id]);
+                    }
                 }
             }
-        }
 
-        Ok(map)
+            Ok(map)
+        })
     }
 }
\ No newline at end of file
@@ -32,75 +32,69 @@ pub trait TrackFavProposalDb: FindById<TrackFavProposal> + Sync {
 
 impl TrackFavProposalDb for DbApi {
     fn new_track_proposal(&self, new_proposal: NewTrackFavProposal) -> Result<TrackFavProposal> {
-        let conn = self.0.get()?;
-        let result = diesel::insert_into(track_fav_proposals::table)
-            .values(&new_proposal)
-            .get_result(&conn);
+        let result = insert_returning!(self.0, track_fav_proposals::table, &new_proposal);
         Ok(result?)
     }
 
     fn load_track_proposals(&self, page : &RequestPage) -> Result<Vec<TrackFavProposal>> {
-        let conn = self.0.get()?;
-        let results = track_fav_proposals::table
+        let results = with_conn!(self.0, conn => track_fav_proposals::table
             .filter(track_fav_proposals::track_id.is_null())
             .offset(page.offset())
             .limit(page.limit())
-            .load::<TrackFavProposal>(&conn);
+            .load::<TrackFavProposal>(conn));
         Ok(results?)
     }
 
     fn find_by_source_and_raw_pattern(&self, source: &str, pattern: &str) -> Result<Option<TrackFavProposal>> {
-        let conn = self.0.get()?;
-        let result = track_fav_proposals::table
+        let result = with_conn!(self.0, conn => track_fav_proposals::table
             .filter(track_fav_proposals::source_name.like(source))
             .filter(track_fav_proposals::source_prop.like(pattern))
-            .first(&conn)
-            .optional();
+            .first(conn)
+            .optional());
         Ok(result?)
     }
 
     fn link_to_track(&self, id: i32, track_id: i32) -> Result<()> {
-        let conn = self.0.get()?;
-        let updated = diesel::update(
-            track_fav_proposals::table.filter(track_fav_proposals::track_fav_id.eq(id))
-        ).set(track_fav_proposals::track_id.eq(track_id))
-            .execute(&conn)?;
+        with_conn!(self.0, conn => {
+            let updated = diesel::update(
+                track_fav_proposals::table.filter(track_fav_proposals::track_fav_id.eq(id))
+            ).set(track_fav_proposals::track_id.eq(track_id))
+                .execute(conn)?;
 
-        if updated == 1 { Ok(()) } else{
-            Err(DbError::Update(format!("Failed to link track proposal {} to track {}", id, track_id)))
-        }
+            if updated == 1 { Ok(()) } else{
+                Err(DbError::Update(format!("Failed to link track proposal {} to track {}", id, track_id)))
+            }
+        })
     }
 
 
     fn delete_track_proposal(&self, id: i32) -> Result<()> {
-        let conn = self.0.get()?;
-        let deleted = diesel::delete(
-            track_fav_proposals::table
-                .filter(track_fav_proposals::track_fav_id.eq(id))
-        ).execute(&conn)?;
+        with_conn!(self.0, conn => {
+            let deleted = diesel::delete(
+                track_fav_proposals::table
+                    .filter(track_fav_proposals::track_fav_id.eq(id))
+            ).execute(conn)?;
 
-        if deleted == 1 { Ok(()) } else{
-            Err(DbError::Delete(format!("Failed to delete proposal {}", id)))
-        }
+            if deleted == 1 { Ok(()) } else{
+                Err(DbError::Delete(format!("Failed to delete proposal {}", id)))
+            }
+        })
     }
 }
 
 impl FindById<TrackFavProposal> for DbApi {
     fn find_by_id(&self, id: i32) -> Result<Option<TrackFavProposal>> {
-        let conn = self.0.get()?;
-        let result = track_fav_proposals::table
+        let result = with_conn!(self.0, conn => track_fav_proposals::table
             .find(id)
-            .first(&conn)
-            .optional();
+            .first(conn)
+            .optional());
         Ok(result?)
     }
 
     fn find_by_ids(&self, ids: Vec<i32>) -> Result<Vec<TrackFavProposal>> {
-        use diesel::dsl::any;
-        let conn = self.0.get()?;
-        let result = track_fav_proposals::table
-            .filter(track_fav_proposals::track_fav_id.eq(any(ids)))
-            .load::<TrackFavProposal>(&conn);
+        let result = with_conn!(self.0, conn => track_fav_proposals::table
+            .filter(track_fav_proposals::track_fav_id.eq_any(ids))
+            .load::<TrackFavProposal>(conn));
         Ok(result?)
     }
 }
\ No newline at end of file
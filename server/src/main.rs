@@ -16,6 +16,8 @@
 
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -23,19 +25,30 @@ use tokio::sync::RwLock;
 
 use tonic::transport::Server;
 use url::Url;
+use crate::playback::autoplay::Autoplay;
 use crate::playback::local_player::LocalPlayer;
-use crate::playback::PlaybackController;
+use crate::playback::mixer::SharedMixer;
+use crate::playback::output::{AudioOutput, AudioOutputConfig};
+use crate::playback::{PlaybackConfig, PlaybackController};
 use crate::playback::spotify_player::SpotifyPlayer;
+use crate::artwork::ArtworkStore;
 
 use crate::services::definition::library_server::LibraryServer;
 use crate::services::definition::tasks_server::TasksServer;
 use crate::services::definition::spotify_auth_server::SpotifyAuthServer;
 use crate::services::definition::playback_controls_server::PlaybackControlsServer;
+use crate::services::definition::stats_server::StatsServer;
+use crate::services::definition::smart_playlists_server::SmartPlaylistsServer;
 use crate::services::library::LibraryService;
 use crate::services::spotify_auth::SpotifyAuthService;
 use crate::services::tasks::TasksService;
 use crate::services::playback::PlaybackControlsService;
+use crate::services::stats::StatsService;
+use crate::services::smart_playlists::SmartPlaylistsService;
 use crate::spotify::SpotifyApi;
+use crate::scrobbler::Scrobbler;
+use crate::streaming::StreamingServer;
+use crate::mpd::MpdServer;
 
 mod model;
 mod services;
@@ -44,6 +57,10 @@ mod string_utils;
 mod db_new;
 mod spotify;
 mod playback;
+mod artwork;
+mod scrobbler;
+mod streaming;
+mod mpd;
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -51,7 +68,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv::dotenv().ok();
     let url_env_val = dotenv::var("DATABASE_URL").expect("Failed to read ENV variable DATABASE_URL");
     let url = Url::parse(&*url_env_val).expect("Url is not valid!");
-    let db_api = db_new::DbApi::new(url);
+    //migrations run at startup unless disabled with --no-migrate; the last switch wins
+    let run_migrations = std::env::args().skip(1).fold(true, |migrate, arg| match &*arg {
+        "--migrate" => true,
+        "--no-migrate" => false,
+        _ => migrate
+    });
+    let db_api = db_new::DbApi::new(url, run_migrations)?;
 
     let spotify = match SpotifyApi::new().await {
         Ok(s) => s,
@@ -64,23 +87,58 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let spot_user = dotenv::var("SPOT_USER").expect("Failed to read ENV variable SPOT_USER");
     let spot_pass = dotenv::var("SPOT_PASS").expect("Failed to read ENV variable SPOT_PASS");
     let spot_cache = ("./.spot_cache/system", "./.spot_cache/audio");
-    let spotify_player = SpotifyPlayer::new(&*spot_user, &*spot_pass, spot_cache).await;
-    let local_player = LocalPlayer::new();
+    let mixer = SharedMixer::from_env()?;
+    let output = AudioOutput::new(AudioOutputConfig::from_env()?);
+    let spotify_player = SpotifyPlayer::new(&*spot_user, &*spot_pass, spot_cache, &mixer, &output).await;
+    let local_player = LocalPlayer::new(mixer.clone(), output.clone());
+    let playback_config = PlaybackConfig::from_env()?;
+
+    let scrobbler = Scrobbler::from_env(db_api.clone())?;
+    if let Some(scrobbler) = &scrobbler {
+        scrobbler.init();
+    }
+
+    let autoplay = Autoplay::from_env(db_api.clone(), spotify.clone())?;
 
     let mut playback_controller = PlaybackController::new(
         db_api.clone(),
         spotify_player,
-        local_player
+        local_player,
+        playback_config,
+        scrobbler,
+        autoplay,
+        mixer
     )?;
     playback_controller.init().await;
 
+    if let Some(streaming) = StreamingServer::from_env(db_api.clone(), output)? {
+        streaming.init();
+    }
+
+    if let Some(mpd) = MpdServer::from_env(db_api.clone(), playback_controller.clone())? {
+        mpd.init();
+    }
+
+    let artwork = ArtworkStore::new(db_api.clone());
+
     let library_service = LibraryService{
+        db : db_api.clone(),
+        artwork : artwork.clone()
+    };
+
+    let stats_service = StatsService{
         db : db_api.clone()
     };
 
+    let smart_playlists_service = SmartPlaylistsService{
+        db : db_api.clone(),
+        queue : playback_controller.queue()
+    };
+
     let tasks_service = TasksService{
         db : db_api.clone(),
-        spotify: spotify.clone()
+        spotify: spotify.clone(),
+        artwork
     };
 
     let spotify_auth = SpotifyAuthService{
@@ -88,6 +146,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     let playback_service = PlaybackControlsService{
+        db : db_api.clone(),
         playback : Arc::new(RwLock::new(playback_controller))
     };
 
@@ -102,6 +161,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Server::builder()
         .add_service(PlaybackControlsServer::new(playback_service))
         .add_service(LibraryServer::new(library_service))
+        .add_service(StatsServer::new(stats_service))
+        .add_service(SmartPlaylistsServer::new(smart_playlists_service))
         .add_service(TasksServer::new(tasks_service))
         .add_service(SpotifyAuthServer::new(spotify_auth))
         .serve(sock_addr)
@@ -1,4 +1,6 @@
 
+use chrono::NaiveDateTime;
+
 #[derive(Clone, Debug)]
 pub struct SimpleTrack {
     pub track_id : i32,
@@ -6,7 +8,9 @@ pub struct SimpleTrack {
     pub album : SimpleAlbum,
     pub artists : Vec<SimpleArtist>,
     pub is_faved : bool,
-    pub duration_ms : i64
+    pub duration_ms : i64,
+    pub play_count : i64,
+    pub last_played : Option<NaiveDateTime>
 }
 
 #[derive(Clone, Debug)]
@@ -14,9 +14,11 @@
  * limitations under the License.
  */
 
+use chrono::{NaiveDate, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 
 pub mod library_models;
+pub mod smart_playlist;
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub enum UniversalId {
@@ -90,21 +92,58 @@ impl RequestPage {
     }
 }
 
+//the i32 encoding is shared by the db and the proto AlbumTypes enum
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum AlbumType {
+    Unknown,
     Single,
     Album,
     Compilation,
     AppearsOn,
+    EP,
+    Live,
+}
+
+impl AlbumType {
+    pub const ALL: [AlbumType; 7] = [
+        AlbumType::Album,
+        AlbumType::EP,
+        AlbumType::Single,
+        AlbumType::Live,
+        AlbumType::Compilation,
+        AlbumType::AppearsOn,
+        AlbumType::Unknown,
+    ];
+
+    pub fn from_spotify(album : &rspotify::model::FullAlbum) -> Self {
+        Self::classify(album.album_type.into(), &album.name, album.tracks.total as i32)
+    }
+
+    //spotify has no dedicated types for EPs and live records, so derive them
+    //using the same rules spotify uses for releases (4-6 tracks make an EP)
+    pub fn classify(base : AlbumType, name : &str, total_tracks : i32) -> Self {
+        let lower_name = name.to_lowercase();
+        let is_live = lower_name.contains("(live") || lower_name.contains("[live")
+            || lower_name.contains(" live at ") || lower_name.contains(" live in ")
+            || lower_name.starts_with("live at ") || lower_name.starts_with("live in ");
+        match base {
+            AlbumType::Single if (4..=6).contains(&total_tracks) => AlbumType::EP,
+            AlbumType::Album if is_live => AlbumType::Live,
+            other => other
+        }
+    }
 }
 
 impl From<i32> for AlbumType {
     fn from(t: i32) -> Self {
         match t {
-            0 => AlbumType::Album,
             1 => AlbumType::Single,
-            2 => AlbumType::Compilation,
-            3 => AlbumType::AppearsOn,
-            _ => panic!("Unknown album type!")
+            2 => AlbumType::Album,
+            3 => AlbumType::Compilation,
+            4 => AlbumType::AppearsOn,
+            5 => AlbumType::EP,
+            6 => AlbumType::Live,
+            _ => AlbumType::Unknown
         }
     }
 }
@@ -112,10 +151,13 @@ impl From<i32> for AlbumType {
 impl From<AlbumType> for i32 {
     fn from(t: AlbumType) -> Self {
         match t {
-            AlbumType::Album => 0,
+            AlbumType::Unknown => 0,
             AlbumType::Single => 1,
-            AlbumType::Compilation => 2,
-            AlbumType::AppearsOn => 3
+            AlbumType::Album => 2,
+            AlbumType::Compilation => 3,
+            AlbumType::AppearsOn => 4,
+            AlbumType::EP => 5,
+            AlbumType::Live => 6
         }
     }
 }
@@ -129,4 +171,218 @@ impl From<rspotify::model::AlbumType> for AlbumType {
             rspotify::model::AlbumType::AppearsOn => AlbumType::AppearsOn
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PlaybackSource {
+    Local,
+    Spotify,
+}
+
+impl PlaybackSource {
+    pub fn from_db(value: Option<i32>) -> Option<Self> {
+        match value {
+            Some(1) => Some(PlaybackSource::Local),
+            Some(2) => Some(PlaybackSource::Spotify),
+            _ => None
+        }
+    }
+
+    pub fn other(&self) -> Self {
+        match self {
+            PlaybackSource::Local => PlaybackSource::Spotify,
+            PlaybackSource::Spotify => PlaybackSource::Local
+        }
+    }
+}
+
+impl From<PlaybackSource> for i32 {
+    fn from(s: PlaybackSource) -> Self {
+        match s {
+            PlaybackSource::Local => 1,
+            PlaybackSource::Spotify => 2
+        }
+    }
+}
+
+impl std::str::FromStr for PlaybackSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "local" => Ok(PlaybackSource::Local),
+            "spotify" => Ok(PlaybackSource::Spotify),
+            _ => Err(format!("Unknown playback source '{}'!", s))
+        }
+    }
+}
+
+/// Replay gain of a local file measured per EBU R128. Gains are dB relative
+/// to the -18 LUFS reference, peaks are linear sample values.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    /// Sample factor to apply in the given mode; album mode falls back to the
+    /// track gain. The gain is limited so the peak doesn't clip.
+    pub fn factor(&self, mode: ReplayGainMode) -> f32 {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Album if self.album_gain.is_some() => (self.album_gain, self.album_peak),
+            ReplayGainMode::Album | ReplayGainMode::Track => (self.track_gain, self.track_peak)
+        };
+        let factor = match gain {
+            Some(gain) => 10f32.powf(gain / 20.0),
+            None => return 1.0
+        };
+        match peak {
+            Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReplayGainMode {
+    Track,
+    Album,
+    Off,
+}
+
+impl std::str::FromStr for ReplayGainMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "track" => Ok(ReplayGainMode::Track),
+            "album" => Ok(ReplayGainMode::Album),
+            "off" => Ok(ReplayGainMode::Off),
+            _ => Err(format!("Unknown replay gain mode '{}'!", s))
+        }
+    }
+}
+
+//the i32 encoding is shared by the db and the proto LibraryEntities enum
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LibraryEntity {
+    Unknown,
+    Artist,
+    Album,
+    Track,
+}
+
+impl From<i32> for LibraryEntity {
+    fn from(e: i32) -> Self {
+        match e {
+            1 => LibraryEntity::Artist,
+            2 => LibraryEntity::Album,
+            3 => LibraryEntity::Track,
+            _ => LibraryEntity::Unknown
+        }
+    }
+}
+
+impl From<LibraryEntity> for i32 {
+    fn from(e: LibraryEntity) -> Self {
+        match e {
+            LibraryEntity::Unknown => 0,
+            LibraryEntity::Artist => 1,
+            LibraryEntity::Album => 2,
+            LibraryEntity::Track => 3
+        }
+    }
+}
+
+//the i32 encoding is shared by the db and the proto LibraryEventKinds enum
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LibraryEventKind {
+    Unknown,
+    Add,
+    Remove,
+    Fav,
+    Unfav,
+}
+
+impl LibraryEventKind {
+    pub fn for_fav_state(now_faved: bool) -> Self {
+        if now_faved { LibraryEventKind::Fav } else { LibraryEventKind::Unfav }
+    }
+}
+
+impl From<i32> for LibraryEventKind {
+    fn from(k: i32) -> Self {
+        match k {
+            1 => LibraryEventKind::Add,
+            2 => LibraryEventKind::Remove,
+            3 => LibraryEventKind::Fav,
+            4 => LibraryEventKind::Unfav,
+            _ => LibraryEventKind::Unknown
+        }
+    }
+}
+
+impl From<LibraryEventKind> for i32 {
+    fn from(k: LibraryEventKind) -> Self {
+        match k {
+            LibraryEventKind::Unknown => 0,
+            LibraryEventKind::Add => 1,
+            LibraryEventKind::Remove => 2,
+            LibraryEventKind::Fav => 3,
+            LibraryEventKind::Unfav => 4
+        }
+    }
+}
+
+//what caused a library change; recorded with every library event
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChangeSource {
+    Api,
+    Proposals,
+    SpotifyImport,
+    AlbumOfWeek,
+}
+
+impl ChangeSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeSource::Api => "api",
+            ChangeSource::Proposals => "proposals",
+            ChangeSource::SpotifyImport => "spotify_import",
+            ChangeSource::AlbumOfWeek => "album_of_week"
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ListOrder {
+    Default,
+    RecentlyAdded,
+    RecentlyFaved,
+}
+
+//all statistics are computed in utc
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StatsRange {
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+}
+
+impl StatsRange {
+    pub fn year(year: i32) -> Option<Self> {
+        let from = NaiveDate::from_ymd_opt(year, 1, 1)?.and_hms_opt(0, 0, 0)?;
+        let to = NaiveDate::from_ymd_opt(year + 1, 1, 1)?.and_hms_opt(0, 0, 0)?;
+        Some(Self { from, to })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum StatsBucket {
+    Day,
+    //weeks start on monday
+    Week,
+}
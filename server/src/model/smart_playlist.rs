@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+/// Filter of a smart playlist; persisted as json, so variants must stay backwards compatible
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaylistRule {
+    //an empty group matches every track
+    All(Vec<PlaylistRule>),
+    //an empty group matches no track
+    Any(Vec<PlaylistRule>),
+    Not(Box<PlaylistRule>),
+    Faved(bool),
+    //the album of the track was an album of the week; since_year refers to the pick
+    AlbumOfWeek { since_year: Option<i32>, source_name: Option<String> },
+    ReleaseYear { from: Option<i32>, to: Option<i32> },
+    Charted { source_name: Option<String>, max_position: Option<i32>, since_year: Option<i32> },
+    Genre(String),
+    Artist(i32),
+    //tracks which were never played match as well
+    NotPlayedForDays(i64),
+    PlayedWithinDays(i64),
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PlaylistSort {
+    Default,
+    Title,
+    RecentlyAdded,
+    RecentlyFaved,
+    Duration,
+    Random,
+}
+
+//the i32 encoding is shared by the db and the proto PlaylistSorts enum
+impl From<i32> for PlaylistSort {
+    fn from(sort: i32) -> Self {
+        match sort {
+            1 => PlaylistSort::Title,
+            2 => PlaylistSort::RecentlyAdded,
+            3 => PlaylistSort::RecentlyFaved,
+            4 => PlaylistSort::Duration,
+            5 => PlaylistSort::Random,
+            _ => PlaylistSort::Default
+        }
+    }
+}
+
+impl From<PlaylistSort> for i32 {
+    fn from(sort: PlaylistSort) -> Self {
+        match sort {
+            PlaylistSort::Default => 0,
+            PlaylistSort::Title => 1,
+            PlaylistSort::RecentlyAdded => 2,
+            PlaylistSort::RecentlyFaved => 3,
+            PlaylistSort::Duration => 4,
+            PlaylistSort::Random => 5
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmartPlaylistQuery {
+    pub rule: PlaylistRule,
+    pub sort: PlaylistSort,
+    pub descending: bool,
+    pub limit: Option<i64>,
+}
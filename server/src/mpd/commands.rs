@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
+use crate::db_new::album::AlbumDb;
+use crate::db_new::artist::ArtistDb;
+use crate::db_new::models::Track;
+use crate::db_new::search::{SearchDb, SearchTag, TagFilter};
+use crate::db_new::track_artist::TrackArtistsDb;
+use crate::model::library_models::SimpleTrack;
+use crate::playback::{PlaybackState, PlaybackTrack};
+
+use super::filter::{parse_filters, parse_tag, split_window};
+use super::protocol::{self, field, parse_arg, parse_ms, parse_range, parse_track_uri, Range, Song};
+use super::{MpdError, MpdServer, Result};
+
+//upper bound of songs or tag values a single search lists
+const RESULT_LIMIT: i64 = 5000;
+
+const COMMANDS: [&str; 42] = [
+    "add", "addid", "clear", "close", "command_list_begin", "command_list_end", "command_list_ok_begin",
+    "commands", "currentsong", "delete", "deleteid", "find", "findadd", "getvol", "idle", "list", "next",
+    "noidle", "notcommands", "outputs", "pause", "ping", "play", "playid", "playlistid", "playlistinfo",
+    "plchanges", "plchangesposid", "previous", "search", "searchadd", "seek", "seekcur", "seekid",
+    "setvol", "stats", "status", "stop", "tagtypes", "urlhandlers", "decoders", "volume",
+];
+
+const TAG_TYPES: [&str; 6] = ["Artist", "Album", "Title", "Track", "Disc", "Date"];
+
+/// The playing track followed by the queue, as the protocol's playlist
+struct Playlist {
+    version: u64,
+    entries: Vec<PlaybackTrack>,
+    has_current: bool,
+}
+
+impl Playlist {
+    fn entry(&self, position: usize) -> Result<&PlaybackTrack> {
+        self.entries.get(position)
+            .ok_or_else(|| MpdError::Argument(format!("Bad song index {}", position)))
+    }
+
+    fn position_of(&self, item_id: u64) -> Result<usize> {
+        self.entries.iter()
+            .position(|t| t.item_id == item_id)
+            .ok_or_else(|| MpdError::NoExist(format!("No such song {}", item_id)))
+    }
+
+    //the queue doesn't hold the playing track
+    fn queue_index(&self, position: usize) -> Option<usize> {
+        match self.has_current {
+            true => position.checked_sub(1),
+            false => Some(position),
+        }
+    }
+
+    /// The entries within the range; an empty playlist has no valid positions at all
+    fn range(&self, start: usize, end: Option<usize>) -> Result<&[PlaybackTrack]> {
+        let end = end.unwrap_or(self.entries.len()).min(self.entries.len());
+        if start >= self.entries.len() && !(start == 0 && self.entries.is_empty()) {
+            return Err(MpdError::Argument(format!("Bad song index {}", start)));
+        }
+        Ok(&self.entries[start.min(end)..end])
+    }
+}
+
+impl MpdServer {
+    pub(super) async fn execute(&self, command: &str, args: &[String], out: &mut String) -> Result<()> {
+        log::debug!("Mpd command {} {:?}", command, args);
+        match command {
+            "ping" => Ok(()),
+            "commands" => {
+                COMMANDS.iter().sorted().for_each(|c| field(out, "command", c));
+                Ok(())
+            }
+            "notcommands" | "decoders" => Ok(()),
+            "tagtypes" => {
+                //only listing and enabling all of them is supported
+                if args.is_empty() {
+                    TAG_TYPES.iter().for_each(|t| field(out, "tagtype", t));
+                }
+                Ok(())
+            }
+            "urlhandlers" => {
+                field(out, "handler", "soundbase:");
+                Ok(())
+            }
+            "stats" => {
+                field(out, "uptime", self.started.elapsed().as_secs());
+                Ok(())
+            }
+            "outputs" => {
+                field(out, "outputid", 0);
+                field(out, "outputname", self.playback.audio_output().backend);
+                field(out, "plugin", self.playback.audio_output().backend);
+                field(out, "outputenabled", 1);
+                Ok(())
+            }
+
+            "status" => self.status(out).await,
+            "currentsong" => {
+                if let Some(track) = self.playback.get_state().await.current_track {
+                    protocol::song(out, &queued_song(&track.meta), Some((0, track.item_id)));
+                }
+                Ok(())
+            }
+            "getvol" => {
+                field(out, "volume", volume(&self.playback.get_state().await));
+                Ok(())
+            }
+            "setvol" => {
+                let volume = parse_arg::<u32>(args, 0, "volume")?;
+                self.playback.set_volume(volume.min(100));
+                Ok(())
+            }
+            "volume" => {
+                let change = parse_arg::<i32>(args, 0, "volume change")?;
+                let current = self.playback.mixer_state().volume as i32;
+                let volume = current.checked_add(change)
+                    .ok_or_else(|| MpdError::Argument(format!("Invalid volume change '{}'", change)))?;
+                self.playback.set_volume(volume.clamp(0, 100) as u32);
+                Ok(())
+            }
+
+            "play" => match args.first() {
+                Some(_) => self.play_entry(parse_arg::<usize>(args, 0, "song position")?).await,
+                None => Ok(self.playback.start_playback().await?)
+            },
+            "playid" => match args.first() {
+                Some(_) => {
+                    let item_id = parse_arg::<u64>(args, 0, "song id")?;
+                    let position = self.playlist().await.position_of(item_id)?;
+                    self.play_entry(position).await
+                }
+                None => Ok(self.playback.start_playback().await?)
+            },
+            "pause" => {
+                let pause = match args.first() {
+                    Some(_) => parse_arg::<u8>(args, 0, "pause state")? == 1,
+                    None => self.playback.get_state().await.is_playing
+                };
+                match pause {
+                    true => Ok(self.playback.pause_playback().await?),
+                    false => Ok(self.playback.start_playback().await?),
+                }
+            }
+            //there is no stopped state with a track loaded
+            "stop" => Ok(self.playback.pause_playback().await?),
+            "next" => Ok(self.playback.next_track().await?),
+            "previous" => Ok(self.playback.previous_track().await?),
+            "seek" => {
+                let position = parse_arg::<usize>(args, 0, "song position")?;
+                self.seek_entry(position, parse_ms(args.get(1).map(String::as_str).unwrap_or_default())?).await
+            }
+            "seekid" => {
+                let item_id = parse_arg::<u64>(args, 0, "song id")?;
+                let position = self.playlist().await.position_of(item_id)?;
+                self.seek_entry(position, parse_ms(args.get(1).map(String::as_str).unwrap_or_default())?).await
+            }
+            "seekcur" => {
+                let time = args.first().ok_or_else(|| MpdError::Argument("Missing argument time".to_string()))?;
+                let target_ms = match time.chars().next() {
+                    //relative to the current position
+                    Some('+') | Some('-') => self.playback.get_state().await.position_ms.checked_add(parse_ms(time)?)
+                        .ok_or_else(|| MpdError::Argument(format!("Invalid time '{}'", time)))?,
+                    _ => parse_ms(time)?
+                };
+                Ok(self.playback.seek_to(target_ms).await?)
+            }
+
+            "add" => {
+                let track_id = parse_track_uri(args.first().map(String::as_str).unwrap_or_default())?;
+                self.playback.queue().append(track_id).await?;
+                Ok(())
+            }
+            "addid" => self.add_id(args, out).await,
+            "delete" => {
+                let (start, end) = parse_range(args.first().map(String::as_str).unwrap_or_default())?;
+                let playlist = self.playlist().await;
+                let item_ids = playlist.range(start, end)?.iter().map(|t| t.item_id).collect_vec();
+                self.delete(&playlist, &item_ids).await
+            }
+            "deleteid" => {
+                let item_id = parse_arg::<u64>(args, 0, "song id")?;
+                let playlist = self.playlist().await;
+                playlist.position_of(item_id)?;
+                self.delete(&playlist, &[item_id]).await
+            }
+            "clear" => {
+                //the playing track keeps playing
+                let mut queue = self.playback.queue();
+                queue.clear().await;
+                Ok(())
+            }
+            "playlistinfo" => {
+                let playlist = self.playlist().await;
+                let (start, end) = match args.first() {
+                    Some(range) => parse_range(range)?,
+                    None => (0, None)
+                };
+                write_entries(out, playlist.range(start, end)?, start);
+                Ok(())
+            }
+            "playlistid" => {
+                let playlist = self.playlist().await;
+                match args.first() {
+                    Some(_) => {
+                        let position = playlist.position_of(parse_arg::<u64>(args, 0, "song id")?)?;
+                        write_entries(out, playlist.range(position, Some(position + 1))?, position);
+                    }
+                    None => write_entries(out, &playlist.entries, 0)
+                }
+                Ok(())
+            }
+            //changes aren't tracked per version, any older version gets the whole playlist
+            "plchanges" | "plchangesposid" => {
+                let version = parse_arg::<u64>(args, 0, "version")?;
+                let playlist = self.playlist().await;
+                if version == playlist.version {
+                    return Ok(());
+                }
+                for (position, track) in playlist.entries.iter().enumerate() {
+                    if command == "plchanges" {
+                        protocol::song(out, &queued_song(&track.meta), Some((position, track.item_id)));
+                    } else {
+                        field(out, "cpos", position);
+                        field(out, "Id", track.item_id);
+                    }
+                }
+                Ok(())
+            }
+
+            "find" | "search" => {
+                let (filters, window) = search_args(args, command == "find")?;
+                let tracks = self.db.search_tracks(&filters, RESULT_LIMIT)?;
+                for song in self.load_songs(windowed(tracks, window))? {
+                    protocol::song(out, &song, None);
+                }
+                Ok(())
+            }
+            "findadd" | "searchadd" => {
+                let (filters, window) = search_args(args, command == "findadd")?;
+                let tracks = self.db.search_tracks(&filters, RESULT_LIMIT)?;
+                let track_ids = windowed(tracks, window).iter().map(|t| t.track_id).collect_vec();
+                self.playback.queue().insert_playable(&track_ids, crate::playback::QueuePosition::End).await?;
+                Ok(())
+            }
+            "list" => self.list(args, out),
+
+            _ => Err(MpdError::UnknownCommand(command.to_string()))
+        }
+    }
+
+    async fn playlist(&self) -> Playlist {
+        let current = self.playback.get_state().await.current_track;
+        let (version, queued) = self.playback.queue().snapshot().await;
+        Playlist {
+            version,
+            has_current: current.is_some(),
+            entries: current.into_iter().chain(queued).collect(),
+        }
+    }
+
+    async fn status(&self, out: &mut String) -> Result<()> {
+        let state = self.playback.get_state().await;
+        let playlist = self.playlist().await;
+        field(out, "volume", volume(&state));
+        field(out, "repeat", 0);
+        field(out, "random", 0);
+        field(out, "single", 0);
+        field(out, "consume", 1);
+        field(out, "playlist", playlist.version);
+        field(out, "playlistlength", playlist.entries.len());
+        if !state.crossfade.is_zero() {
+            field(out, "xfade", state.crossfade.as_secs());
+        }
+        let player_state = match (&state.current_track, state.is_playing) {
+            (None, _) => "stop",
+            (Some(_), true) => "play",
+            (Some(_), false) => "pause",
+        };
+        field(out, "state", player_state);
+
+        if let Some(track) = &state.current_track {
+            field(out, "song", 0);
+            field(out, "songid", track.item_id);
+            field(out, "time", format!("{}:{}", state.position_ms / 1000, track.meta.duration_ms / 1000));
+            field(out, "elapsed", protocol::seconds(state.position_ms));
+            field(out, "duration", protocol::seconds(track.meta.duration_ms));
+        }
+        let next_position = if playlist.has_current { 1 } else { 0 };
+        if let Some(next) = playlist.entries.get(next_position) {
+            field(out, "nextsong", next_position);
+            field(out, "nextsongid", next.item_id);
+        }
+        Ok(())
+    }
+
+    /// Starts the entry; queued ones are moved up front and skipped to
+    async fn play_entry(&self, position: usize) -> Result<()> {
+        let playlist = self.playlist().await;
+        let entry = playlist.entry(position)?;
+        match playlist.queue_index(position) {
+            None => {
+                self.playback.seek_to(0).await?;
+                self.playback.start_playback().await?;
+            }
+            Some(_) => {
+                self.playback.queue().move_item(entry.item_id, 0).await?;
+                self.playback.next_track().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn seek_entry(&self, position: usize, target_ms: i64) -> Result<()> {
+        if self.playlist().await.queue_index(position).is_some() {
+            self.play_entry(position).await?;
+        }
+        Ok(self.playback.seek_to(target_ms).await?)
+    }
+
+    async fn add_id(&self, args: &[String], out: &mut String) -> Result<()> {
+        let track_id = parse_track_uri(args.first().map(String::as_str).unwrap_or_default())?;
+        let position = match args.get(1) {
+            Some(_) => Some(parse_arg::<usize>(args, 1, "song position")?),
+            None => None
+        };
+        let queue = self.playback.queue();
+        let item_id = queue.append(track_id).await?;
+        if let Some(position) = position {
+            //nothing goes before the playing track
+            let index = self.playlist().await.queue_index(position).unwrap_or(0);
+            queue.move_item(item_id, index).await?;
+        }
+        field(out, "Id", item_id);
+        Ok(())
+    }
+
+    /// Removes the entries; removing the playing track skips to the next one
+    async fn delete(&self, playlist: &Playlist, item_ids: &[u64]) -> Result<()> {
+        let queue = self.playback.queue();
+        let mut skip_current = false;
+        for item_id in item_ids {
+            match playlist.queue_index(playlist.position_of(*item_id)?) {
+                Some(_) => queue.remove_item(*item_id).await?,
+                None => skip_current = true,
+            }
+        }
+        if skip_current {
+            self.playback.next_track().await?;
+        }
+        Ok(())
+    }
+
+    /// Lists the values of a tag, optionally among the songs matching filters;
+    /// a single filter value is an artist. Results aren't grouped.
+    fn list(&self, args: &[String], out: &mut String) -> Result<()> {
+        let tag = parse_tag(args.first().map(String::as_str).unwrap_or_default())?;
+        let search_tag = tag.tag
+            .ok_or_else(|| MpdError::Argument("Can't list any".to_string()))?;
+        let mut filter_args = &args[1..];
+        if let Some(group) = filter_args.iter().position(|a| a.eq_ignore_ascii_case("group")) {
+            filter_args = &filter_args[..group];
+        }
+        let filters = match filter_args {
+            [artist] if !artist.starts_with('(') => vec![TagFilter {
+                tag: Some(SearchTag::Artist),
+                value: artist.clone(),
+                exact: true,
+            }],
+            _ => parse_filters(filter_args, true)?
+        };
+
+        for value in self.db.load_tag_values(search_tag, &filters, RESULT_LIMIT)? {
+            field(out, tag.name, value);
+        }
+        Ok(())
+    }
+
+    /// Loads the album and artists of the tracks with a fixed number of queries
+    fn load_songs(&self, tracks: Vec<Track>) -> Result<Vec<Song>> {
+        let albums: HashMap<i32, _> = self.db.load_albums_for_tracks(&tracks)?
+            .into_iter()
+            .map(|a| (a.album_id, a))
+            .collect();
+        let track_artist_ids = self.db.load_artist_ids_for_tracks(&tracks)?;
+        let api: &dyn ArtistDb = &self.db;
+        let artists: HashMap<i32, String> = api.find_by_ids(track_artist_ids.values().flatten().unique().cloned().collect_vec())?
+            .into_iter()
+            .map(|a| (a.artist_id, a.name))
+            .collect();
+
+        Ok(tracks.into_iter()
+            .map(|track| {
+                let album = albums.get(&track.album_id);
+                Song {
+                    track_id: track.track_id,
+                    artists: track_artist_ids.get(&track.track_id)
+                        .map(|ids| ids.iter().filter_map(|id| artists.get(id).cloned()).collect_vec())
+                        .unwrap_or_default(),
+                    album: album.map(|a| a.name.clone()).unwrap_or_default(),
+                    year: album.map(|a| a.year),
+                    title: track.title,
+                    duration_ms: track.duration_ms,
+                    track: track.track_number,
+                    disc: track.disc_number,
+                }
+            })
+            .collect_vec())
+    }
+}
+
+fn search_args(args: &[String], exact: bool) -> Result<(Vec<TagFilter>, Option<Range>)> {
+    let (filter_args, window) = split_window(args)?;
+    if filter_args.is_empty() {
+        return Err(MpdError::Argument("Missing filter".to_string()));
+    }
+    Ok((parse_filters(filter_args, exact)?, window))
+}
+
+fn windowed(tracks: Vec<Track>, window: Option<Range>) -> Vec<Track> {
+    match window {
+        Some((start, end)) => tracks.into_iter()
+            .skip(start)
+            .take(end.map(|end| end - start).unwrap_or(usize::MAX))
+            .collect_vec(),
+        None => tracks
+    }
+}
+
+fn write_entries(out: &mut String, entries: &[PlaybackTrack], first_position: usize) {
+    for (offset, track) in entries.iter().enumerate() {
+        protocol::song(out, &queued_song(&track.meta), Some((first_position + offset, track.item_id)));
+    }
+}
+
+fn queued_song(track: &SimpleTrack) -> Song {
+    Song {
+        track_id: track.track_id,
+        title: track.title.clone(),
+        artists: track.artists.iter().map(|a| a.name.clone()).collect_vec(),
+        album: track.album.name.clone(),
+        duration_ms: track.duration_ms,
+        track: None,
+        disc: None,
+        year: None,
+    }
+}
+
+fn volume(state: &PlaybackState) -> u32 {
+    if state.muted { 0 } else { state.volume }
+}
@@ -0,0 +1,200 @@
+use crate::db_new::search::{SearchTag, TagFilter};
+
+use super::protocol::Range;
+use super::{MpdError, Result};
+
+/// A search tag as named by the protocol
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Tag {
+    pub name: &'static str,
+    //None for any
+    pub tag: Option<SearchTag>,
+}
+
+pub fn parse_tag(name: &str) -> Result<Tag> {
+    let (name, tag) = match name.to_lowercase().as_str() {
+        "title" => ("Title", Some(SearchTag::Title)),
+        "artist" => ("Artist", Some(SearchTag::Artist)),
+        //soundbase doesn't tell album from track artists
+        "albumartist" => ("AlbumArtist", Some(SearchTag::Artist)),
+        "album" => ("Album", Some(SearchTag::Album)),
+        "genre" => ("Genre", Some(SearchTag::Genre)),
+        "any" => ("any", None),
+        _ => return Err(MpdError::Argument(format!("Unsupported tag '{}'", name)))
+    };
+    Ok(Tag { name, tag })
+}
+
+/// Parses the filters of find, search and list: either TAG VALUE pairs or a
+/// filter expression like ((artist == 'x') AND (album contains 'y')).
+/// Pairs match the whole tag if exact is set and a part of it otherwise.
+pub fn parse_filters(args: &[String], exact: bool) -> Result<Vec<TagFilter>> {
+    match args {
+        [expression] if expression.starts_with('(') => parse_expression(expression),
+        _ if args.len() % 2 == 1 => Err(MpdError::Argument("Filters come in pairs of tag and value".to_string())),
+        _ => args.chunks(2)
+            .map(|pair| Ok(TagFilter {
+                tag: parse_tag(&pair[0])?.tag,
+                value: pair[1].clone(),
+                exact,
+            }))
+            .collect()
+    }
+}
+
+/// Splits off the trailing "sort TAG" and "window START:END" arguments; results
+/// are already sorted by album, so only the window is returned
+pub fn split_window(args: &[String]) -> Result<(&[String], Option<Range>)> {
+    let mut args = args;
+    let mut window = None;
+    while args.len() >= 2 {
+        let (head, tail) = args.split_at(args.len() - 2);
+        match tail[0].to_lowercase().as_str() {
+            "window" => window = Some(super::protocol::parse_range(&tail[1])?),
+            "sort" => {}
+            _ => break
+        }
+        args = head;
+    }
+    Ok((args, window))
+}
+
+//only conjunctions of == and contains are supported
+fn parse_expression(expression: &str) -> Result<Vec<TagFilter>> {
+    let invalid = || MpdError::Argument(format!("Unsupported filter expression '{}'", expression));
+    let inner = expression.trim()
+        .strip_prefix('(')
+        .and_then(|e| e.strip_suffix(')'))
+        .ok_or_else(invalid)?
+        .trim();
+
+    if !inner.starts_with('(') {
+        return parse_condition(inner).ok_or_else(invalid).map(|filter| vec![filter]);
+    }
+
+    let mut filters = Vec::new();
+    let mut rest = inner;
+    loop {
+        let end = group_end(rest).ok_or_else(invalid)?;
+        filters.extend(parse_expression(&rest[..end])?);
+        rest = rest[end..].trim_start();
+        if rest.is_empty() {
+            return Ok(filters);
+        }
+        rest = rest.strip_prefix("AND").ok_or_else(invalid)?.trim_start();
+    }
+}
+
+//byte offset right after the parenthesized group the text starts with
+fn group_end(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+//TAG == 'VALUE' or TAG contains 'VALUE'
+fn parse_condition(condition: &str) -> Option<TagFilter> {
+    let (tag, rest) = condition.split_once(char::is_whitespace)?;
+    let (operator, value) = rest.trim_start().split_once(char::is_whitespace)?;
+    let exact = match operator {
+        "==" => true,
+        "contains" => false,
+        _ => return None
+    };
+    Some(TagFilter {
+        tag: parse_tag(tag).ok()?.tag,
+        value: unquote(value.trim())?,
+        exact,
+    })
+}
+
+fn unquote(value: &str) -> Option<String> {
+    let quote = value.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    let inner = value.strip_prefix(quote)?.strip_suffix(quote)?;
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.push(chars.next()?),
+            c => unquoted.push(c),
+        }
+    }
+    Some(unquoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn filter(tag: Option<SearchTag>, value: &str, exact: bool) -> TagFilter {
+        TagFilter { tag, value: value.to_string(), exact }
+    }
+
+    #[test]
+    fn parses_tags_ignoring_case() {
+        assert_eq!(parse_tag("ARTIST").unwrap(), Tag { name: "Artist", tag: Some(SearchTag::Artist) });
+        assert_eq!(parse_tag("albumartist").unwrap(), Tag { name: "AlbumArtist", tag: Some(SearchTag::Artist) });
+        assert_eq!(parse_tag("Any").unwrap(), Tag { name: "any", tag: None });
+        assert!(matches!(parse_tag("file"), Err(MpdError::Argument(_))));
+    }
+
+    #[test]
+    fn parses_filter_pairs() {
+        assert_eq!(parse_filters(&args(&["artist", "x", "any", "y"]), true).unwrap(),
+                   vec![filter(Some(SearchTag::Artist), "x", true), filter(None, "y", true)]);
+        assert_eq!(parse_filters(&args(&["title", "x"]), false).unwrap(), vec![filter(Some(SearchTag::Title), "x", false)]);
+        assert!(parse_filters(&args(&[]), false).unwrap().is_empty());
+        assert!(parse_filters(&args(&["artist"]), false).is_err());
+        assert!(parse_filters(&args(&["file", "x"]), false).is_err());
+    }
+
+    #[test]
+    fn parses_filter_expressions() {
+        assert_eq!(parse_filters(&args(&["(title == 'x')"]), false).unwrap(), vec![filter(Some(SearchTag::Title), "x", true)]);
+        assert_eq!(parse_filters(&args(&[r#"((artist == 'AC\'DC') AND (album contains "High (Live)"))"#]), false).unwrap(),
+                   vec![filter(Some(SearchTag::Artist), "AC'DC", true), filter(Some(SearchTag::Album), "High (Live)", false)]);
+        assert_eq!(parse_filters(&args(&["((genre contains 'rock') AND ((any == 'a') AND (title == 'b')))"]), true).unwrap(),
+                   vec![filter(Some(SearchTag::Genre), "rock", false), filter(None, "a", true), filter(Some(SearchTag::Title), "b", true)]);
+    }
+
+    #[test]
+    fn rejects_unsupported_expressions() {
+        for expression in ["(title != 'x')", "((title == 'x') OR (album == 'y'))", "(title == x)", "(title == 'x'", "((title == 'x') AND)"] {
+            assert!(matches!(parse_filters(&args(&[expression]), false), Err(MpdError::Argument(_))), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn splits_off_sort_and_window() {
+        let all = args(&["artist", "x", "sort", "Title", "window", "0:2"]);
+        let (filters, window) = split_window(&all).unwrap();
+        assert_eq!(filters, &all[..2]);
+        assert_eq!(window, Some((0, Some(2))));
+
+        let plain = args(&["artist", "x"]);
+        assert_eq!(split_window(&plain).unwrap(), (&plain[..], None));
+        assert!(split_window(&args(&["window", "3:1"])).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+use crate::db_new::DbApi;
+use crate::playback::{PlaybackController, PlaybackError, PlaybackState};
+
+mod commands;
+mod filter;
+mod protocol;
+
+type Result<T> = std::result::Result<T, MpdError>;
+
+const GREETING: &str = "OK MPD 0.23.0\n";
+
+//the subsystems idle reports, derived from changes of the playback state
+const SUBSYSTEMS: [&str; 4] = ["player", "mixer", "playlist", "options"];
+
+#[derive(Error, Debug)]
+pub enum MpdError {
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("{0}")]
+    Argument(String),
+
+    #[error("unknown command \"{0}\"")]
+    UnknownCommand(String),
+
+    #[error("{0}")]
+    NoExist(String),
+
+    #[error("{0}")]
+    Playback(#[from] PlaybackError),
+
+    #[error("{0}")]
+    Database(#[from] crate::db_new::DbError),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl MpdError {
+    //the ack error codes of the protocol
+    fn ack_code(&self) -> u32 {
+        match self {
+            MpdError::Argument(_) => 2,
+            MpdError::UnknownCommand(_) => 5,
+            MpdError::NoExist(_) => 50,
+            MpdError::Playback(PlaybackError::QueuePosition(_) | PlaybackError::QueueRemoval) => 2,
+            MpdError::Playback(PlaybackError::QueueItemNotFound(_)
+                               | PlaybackError::QueueTrackNotFound
+                               | PlaybackError::NoPlayableSource(_)) => 50,
+            MpdError::Playback(PlaybackError::NotPlaying | PlaybackError::NoTrackInQueue) => 55,
+            _ => 52
+        }
+    }
+}
+
+/// Lets mpd clients control the playback. The playlist is the playing track
+/// followed by the queue, which drops tracks once they start, like consume mode.
+/// Tracks are addressed as soundbase:track:{id}.
+#[derive(Clone)]
+pub struct MpdServer {
+    playback: PlaybackController,
+    db: DbApi,
+    addr: SocketAddr,
+    started: Instant,
+}
+
+impl MpdServer {
+    /// Returns None unless MPD_ADDR (e.g. 0.0.0.0:6600) is configured;
+    /// the controller has to be initialized already
+    pub fn from_env(db: DbApi, playback: PlaybackController) -> Result<Option<Self>> {
+        let addr = match dotenv::var("MPD_ADDR") {
+            Ok(addr) if !addr.is_empty() => addr,
+            _ => return Ok(None)
+        };
+        let addr = addr.parse::<SocketAddr>()
+            .map_err(|e| MpdError::Config(format!("MPD_ADDR {}: {}", addr, e)))?;
+
+        Ok(Some(Self { playback, db, addr, started: Instant::now() }))
+    }
+
+    /// Spawns the listener, every client is served by its own task
+    pub fn init(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let listener = match TcpListener::bind(this.addr).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    log::error!("Failed to listen for mpd clients on {}: {}", this.addr, e);
+                    return;
+                }
+            };
+            log::info!("Serving mpd clients on {}", this.addr);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let this = this.clone();
+                        tokio::spawn(async move {
+                            log::info!("Mpd client {} connected", peer);
+                            if let Err(e) = this.serve(stream).await {
+                                log::warn!("Mpd client {} failed: {}", peer, e);
+                            }
+                            log::info!("Mpd client {} disconnected", peer);
+                        });
+                    }
+                    Err(e) => log::warn!("Failed to accept mpd client: {}", e)
+                }
+            }
+        });
+    }
+
+    async fn serve(&self, stream: TcpStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut idle = IdleState::new(self.playback.state_update_rx());
+        //commands of an open command list and whether each one gets acknowledged
+        let mut command_list: Option<(Vec<protocol::Request>, bool)> = None;
+
+        writer.write_all(GREETING.as_bytes()).await?;
+        while let Some(line) = lines.next_line().await? {
+            let (command, args) = match protocol::parse_line(&line) {
+                Ok(request) => request,
+                Err(e) => {
+                    writer.write_all(protocol::ack(&e, 0, "").as_bytes()).await?;
+                    continue;
+                }
+            };
+
+            if let Some((commands, list_ok)) = command_list.as_mut() {
+                if command != "command_list_end" {
+                    commands.push((command, args));
+                    continue;
+                }
+                let response = self.execute_list(commands, *list_ok).await;
+                command_list = None;
+                writer.write_all(response.as_bytes()).await?;
+                continue;
+            }
+
+            let response = match command.as_str() {
+                "command_list_begin" => {
+                    command_list = Some((Vec::new(), false));
+                    continue;
+                }
+                "command_list_ok_begin" => {
+                    command_list = Some((Vec::new(), true));
+                    continue;
+                }
+                "close" => return Ok(()),
+                "idle" => {
+                    let subsystems = if args.is_empty() {
+                        SUBSYSTEMS.to_vec()
+                    } else {
+                        SUBSYSTEMS.iter().filter(|s| args.iter().any(|a| a == *s)).cloned().collect()
+                    };
+                    match idle.wait(&subsystems, &mut lines).await? {
+                        Some(response) => response,
+                        //anything but noidle ends the connection
+                        None => return Ok(())
+                    }
+                }
+                //only meaningful while idle
+                "noidle" => continue,
+                _ => {
+                    let mut out = String::new();
+                    match self.execute(&command, &args, &mut out).await {
+                        Ok(_) => {
+                            out.push_str("OK\n");
+                            out
+                        }
+                        Err(e) => protocol::ack(&e, 0, &command)
+                    }
+                }
+            };
+            writer.write_all(response.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs the commands until the first one fails, which is acknowledged with its index
+    async fn execute_list(&self, commands: &[protocol::Request], list_ok: bool) -> String {
+        let mut out = String::new();
+        for (index, (command, args)) in commands.iter().enumerate() {
+            let result = match command.as_str() {
+                "idle" | "noidle" | "close" | "command_list_begin" | "command_list_ok_begin" =>
+                    Err(MpdError::Argument(format!("{} isn't allowed in a command list", command))),
+                _ => self.execute(command, args, &mut out).await
+            };
+            if let Err(e) = result {
+                out.push_str(&protocol::ack(&e, index, command));
+                return out;
+            }
+            if list_ok {
+                out.push_str("list_OK\n");
+            }
+        }
+        out.push_str("OK\n");
+        out
+    }
+}
+
+/// Collects the subsystems changed since the client last saw them
+struct IdleState {
+    state_rx: watch::Receiver<PlaybackState>,
+    seen: PlaybackState,
+    pending: Vec<&'static str>,
+}
+
+impl IdleState {
+    fn new(mut state_rx: watch::Receiver<PlaybackState>) -> Self {
+        let seen = state_rx.borrow_and_update().clone();
+        Self { state_rx, seen, pending: Vec::new() }
+    }
+
+    /// Waits until one of the subsystems changed or the client sent noidle;
+    /// None if it sent anything else
+    async fn wait<R>(&mut self, subsystems: &[&'static str], lines: &mut tokio::io::Lines<R>) -> Result<Option<String>>
+    where
+        R: tokio::io::AsyncBufRead + Unpin,
+    {
+        loop {
+            if self.state_rx.has_changed().unwrap_or(false) {
+                self.collect();
+            }
+            let changed: Vec<&'static str> = self.pending.iter()
+                .filter(|s| subsystems.contains(s))
+                .cloned()
+                .collect();
+            if !changed.is_empty() {
+                self.pending.retain(|s| !changed.contains(s));
+                let mut out = String::new();
+                for subsystem in changed {
+                    protocol::field(&mut out, "changed", subsystem);
+                }
+                out.push_str("OK\n");
+                return Ok(Some(out));
+            }
+
+            tokio::select! {
+                //fails once the controller is gone, nothing changes from then on
+                Ok(_) = self.state_rx.changed() => self.collect(),
+                line = lines.next_line() => {
+                    return match line?.as_deref().map(str::trim) {
+                        Some("noidle") => Ok(Some("OK\n".to_string())),
+                        _ => Ok(None)
+                    };
+                }
+            }
+        }
+    }
+
+    fn collect(&mut self) {
+        let state = self.state_rx.borrow_and_update().clone();
+        let seen_item = self.seen.current_track.as_ref().map(|t| t.item_id);
+        let item = state.current_track.as_ref().map(|t| t.item_id);
+
+        let mut changed = Vec::new();
+        if seen_item != item || self.seen.queue_version != state.queue_version {
+            changed.push("playlist");
+        }
+        if self.seen.volume != state.volume || self.seen.muted != state.muted {
+            changed.push("mixer");
+        }
+        if self.seen.crossfade != state.crossfade || self.seen.autoplay != state.autoplay {
+            changed.push("options");
+        }
+        //updates without any other change come from the players, e.g. after seeking
+        if seen_item != item || self.seen.is_playing != state.is_playing || changed.is_empty() {
+            changed.push("player");
+        }
+
+        for subsystem in changed {
+            if !self.pending.contains(&subsystem) {
+                self.pending.push(subsystem);
+            }
+        }
+        self.seen = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_errors_to_ack_codes() {
+        assert_eq!(MpdError::Argument(String::new()).ack_code(), 2);
+        assert_eq!(MpdError::UnknownCommand(String::new()).ack_code(), 5);
+        assert_eq!(MpdError::NoExist(String::new()).ack_code(), 50);
+        assert_eq!(MpdError::Config(String::new()).ack_code(), 52);
+        assert_eq!(MpdError::Playback(PlaybackError::QueueRemoval).ack_code(), 2);
+        assert_eq!(MpdError::Playback(PlaybackError::QueueTrackNotFound).ack_code(), 50);
+        assert_eq!(MpdError::Playback(PlaybackError::NotPlaying).ack_code(), 55);
+        assert_eq!(MpdError::Playback(PlaybackError::NoTrackInQueue).ack_code(), 55);
+    }
+}
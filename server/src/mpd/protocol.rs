@@ -0,0 +1,228 @@
+use std::fmt::{Display, Write};
+use std::str::FromStr;
+
+use super::{MpdError, Result};
+
+const TRACK_URI_PREFIX: &str = "soundbase:track:";
+
+/// A command and its arguments
+pub type Request = (String, Vec<String>);
+
+/// A start position and an exclusive end, open if None
+pub type Range = (usize, Option<usize>);
+
+/// A song as listed by the protocol, taken from the queue or the library
+pub struct Song {
+    pub track_id: i32,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub duration_ms: i64,
+    //only known for songs loaded from the library
+    pub track: Option<i32>,
+    pub disc: Option<i32>,
+    pub year: Option<i32>,
+}
+
+/// Splits a request into the command and its arguments; arguments with
+/// spaces are double quoted and escape quotes and backslashes with a backslash
+pub fn parse_line(line: &str) -> Result<Request> {
+    let mut tokens = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => continue,
+            '"' => {
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => token.push(escaped),
+                            None => return Err(MpdError::Argument("Missing closing '\"'".to_string()))
+                        },
+                        Some('"') => break,
+                        Some(c) => token.push(c),
+                        None => return Err(MpdError::Argument("Missing closing '\"'".to_string()))
+                    }
+                }
+                tokens.push(token);
+            }
+            c => {
+                let mut token = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    token.push(c);
+                }
+                tokens.push(token);
+            }
+        }
+    }
+
+    if tokens.is_empty() {
+        return Err(MpdError::Argument("No command given".to_string()));
+    }
+    let command = tokens.remove(0).to_lowercase();
+    Ok((command, tokens))
+}
+
+/// The error line answering the command at the index of a command list
+pub fn ack(error: &MpdError, index: usize, command: &str) -> String {
+    format!("ACK [{}@{}] {{{}}} {}\n", error.ack_code(), index, command, single_line(&error.to_string()))
+}
+
+pub fn field(out: &mut String, key: &str, value: impl Display) {
+    let _ = writeln!(out, "{}: {}", key, single_line(&value.to_string()));
+}
+
+//a line break in a value would end the response line
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Writes the song; queue entries also carry their position and id
+pub fn song(out: &mut String, song: &Song, entry: Option<(usize, u64)>) {
+    field(out, "file", track_uri(song.track_id));
+    field(out, "Title", &song.title);
+    for artist in &song.artists {
+        field(out, "Artist", artist);
+    }
+    field(out, "Album", &song.album);
+    if let Some(track) = song.track {
+        field(out, "Track", track);
+    }
+    if let Some(disc) = song.disc {
+        field(out, "Disc", disc);
+    }
+    if let Some(year) = song.year {
+        field(out, "Date", year);
+    }
+    field(out, "Time", song.duration_ms / 1000);
+    field(out, "duration", seconds(song.duration_ms));
+    if let Some((position, item_id)) = entry {
+        field(out, "Pos", position);
+        field(out, "Id", item_id);
+    }
+}
+
+/// Tracks are addressed as soundbase:track:{id}
+pub fn track_uri(track_id: i32) -> String {
+    format!("{}{}", TRACK_URI_PREFIX, track_id)
+}
+
+pub fn parse_track_uri(uri: &str) -> Result<i32> {
+    uri.strip_prefix(TRACK_URI_PREFIX)
+        .and_then(|id| id.parse::<i32>().ok())
+        .ok_or_else(|| MpdError::NoExist(format!("Unknown uri '{}', tracks are {}<id>", uri, TRACK_URI_PREFIX)))
+}
+
+pub fn seconds(ms: i64) -> String {
+    format!("{:.3}", ms as f64 / 1000.0)
+}
+
+/// Parses a time in seconds with fractions into milliseconds
+pub fn parse_ms(arg: &str) -> Result<i64> {
+    arg.parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite())
+        .map(|s| (s * 1000.0).round() as i64)
+        .ok_or_else(|| MpdError::Argument(format!("Invalid time '{}'", arg)))
+}
+
+pub fn parse_arg<T: FromStr>(args: &[String], index: usize, name: &str) -> Result<T> {
+    let arg = args.get(index)
+        .ok_or_else(|| MpdError::Argument(format!("Missing argument {}", name)))?;
+    arg.parse::<T>()
+        .map_err(|_| MpdError::Argument(format!("Invalid {} '{}'", name, arg)))
+}
+
+/// Parses a position or a START:END range with an exclusive and optional end
+pub fn parse_range(arg: &str) -> Result<Range> {
+    let invalid = || MpdError::Argument(format!("Invalid range '{}'", arg));
+    match arg.split_once(':') {
+        Some((start, "")) => Ok((start.parse().map_err(|_| invalid())?, None)),
+        Some((start, end)) => {
+            let (start, end) = (start.parse::<usize>().map_err(|_| invalid())?, end.parse::<usize>().map_err(|_| invalid())?);
+            if end < start {
+                return Err(invalid());
+            }
+            Ok((start, Some(end)))
+        }
+        None => {
+            let position = arg.parse::<usize>().map_err(|_| invalid())?;
+            let end = position.checked_add(1).ok_or_else(invalid)?;
+            Ok((position, Some(end)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playback::PlaybackError;
+
+    fn request(command: &str, args: &[&str]) -> Request {
+        (command.to_string(), args.iter().map(|a| a.to_string()).collect())
+    }
+
+    #[test]
+    fn splits_commands_and_arguments() {
+        assert_eq!(parse_line("  STATUS  ").unwrap(), request("status", &[]));
+        assert_eq!(parse_line("seek 0 12.5").unwrap(), request("seek", &["0", "12.5"]));
+        assert_eq!(parse_line("add \"soundbase:track:1\"").unwrap(), request("add", &["soundbase:track:1"]));
+        assert_eq!(parse_line("find artist \"\"").unwrap(), request("find", &["artist", ""]));
+    }
+
+    #[test]
+    fn unescapes_quoted_arguments() {
+        assert_eq!(parse_line(r#"find "artist" "Guns \"N\" Roses""#).unwrap(), request("find", &["artist", "Guns \"N\" Roses"]));
+        assert_eq!(parse_line(r#"find album "AC\\DC Live""#).unwrap(), request("find", &["album", "AC\\DC Live"]));
+        //only quoted arguments have escapes
+        assert_eq!(parse_line(r"find album x\y").unwrap(), request("find", &["album", "x\\y"]));
+    }
+
+    #[test]
+    fn rejects_unterminated_quotes() {
+        assert!(matches!(parse_line(r#"find "open"#), Err(MpdError::Argument(_))));
+        assert!(matches!(parse_line(r#"find "open\""#), Err(MpdError::Argument(_))));
+        assert!(matches!(parse_line(r#"find "open\"#), Err(MpdError::Argument(_))));
+        assert!(matches!(parse_line("   "), Err(MpdError::Argument(_))));
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("3").unwrap(), (3, Some(4)));
+        assert_eq!(parse_range("1:4").unwrap(), (1, Some(4)));
+        assert_eq!(parse_range("2:2").unwrap(), (2, Some(2)));
+        assert_eq!(parse_range("2:").unwrap(), (2, None));
+        assert!(parse_range("4:1").is_err());
+        assert!(parse_range(":3").is_err());
+        assert!(parse_range("-1").is_err());
+        assert!(parse_range("a:b").is_err());
+        assert!(parse_range("18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn parses_times_and_uris() {
+        assert_eq!(parse_ms("12.5").unwrap(), 12500);
+        assert_eq!(parse_ms("+3").unwrap(), 3000);
+        assert_eq!(parse_ms("-1.25").unwrap(), -1250);
+        assert!(parse_ms("inf").is_err());
+        assert_eq!(parse_track_uri(&track_uri(42)).unwrap(), 42);
+        assert!(matches!(parse_track_uri("music/song.mp3"), Err(MpdError::NoExist(_))));
+    }
+
+    #[test]
+    fn formats_ack_lines() {
+        assert_eq!(ack(&MpdError::UnknownCommand("foo".to_string()), 3, "foo"), "ACK [5@3] {foo} unknown command \"foo\"\n");
+        assert_eq!(ack(&MpdError::Playback(PlaybackError::NotPlaying), 0, "seekcur"), "ACK [55@0] {seekcur} No track is playing!\n");
+        //the message stays on one line
+        assert_eq!(ack(&MpdError::Argument("bad\nvalue".to_string()), 1, "add"), "ACK [2@1] {add} bad value\n");
+    }
+
+    #[test]
+    fn writes_fields_on_one_line() {
+        let mut out = String::new();
+        field(&mut out, "Title", "two\r\nlines");
+        field(&mut out, "Time", 42);
+        assert_eq!(out, "Title: two  lines\nTime: 42\n");
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use itertools::Itertools;
+use rand::Rng;
+
+use crate::db_new::autoplay::{AutoplayDb, SimilarTrack};
+use crate::db_new::play::{PlayDb, PlayStats};
+use crate::db_new::track::TrackDb;
+use crate::db_new::DbApi;
+use crate::model::{RequestPage, UniversalId};
+use crate::spotify::db_utils::insert_track_from_spotify_id;
+use crate::spotify::SpotifyApi;
+
+//candidates loaded per kind of similarity
+const CANDIDATE_POOL_SIZE: i64 = 50;
+//tracks played this recently are never picked again
+const RECENTLY_PLAYED_HOURS: i64 = 6;
+const LONG_NOT_PLAYED_DAYS: i64 = 30;
+//keeps the picks for the same seed from always being identical
+const SCORE_JITTER: f64 = 2.0;
+
+/// Picks follow up tracks once the playback queue ran dry
+#[derive(Clone)]
+pub struct Autoplay {
+    db: DbApi,
+    spotify: Option<SpotifyApi>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl Autoplay {
+    /// Reads AUTOPLAY and AUTOPLAY_SPOTIFY; both are off unless set to true
+    pub fn from_env(db: DbApi, spotify: SpotifyApi) -> Result<Self, std::str::ParseBoolError> {
+        let enabled = match dotenv::var("AUTOPLAY") {
+            Ok(value) => value.parse::<bool>()?,
+            Err(_) => false
+        };
+        let use_spotify = match dotenv::var("AUTOPLAY_SPOTIFY") {
+            Ok(value) => value.parse::<bool>()?,
+            Err(_) => false
+        };
+
+        Ok(Self {
+            db,
+            spotify: if use_spotify { Some(spotify) } else { None },
+            enabled: Arc::new(AtomicBool::new(enabled)),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed)
+    }
+
+    /// Picks up to count tracks to follow the seed track. Spotify recommendations
+    /// come first if enabled, similar library tracks fill up the rest.
+    pub async fn next_tracks(&self, seed_track_id: i32, count: usize) -> Vec<i32> {
+        let mut picks = Vec::with_capacity(count);
+        if let Some(spotify) = &self.spotify {
+            match self.spotify_picks(spotify, seed_track_id, count).await {
+                Ok(tracks) => picks.extend(tracks),
+                Err(e) => log::warn!("Failed to fetch spotify recommendations for track {}: {:?}", seed_track_id, e)
+            }
+        }
+
+        if picks.len() < count {
+            match self.library_picks(seed_track_id, &picks, count - picks.len()) {
+                Ok(tracks) => picks.extend(tracks),
+                Err(e) => log::error!("Failed to pick similar tracks for track {}: {:?}", seed_track_id, e)
+            }
+        }
+        picks
+    }
+
+    /// The most recently played track; seeds autoplay if nothing is playing
+    pub fn last_played_track(&self) -> Option<i32> {
+        match self.db.load_plays(&RequestPage::new(0, 1)) {
+            Ok(plays) => plays.first().map(|play| play.track_id),
+            Err(e) => {
+                log::error!("Failed to load last play: {:?}", e);
+                None
+            }
+        }
+    }
+
+    async fn spotify_picks(&self, spotify: &SpotifyApi, seed_track_id: i32, count: usize) -> Result<Vec<i32>, Box<dyn std::error::Error>> {
+        let api: &dyn TrackDb = &self.db;
+        let spot_id = match api.find_by_id(seed_track_id)?.and_then(|track| track.spot_id) {
+            Some(spot_id) => spot_id,
+            None => return Ok(Vec::new())
+        };
+
+        //ask for some more as recently played ones get dropped
+        let recommended = spotify.get_recommendations(&spot_id, (count * 2) as u32).await?;
+        let mut known = HashMap::with_capacity(recommended.len());
+        for spot_id in &recommended {
+            if let Some(track) = api.find_track_by_universal_id(&UniversalId::Spotify(spot_id.clone()))? {
+                known.insert(spot_id.clone(), track.track_id);
+            }
+        }
+
+        //only tracks that actually get picked are added to the library
+        let stats = self.db.load_play_stats_for_tracks(&known.values().cloned().collect_vec())?;
+        let now = Utc::now().naive_utc();
+        let picked = recommended.iter()
+            .unique()
+            .filter(|spot_id| match known.get(*spot_id) {
+                Some(track_id) => *track_id != seed_track_id && !played_recently(stats.get(track_id), &now),
+                None => true
+            })
+            .take(count)
+            .collect_vec();
+
+        let mut track_ids = Vec::with_capacity(picked.len());
+        for spot_id in picked {
+            match known.get(spot_id) {
+                Some(track_id) => track_ids.push(*track_id),
+                None => track_ids.push(insert_track_from_spotify_id(&self.db, spotify, spot_id).await?.track_id)
+            }
+        }
+        Ok(track_ids)
+    }
+
+    fn library_picks(&self, seed_track_id: i32, picked: &[i32], count: usize) -> Result<Vec<i32>, crate::db_new::DbError> {
+        let candidates = self.db.load_similar_tracks(seed_track_id, CANDIDATE_POOL_SIZE)?;
+        let stats = self.db.load_play_stats_for_tracks(&candidates.iter().map(|c| c.track.track_id).collect_vec())?;
+        let picked = picked.iter().collect::<HashSet<_>>();
+        let now = Utc::now().naive_utc();
+
+        let mut rng = rand::thread_rng();
+        let mut scored = candidates.iter()
+            .filter(|c| !picked.contains(&c.track.track_id))
+            .filter(|c| !played_recently(stats.get(&c.track.track_id), &now))
+            .map(|c| (c.track.track_id, score(c, &stats, &now) + rng.gen_range(0.0..SCORE_JITTER)))
+            .collect_vec();
+        scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        Ok(scored.into_iter()
+            .map(|(track_id, _)| track_id)
+            .take(count)
+            .collect_vec())
+    }
+}
+
+fn played_recently(stats: Option<&PlayStats>, now: &NaiveDateTime) -> bool {
+    match stats.and_then(|s| s.last_played) {
+        Some(last) => *now - last < Duration::hours(RECENTLY_PLAYED_HOURS),
+        None => false
+    }
+}
+
+//shared artists weigh most, faved and long not heard tracks are favoured
+fn score(candidate: &SimilarTrack, stats: &HashMap<i32, PlayStats>, now: &NaiveDateTime) -> f64 {
+    let mut score = 3.0 * candidate.shared_artists.min(2) as f64
+        + 1.0 * candidate.shared_genres.min(3) as f64;
+    if let Some(distance) = candidate.year_distance {
+        score += 1.0 - 0.25 * distance as f64;
+    }
+    if candidate.track.is_faved {
+        score += 2.0;
+    }
+    match stats.get(&candidate.track.track_id).and_then(|s| s.last_played) {
+        None => score += 1.5,
+        Some(last) if *now - last > Duration::days(LONG_NOT_PLAYED_DAYS) => score += 1.0,
+        Some(_) => {}
+    }
+    score
+}
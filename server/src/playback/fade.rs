@@ -0,0 +1,177 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use librespot::playback::mixer::AudioFilter;
+use rodio::Source;
+
+/// A linear gain ramp counted in samples, so it follows the audio instead of the wall clock
+#[derive(Clone, Copy, Debug)]
+struct Ramp {
+    from: f32,
+    to: f32,
+    length: u64,
+    position: u64,
+}
+
+impl Ramp {
+    fn new(from: f32, to: f32, length: u64) -> Self {
+        Self { from, to, length, position: 0 }
+    }
+
+    fn constant(gain: f32) -> Self {
+        Self::new(gain, gain, 0)
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.length
+    }
+
+    fn next_gain(&mut self) -> f32 {
+        if self.is_done() {
+            return self.to;
+        }
+        let gain = self.from + (self.to - self.from) * (self.position as f32 / self.length as f32);
+        self.position += 1;
+        gain
+    }
+}
+
+fn samples_for(duration: Duration, sample_rate: u32, channels: u16) -> u64 {
+    duration.as_millis() as u64 * sample_rate as u64 * channels as u64 / 1000
+}
+
+/// Wraps the audio filter of the mixer to fade the spotify output in and out.
+/// A finished fade out keeps the output silent until the next fade or reset.
+pub struct FadeFilter {
+    inner: Option<Box<dyn AudioFilter + Send>>,
+    ramp: Arc<Mutex<Ramp>>,
+}
+
+impl FadeFilter {
+    pub fn new(inner: Option<Box<dyn AudioFilter + Send>>) -> (Self, FadeHandle) {
+        let ramp = Arc::new(Mutex::new(Ramp::constant(1.0)));
+        (Self { inner, ramp: ramp.clone() }, FadeHandle { ramp })
+    }
+}
+
+impl AudioFilter for FadeFilter {
+    fn modify_stream(&self, data: &mut [f64]) {
+        if let Some(inner) = &self.inner {
+            inner.modify_stream(data);
+        }
+        let mut ramp = self.ramp.lock().unwrap();
+        if ramp.is_done() && ramp.to >= 1.0 {
+            return;
+        }
+        for sample in data.iter_mut() {
+            *sample *= ramp.next_gain() as f64;
+        }
+    }
+}
+
+/// Controls the fades of a FadeFilter
+#[derive(Clone)]
+pub struct FadeHandle {
+    ramp: Arc<Mutex<Ramp>>,
+}
+
+impl FadeHandle {
+    pub fn fade_in(&self, duration: Duration) {
+        *self.ramp.lock().unwrap() = Ramp::new(0.0, 1.0, spotify_samples(duration));
+    }
+
+    pub fn fade_out(&self, duration: Duration) {
+        let mut ramp = self.ramp.lock().unwrap();
+        let from = ramp.next_gain();
+        *ramp = Ramp::new(from, 0.0, spotify_samples(duration));
+    }
+
+    pub fn reset(&self) {
+        *self.ramp.lock().unwrap() = Ramp::constant(1.0);
+    }
+}
+
+//librespot always decodes to 44.1kHz stereo
+fn spotify_samples(duration: Duration) -> u64 {
+    samples_for(duration, librespot::playback::SAMPLE_RATE, librespot::playback::NUM_CHANNELS as u16)
+}
+
+/// Lets the local player fade out or drop a source it already handed to rodio
+#[derive(Default)]
+pub struct TrackControl {
+    //fade out duration in ms requested but not yet picked up; 0 if none
+    fade_out_ms: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl TrackControl {
+    /// The source ends once faded out
+    pub fn fade_out(&self, duration: Duration) {
+        self.fade_out_ms.store(duration.as_millis().max(1) as u64, Ordering::Relaxed);
+    }
+
+    /// The source ends with its next sample
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A local track scaled by its replay gain and faded as requested through its TrackControl
+pub struct FadingSource<S: Source<Item = f32>> {
+    inner: S,
+    gain: f32,
+    ramp: Ramp,
+    fading_out: bool,
+    control: Arc<TrackControl>,
+}
+
+impl<S: Source<Item = f32>> FadingSource<S> {
+    pub fn new(inner: S, gain: f32, fade_in: Option<Duration>, control: Arc<TrackControl>) -> Self {
+        let ramp = match fade_in {
+            Some(duration) => Ramp::new(0.0, 1.0, samples_for(duration, inner.sample_rate(), inner.channels())),
+            None => Ramp::constant(1.0)
+        };
+        Self { inner, gain, ramp, fading_out: false, control }
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for FadingSource<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.control.cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let fade_out_ms = self.control.fade_out_ms.swap(0, Ordering::Relaxed);
+        if fade_out_ms > 0 {
+            let length = samples_for(Duration::from_millis(fade_out_ms), self.inner.sample_rate(), self.inner.channels());
+            self.ramp = Ramp::new(self.ramp.next_gain(), 0.0, length);
+            self.fading_out = true;
+        }
+        if self.fading_out && self.ramp.is_done() {
+            return None;
+        }
+
+        let sample = self.inner.next()?;
+        Some(sample * self.gain * self.ramp.next_gain())
+    }
+}
+
+impl<S: Source<Item = f32>> Source for FadingSource<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
@@ -1,39 +1,313 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
 use async_trait::async_trait;
-use futures::Future;
+use rodio::{Decoder, Sink, Source};
 
+use crate::playback::fade::{FadingSource, TrackControl};
+use crate::playback::mixer::SharedMixer;
+use crate::playback::output::AudioOutput;
 use crate::playback::PlaybackError;
-use super::Player;
+use super::{Player, StartOptions};
 
 #[derive(Clone)]
 pub struct LocalPlayer {
+    cmd_tx: Sender<LocalPlayerCommand>,
+    output: AudioOutput,
+}
 
+enum LocalPlayerCommand {
+    ConnectEvents(tokio::sync::mpsc::UnboundedSender<super::PlayerEvent>),
+    Load(LocalTrackRequest, tokio::sync::oneshot::Sender<Result<(), PlaybackError>>),
+    //appended behind the current track so it follows without a gap
+    Preload(LocalTrackRequest),
+    CancelPreload,
+    FadeOut(Duration),
+    Seek(u64),
+    Resume,
+    Pause,
+    Stop,
 }
 
-impl LocalPlayer{
-    pub fn new() -> Self {
-        Self{}
+struct LocalTrackRequest {
+    file: String,
+    //replay gain factor
+    gain: f32,
+    fade_in: Option<Duration>,
+}
+
+//a source handed to the sink, in the order they play
+struct QueuedSource {
+    file: String,
+    gain: f32,
+    control: Arc<TrackControl>,
+}
+
+impl LocalPlayer {
+    pub fn new(mixer: SharedMixer, output: AudioOutput) -> Self {
+        // the whole playback is owned by a dedicated thread which watches
+        // the sinks for finished tracks and is controlled through commands
+        let (cmd_tx, cmd_rx) = std::sync::mpsc::channel();
+        let sink_output = output.clone();
+        std::thread::Builder::new()
+            .name("local-player".to_string())
+            .spawn(move || run_local_player(cmd_rx, mixer, sink_output))
+            .expect("Failed to spawn local player thread!");
+
+        Self { cmd_tx, output }
+    }
+
+    /// The output shared with the spotify player
+    pub fn output(&self) -> &AudioOutput {
+        &self.output
+    }
+
+    pub fn preload(&self, file: &str, gain: f32) -> Result<(), PlaybackError> {
+        self.send(LocalPlayerCommand::Preload(LocalTrackRequest { file: file.to_string(), gain, fade_in: None }))
+    }
+
+    pub fn cancel_preload(&self) -> Result<(), PlaybackError> {
+        self.send(LocalPlayerCommand::CancelPreload)
+    }
+
+    /// Lets the current track fade out without reporting its end
+    pub fn fade_out(&self, duration: Duration) -> Result<(), PlaybackError> {
+        self.send(LocalPlayerCommand::FadeOut(duration))
+    }
+
+    fn send(&self, cmd: LocalPlayerCommand) -> Result<(), PlaybackError> {
+        self.cmd_tx
+            .send(cmd)
+            .map_err(|_| PlaybackError::PlayerUnavailable("local player thread terminated".to_string()))
     }
 }
 
 #[async_trait]
 impl Player for LocalPlayer {
     async fn connect_player_events(&mut self, tx: tokio::sync::mpsc::UnboundedSender<super::PlayerEvent>) {
-        todo!()
+        self.send(LocalPlayerCommand::ConnectEvents(tx))
+            .expect("Failed to connect local player events!");
     }
 
-    async fn start(&self, track_ident: &str) -> Result<(), PlaybackError> {
-        todo!()
+    /// Starts the file scaled by the replay gain factor, optionally fading
+    /// it in; the shared volume is applied on top. Starting the preloaded file
+    /// once the current one ended keeps playing it without a gap.
+    async fn start(&self, file: &str, options: StartOptions) -> Result<(), PlaybackError> {
+        if !Path::new(file).is_file() {
+            return Err(PlaybackError::LocalFileMissing(file.to_string()));
+        }
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let request = LocalTrackRequest { file: file.to_string(), gain: options.gain, fade_in: options.fade_in };
+        self.send(LocalPlayerCommand::Load(request, tx))?;
+        match rx.await {
+            Ok(result) => result,
+            Err(_) => Err(PlaybackError::PlayerUnavailable("local player dropped the load request".to_string()))
+        }
     }
 
     async fn resume(&self) -> Result<(), PlaybackError> {
-        todo!()
+        self.send(LocalPlayerCommand::Resume)
     }
 
     async fn pause(&self) -> Result<(), PlaybackError> {
-        todo!()
+        self.send(LocalPlayerCommand::Pause)
+    }
+
+    async fn stop(&self) -> Result<(), PlaybackError> {
+        self.send(LocalPlayerCommand::Stop)
     }
 
     async fn seek(&self, target_pos_ms: i64) -> Result<(), PlaybackError> {
-        todo!()
+        self.send(LocalPlayerCommand::Seek(target_pos_ms.max(0) as u64))
     }
-}
\ No newline at end of file
+}
+
+fn run_local_player(cmd_rx: Receiver<LocalPlayerCommand>, mixer: SharedMixer, output: AudioOutput) {
+    let mut events: Option<tokio::sync::mpsc::UnboundedSender<super::PlayerEvent>> = None;
+    let mut sink: Option<Sink> = None;
+    //sources of the sink; the first one is playing
+    let mut sources: VecDeque<QueuedSource> = VecDeque::new();
+    //sinks fading out on their own; they never report an end
+    let mut fading: Vec<Sink> = Vec::new();
+    //the playing source followed its predecessor without being loaded explicitly
+    let mut continued = false;
+
+    let notify = |events: &Option<tokio::sync::mpsc::UnboundedSender<super::PlayerEvent>>, evt: super::PlayerEvent| {
+        if let Some(tx) = events {
+            log::info!("Forwarding local player event {:?}", evt);
+            if tx.send(evt).is_err() {
+                log::warn!("Failed to notify PlayerController about local player event!");
+            }
+        }
+    };
+
+    loop {
+        match cmd_rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(LocalPlayerCommand::ConnectEvents(tx)) => {
+                events = Some(tx);
+            }
+            Ok(LocalPlayerCommand::Load(request, reply)) => {
+                //the preloaded file is already playing
+                if continued && sources.front().map(|s| s.file == request.file).unwrap_or(false) {
+                    continued = false;
+                    let _ = reply.send(Ok(()));
+                    notify(&events, super::PlayerEvent::Playing);
+                    continue;
+                }
+
+                continued = false;
+                sources.clear();
+                if let Some(old) = sink.take() {
+                    old.stop();
+                }
+
+                let new_sink = new_sink(&output, mixer.software_gain());
+                match append_file(&new_sink, &request, 0) {
+                    Ok(source) => {
+                        log::info!("Playing local file {}", request.file);
+                        sink = Some(new_sink);
+                        sources.push_back(source);
+                        let _ = reply.send(Ok(()));
+                        notify(&events, super::PlayerEvent::Playing);
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+            Ok(LocalPlayerCommand::Preload(request)) => {
+                if let Some(s) = &sink {
+                    match append_file(s, &request, 0) {
+                        Ok(source) => {
+                            log::info!("Preloaded local file {}", request.file);
+                            sources.push_back(source);
+                        }
+                        Err(e) => log::warn!("Failed to preload local file {}: {}", request.file, e)
+                    }
+                }
+            }
+            Ok(LocalPlayerCommand::CancelPreload) => {
+                while sources.len() > 1 {
+                    if let Some(source) = sources.pop_back() {
+                        source.control.cancel();
+                    }
+                }
+            }
+            Ok(LocalPlayerCommand::FadeOut(duration)) => {
+                if let Some(s) = sink.take() {
+                    for (i, source) in sources.drain(..).enumerate() {
+                        if i == 0 {
+                            source.control.fade_out(duration);
+                        } else {
+                            source.control.cancel();
+                        }
+                    }
+                    fading.push(s);
+                }
+                continued = false;
+            }
+            Ok(LocalPlayerCommand::Seek(position_ms)) => {
+                //rodio can't seek, so reopen the file and skip ahead; a preloaded file is dropped
+                if let (Some(old), Some(current)) = (sink.take(), sources.pop_front()) {
+                    let was_paused = old.is_paused();
+                    old.stop();
+                    sources.clear();
+                    let request = LocalTrackRequest { file: current.file, gain: current.gain, fade_in: None };
+                    let new_sink = new_sink(&output, mixer.software_gain());
+                    match append_file(&new_sink, &request, position_ms) {
+                        Ok(source) => {
+                            if was_paused {
+                                new_sink.pause();
+                            }
+                            sink = Some(new_sink);
+                            sources.push_back(source);
+                        }
+                        Err(e) => log::error!("Failed to seek in local file {}: {}", request.file, e)
+                    }
+                }
+            }
+            Ok(LocalPlayerCommand::Resume) => {
+                fading.iter().for_each(|s| s.play());
+                if let Some(s) = &sink {
+                    s.play();
+                    notify(&events, super::PlayerEvent::Playing);
+                }
+            }
+            Ok(LocalPlayerCommand::Pause) => {
+                fading.iter().for_each(|s| s.pause());
+                if let Some(s) = &sink {
+                    s.pause();
+                    notify(&events, super::PlayerEvent::Paused);
+                }
+            }
+            Ok(LocalPlayerCommand::Stop) => {
+                fading.drain(..).for_each(|s| s.stop());
+                if let Some(s) = sink.take() {
+                    s.stop();
+                }
+                sources.clear();
+                continued = false;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        //follow the shared volume; a hardware mixer leaves its part at 1.0
+        let gain = mixer.software_gain();
+        for s in sink.iter().chain(fading.iter()) {
+            if (s.volume() - gain).abs() > f32::EPSILON {
+                s.set_volume(gain);
+            }
+        }
+        fading.retain(|s| !s.empty());
+
+        if let Some(s) = &sink {
+            //finished sources left the sink; if one is still left it took over gaplessly
+            let finished = sources.len().saturating_sub(s.len());
+            if finished > 0 {
+                sources.drain(..finished);
+                if !sources.is_empty() {
+                    continued = true;
+                    notify(&events, super::PlayerEvent::EndOfTrack);
+                }
+            }
+        }
+
+        //a sink which ran dry has finished its track
+        if sink.as_ref().map(|s| s.empty()).unwrap_or(false) {
+            sink = None;
+            sources.clear();
+            continued = false;
+            notify(&events, super::PlayerEvent::EndOfTrack);
+        }
+    }
+}
+
+fn new_sink(output: &AudioOutput, volume: f32) -> Sink {
+    let (sink, queue) = Sink::new_idle();
+    output.add(queue);
+    sink.set_volume(volume);
+    sink
+}
+
+fn append_file(sink: &Sink, request: &LocalTrackRequest, position_ms: u64) -> Result<QueuedSource, PlaybackError> {
+    let reader = BufReader::new(File::open(&request.file)?);
+    let decoder = Decoder::new(reader)
+        .map_err(|e| PlaybackError::LocalDecode(request.file.clone(), e.to_string()))?;
+    let control = Arc::new(TrackControl::default());
+    let source = decoder
+        .skip_duration(Duration::from_millis(position_ms))
+        .convert_samples::<f32>();
+    sink.append(FadingSource::new(source, request.gain, request.fade_in, control.clone()));
+    Ok(QueuedSource {
+        file: request.file.clone(),
+        gain: request.gain,
+        control,
+    })
+}
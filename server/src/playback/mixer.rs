@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use librespot::playback::config::VolumeCtrl;
+use librespot::playback::mixer::mappings::MappedCtrl;
+use librespot::playback::mixer::{AudioFilter, Mixer, MixerConfig};
+
+use crate::playback::PlaybackError;
+
+const MAX_VOLUME: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MixerState {
+    //percent
+    pub volume: u32,
+    pub muted: bool,
+}
+
+/// Volume control shared by all players, so the level stays the same when
+/// playback moves between sources. The software mixer scales the samples of
+/// each player, a hardware mixer (e.g. alsa) controls the output device itself.
+#[derive(Clone)]
+pub struct SharedMixer {
+    mixer: Arc<Mutex<Box<dyn Mixer>>>,
+    volume_ctrl: VolumeCtrl,
+    //gain the local player applies itself; stays 1.0 for hardware mixers
+    software_gain: Arc<AtomicU64>,
+    is_software: bool,
+    state: Arc<tokio::sync::watch::Sender<MixerState>>,
+}
+
+impl SharedMixer {
+    /// Reads MIXER (softvol or alsa; defaults to softvol), MIXER_DEVICE,
+    /// MIXER_CONTROL and the INITIAL_VOLUME in percent
+    pub fn from_env() -> Result<Self, PlaybackError> {
+        let name = dotenv::var("MIXER").ok();
+        let mixer_fn = librespot::playback::mixer::find(name.as_deref())
+            .ok_or_else(|| PlaybackError::Mixer(format!("Unsupported mixer '{}'", name.clone().unwrap_or_default())))?;
+
+        let mut config = MixerConfig::default();
+        if let Ok(device) = dotenv::var("MIXER_DEVICE") {
+            config.device = device;
+        }
+        if let Ok(control) = dotenv::var("MIXER_CONTROL") {
+            config.control = control;
+        }
+        let initial_volume = match dotenv::var("INITIAL_VOLUME") {
+            Ok(volume) => volume.parse::<u32>()
+                .map_err(|e| PlaybackError::Mixer(format!("Invalid INITIAL_VOLUME: {}", e)))?,
+            Err(_) => 50
+        };
+
+        let volume_ctrl = config.volume_ctrl;
+        let mixer = mixer_fn(config);
+        let is_software = mixer.get_audio_filter().is_some();
+        let (state, _) = tokio::sync::watch::channel(MixerState { volume: 0, muted: false });
+        let s = Self {
+            mixer: Arc::new(Mutex::new(mixer)),
+            volume_ctrl,
+            software_gain: Arc::new(AtomicU64::new(f64::to_bits(1.0))),
+            is_software,
+            state: Arc::new(state),
+        };
+        s.set_volume(initial_volume);
+        Ok(s)
+    }
+
+    pub fn state(&self) -> MixerState {
+        *self.state.borrow()
+    }
+
+    pub fn subscribe(&self) -> tokio::sync::watch::Receiver<MixerState> {
+        self.state.subscribe()
+    }
+
+    /// Sets the volume in percent; values above 100 are capped. Unmutes.
+    pub fn set_volume(&self, volume: u32) {
+        self.apply(MixerState { volume: volume.min(MAX_VOLUME), muted: false });
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        let state = self.state();
+        self.apply(MixerState { muted, ..state });
+    }
+
+    /// Filter to hand to librespot; it scales the samples if this is a software mixer
+    pub fn audio_filter(&self) -> Option<Box<dyn AudioFilter + Send>> {
+        self.mixer.lock().unwrap().get_audio_filter()
+    }
+
+    /// Sample factor the local player has to apply
+    pub fn software_gain(&self) -> f32 {
+        f64::from_bits(self.software_gain.load(Ordering::Relaxed)) as f32
+    }
+
+    fn apply(&self, state: MixerState) {
+        let effective = if state.muted { 0 } else { state.volume };
+        let volume = (effective as u64 * u16::MAX as u64 / MAX_VOLUME as u64) as u16;
+        self.mixer.lock().unwrap().set_volume(volume);
+        if self.is_software {
+            let gain = self.volume_ctrl.to_mapped(volume);
+            self.software_gain.store(gain.to_bits(), Ordering::Relaxed);
+        }
+        self.state.send_replace(state);
+    }
+}
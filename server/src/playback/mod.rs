@@ -1,7 +1,10 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
 use itertools::Itertools;
 use log::info;
 use thiserror::Error;
@@ -9,15 +12,28 @@ use tokio::sync::RwLock;
 
 use crate::db_new;
 use crate::db_new::album::AlbumDb;
+use crate::db_new::artist::ArtistDb;
+use crate::db_new::models::NewPlay;
+use crate::db_new::play::PlayDb;
 use crate::db_new::track::TrackDb;
 use crate::db_new::track_artist::TrackArtistsDb;
 use crate::db_new::DbApi;
 use crate::model::library_models::{SimpleAlbum, SimpleArtist, SimpleTrack};
+use crate::model::{PlaybackSource, ReplayGain, ReplayGainMode, RequestPage};
+use crate::playback::autoplay::Autoplay;
 use crate::playback::local_player::LocalPlayer;
+use crate::playback::mixer::{MixerState, SharedMixer};
+use crate::playback::output::AudioOutputConfig;
 use crate::playback::spotify_player::SpotifyPlayer;
+use crate::scrobbler::Scrobbler;
 
+pub mod autoplay;
+pub mod fade;
 pub mod local_player;
+pub mod mixer;
+pub mod output;
 pub mod spotify_player;
+pub mod stream;
 
 //TODO:
 //  - How to handle shuffle mode
@@ -28,12 +44,49 @@ pub mod spotify_player;
 
 #[derive(Clone)]
 pub struct PlaybackController {
+    db: DbApi,
     queue: PlaybackQueue,
     local_player: LocalPlayer,
     spotify_player: SpotifyPlayer,
+    config: PlaybackConfig,
+    scrobbler: Option<Scrobbler>,
+    autoplay: Autoplay,
+    mixer: SharedMixer,
+    //0 plays tracks back to back
+    crossfade_ms: Arc<AtomicU64>,
 
     state: Arc<RwLock<PlaybackControllerState>>,
     state_update_rx: Option<tokio::sync::watch::Receiver<PlaybackState>>,
+    //republishes the state for changes no player reports, like seeking
+    state_changed: Arc<tokio::sync::Notify>,
+}
+
+/// How tracks get played, independent of what is queued
+#[derive(Copy, Clone, Debug)]
+pub struct PlaybackConfig {
+    pub preferred_source: PlaybackSource,
+    pub replay_gain: ReplayGainMode,
+    pub crossfade: Duration,
+}
+
+impl PlaybackConfig {
+    /// Reads PLAYBACK_SOURCE (local or spotify; defaults to local),
+    /// REPLAY_GAIN (track, album or off; defaults to track) and CROSSFADE_MS (defaults to 0)
+    pub fn from_env() -> Result<Self, String> {
+        let preferred_source = match dotenv::var("PLAYBACK_SOURCE") {
+            Ok(source) => source.parse::<PlaybackSource>()?,
+            Err(_) => PlaybackSource::Local
+        };
+        let replay_gain = match dotenv::var("REPLAY_GAIN") {
+            Ok(mode) => mode.parse::<ReplayGainMode>()?,
+            Err(_) => ReplayGainMode::Track
+        };
+        let crossfade = match dotenv::var("CROSSFADE_MS") {
+            Ok(ms) => Duration::from_millis(ms.parse::<u64>().map_err(|e| format!("Invalid CROSSFADE_MS: {}", e))?),
+            Err(_) => Duration::ZERO
+        };
+        Ok(Self { preferred_source, replay_gain, crossfade })
+    }
 }
 
 #[derive(Clone)]
@@ -41,6 +94,11 @@ struct PlaybackControllerState {
     active_player: Option<TargetPlayer>,
     current_track: Option<PlaybackTrack>,
     current_state: ControllerStates,
+    current_play: Option<PlayRecord>,
+    //queue entry prepared to follow the current track and the player it will play on
+    preloaded: Option<(u64, TargetPlayer)>,
+    //the next entry was looked at for the current track; reset by queue changes
+    preload_checked: bool,
 }
 
 impl PlaybackController {
@@ -48,43 +106,112 @@ impl PlaybackController {
         db: DbApi,
         spotify_player: SpotifyPlayer,
         local_player: LocalPlayer,
+        config: PlaybackConfig,
+        scrobbler: Option<Scrobbler>,
+        autoplay: Autoplay,
+        mixer: SharedMixer,
     ) -> Result<Self, PlaybackError> {
         let s = Self {
-            queue: PlaybackQueue {
-                db,
-                queued_tracks: Arc::new(RwLock::new(VecDeque::new())),
-            },
+            db: db.clone(),
+            queue: PlaybackQueue::new(db),
 
             local_player,
             spotify_player,
+            config,
+            scrobbler,
+            autoplay,
+            mixer,
+            crossfade_ms: Arc::new(AtomicU64::new(config.crossfade.as_millis() as u64)),
 
             state: Arc::new(RwLock::new(PlaybackControllerState {
                 active_player: None,
                 current_track: None,
                 current_state: ControllerStates::NotPlaying,
+                current_play: None,
+                preloaded: None,
+                preload_checked: false,
             })),
             state_update_rx: None,
+            state_changed: Arc::new(tokio::sync::Notify::new()),
         };
         Ok(s)
     }
 
     pub async fn init(&mut self) {
         let (gtx, grx) = tokio::sync::watch::channel(self.get_state().await);
+        let mut track_rx = grx.clone();
         self.state_update_rx = Some(grx);
 
-        //connect the spotify player events to the corresponding notify handlers
-        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-        self.spotify_player.connect_player_events(tx).await;
+        //announce track changes to readers of a stream output
+        let output = self.local_player.output().clone();
+        tokio::spawn(async move {
+            let mut playing = None;
+            while track_rx.changed().await.is_ok() {
+                let track = track_rx.borrow().current_track.as_ref().map(|t| (t.item_id, t.meta.clone()));
+                let current = track.as_ref().map(|(item_id, meta)| (*item_id, meta.track_id));
+                if current != playing {
+                    playing = current;
+                    output.publish_track(track.map(|(_, meta)| meta));
+                }
+            }
+        });
+
+        //connect the player events to the corresponding notify handlers
+        let (spot_tx, mut spot_rx) = tokio::sync::mpsc::unbounded_channel();
+        self.spotify_player.connect_player_events(spot_tx).await;
+        let (local_tx, mut local_rx) = tokio::sync::mpsc::unbounded_channel();
+        self.local_player.connect_player_events(local_tx).await;
+        let mut queue_rx = self.queue.subscribe();
+        let mut mixer_rx = self.mixer.subscribe();
+        let state_changed = self.state_changed.clone();
+        let mut transition_check = tokio::time::interval(TRANSITION_CHECK_INTERVAL);
         let this = self.clone();
         tokio::spawn(async move {
-            log::info!("Waiting for Spotify Player Events");
-            while let Some(evt) = rx.recv().await {
-                log::info!("Received Spotify Player Event {:?}", evt);
+            log::info!("Waiting for Player Events");
+            loop {
+                let (source, evt) = tokio::select! {
+                    Some(evt) = spot_rx.recv() => (TargetPlayer::Spotify, evt),
+                    Some(evt) = local_rx.recv() => (TargetPlayer::Local, evt),
+                    //queue changes affect has_next and the queue version of the state
+                    Ok(_) = queue_rx.recv() => {
+                        this.check_preload().await;
+                        gtx.send(this.get_state().await)
+                            .expect("Failed to send state Update! Watch is presumably closed!");
+                        continue;
+                    }
+                    _ = transition_check.tick() => {
+                        if this.check_transition().await {
+                            gtx.send(this.get_state().await)
+                                .expect("Failed to send state Update! Watch is presumably closed!");
+                        }
+                        continue;
+                    }
+                    Ok(_) = mixer_rx.changed() => {
+                        gtx.send(this.get_state().await)
+                            .expect("Failed to send state Update! Watch is presumably closed!");
+                        continue;
+                    }
+                    _ = state_changed.notified() => {
+                        gtx.send(this.get_state().await)
+                            .expect("Failed to send state Update! Watch is presumably closed!");
+                        continue;
+                    }
+                    else => break
+                };
+                log::info!("Received {:?} Player Event {:?}", source, evt);
+
+                //events of a player which isn't playing anymore are stale
+                if this.state.read().await.active_player != Some(source) {
+                    log::info!("Ignoring event of inactive player {:?}", source);
+                    continue;
+                }
+
                 match evt {
                     PlayerEvent::EndOfTrack => this.notify_end_track().await,
                     PlayerEvent::Playing => this.notify_playing().await,
                     PlayerEvent::Paused => this.notify_paused().await,
-                    PlayerEvent::Stopped => this.notify_stopped().await
+                    PlayerEvent::Stopped => this.notify_stopped().await,
+                    PlayerEvent::Unavailable => this.notify_unavailable().await
                 }
 
                 let state = this.get_state().await;
@@ -135,6 +262,7 @@ impl PlaybackController {
                     }
                     Some(TargetPlayer::Local) => {
                         log::info!("Resuming locally");
+                        self.local_player.resume().await?;
                     }
 
                     None => {
@@ -145,6 +273,9 @@ impl PlaybackController {
                 {
                     let mut state = self.state.write().await;
                     state.current_state = ControllerStates::Playing;
+                    if let Some(play) = state.current_play.as_mut() {
+                        play.resume();
+                    }
                 }
                 Ok(())
             }
@@ -171,6 +302,7 @@ impl PlaybackController {
                     }
                     Some(TargetPlayer::Local) => {
                         log::info!("Pausing Playback Locally");
+                        self.local_player.pause().await?;
                     }
 
                     None => {
@@ -180,6 +312,9 @@ impl PlaybackController {
                 {
                     let mut state = self.state.write().await;
                     state.current_state = ControllerStates::Paused;
+                    if let Some(play) = state.current_play.as_mut() {
+                        play.pause();
+                    }
                 }
                 Ok(())
             }
@@ -193,6 +328,13 @@ impl PlaybackController {
         }
     }
 
+    /// Replaces the queue with the playable ones of the tracks and starts the first one
+    pub async fn replace_queue_and_play(&self, track_ids: &[i32]) -> Result<usize, PlaybackError> {
+        let queued = self.queue.replace_playable(track_ids).await?;
+        self.next_track().await?;
+        Ok(queued)
+    }
+
     pub async fn play_directly(&self, track_id : i32) -> Result<(), PlaybackError> {
         self.queue.prepend(track_id).await?;
         self.next_track().await
@@ -200,25 +342,128 @@ impl PlaybackController {
 
     pub async fn next_track(&self) -> Result<(), PlaybackError> {
         //handle the track end event properly
-        //play next track from queue if any
-        match self.queue.next_track_for_playback().await {
-            Some(track) => {
-                match self.start_track(track).await {
-                    Ok(_) => {},
-                    Err(e) => panic!("Failed to start scheduled track with Error {:?}", e)
+        //play next track from queue if any; tracks which can't be played
+        //from any source are skipped. A dry queue is refilled once by autoplay.
+        let mut last_error = None;
+        let mut refilled = false;
+        loop {
+            let track = match self.queue.next_track_for_playback().await {
+                Some(track) => track,
+                None if !refilled && self.autoplay.is_enabled() => {
+                    refilled = true;
+                    self.refill_autoplay().await;
+                    continue;
                 }
-            },
-            None => self.reset_state().await
+                None => break
+            };
+            match self.start_track(track).await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    log::error!("Failed to start scheduled track with Error {:?}; skipping it", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        //gapless playback keeps the spotify output open until stopped
+        if self.state.read().await.active_player == Some(TargetPlayer::Spotify) {
+            if let Err(e) = self.spotify_player.stop().await {
+                log::warn!("Failed to stop spotify after the queue ran dry: {:?}", e);
+            }
+        }
+        self.reset_state().await;
+        match last_error {
+            Some(e) => Err(e),
+            None => Ok(())
         }
-        Ok(())
     }
 
-    pub fn previous_track(&self) -> Result<(), PlaybackError> {
-        unimplemented!()
+    /// Appends tracks similar to the current or last played one to the queue
+    async fn refill_autoplay(&self) {
+        let current = { self.state.read().await.current_track.as_ref().map(|t| t.meta.track_id) };
+        let seed = match current.or_else(|| self.autoplay.last_played_track()) {
+            Some(seed) => seed,
+            None => return
+        };
+
+        let picks = self.autoplay.next_tracks(seed, AUTOPLAY_BATCH_SIZE).await;
+        log::info!("Autoplay picked {:?} to follow track {}", picks, seed);
+        self.queue.append_autoplayed(&picks).await;
     }
 
-    pub fn seek_to(&self, _target_ms: i64) -> Result<(), PlaybackError> {
-        unimplemented!()
+    pub async fn set_autoplay(&self, enabled: bool) {
+        self.autoplay.set_enabled(enabled);
+        if !enabled {
+            self.queue.remove_autoplayed().await;
+        }
+    }
+
+    /// Sets the volume in percent for all players
+    pub fn set_volume(&self, volume: u32) {
+        self.mixer.set_volume(volume);
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.mixer.set_muted(muted);
+    }
+
+    pub fn mixer_state(&self) -> MixerState {
+        self.mixer.state()
+    }
+
+    /// Sets how long consecutive tracks overlap; 0 plays them back to back
+    pub fn set_crossfade(&self, duration: Duration) {
+        self.crossfade_ms.store(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn crossfade(&self) -> Duration {
+        Duration::from_millis(self.crossfade_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn audio_output(&self) -> AudioOutputConfig {
+        self.local_player.output().config()
+    }
+
+    /// Moves the output of both players to another backend, device or format
+    pub fn set_audio_output(&self, config: AudioOutputConfig) -> Result<(), PlaybackError> {
+        self.local_player.output().set_config(config)
+    }
+
+    /// Restarts the current track; played tracks aren't kept, so there is nothing to go back to
+    pub async fn previous_track(&self) -> Result<(), PlaybackError> {
+        if self.state.read().await.current_track.is_none() {
+            return Ok(());
+        }
+        self.seek_to(0).await
+    }
+
+    /// Jumps to the position within the current track, clamped to its duration
+    pub async fn seek_to(&self, target_ms: i64) -> Result<(), PlaybackError> {
+        let (player, duration_ms) = {
+            let state = self.state.read().await;
+            match (state.active_player, &state.current_track) {
+                (Some(player), Some(track)) => (player, track.meta.duration_ms),
+                _ => return Err(PlaybackError::NotPlaying)
+            }
+        };
+        let target_ms = target_ms.clamp(0, duration_ms.max(0));
+        log::info!("Seeking to {}ms on {:?}", target_ms, player);
+        match player {
+            TargetPlayer::Spotify => self.spotify_player.seek(target_ms).await?,
+            TargetPlayer::Local => self.local_player.seek(target_ms).await?,
+        }
+
+        {
+            let mut state = self.state.write().await;
+            if let Some(play) = state.current_play.as_mut() {
+                play.seek(target_ms);
+            }
+            //the local player drops what it preloaded, and seeking back leaves plenty of time to redo it
+            state.preloaded = None;
+            state.preload_checked = false;
+        }
+        self.state_changed.notify_one();
+        Ok(())
     }
 
     pub fn set_shuffling(&self, _target_state: bool) {
@@ -233,10 +478,17 @@ impl PlaybackController {
         let state = {
             self.state.read().await.clone()
         };
+        let mixer = self.mixer.state();
         PlaybackState {
             has_previous: false,
             has_next: self.queue.has_next().await,
             looping_state: LoopingStates::Off,
+            autoplay: self.autoplay.is_enabled(),
+            queue_version: self.queue.version().await,
+            volume: mixer.volume,
+            muted: mixer.muted,
+            crossfade: self.crossfade(),
+            position_ms: state.current_play.as_ref().map(PlayRecord::position_ms).unwrap_or(0),
             is_playing: state.current_state == ControllerStates::Playing,
             current_track: state.current_track.clone(),
         }
@@ -252,58 +504,370 @@ impl PlaybackController {
 
     async fn notify_end_track(&self) {
         log::info!("Handling Track End Event");
+        let finished = { self.state.write().await.current_play.take() };
+        self.record_play(finished, true);
         let _r = self.next_track().await;
     }
 
     async fn notify_playing(&self) {
         log::info!("Handling Playing Event");
-        let mut state = self.state.write().await;
-        state.current_state = ControllerStates::Playing;
-        debug_assert!(state.current_track.is_some());
-        debug_assert!(state.active_player.is_some());
+        let started = {
+            let mut state = self.state.write().await;
+            state.current_state = ControllerStates::Playing;
+            debug_assert!(state.current_track.is_some());
+            debug_assert!(state.active_player.is_some());
+            state.current_play.as_mut().and_then(|play| {
+                play.resume();
+                play.announce()
+            })
+        };
+
+        if let (Some(track), Some(scrobbler)) = (started, &self.scrobbler) {
+            scrobbler.now_playing(&track);
+        }
     }
 
     async fn notify_paused(&self) {
         log::info!("Handling Paused Event");
         let mut state = self.state.write().await;
         state.current_state = ControllerStates::Paused;
+        if let Some(play) = state.current_play.as_mut() {
+            play.pause();
+        }
         debug_assert!(state.current_track.is_some());
         debug_assert!(state.active_player.is_some());
     }
 
+    async fn notify_unavailable(&self) {
+        log::info!("Handling Unavailable Event");
+        let state = { self.state.read().await.clone() };
+        if let (Some(track), Some(failed)) = (state.current_track, state.active_player) {
+            if let Err(e) = self.fallback_track(track, failed).await {
+                log::error!("No fallback available for unavailable track: {:?}", e);
+                let _r = self.next_track().await;
+            }
+        }
+    }
+
     async fn notify_stopped(&self) {
         log::info!("Handling Stopped Event");
         let mut state = self.state.write().await;
         state.current_state = ControllerStates::NotPlaying;
+        if let Some(play) = state.current_play.as_mut() {
+            play.pause();
+        }
         debug_assert!(state.active_player.is_none()); // already set at TrackEndNotify?
         debug_assert!(state.active_player.is_none());
     }
 
     async fn start_track(&self, track: PlaybackTrack) -> Result<(), PlaybackError> {
-        {
+        let candidates = track.candidate_players(self.config.preferred_source);
+        self.start_on_candidates(&track, candidates).await
+    }
+
+    /// Starts the track while the current one fades out. Falls back to a plain
+    /// start on the remaining sources if its preferred one fails.
+    async fn crossfade_to(&self, track: PlaybackTrack, fade: Duration) -> Result<(), PlaybackError> {
+        let mut candidates = track.candidate_players(self.config.preferred_source);
+        if candidates.is_empty() {
+            return Err(PlaybackError::NoPlayableSource(track.meta.track_id));
+        }
+        let (player, track_ident) = candidates.remove(0);
+        match self.start_on_player(&track, player, &track_ident, Transition::Crossfade(fade)).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::warn!("Failed to crossfade to track {} on {:?}: {}; falling back", track.meta.track_id, player, e);
+                self.start_on_candidates(&track, candidates).await
+            }
+        }
+    }
+
+    /// Retries a track which failed on the given player on the remaining sources
+    async fn fallback_track(&self, track: PlaybackTrack, failed: TargetPlayer) -> Result<(), PlaybackError> {
+        let candidates = track.candidate_players(self.config.preferred_source)
+            .into_iter()
+            .filter(|(player, _)| *player != failed)
+            .collect_vec();
+        self.start_on_candidates(&track, candidates).await
+    }
+
+    async fn start_on_candidates(&self, track: &PlaybackTrack, candidates: Vec<(TargetPlayer, String)>) -> Result<(), PlaybackError> {
+        let mut last_error = PlaybackError::NoPlayableSource(track.meta.track_id);
+        for (player, track_ident) in candidates {
+            match self.start_on_player(track, player, &track_ident, Transition::Cut).await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    log::warn!("Failed to start track {} on {:?}: {}; falling back", track.meta.track_id, player, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn start_on_player(&self, track: &PlaybackTrack, player: TargetPlayer, track_ident: &str, transition: Transition)
+        -> Result<(), PlaybackError> {
+        let (previous_player, previous_play) = {
             //set the target state before requesting it
             let mut state = self.state.write().await;
-            state.active_player = Some(track.player);
+            let previous = state.active_player.replace(player);
             state.current_track = Some(track.clone());
-        }
-        match track.player {
+            state.preloaded = None;
+            state.preload_checked = false;
+            let previous_play = state.current_play.replace(PlayRecord::new(&track.meta, player));
+            (previous, previous_play)
+        };
+        //whatever was playing before didn't reach its end, unless it is faded out at its end
+        self.record_play(previous_play, transition != Transition::Cut);
+
+        let fade_in = match transition {
+            Transition::Cut => {
+                //silence the player we are switching away from
+                match previous_player {
+                    Some(TargetPlayer::Spotify) if player != TargetPlayer::Spotify => self.spotify_player.stop().await?,
+                    Some(TargetPlayer::Local) if player != TargetPlayer::Local => self.local_player.stop().await?,
+                    _ => {}
+                }
+                None
+            }
+            Transition::Crossfade(fade) => {
+                match previous_player {
+                    Some(TargetPlayer::Spotify) => self.fade_out_spotify(fade),
+                    Some(TargetPlayer::Local) => self.local_player.fade_out(fade)?,
+                    None => {}
+                }
+                Some(fade)
+            }
+        };
+
+        match player {
             TargetPlayer::Spotify => {
                 log::info!("Playing Track on spotify.");
-                self.spotify_player.start(&*track.track_ident).await?;
+                self.spotify_player.start(track_ident, StartOptions { gain: 1.0, fade_in }).await
             }
             TargetPlayer::Local => {
-                log::info!("Track would be played locally");
+                log::info!("Playing Track locally.");
+                let gain = track.replay_gain.factor(self.config.replay_gain);
+                self.local_player.start(track_ident, StartOptions { gain, fade_in }).await
             }
         }
-        Ok(())
+    }
+
+    //spotify plays one track at a time, so it is stopped once faded out unless it took over again
+    fn fade_out_spotify(&self, fade: Duration) {
+        self.spotify_player.fade_out(fade);
+        let this = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(fade).await;
+            if this.state.read().await.active_player != Some(TargetPlayer::Spotify) {
+                if let Err(e) = this.spotify_player.stop().await {
+                    log::warn!("Failed to stop faded out spotify playback: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Prepares the next queue entry near the end of the current track and
+    /// starts the crossfade to it. Returns whether the current track changed.
+    async fn check_transition(&self) -> bool {
+        let (player, duration_ms, remaining_ms, preloaded, preload_checked) = {
+            let state = self.state.read().await;
+            if state.current_state != ControllerStates::Playing {
+                return false;
+            }
+            match (&state.current_track, state.active_player, &state.current_play) {
+                (Some(track), Some(player), Some(play)) => (
+                    player,
+                    track.meta.duration_ms,
+                    track.meta.duration_ms - play.position_ms(),
+                    state.preloaded,
+                    state.preload_checked,
+                ),
+                _ => return false
+            }
+        };
+        let crossfade_ms = self.crossfade().as_millis() as i64;
+        if remaining_ms > PRELOAD_BEFORE_END_MS.max(crossfade_ms) {
+            return false;
+        }
+
+        if !preload_checked {
+            self.preload_next(player).await;
+            return false;
+        }
+
+        //librespot decodes one track at a time, spotify to spotify stays gapless only;
+        //tracks too short to fade out and in aren't faded either
+        match preloaded {
+            Some((_, next_player)) if crossfade_ms > 0
+                && remaining_ms <= crossfade_ms
+                && duration_ms >= 2 * crossfade_ms
+                && !(player == TargetPlayer::Spotify && next_player == TargetPlayer::Spotify) => {
+                let track = match self.queue.next_track_for_playback().await {
+                    Some(track) => track,
+                    None => return false
+                };
+                log::info!("Crossfading to track {}", track.meta.track_id);
+                if let Err(e) = self.crossfade_to(track, self.crossfade()).await {
+                    log::error!("Failed to crossfade to the next track: {:?}", e);
+                    let _r = self.next_track().await;
+                }
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// Hands the next queue entry to the player it will play on if that one is
+    /// already playing, so it follows without a gap. A dry queue is refilled by autoplay first.
+    async fn preload_next(&self, player: TargetPlayer) {
+        { self.state.write().await.preload_checked = true; }
+        if self.autoplay.is_enabled() && !self.queue.has_next().await {
+            self.refill_autoplay().await;
+        }
+        let next = match self.queue.peek_next().await {
+            Some(next) => next,
+            None => return
+        };
+        let (next_player, track_ident) = match next.candidate_players(self.config.preferred_source).into_iter().next() {
+            Some(candidate) => candidate,
+            None => return
+        };
+
+        if next_player == player {
+            log::info!("Preloading track {} on {:?}", next.meta.track_id, next_player);
+            let result = match next_player {
+                TargetPlayer::Spotify => self.spotify_player.preload(&track_ident).await,
+                TargetPlayer::Local => self.local_player.preload(&track_ident, next.replay_gain.factor(self.config.replay_gain))
+            };
+            if let Err(e) = result {
+                log::warn!("Failed to preload track {}: {:?}", next.meta.track_id, e);
+                return;
+            }
+        }
+        self.state.write().await.preloaded = Some((next.item_id, next_player));
+    }
+
+    /// Drops a preloaded entry which isn't next in the queue anymore
+    async fn check_preload(&self) {
+        let next_item_id = self.queue.peek_next().await.map(|t| t.item_id);
+        let mut state = self.state.write().await;
+        if !state.preload_checked || state.preloaded.map(|(item_id, _)| item_id) == next_item_id {
+            return;
+        }
+        if let (Some(_), Some(TargetPlayer::Local)) = (state.preloaded, state.active_player) {
+            if let Err(e) = self.local_player.cancel_preload() {
+                log::warn!("Failed to cancel local preload: {:?}", e);
+            }
+        }
+        state.preloaded = None;
+        state.preload_checked = false;
     }
 
     async fn reset_state(&self) {
         //set state to not playing
-        let mut state = self.state.write().await;
-        state.current_track = None;
-        state.active_player = None;
-        state.current_state = ControllerStates::NotPlaying;
+        let previous_play = {
+            let mut state = self.state.write().await;
+            state.current_track = None;
+            state.active_player = None;
+            state.current_state = ControllerStates::NotPlaying;
+            state.current_play.take()
+        };
+        self.record_play(previous_play, false);
+    }
+
+    fn record_play(&self, play: Option<PlayRecord>, completed: bool) {
+        let play = match play {
+            Some(play) => play,
+            None => return
+        };
+        //tracks which never audibly started (e.g. unavailable on a player) aren't plays
+        let played_ms = play.played_ms();
+        if played_ms == 0 {
+            return;
+        }
+        if let Some(scrobbler) = &self.scrobbler {
+            if Scrobbler::is_listen(play.track.duration_ms, played_ms) {
+                if let Err(e) = scrobbler.queue_listen(&play.track, play.started_at) {
+                    log::error!("Failed to queue listen of track {}: {:?}", play.track.track_id, e);
+                }
+            }
+        }
+        let result = self.db.new_play(NewPlay {
+            track_id: play.track.track_id,
+            started_at: play.started_at,
+            played_ms,
+            completed,
+            source: play.source.into(),
+        });
+        if let Err(e) = result {
+            log::error!("Failed to record play of track {}: {:?}", play.track.track_id, e);
+        }
+    }
+}
+
+/// Bookkeeping for the play of the current track; the clock only runs while
+/// the player reports to be playing
+#[derive(Clone, Debug)]
+struct PlayRecord {
+    track: SimpleTrack,
+    source: PlaybackSource,
+    started_at: NaiveDateTime,
+    played: Duration,
+    playing_since: Option<Instant>,
+    //position within the track minus the time played, moved by seeking
+    seek_offset_ms: i64,
+    announced: bool,
+}
+
+impl PlayRecord {
+    fn new(track: &SimpleTrack, player: TargetPlayer) -> Self {
+        Self {
+            track: track.clone(),
+            source: match player {
+                TargetPlayer::Local => PlaybackSource::Local,
+                TargetPlayer::Spotify => PlaybackSource::Spotify,
+            },
+            started_at: Utc::now().naive_utc(),
+            played: Duration::ZERO,
+            playing_since: None,
+            seek_offset_ms: 0,
+            announced: false,
+        }
+    }
+
+    /// Returns the track the first time the play actually started
+    fn announce(&mut self) -> Option<SimpleTrack> {
+        if self.announced {
+            return None;
+        }
+        self.announced = true;
+        Some(self.track.clone())
+    }
+
+    fn resume(&mut self) {
+        if self.playing_since.is_none() {
+            self.playing_since = Some(Instant::now());
+        }
+    }
+
+    fn pause(&mut self) {
+        if let Some(since) = self.playing_since.take() {
+            self.played += since.elapsed();
+        }
+    }
+
+    fn played_ms(&self) -> i64 {
+        let running = self.playing_since.map(|since| since.elapsed()).unwrap_or_default();
+        (self.played + running).as_millis() as i64
+    }
+
+    fn position_ms(&self) -> i64 {
+        (self.played_ms() + self.seek_offset_ms).max(0)
+    }
+
+    fn seek(&mut self, target_ms: i64) {
+        self.seek_offset_ms = target_ms - self.played_ms();
     }
 }
 
@@ -313,6 +877,13 @@ pub struct PlaybackState {
     pub has_previous: bool,
     pub has_next: bool,
     pub looping_state: LoopingStates,
+    pub autoplay: bool,
+    pub queue_version: u64,
+    pub volume: u32,
+    pub muted: bool,
+    pub crossfade: Duration,
+    //within the current track, 0 without one
+    pub position_ms: i64,
     pub current_track: Option<PlaybackTrack>,
 }
 
@@ -322,52 +893,246 @@ enum PlayerEvent {
     Paused,
     Stopped,
     EndOfTrack,
+    Unavailable,
+}
+
+/// How a player starts a track
+#[derive(Copy, Clone, Debug)]
+struct StartOptions {
+    //replay gain factor, only applied by the local player
+    gain: f32,
+    fade_in: Option<Duration>,
 }
 
 #[async_trait]
 trait Player {
     async fn connect_player_events(&mut self, tx: tokio::sync::mpsc::UnboundedSender<PlayerEvent>);
-    async fn start(&self, track_ident: &str) -> Result<(), PlaybackError>;
+    async fn start(&self, track_ident: &str, options: StartOptions) -> Result<(), PlaybackError>;
     async fn resume(&self) -> Result<(), PlaybackError>;
     async fn pause(&self) -> Result<(), PlaybackError>;
+    async fn stop(&self) -> Result<(), PlaybackError>;
     async fn seek(&self, target_pos_ms: i64) -> Result<(), PlaybackError>;
 }
 
 #[derive(Clone)]
 pub struct PlaybackQueue {
     db: DbApi,
-    queued_tracks: Arc<RwLock<VecDeque<PlaybackTrack>>>,
+    state: Arc<RwLock<QueueState>>,
+    updates: tokio::sync::broadcast::Sender<QueueUpdate>,
+}
+
+struct QueueState {
+    tracks: VecDeque<PlaybackTrack>,
+    //handed out per queued entry and never reused
+    next_item_id: u64,
+    //bumped with every published change so clients can detect stale views
+    version: u64,
+    updates: tokio::sync::broadcast::Sender<QueueUpdate>,
+}
+
+/// A single change of the queue; applying them in version order reproduces the queue
+#[derive(Clone, Debug)]
+pub struct QueueUpdate {
+    pub version: u64,
+    pub change: QueueChange,
+}
+
+#[derive(Clone, Debug)]
+pub enum QueueChange {
+    Inserted { position: usize, tracks: Vec<PlaybackTrack> },
+    Removed { item_ids: Vec<u64> },
+    Moved { item_id: u64, new_position: usize },
+    Cleared,
+}
+
+impl QueueState {
+    fn new() -> Self {
+        let (updates, _) = tokio::sync::broadcast::channel(QUEUE_UPDATES_CAPACITY);
+        Self {
+            tracks: VecDeque::new(),
+            next_item_id: 0,
+            version: 0,
+            updates,
+        }
+    }
+
+    fn insert(&mut self, index: usize, mut track: PlaybackTrack) {
+        self.next_item_id += 1;
+        track.item_id = self.next_item_id;
+        self.tracks.insert(index, track);
+    }
+
+    fn publish(&mut self, change: QueueChange) {
+        self.version += 1;
+        //nobody listening is fine
+        let _ = self.updates.send(QueueUpdate {
+            version: self.version,
+            change,
+        });
+    }
+
+    fn publish_inserted(&mut self, position: usize, count: usize) {
+        let tracks = self.tracks.range(position..position + count).cloned().collect_vec();
+        self.publish(QueueChange::Inserted { position, tracks });
+    }
+
+    fn position_of(&self, item_id: u64) -> Result<usize, PlaybackError> {
+        self.tracks.iter()
+            .position(|track| track.item_id == item_id)
+            .ok_or(PlaybackError::QueueItemNotFound(item_id))
+    }
+
+    //autoplayed tracks stay behind the ones queued by the user
+    fn manual_end(&self) -> usize {
+        self.tracks.iter().position(|track| track.autoplayed).unwrap_or(self.tracks.len())
+    }
 }
 
 impl PlaybackQueue {
+    fn new(db: DbApi) -> Self {
+        let state = QueueState::new();
+        let updates = state.updates.clone();
+        Self {
+            db,
+            state: Arc::new(RwLock::new(state)),
+            updates,
+        }
+    }
+
     async fn next_track_for_playback(&self) -> Option<PlaybackTrack> {
-        self.queued_tracks.write().await.pop_front()
+        let mut state = self.state.write().await;
+        let next = state.tracks.pop_front();
+        if let Some(track) = &next {
+            state.publish(QueueChange::Removed { item_ids: vec![track.item_id] });
+        }
+        next
     }
 
-    pub async fn tracks(&self) -> VecDeque<PlaybackTrack> {
-        self.queued_tracks.read().await.clone()
+    async fn peek_next(&self) -> Option<PlaybackTrack> {
+        self.state.read().await.tracks.front().cloned()
     }
 
-    pub async fn append(&self, track_id: i32) -> Result<(), PlaybackError> {
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<QueueUpdate> {
+        //the sender is never replaced, so there is no need to wait for the lock
+        self.updates.subscribe()
+    }
+
+    /// The queue version and all queued tracks, taken at once
+    pub async fn snapshot(&self) -> (u64, Vec<PlaybackTrack>) {
+        let state = self.state.read().await;
+        (state.version, state.tracks.iter().cloned().collect_vec())
+    }
+
+    /// The queue version and the tracks within the page, taken at once
+    pub async fn page(&self, page: &RequestPage) -> (u64, Vec<PlaybackTrack>) {
+        let state = self.state.read().await;
+        let tracks = state.tracks.iter()
+            .skip(page.offset() as usize)
+            .take(page.limit() as usize)
+            .cloned()
+            .collect_vec();
+        (state.version, tracks)
+    }
+
+    pub async fn version(&self) -> u64 {
+        self.state.read().await.version
+    }
+
+    /// Queues the track behind the ones queued by the user and returns the id of its entry
+    pub async fn append(&self, track_id: i32) -> Result<u64, PlaybackError> {
         match self.get_track(track_id) {
             Ok(track) => {
                 log::info!("Found track '{:?}'; adding to queue", track);
-                self.queued_tracks.write().await.push_back(track);
+                let mut state = self.state.write().await;
+                let position = state.manual_end();
+                state.insert(position, track);
+                state.publish_inserted(position, 1);
                 log::info!(
                     "Current Queue size {}",
-                    self.queued_tracks.read().await.len()
+                    state.tracks.len()
                 );
-                Ok(())
+                Ok(state.next_item_id)
             }
             Err(e) => Err(e),
         }
     }
 
+    /// Inserts the tracks in the given order at the position; tracks which
+    /// can't be played from any source are skipped
+    pub async fn insert_playable(&self, track_ids: &[i32], position: QueuePosition) -> Result<usize, PlaybackError> {
+        let tracks = self.get_playable_tracks(track_ids)?;
+        let queued = tracks.len();
+
+        let mut state = self.state.write().await;
+        let index = match position {
+            QueuePosition::Next => 0,
+            QueuePosition::End => state.manual_end(),
+            QueuePosition::AfterIndex(index) if index < state.tracks.len() => index + 1,
+            QueuePosition::AfterIndex(index) => return Err(PlaybackError::QueuePosition(index)),
+        };
+        for (offset, track) in tracks.into_iter().enumerate() {
+            state.insert(index + offset, track);
+        }
+        state.publish_inserted(index, queued);
+        Ok(queued)
+    }
+
+    /// Replaces the whole queue; it's left untouched if none of the tracks is playable
+    pub async fn replace_playable(&self, track_ids: &[i32]) -> Result<usize, PlaybackError> {
+        let tracks = self.get_playable_tracks(track_ids)?;
+        if tracks.is_empty() {
+            return Err(PlaybackError::NoTrackInQueue);
+        }
+
+        let queued = tracks.len();
+        let mut state = self.state.write().await;
+        state.tracks.clear();
+        state.publish(QueueChange::Cleared);
+        for track in tracks {
+            let end = state.tracks.len();
+            state.insert(end, track);
+        }
+        state.publish_inserted(0, queued);
+        Ok(queued)
+    }
+
+    /// Appends tracks picked by autoplay; tracks which can't be queued are skipped
+    pub async fn append_autoplayed(&self, track_ids: &[i32]) {
+        match self.get_playable_tracks(track_ids) {
+            Ok(tracks) => {
+                let mut state = self.state.write().await;
+                let position = state.tracks.len();
+                let count = tracks.len();
+                for mut track in tracks {
+                    track.autoplayed = true;
+                    let end = state.tracks.len();
+                    state.insert(end, track);
+                }
+                state.publish_inserted(position, count);
+            }
+            Err(e) => log::warn!("Skipping autoplay picks {:?}: {:?}", track_ids, e)
+        }
+    }
+
+    pub async fn remove_autoplayed(&self) {
+        let mut state = self.state.write().await;
+        let item_ids = state.tracks.iter()
+            .filter(|track| track.autoplayed)
+            .map(|track| track.item_id)
+            .collect_vec();
+        if !item_ids.is_empty() {
+            state.tracks.retain(|track| !track.autoplayed);
+            state.publish(QueueChange::Removed { item_ids });
+        }
+    }
+
     pub async fn prepend(&self, track_id: i32) -> Result<(), PlaybackError> {
         //the queue doesn't hold the currently playing track
         match self.get_track(track_id) {
             Ok(track) => {
-                self.queued_tracks.write().await.push_front(track);
+                let mut state = self.state.write().await;
+                state.insert(0, track);
+                state.publish_inserted(0, 1);
                 Ok(())
             }
             Err(e) => Err(e),
@@ -375,67 +1140,181 @@ impl PlaybackQueue {
     }
 
     pub async fn remove(&self, index: usize) -> Result<(), PlaybackError> {
-        if index >= self.queued_tracks.read().await.len() {
-            return Err(PlaybackError::QueueRemoval);
+        let mut state = self.state.write().await;
+        match state.tracks.remove(index) {
+            Some(track) => {
+                state.publish(QueueChange::Removed { item_ids: vec![track.item_id] });
+                Ok(())
+            }
+            None => Err(PlaybackError::QueueRemoval)
+        }
+    }
+
+    pub async fn remove_item(&self, item_id: u64) -> Result<(), PlaybackError> {
+        let mut state = self.state.write().await;
+        let index = state.position_of(item_id)?;
+        let _ = state.tracks.remove(index);
+        state.publish(QueueChange::Removed { item_ids: vec![item_id] });
+        Ok(())
+    }
+
+    /// Moves the entry so it ends up at the given position of the queue
+    pub async fn move_item(&self, item_id: u64, new_position: usize) -> Result<(), PlaybackError> {
+        let mut state = self.state.write().await;
+        let index = state.position_of(item_id)?;
+        if new_position >= state.tracks.len() {
+            return Err(PlaybackError::QueuePosition(new_position));
+        }
+        if let Some(track) = state.tracks.remove(index) {
+            state.tracks.insert(new_position, track);
         }
-        let _ = self.queued_tracks.write().await.remove(index);
+        state.publish(QueueChange::Moved { item_id, new_position });
         Ok(())
     }
 
     pub async fn clear(&mut self) {
-        self.queued_tracks.write().await.clear()
+        let mut state = self.state.write().await;
+        state.tracks.clear();
+        state.publish(QueueChange::Cleared);
     }
 
     pub async fn has_next(&self) -> bool {
-        !self.queued_tracks.read().await.is_empty()
+        !self.state.read().await.tracks.is_empty()
     }
 
     fn get_track(&self, track_id: i32) -> Result<PlaybackTrack, PlaybackError> {
+        let track = self.get_tracks(&[track_id])?.remove(0);
+        if !track.is_playable() {
+            return Err(PlaybackError::NoPlayableSource(track_id));
+        }
+        Ok(track)
+    }
+
+    fn get_playable_tracks(&self, track_ids: &[i32]) -> Result<Vec<PlaybackTrack>, PlaybackError> {
+        let (playable, unplayable): (Vec<_>, Vec<_>) = self.get_tracks(track_ids)?
+            .into_iter()
+            .partition(PlaybackTrack::is_playable);
+        if !unplayable.is_empty() {
+            log::info!("Skipping unplayable tracks {:?}", unplayable.iter().map(|t| t.meta.track_id).collect_vec());
+        }
+        Ok(playable)
+    }
+
+    /// Loads the tracks in the given order with a fixed number of queries
+    fn get_tracks(&self, track_ids: &[i32]) -> Result<Vec<PlaybackTrack>, PlaybackError> {
         let api: &dyn TrackDb = &self.db;
-        match api.find_by_id(track_id)? {
-            Some(track) => {
-                let artists = self.db.load_artists_for_track(&track)?;
-                let album = self.db.load_album_for_track(&track)?;
-                let (target_player, track_ident) = if track.local_file.is_some() {
-                    (TargetPlayer::Local, track.local_file.unwrap())
-                } else {
-                    (TargetPlayer::Spotify, track.spot_id.unwrap())
-                };
+        let tracks = api.find_by_ids(track_ids.iter().unique().cloned().collect_vec())?;
+        let albums = self.db.load_albums_for_tracks(&tracks)?;
+        let track_artist_ids = self.db.load_artist_ids_for_tracks(&tracks)?;
+        let api: &dyn ArtistDb = &self.db;
+        let artists = api.find_by_ids(track_artist_ids.values().flatten().unique().cloned().collect_vec())?;
+        let mut stats = self.db.load_play_stats_for_tracks(&tracks.iter().map(|t| t.track_id).collect_vec())?;
 
-                Ok(PlaybackTrack {
-                    meta: SimpleTrack {
-                        track_id: track.track_id,
-                        title: track.title,
-                        is_faved: track.is_faved,
-                        duration_ms: track.duration_ms,
-                        album: SimpleAlbum {
-                            album_id: album.album_id,
-                            name: album.name,
-                        },
-                        artists: artists
-                            .iter()
-                            .map(|a| SimpleArtist {
-                                artist_id: a.artist_id,
-                                name: a.name.clone(),
-                            })
-                            .collect_vec(),
+        let mut by_id = HashMap::new();
+        for track in tracks {
+            let album = albums.iter()
+                .find(|a| a.album_id == track.album_id)
+                .ok_or(PlaybackError::QueueTrackNotFound)?;
+            let track_artists = track_artist_ids.get(&track.track_id)
+                .map(|ids| artists.iter()
+                    .filter(|a| ids.contains(&a.artist_id))
+                    .map(|a| SimpleArtist {
+                        artist_id: a.artist_id,
+                        name: a.name.clone(),
+                    })
+                    .collect_vec())
+                .unwrap_or_default();
+            let stats = stats.remove(&track.track_id).unwrap_or_default();
+            let replay_gain = track.replay_gain();
+
+            by_id.insert(track.track_id, PlaybackTrack {
+                meta: SimpleTrack {
+                    track_id: track.track_id,
+                    title: track.title,
+                    is_faved: track.is_faved,
+                    duration_ms: track.duration_ms,
+                    play_count: stats.play_count,
+                    last_played: stats.last_played,
+                    album: SimpleAlbum {
+                        album_id: album.album_id,
+                        name: album.name.clone(),
                     },
+                    artists: track_artists,
+                },
+                autoplayed: false,
+                item_id: 0,
 
-                    player: target_player,
-                    track_ident,
-                })
-            }
-            None => Err(PlaybackError::QueueTrackNotFound),
+                local_file: track.local_file,
+                spot_id: track.spot_id,
+                preferred_source: PlaybackSource::from_db(track.preferred_source),
+                replay_gain,
+            });
         }
+
+        //ids may repeat, so tracks are cloned out instead of moved
+        track_ids.iter()
+            .map(|track_id| by_id.get(track_id).cloned().ok_or(PlaybackError::QueueTrackNotFound))
+            .collect()
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct PlaybackTrack {
     pub meta: SimpleTrack,
+    //queued by autoplay instead of the user
+    pub autoplayed: bool,
+    //identifies the queue entry; 0 until queued
+    pub item_id: u64,
 
-    player: TargetPlayer,
-    track_ident: String,
+    local_file: Option<String>,
+    spot_id: Option<String>,
+    preferred_source: Option<PlaybackSource>,
+    replay_gain: ReplayGain,
+}
+
+impl PlaybackTrack {
+    fn is_playable(&self) -> bool {
+        self.local_file.is_some() || self.spot_id.is_some()
+    }
+
+    /// Lists the players able to play this track, most preferred first.
+    /// A per track preference takes precedence over the configured default.
+    fn candidate_players(&self, default_source: PlaybackSource) -> Vec<(TargetPlayer, String)> {
+        let first = self.preferred_source.unwrap_or(default_source);
+        [first, first.other()].iter()
+            .filter_map(|source| match source {
+                PlaybackSource::Local => self.local_file.clone().map(|f| (TargetPlayer::Local, f)),
+                PlaybackSource::Spotify => self.spot_id.clone().map(|id| (TargetPlayer::Spotify, id))
+            })
+            .collect_vec()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueuePosition {
+    //right after the currently playing track
+    Next,
+    //after the tracks queued by the user but before autoplayed ones
+    End,
+    AfterIndex(usize),
+}
+
+//updates a subscriber may fall behind before it has to resync
+const QUEUE_UPDATES_CAPACITY: usize = 64;
+
+//tracks queued per autoplay refill
+const AUTOPLAY_BATCH_SIZE: usize = 5;
+
+//how long before the end of a track the next one gets preloaded
+const PRELOAD_BEFORE_END_MS: i64 = 30_000;
+const TRANSITION_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Transition {
+    //the previous track stops right away
+    Cut,
+    //the previous track fades out while the new one fades in
+    Crossfade(Duration),
 }
 
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash)]
@@ -463,6 +1342,12 @@ pub enum PlaybackError {
     #[error("Index larger than queue size!")]
     QueueRemoval,
 
+    #[error("Queue position {0} is out of range!")]
+    QueuePosition(usize),
+
+    #[error("Queue item {0} doesn't exist!")]
+    QueueItemNotFound(u64),
+
     #[error("Tried to insert non existent track to queue!")]
     QueueTrackNotFound,
 
@@ -474,4 +1359,28 @@ pub enum PlaybackError {
 
     #[error("Can't start playback with no track in queue!")]
     NoTrackInQueue,
+
+    #[error("No track is playing!")]
+    NotPlaying,
+
+    #[error("Track {0} can't be played from any source!")]
+    NoPlayableSource(i32),
+
+    #[error("Local file '{0}' is missing!")]
+    LocalFileMissing(String),
+
+    #[error("Failed to decode local file '{0}': {1}")]
+    LocalDecode(String, String),
+
+    #[error("Mixer error: {0}")]
+    Mixer(String),
+
+    #[error("Audio output error: {0}")]
+    Output(String),
+
+    #[error("Player unavailable: {0}")]
+    PlayerUnavailable(String),
+
+    #[error("IO error occurred: {0}")]
+    Io(#[from] std::io::Error),
 }
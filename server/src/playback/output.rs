@@ -0,0 +1,425 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use librespot::playback::audio_backend::{self, Sink, SinkError, SinkResult, BACKENDS};
+use librespot::playback::config::AudioFormat;
+use librespot::playback::convert::Converter;
+use librespot::playback::decoder::AudioPacket;
+use librespot::playback::{NUM_CHANNELS, SAMPLE_RATE};
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use rodio::dynamic_mixer::{DynamicMixer, DynamicMixerController};
+use rodio::Source;
+
+use crate::model::library_models::SimpleTrack;
+use crate::playback::stream::{self, MetadataChannel, StreamEncoding, StreamSink, STREAM_BACKEND};
+use crate::playback::PlaybackError;
+
+//samples handed to the backend at once, ~46ms
+const CHUNK_SAMPLES: usize = 2048 * NUM_CHANNELS as usize;
+//how often an idle output looks for new sources
+const IDLE_POLL: Duration = Duration::from_millis(20);
+//decoded spotify packets buffered ahead of the output
+const FEED_BUFFER_PACKETS: usize = 4;
+//chunks a slow listener may fall behind before it skips ahead
+const LISTENER_BUFFER_CHUNKS: usize = 64;
+
+pub const FORMATS: &[AudioFormat] = &[
+    AudioFormat::F64,
+    AudioFormat::F32,
+    AudioFormat::S32,
+    AudioFormat::S24,
+    AudioFormat::S24_3,
+    AudioFormat::S16,
+];
+
+/// Where the audio goes: one of the librespot backends or the stream
+/// backend, its device and the sample format
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AudioOutputConfig {
+    pub backend: String,
+    //device name for rodio/alsa, sink for pulseaudio, file for pipe (stdout if unset),
+    //command for subprocess, pipe or tcp://host:port for stream
+    pub device: Option<String>,
+    pub format: AudioFormat,
+    //only the stream backend resamples, the others play at 44.1kHz
+    pub sample_rate: u32,
+    pub encoding: StreamEncoding,
+    //pipe or tcp://host:port receiving a json line for every track change
+    pub metadata: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AudioBackend {
+    pub name: String,
+    //empty if the devices of the backend can't be listed
+    pub devices: Vec<String>,
+}
+
+impl AudioOutputConfig {
+    /// Reads AUDIO_BACKEND (defaults to the first backend compiled in), AUDIO_DEVICE,
+    /// AUDIO_FORMAT (F64, F32, S32, S24, S24_3 or S16; defaults to S16) and for the
+    /// stream backend AUDIO_SAMPLE_RATE, AUDIO_ENCODING (pcm or flac) and AUDIO_METADATA
+    pub fn from_env() -> Result<Self, PlaybackError> {
+        let backend = match dotenv::var("AUDIO_BACKEND") {
+            Ok(backend) => backend,
+            Err(_) => BACKENDS.first().map(|b| b.0.to_string()).unwrap_or_default()
+        };
+        let format = match dotenv::var("AUDIO_FORMAT") {
+            Ok(format) => parse_format(&format)?,
+            Err(_) => AudioFormat::default()
+        };
+        let sample_rate = match dotenv::var("AUDIO_SAMPLE_RATE") {
+            Ok(rate) => rate.parse::<u32>()
+                .map_err(|e| PlaybackError::Output(format!("Invalid AUDIO_SAMPLE_RATE: {}", e)))?,
+            Err(_) => SAMPLE_RATE
+        };
+        let encoding = match dotenv::var("AUDIO_ENCODING") {
+            Ok(encoding) => encoding.parse::<StreamEncoding>().map_err(PlaybackError::Output)?,
+            Err(_) => StreamEncoding::Pcm
+        };
+        let config = Self {
+            backend,
+            device: dotenv::var("AUDIO_DEVICE").ok(),
+            format,
+            sample_rate,
+            encoding,
+            metadata: dotenv::var("AUDIO_METADATA").ok(),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings librespot would panic or exit on
+    pub fn validate(&self) -> Result<(), PlaybackError> {
+        if self.backend == STREAM_BACKEND {
+            return self.validate_stream();
+        }
+        if audio_backend::find(Some(self.backend.clone())).is_none() {
+            return Err(PlaybackError::Output(format!("Unsupported audio backend '{}'", self.backend)));
+        }
+        if self.sample_rate != SAMPLE_RATE || self.encoding != StreamEncoding::Pcm {
+            return Err(PlaybackError::Output(format!("Only the {} backend supports other sample rates and encodings", STREAM_BACKEND)));
+        }
+        match (self.backend.as_str(), self.device.as_deref()) {
+            (_, Some("?")) => Err(PlaybackError::Output("Use ListAudioOutputs to list devices".to_string())),
+            ("subprocess", None) => Err(PlaybackError::Output("The subprocess backend needs a command as device".to_string())),
+            ("rodio", _) if self.format != AudioFormat::S16 && self.format != AudioFormat::F32 =>
+                Err(PlaybackError::Output("The rodio backend only supports S16 and F32".to_string())),
+            ("rodio", Some(device)) if !output_devices().iter().any(|d| d == device) =>
+                Err(PlaybackError::Output(format!("Unknown output device '{}'", device))),
+            _ => Ok(())
+        }
+    }
+
+    fn validate_stream(&self) -> Result<(), PlaybackError> {
+        if self.device.is_none() {
+            return Err(PlaybackError::Output(format!("The {} backend needs a pipe or tcp://host:port as device", STREAM_BACKEND)));
+        }
+        if !(8_000..=192_000).contains(&self.sample_rate) {
+            return Err(PlaybackError::Output(format!("Unsupported sample rate {}", self.sample_rate)));
+        }
+        if self.encoding == StreamEncoding::Flac && !stream::supports_flac(self.format) {
+            return Err(PlaybackError::Output("Flac streams need S16, S24 or S24_3 samples".to_string()));
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_format(format: &str) -> Result<AudioFormat, PlaybackError> {
+    format.parse::<AudioFormat>()
+        .map_err(|_| PlaybackError::Output(format!("Unsupported sample format '{}'", format)))
+}
+
+/// The backends compiled in and the stream backend; rodio and alsa both address the alsa devices
+pub fn available_backends() -> Vec<AudioBackend> {
+    let devices = output_devices();
+    BACKENDS
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(std::iter::once(STREAM_BACKEND))
+        .map(|name| AudioBackend {
+            name: name.to_string(),
+            devices: match name {
+                "rodio" | "alsa" => devices.clone(),
+                _ => Vec::new()
+            },
+        })
+        .collect()
+}
+
+fn output_devices() -> Vec<String> {
+    match rodio::cpal::default_host().output_devices() {
+        Ok(devices) => devices.filter_map(|d| d.name().ok()).collect(),
+        Err(e) => {
+            log::warn!("Failed to list output devices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Mixes everything the players produce and writes it to the configured
+/// backend, so both players share device and format and switching the
+/// output doesn't interrupt playback.
+#[derive(Clone)]
+pub struct AudioOutput {
+    mixer: Arc<DynamicMixerController<f32>>,
+    config: Arc<Mutex<AudioOutputConfig>>,
+    config_tx: Sender<AudioOutputConfig>,
+    metadata: MetadataChannel,
+    //copies of the mixed chunks for listen along streams; silence while idle
+    listeners: tokio::sync::broadcast::Sender<Arc<Vec<f64>>>,
+    now_playing: Arc<tokio::sync::watch::Sender<Option<SimpleTrack>>>,
+}
+
+impl AudioOutput {
+    pub fn new(config: AudioOutputConfig) -> Self {
+        let (mixer, mixed) = rodio::dynamic_mixer::mixer::<f32>(NUM_CHANNELS as u16, SAMPLE_RATE);
+        let (config_tx, config_rx) = std::sync::mpsc::channel();
+        let initial = config.clone();
+        let metadata = MetadataChannel::new(config.metadata.clone());
+        let (listeners, _) = tokio::sync::broadcast::channel(LISTENER_BUFFER_CHUNKS);
+        let (now_playing, _) = tokio::sync::watch::channel(None);
+        let tap = listeners.clone();
+        // backend sinks are not Send, therefore they live on the output thread
+        std::thread::Builder::new()
+            .name("audio-output".to_string())
+            .spawn(move || run_output(mixed, initial, config_rx, tap))
+            .expect("Failed to spawn audio output thread!");
+
+        Self {
+            mixer,
+            config: Arc::new(Mutex::new(config)),
+            config_tx,
+            metadata,
+            listeners,
+            now_playing: Arc::new(now_playing),
+        }
+    }
+
+    pub fn config(&self) -> AudioOutputConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Reopens the output with the new settings; playing sources carry on
+    pub fn set_config(&self, config: AudioOutputConfig) -> Result<(), PlaybackError> {
+        config.validate()?;
+        self.config_tx
+            .send(config.clone())
+            .map_err(|_| PlaybackError::Output("audio output thread terminated".to_string()))?;
+        self.metadata.set_target(config.metadata.clone());
+        *self.config.lock().unwrap() = config;
+        Ok(())
+    }
+
+    /// Announces the track now playing on the metadata side channel
+    pub fn publish_track(&self, track: Option<SimpleTrack>) {
+        self.now_playing.send_replace(track.clone());
+        self.metadata.publish(track);
+    }
+
+    pub fn now_playing(&self) -> tokio::sync::watch::Receiver<Option<SimpleTrack>> {
+        self.now_playing.subscribe()
+    }
+
+    /// Receives the mixed samples (interleaved stereo at 44.1kHz) as they are played
+    pub fn listen(&self) -> tokio::sync::broadcast::Receiver<Arc<Vec<f64>>> {
+        self.listeners.subscribe()
+    }
+
+    /// Plays the source until it ends
+    pub fn add<S>(&self, source: S)
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        self.mixer.add(source);
+    }
+
+    /// Sink for a librespot player to write into
+    pub fn player_sink(&self) -> PlayerSink {
+        PlayerSink { output: self.clone(), feed: None }
+    }
+}
+
+fn run_output(
+    mut mixed: DynamicMixer<f32>,
+    mut config: AudioOutputConfig,
+    config_rx: Receiver<AudioOutputConfig>,
+    tap: tokio::sync::broadcast::Sender<Arc<Vec<f64>>>,
+) {
+    let mut converter = Converter::new(None);
+    let mut sink: Option<Box<dyn Sink>> = None;
+    //log a failing backend once instead of for every chunk
+    let mut failing = false;
+
+    loop {
+        match config_rx.try_recv() {
+            Ok(new_config) => {
+                log::info!("Switching audio output to {:?}", new_config);
+                close_sink(&mut sink);
+                config = new_config;
+                failing = false;
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => break,
+        }
+
+        let samples: Vec<f64> = mixed.by_ref().take(CHUNK_SAMPLES).map(|s| s as f64).collect();
+        if tap.receiver_count() > 0 {
+            //keep listeners going through pauses
+            let chunk = if samples.is_empty() {
+                vec![0.0; (chunk_frames(IDLE_POLL) * NUM_CHANNELS as f64) as usize]
+            } else {
+                samples.clone()
+            };
+            let _ = tap.send(Arc::new(chunk));
+        }
+        if samples.is_empty() {
+            //nothing playing, release the device
+            close_sink(&mut sink);
+            match config_rx.recv_timeout(IDLE_POLL) {
+                Ok(new_config) => {
+                    config = new_config;
+                    failing = false;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            continue;
+        }
+
+        if sink.is_none() {
+            match open_sink(&config) {
+                Ok(s) => {
+                    sink = Some(s);
+                    failing = false;
+                }
+                Err(e) => {
+                    if !failing {
+                        log::error!("Failed to open audio output {:?}: {}", config, e);
+                        failing = true;
+                    }
+                    //drop the audio at the pace it would have played
+                    std::thread::sleep(chunk_duration(samples.len()));
+                    continue;
+                }
+            }
+        }
+
+        if let Some(s) = sink.as_mut() {
+            if let Err(e) = s.write(&AudioPacket::Samples(samples), &mut converter) {
+                log::error!("Failed to write to audio output: {}", e);
+                close_sink(&mut sink);
+            }
+        }
+    }
+}
+
+fn open_sink(config: &AudioOutputConfig) -> Result<Box<dyn Sink>, SinkError> {
+    if config.backend == STREAM_BACKEND {
+        let target = config.device.as_deref().unwrap_or_default();
+        let mut sink = StreamSink::new(target, config.format, config.sample_rate, config.encoding);
+        sink.start()?;
+        return Ok(Box::new(sink));
+    }
+    let builder = audio_backend::find(Some(config.backend.clone()))
+        .ok_or_else(|| SinkError::InvalidParams(format!("Unsupported audio backend '{}'", config.backend)))?;
+    let (device, format) = (config.device.clone(), config.format);
+    //some backends panic on devices which went away in the meantime
+    let mut sink = std::panic::catch_unwind(AssertUnwindSafe(|| builder(device, format)))
+        .map_err(|_| SinkError::ConnectionRefused("backend panicked while opening".to_string()))?;
+    sink.start()?;
+    Ok(sink)
+}
+
+fn close_sink(sink: &mut Option<Box<dyn Sink>>) {
+    if let Some(mut s) = sink.take() {
+        if let Err(e) = s.stop() {
+            log::warn!("Failed to stop audio output: {}", e);
+        }
+    }
+}
+
+fn chunk_duration(samples: usize) -> Duration {
+    Duration::from_secs_f64(samples as f64 / (SAMPLE_RATE as f64 * NUM_CHANNELS as f64))
+}
+
+fn chunk_frames(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * SAMPLE_RATE as f64).round()
+}
+
+/// Librespot sink feeding the shared output. Writes block while the
+/// output is behind, which paces the decoding like a real device would.
+pub struct PlayerSink {
+    output: AudioOutput,
+    feed: Option<SyncSender<Vec<f32>>>,
+}
+
+impl Sink for PlayerSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if self.feed.is_none() {
+            let (tx, rx) = std::sync::mpsc::sync_channel(FEED_BUFFER_PACKETS);
+            self.output.add(FeedSource { rx, buffer: Vec::new().into_iter() });
+            self.feed = Some(tx);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        //the buffered packets still play, then the source ends
+        self.feed = None;
+        Ok(())
+    }
+
+    fn write(&mut self, packet: &AudioPacket, _converter: &mut Converter) -> SinkResult<()> {
+        let samples = packet.samples().map_err(|e| SinkError::OnWrite(e.to_string()))?;
+        match &self.feed {
+            Some(tx) => tx
+                .send(samples.iter().map(|s| *s as f32).collect())
+                .map_err(|_| SinkError::NotConnected("audio output closed".to_string())),
+            None => Err(SinkError::NotConnected("player sink isn't started".to_string()))
+        }
+    }
+}
+
+struct FeedSource {
+    rx: Receiver<Vec<f32>>,
+    buffer: std::vec::IntoIter<f32>,
+}
+
+impl Iterator for FeedSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        loop {
+            if let Some(sample) = self.buffer.next() {
+                return Some(sample);
+            }
+            self.buffer = match self.rx.try_recv() {
+                Ok(packet) => packet.into_iter(),
+                //fill underruns with whole silent frames to keep the channels aligned
+                Err(TryRecvError::Empty) => vec![0.0; NUM_CHANNELS as usize].into_iter(),
+                Err(TryRecvError::Disconnected) => return None,
+            };
+        }
+    }
+}
+
+impl Source for FeedSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        NUM_CHANNELS as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
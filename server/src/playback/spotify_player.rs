@@ -1,4 +1,7 @@
-use super::Player;
+use super::{Player, StartOptions};
+use crate::playback::fade::{FadeFilter, FadeHandle};
+use crate::playback::mixer::SharedMixer;
+use crate::playback::output::AudioOutput;
 use crate::playback::PlaybackError;
 use async_trait::async_trait;
 use futures::Future;
@@ -7,48 +10,62 @@ use librespot::core::config::SessionConfig;
 use librespot::core::session::Session;
 use librespot::core::spotify_id::SpotifyId;
 use librespot::discovery::Credentials;
-use librespot::playback::audio_backend;
-use librespot::playback::config::{AudioFormat, Bitrate, NormalisationType, PlayerConfig};
+use librespot::playback::config::{Bitrate, NormalisationType, PlayerConfig};
 use librespot::playback::player::PlayerEvent;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 
 #[derive(Clone)]
 pub struct SpotifyPlayer {
     librespot_player: Arc<RwLock<librespot::playback::player::Player>>,
+    fade: FadeHandle,
     has_track_end_cb: bool,
 }
 
 impl SpotifyPlayer {
-    pub async fn new(username: &str, password: &str, cache_locations: (&str, &str)) -> Self {
+    /// The player writes into the shared output instead of opening a device itself
+    pub async fn new(username: &str, password: &str, cache_locations: (&str, &str), mixer: &SharedMixer, output: &AudioOutput) -> Self {
         let session_config = SessionConfig::default();
         let player_config = PlayerConfig {
             bitrate: Bitrate::Bitrate320,
-            gapless: false,
+            gapless: true,
             normalisation: true,
             normalisation_type: NormalisationType::Auto,
             ..PlayerConfig::default()
         };
-        let audio_format = AudioFormat::default();
 
         let credentials = Credentials::with_password(username, password);
-        let backend = audio_backend::find(None).unwrap();
         let cache = Cache::new(Some(cache_locations.0), Some(cache_locations.1), None).unwrap();
 
         let session = Session::connect(session_config, credentials, Some(cache))
             .await
             .unwrap();
 
+        let (filter, fade) = FadeFilter::new(mixer.audio_filter());
+        let sink = output.player_sink();
         let (player, _event_stream) =
-            librespot::playback::player::Player::new(player_config, session, None, move || {
-                backend(None, audio_format)
+            librespot::playback::player::Player::new(player_config, session, Some(Box::new(filter)), move || {
+                Box::new(sink)
             });
 
         Self {
             librespot_player: Arc::new(RwLock::new(player)),
+            fade,
             has_track_end_cb: false,
         }
     }
+
+    pub async fn preload(&self, track_ident: &str) -> Result<(), PlaybackError> {
+        let id = SpotifyId::from_uri(track_ident).map_err(PlaybackError::SpotifyIdError)?;
+        self.librespot_player.read().await.preload(id);
+        Ok(())
+    }
+
+    /// Silences the output over the given duration; the track keeps running until stopped
+    pub fn fade_out(&self, duration: Duration) {
+        self.fade.fade_out(duration);
+    }
 }
 
 #[async_trait]
@@ -106,6 +123,14 @@ impl Player for SpotifyPlayer {
                         tx.send(super::PlayerEvent::EndOfTrack)
                             .expect("Failed to notify PlayerController");
                     }
+                    PlayerEvent::Unavailable {
+                        play_request_id: _id,
+                        track_id: _track_id,
+                    } => {
+                        log::info!("Forwarding Unavailable event!");
+                        tx.send(super::PlayerEvent::Unavailable)
+                            .expect("Failed to notify PlayerController");
+                    }
                     _ => {}
                 }
             }
@@ -114,16 +139,17 @@ impl Player for SpotifyPlayer {
         self.has_track_end_cb = true;
     }
 
-    async fn start(&self, track_ident: &str) -> Result<(), PlaybackError> {
-        let result = SpotifyId::from_uri(track_ident);
-        match result {
-            Ok(id) => {
-                self.librespot_player.write().await.load(id, true, 0);
-                log::info!("Track loading in Librespot!");
-                Ok(())
-            }
-            Err(e) => Err(PlaybackError::SpotifyIdError(e)),
+    /// Loads the track, optionally fading it in. Loading the preloaded track
+    /// right at the end of the current one continues without a gap.
+    async fn start(&self, track_ident: &str, options: StartOptions) -> Result<(), PlaybackError> {
+        let id = SpotifyId::from_uri(track_ident).map_err(PlaybackError::SpotifyIdError)?;
+        match options.fade_in {
+            Some(duration) => self.fade.fade_in(duration),
+            None => self.fade.reset()
         }
+        self.librespot_player.write().await.load(id, true, 0);
+        log::info!("Track loading in Librespot!");
+        Ok(())
     }
 
     async fn resume(&self) -> Result<(), PlaybackError> {
@@ -138,6 +164,12 @@ impl Player for SpotifyPlayer {
         Ok(())
     }
 
+    async fn stop(&self) -> Result<(), PlaybackError> {
+        self.librespot_player.read().await.stop();
+        log::info!("Stopped Playback!");
+        Ok(())
+    }
+
     async fn seek(&self, target_pos_ms: i64) -> Result<(), PlaybackError> {
         self.librespot_player
             .read()
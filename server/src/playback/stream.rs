@@ -0,0 +1,554 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, Sender};
+
+use librespot::playback::audio_backend::{Sink, SinkError, SinkResult};
+use librespot::playback::config::AudioFormat;
+use librespot::playback::convert::Converter;
+use librespot::playback::decoder::AudioPacket;
+use librespot::playback::{NUM_CHANNELS, SAMPLE_RATE};
+use serde::Serialize;
+
+use crate::model::library_models::SimpleTrack;
+
+pub const STREAM_BACKEND: &str = "stream";
+
+//samples per channel in a flac frame
+const FLAC_BLOCK_SIZE: usize = 4096;
+
+/// What the stream backend writes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StreamEncoding {
+    //interleaved little endian samples in the configured format
+    Pcm,
+    //uncompressed (verbatim) flac frames, self describing for the reader
+    Flac,
+}
+
+impl FromStr for StreamEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pcm" | "" => Ok(StreamEncoding::Pcm),
+            "flac" => Ok(StreamEncoding::Flac),
+            _ => Err(format!("Unknown stream encoding '{}', expected pcm or flac", s))
+        }
+    }
+}
+
+impl std::fmt::Display for StreamEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamEncoding::Pcm => write!(f, "pcm"),
+            StreamEncoding::Flac => write!(f, "flac"),
+        }
+    }
+}
+
+/// Opens a stream target: tcp://host:port connects to a listening reader
+/// (e.g. a snapserver tcp source in server mode), anything else is a path,
+/// usually a named pipe. Opening a pipe blocks until it has a reader.
+fn open_target(target: &str) -> std::io::Result<Box<dyn Write + Send>> {
+    match target.strip_prefix("tcp://") {
+        Some(address) => Ok(Box::new(TcpStream::connect(address)?)),
+        None => Ok(Box::new(OpenOptions::new().write(true).open(target)?))
+    }
+}
+
+/// Backend writing the mixed output to a pipe or tcp stream
+pub struct StreamSink {
+    target: String,
+    format: AudioFormat,
+    encoding: StreamEncoding,
+    resampler: Resampler,
+    converter: Converter,
+    writer: Option<Box<dyn Write + Send>>,
+    flac: FlacEncoder,
+}
+
+impl StreamSink {
+    pub fn new(target: &str, format: AudioFormat, sample_rate: u32, encoding: StreamEncoding) -> Self {
+        Self {
+            target: target.to_string(),
+            format,
+            encoding,
+            resampler: Resampler::new(SAMPLE_RATE, sample_rate),
+            converter: Converter::new(None),
+            writer: None,
+            flac: FlacEncoder::new(sample_rate, flac_bits(format)),
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> SinkResult<()> {
+        match self.writer.as_mut() {
+            Some(w) => w.write_all(bytes).map_err(|e| SinkError::OnWrite(e.to_string())),
+            None => Err(SinkError::NotConnected(self.target.clone()))
+        }
+    }
+}
+
+impl Sink for StreamSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if self.writer.is_none() {
+            let writer = open_target(&self.target)
+                .map_err(|e| SinkError::ConnectionRefused(format!("{}: {}", self.target, e)))?;
+            self.writer = Some(writer);
+            if self.encoding == StreamEncoding::Flac {
+                self.flac.reset();
+                let header = self.flac.stream_header();
+                self.write_bytes(&header)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        if self.encoding == StreamEncoding::Flac && self.writer.is_some() {
+            let rest = self.flac.flush();
+            self.write_bytes(&rest)?;
+        }
+        if let Some(mut w) = self.writer.take() {
+            w.flush().map_err(|e| SinkError::OnWrite(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, packet: &AudioPacket, _converter: &mut Converter) -> SinkResult<()> {
+        let samples = packet.samples().map_err(|e| SinkError::OnWrite(e.to_string()))?;
+        let samples = self.resampler.process(samples);
+        let bytes = match self.encoding {
+            StreamEncoding::Pcm => pcm_bytes(&mut self.converter, self.format, &samples),
+            StreamEncoding::Flac => {
+                let quantized = match self.flac.bits {
+                    16 => self.converter.f64_to_s16(&samples).into_iter().map(i32::from).collect(),
+                    _ => self.converter.f64_to_s24(&samples),
+                };
+                self.flac.encode(&quantized)
+            }
+        };
+        self.write_bytes(&bytes)
+    }
+}
+
+//flac carries 16 or 24 bit samples here
+fn flac_bits(format: AudioFormat) -> u32 {
+    match format {
+        AudioFormat::S24 | AudioFormat::S24_3 => 24,
+        _ => 16
+    }
+}
+
+pub fn supports_flac(format: AudioFormat) -> bool {
+    matches!(format, AudioFormat::S16 | AudioFormat::S24 | AudioFormat::S24_3)
+}
+
+fn pcm_bytes(converter: &mut Converter, format: AudioFormat, samples: &[f64]) -> Vec<u8> {
+    match format {
+        AudioFormat::F64 => samples.iter().flat_map(|s| s.to_le_bytes()).collect(),
+        AudioFormat::F32 => converter.f64_to_f32(samples).iter().flat_map(|s| s.to_le_bytes()).collect(),
+        AudioFormat::S32 => converter.f64_to_s32(samples).iter().flat_map(|s| s.to_le_bytes()).collect(),
+        //24 bit in the lower three bytes of 32
+        AudioFormat::S24 => converter.f64_to_s24(samples).iter().flat_map(|s| s.to_le_bytes()).collect(),
+        AudioFormat::S24_3 => converter.f64_to_s24(samples).iter().flat_map(|s| s.to_le_bytes()[..3].to_vec()).collect(),
+        AudioFormat::S16 => converter.f64_to_s16(samples).iter().flat_map(|s| s.to_le_bytes()).collect(),
+    }
+}
+
+/// Linear interpolation between the frames of the mix; keeps the last frame
+/// of a chunk so consecutive chunks join without clicks
+struct Resampler {
+    //input frames per output frame
+    step: f64,
+    //position of the next output frame, in input frames after `last`
+    position: f64,
+    last: Vec<f64>,
+}
+
+impl Resampler {
+    fn new(from: u32, to: u32) -> Self {
+        Self {
+            step: from as f64 / to as f64,
+            position: 0.0,
+            last: vec![0.0; NUM_CHANNELS as usize],
+        }
+    }
+
+    fn process(&mut self, samples: &[f64]) -> Vec<f64> {
+        if (self.step - 1.0).abs() < f64::EPSILON {
+            return samples.to_vec();
+        }
+
+        let channels = NUM_CHANNELS as usize;
+        let frames = samples.len() / channels;
+        let frame = |i: usize| -> &[f64] {
+            if i == 0 { &self.last } else { &samples[(i - 1) * channels..i * channels] }
+        };
+
+        let mut out = Vec::with_capacity((frames as f64 / self.step) as usize * channels + channels);
+        while self.position < frames as f64 {
+            let i = self.position as usize;
+            let fraction = self.position - i as f64;
+            let (a, b) = (frame(i), frame(i + 1));
+            out.extend((0..channels).map(|c| a[c] + (b[c] - a[c]) * fraction));
+            self.position += self.step;
+        }
+        let last = frame(frames).to_vec();
+        self.position -= frames as f64;
+        self.last = last;
+        out
+    }
+}
+
+/// Minimal flac encoder writing verbatim subframes with a fixed block size
+pub struct FlacEncoder {
+    sample_rate: u32,
+    bits: u32,
+    frame_number: u32,
+    //interleaved samples waiting for a full block
+    pending: Vec<i32>,
+}
+
+impl FlacEncoder {
+    pub fn new(sample_rate: u32, bits: u32) -> Self {
+        Self { sample_rate, bits, frame_number: 0, pending: Vec::new() }
+    }
+
+    fn reset(&mut self) {
+        self.frame_number = 0;
+        self.pending.clear();
+    }
+
+    pub fn stream_header(&self) -> Vec<u8> {
+        let mut out = b"fLaC".to_vec();
+        //last metadata block, STREAMINFO with 34 bytes
+        out.extend_from_slice(&[0x80, 0x00, 0x00, 34]);
+        out.extend_from_slice(&(FLAC_BLOCK_SIZE as u16).to_be_bytes());
+        out.extend_from_slice(&(FLAC_BLOCK_SIZE as u16).to_be_bytes());
+        //unknown min/max frame size
+        out.extend_from_slice(&[0; 6]);
+        //20 bit rate, 3 bit channels-1, 5 bit bits-1, 36 bit total samples (unknown)
+        let packed: u64 = (self.sample_rate as u64) << 44
+            | ((NUM_CHANNELS as u64 - 1) << 41)
+            | ((self.bits as u64 - 1) << 36);
+        out.extend_from_slice(&packed.to_be_bytes());
+        //no md5 for a stream
+        out.extend_from_slice(&[0; 16]);
+        out
+    }
+
+    /// Takes interleaved samples of the configured bit depth, returns the completed frames
+    pub fn encode(&mut self, samples: &[i32]) -> Vec<u8> {
+        self.pending.extend_from_slice(samples);
+        let block = FLAC_BLOCK_SIZE * NUM_CHANNELS as usize;
+        let mut out = Vec::new();
+        while self.pending.len() >= block {
+            let rest = self.pending.split_off(block);
+            let full = std::mem::replace(&mut self.pending, rest);
+            out.extend(self.frame(&full));
+        }
+        out
+    }
+
+    /// Writes what is left as a shorter last frame
+    fn flush(&mut self) -> Vec<u8> {
+        let usable = self.pending.len() - self.pending.len() % NUM_CHANNELS as usize;
+        let rest: Vec<i32> = self.pending.drain(..).take(usable).collect();
+        if rest.is_empty() {
+            Vec::new()
+        } else {
+            self.frame(&rest)
+        }
+    }
+
+    fn frame(&mut self, samples: &[i32]) -> Vec<u8> {
+        let channels = NUM_CHANNELS as usize;
+        let block_size = samples.len() / channels;
+        let sample_size_code: u8 = if self.bits == 24 { 0b110 } else { 0b100 };
+
+        //sync code, fixed block size
+        let mut out = vec![0xFF, 0xF8];
+        //16 bit block size at the end of the header, rate from STREAMINFO
+        out.push(0b0111_0000);
+        //independent channels
+        out.push(((channels as u8 - 1) << 4) | (sample_size_code << 1));
+        out.extend(utf8_number(self.frame_number));
+        out.extend_from_slice(&((block_size - 1) as u16).to_be_bytes());
+        out.push(crc8(&out));
+
+        let bytes_per_sample = (self.bits / 8) as usize;
+        for channel in 0..channels {
+            //verbatim subframe without wasted bits
+            out.push(0b0000_0010);
+            for frame in samples.chunks_exact(channels) {
+                let be = frame[channel].to_be_bytes();
+                out.extend_from_slice(&be[4 - bytes_per_sample..]);
+            }
+        }
+        out.extend_from_slice(&crc16(&out).to_be_bytes());
+
+        self.frame_number = self.frame_number.wrapping_add(1) & 0x7FFF_FFFF;
+        out
+    }
+}
+
+//flac's variant of utf-8 for frame numbers up to 31 bits
+fn utf8_number(n: u32) -> Vec<u8> {
+    if n < 0x80 {
+        return vec![n as u8];
+    }
+    let len = match n {
+        0..=0x7FF => 2,
+        0x800..=0xFFFF => 3,
+        0x1_0000..=0x1F_FFFF => 4,
+        0x20_0000..=0x3FF_FFFF => 5,
+        _ => 6,
+    };
+    let mut out = vec![0u8; len];
+    let mut rest = n;
+    for byte in out.iter_mut().skip(1).rev() {
+        *byte = 0x80 | (rest & 0x3F) as u8;
+        rest >>= 6;
+    }
+    out[0] = (0xFF00u16 >> len) as u8 | rest as u8;
+    out
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |c, _| if c & 0x80 != 0 { (c << 1) ^ 0x07 } else { c << 1 })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |c, _| if c & 0x8000 != 0 { (c << 1) ^ 0x8005 } else { c << 1 })
+    })
+}
+
+#[derive(Serialize)]
+struct TrackMetadata {
+    track_id: i32,
+    title: String,
+    artists: Vec<String>,
+    album: String,
+    duration_ms: i64,
+}
+
+/// One json line per message; track is null once playback stopped
+#[derive(Serialize)]
+struct MetadataMessage {
+    track: Option<TrackMetadata>,
+}
+
+enum MetadataCommand {
+    Target(Option<String>),
+    Track(Option<SimpleTrack>),
+}
+
+/// Side channel announcing the playing track to readers of the stream,
+/// written to a path or tcp://host:port like the stream itself
+#[derive(Clone)]
+pub struct MetadataChannel {
+    cmd_tx: Sender<MetadataCommand>,
+}
+
+impl MetadataChannel {
+    pub fn new(target: Option<String>) -> Self {
+        let (cmd_tx, cmd_rx) = std::sync::mpsc::channel();
+        // opening a pipe blocks until there is a reader, so don't hold up playback
+        std::thread::Builder::new()
+            .name("stream-metadata".to_string())
+            .spawn(move || run_metadata(cmd_rx, target))
+            .expect("Failed to spawn stream metadata thread!");
+        Self { cmd_tx }
+    }
+
+    pub fn set_target(&self, target: Option<String>) {
+        let _ = self.cmd_tx.send(MetadataCommand::Target(target));
+    }
+
+    pub fn publish(&self, track: Option<SimpleTrack>) {
+        let _ = self.cmd_tx.send(MetadataCommand::Track(track));
+    }
+}
+
+fn run_metadata(cmd_rx: Receiver<MetadataCommand>, mut target: Option<String>) {
+    let mut writer: Option<Box<dyn Write + Send>> = None;
+    //sent again to every new reader
+    let mut last: Option<String> = None;
+
+    while let Ok(cmd) = cmd_rx.recv() {
+        match cmd {
+            MetadataCommand::Target(new_target) => {
+                if new_target == target {
+                    continue;
+                }
+                target = new_target;
+                writer = None;
+            }
+            MetadataCommand::Track(track) => {
+                let message = MetadataMessage { track: track.map(TrackMetadata::from) };
+                match serde_json::to_string(&message) {
+                    Ok(line) => last = Some(line),
+                    Err(e) => {
+                        log::warn!("Failed to serialize stream metadata: {}", e);
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let (target, line) = match (&target, &last) {
+            (Some(target), Some(line)) => (target, line),
+            _ => continue,
+        };
+        if writer.is_none() {
+            match open_target(target) {
+                Ok(w) => {
+                    log::info!("Connected stream metadata to {}", target);
+                    writer = Some(w);
+                }
+                Err(e) => {
+                    log::warn!("Failed to open stream metadata target {}: {}", target, e);
+                    continue;
+                }
+            }
+        }
+        if let Some(w) = writer.as_mut() {
+            if let Err(e) = writeln!(w, "{}", line).and_then(|_| w.flush()) {
+                //the reader went away; reconnect with the next message
+                log::warn!("Failed to write stream metadata to {}: {}", target, e);
+                writer = None;
+            }
+        }
+    }
+}
+
+impl From<SimpleTrack> for TrackMetadata {
+    fn from(track: SimpleTrack) -> Self {
+        TrackMetadata {
+            track_id: track.track_id,
+            title: track.title,
+            artists: track.artists.into_iter().map(|a| a.name).collect(),
+            album: track.album.name,
+            duration_ms: track.duration_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rodio::Source;
+
+    use super::*;
+
+    #[test]
+    fn crc_check_values() {
+        //CRC-8 with polynomial 0x07 and CRC-16/UMTS, both without reflection
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+        assert_eq!(crc8(&[]), 0);
+        assert_eq!(crc16(&[]), 0);
+    }
+
+    #[test]
+    fn utf8_number_boundaries() {
+        assert_eq!(utf8_number(0), vec![0x00]);
+        assert_eq!(utf8_number(0x7F), vec![0x7F]);
+        assert_eq!(utf8_number(0x80), vec![0xC2, 0x80]);
+        assert_eq!(utf8_number(0x7FF), vec![0xDF, 0xBF]);
+        assert_eq!(utf8_number(0x800), vec![0xE0, 0xA0, 0x80]);
+        assert_eq!(utf8_number(0x7FFF_FFFF), vec![0xFD, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]);
+    }
+
+    #[test]
+    fn stream_header_16_bit_stereo() {
+        let header = FlacEncoder::new(44100, 16).stream_header();
+        let mut expected = b"fLaC".to_vec();
+        expected.extend_from_slice(&[0x80, 0x00, 0x00, 0x22]);
+        expected.extend_from_slice(&[0x10, 0x00, 0x10, 0x00]);
+        expected.extend_from_slice(&[0x00; 6]);
+        expected.extend_from_slice(&[0x0A, 0xC4, 0x42, 0xF0, 0x00, 0x00, 0x00, 0x00]);
+        expected.extend_from_slice(&[0x00; 16]);
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn encoded_block_decodes() {
+        let samples: Vec<i32> = (0..FLAC_BLOCK_SIZE as i32 * 2)
+            .map(|i| if i % 2 == 0 { i * 7 % 65536 - 32768 } else { -(i * 13 % 32768) })
+            .collect();
+        let mut encoder = FlacEncoder::new(44100, 16);
+        //split so a block is only completed by the second call
+        let mut stream = encoder.stream_header();
+        let first = encoder.encode(&samples[..1000]);
+        assert!(first.is_empty());
+        stream.extend(encoder.encode(&samples[1000..]));
+        assert!(encoder.flush().is_empty());
+
+        let decoder = rodio::Decoder::new_flac(Cursor::new(stream)).expect("valid flac stream");
+        assert_eq!(decoder.channels(), 2);
+        assert_eq!(decoder.sample_rate(), 44100);
+        let decoded: Vec<i32> = decoder.map(i32::from).collect();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn flushed_frames_decode() {
+        let samples: Vec<i32> = (0..(FLAC_BLOCK_SIZE as i32 + 100) * 2).map(|i| i % 1000 - 500).collect();
+        let mut encoder = FlacEncoder::new(48000, 16);
+        let mut stream = encoder.stream_header();
+        stream.extend(encoder.encode(&samples));
+        stream.extend(encoder.flush());
+
+        let decoder = rodio::Decoder::new_flac(Cursor::new(stream)).expect("valid flac stream");
+        let decoded: Vec<i32> = decoder.map(i32::from).collect();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn resampler_passes_equal_rates_through() {
+        let mut resampler = Resampler::new(44100, 44100);
+        let samples = vec![0.1, -0.1, 0.2, -0.2];
+        assert_eq!(resampler.process(&samples), samples);
+    }
+
+    #[test]
+    fn resampler_is_continuous_across_chunks() {
+        //a ramp stays a ramp under linear interpolation, so every output
+        //frame has to sit exactly at its position in the input
+        let frames = 4411;
+        let ramp: Vec<f64> = (1..=frames).flat_map(|i| [i as f64, -(i as f64)]).collect();
+        let step = 44100.0 / 48000.0;
+
+        let mut resampler = Resampler::new(44100, 48000);
+        let mut out = Vec::new();
+        let mut rest = &ramp[..];
+        for chunk_frames in [1, 7, 300, 441, 1000].iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (chunk, tail) = rest.split_at((chunk_frames * 2).min(rest.len()));
+            out.extend(resampler.process(chunk));
+            rest = tail;
+        }
+
+        let expected_frames = (frames as f64 / step).ceil() as usize;
+        assert_eq!(out.len(), expected_frames * 2);
+        for (n, frame) in out.chunks_exact(2).enumerate() {
+            let position = n as f64 * step;
+            assert!((frame[0] - position).abs() < 1e-6, "frame {} is {} instead of {}", n, frame[0], position);
+            assert!((frame[1] + position).abs() < 1e-6, "frame {} is {} instead of {}", n, frame[1], -position);
+        }
+
+        let mut whole = Resampler::new(44100, 48000);
+        let at_once = whole.process(&ramp);
+        assert_eq!(at_once.len(), out.len());
+        assert!(at_once.iter().zip(&out).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use itertools::Itertools;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::sync::Notify;
+use url::Url;
+
+use crate::db_new::models::{NewScrobble, Scrobble};
+use crate::db_new::scrobble::ScrobbleDb;
+use crate::db_new::DbApi;
+use crate::model::library_models::SimpleTrack;
+
+type Result<T> = std::result::Result<T, ScrobblerError>;
+
+const DEFAULT_LISTENBRAINZ_URL: &str = "https://api.listenbrainz.org";
+//a listen counts after half of the track or four minutes, whichever comes first
+const LISTEN_THRESHOLD_MS: i64 = 4 * 60 * 1000;
+const SUBMIT_BATCH_SIZE: i64 = 50;
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Error, Debug)]
+pub enum ScrobblerError {
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("database error: {0}")]
+    Database(#[from] crate::db_new::DbError),
+
+    #[error("Reqwest error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("endpoint rejected submission with {0}: {1}")]
+    Rejected(reqwest::StatusCode, String),
+}
+
+/// Submits listens to a ListenBrainz compatible endpoint; listens are queued in
+/// the db first so they survive outages and restarts
+#[derive(Clone)]
+pub struct Scrobbler {
+    db: DbApi,
+    client: reqwest::Client,
+    submit_url: Url,
+    token: String,
+    pending: Arc<Notify>,
+}
+
+impl Scrobbler {
+    /// Returns None unless a LISTENBRAINZ_TOKEN is configured
+    pub fn from_env(db: DbApi) -> Result<Option<Self>> {
+        let token = match dotenv::var("LISTENBRAINZ_TOKEN") {
+            Ok(token) if !token.is_empty() => token,
+            _ => return Ok(None)
+        };
+        let base_url = match dotenv::var("LISTENBRAINZ_URL") {
+            Ok(url) => url,
+            Err(_) => DEFAULT_LISTENBRAINZ_URL.to_string()
+        };
+        let submit_url = Url::parse(&base_url)
+            .and_then(|url| url.join("1/submit-listens"))
+            .map_err(|e| ScrobblerError::Config(format!("LISTENBRAINZ_URL {}: {}", base_url, e)))?;
+
+        Ok(Some(Self {
+            db,
+            client: reqwest::Client::new(),
+            submit_url,
+            token,
+            pending: Arc::new(Notify::new()),
+        }))
+    }
+
+    /// Spawns the worker submitting queued listens, including those left over from earlier runs
+    pub fn init(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let retry = match this.submit_pending().await {
+                    Ok(_) => false,
+                    Err(e) => {
+                        log::warn!("Failed to submit listens: {}; retrying later", e);
+                        true
+                    }
+                };
+
+                if retry {
+                    let _ = tokio::time::timeout(RETRY_INTERVAL, this.pending.notified()).await;
+                } else {
+                    this.pending.notified().await;
+                }
+            }
+        });
+    }
+
+    pub fn is_listen(duration_ms: i64, played_ms: i64) -> bool {
+        played_ms > 0 && played_ms >= std::cmp::min(duration_ms / 2, LISTEN_THRESHOLD_MS)
+    }
+
+    /// Announces the track as playing now; not retried since it is outdated soon anyway
+    pub fn now_playing(&self, track: &SimpleTrack) {
+        let payload = json!({
+            "listen_type": "playing_now",
+            "payload": [{ "track_metadata": track_metadata(&artist_name(track), &track.title, Some(&track.album.name), track.duration_ms) }]
+        });
+        let this = self.clone();
+        let track_id = track.track_id;
+        tokio::spawn(async move {
+            if let Err(e) = this.submit(&payload).await {
+                log::warn!("Failed to send now playing for track {}: {}", track_id, e);
+            }
+        });
+    }
+
+    pub fn queue_listen(&self, track: &SimpleTrack, listened_at: NaiveDateTime) -> Result<()> {
+        self.db.new_scrobble(NewScrobble {
+            artist_name: artist_name(track),
+            track_name: track.title.clone(),
+            release_name: Some(track.album.name.clone()),
+            duration_ms: track.duration_ms,
+            listened_at,
+        })?;
+        self.pending.notify_one();
+        Ok(())
+    }
+
+    async fn submit_pending(&self) -> Result<()> {
+        loop {
+            let scrobbles = self.db.load_pending_scrobbles(SUBMIT_BATCH_SIZE)?;
+            if scrobbles.is_empty() {
+                return Ok(());
+            }
+
+            let ids = scrobbles.iter().map(|s| s.scrobble_id).collect_vec();
+            match self.submit(&listens_payload(&scrobbles)).await {
+                Ok(_) => self.db.remove_scrobbles(&ids)?,
+                Err(ScrobblerError::Rejected(status, message)) if status == reqwest::StatusCode::BAD_REQUEST => {
+                    //resubmitting the same malformed listens would block the queue forever
+                    log::error!("Dropping {} listens rejected as invalid: {}", ids.len(), message);
+                    self.db.remove_scrobbles(&ids)?;
+                }
+                Err(e) => {
+                    self.db.mark_scrobbles_failed(&ids, &e.to_string())?;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    async fn submit(&self, payload: &Value) -> Result<()> {
+        let response = self.client.post(self.submit_url.clone())
+            .header(reqwest::header::AUTHORIZATION, format!("Token {}", self.token))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string())
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(ScrobblerError::Rejected(status, response.text().await.unwrap_or_default()))
+        }
+    }
+}
+
+fn artist_name(track: &SimpleTrack) -> String {
+    track.artists.iter().map(|a| a.name.as_str()).join(", ")
+}
+
+fn track_metadata(artist_name: &str, track_name: &str, release_name: Option<&str>, duration_ms: i64) -> Value {
+    let mut metadata = json!({
+        "artist_name": artist_name,
+        "track_name": track_name,
+        "additional_info": {
+            "duration_ms": duration_ms,
+            "submission_client": "soundbase"
+        }
+    });
+    if let Some(release_name) = release_name {
+        metadata["release_name"] = json!(release_name);
+    }
+    metadata
+}
+
+fn listens_payload(scrobbles: &[Scrobble]) -> Value {
+    let listens = scrobbles.iter().map(|s| json!({
+        "listened_at": s.listened_at.and_utc().timestamp(),
+        "track_metadata": track_metadata(&s.artist_name, &s.track_name, s.release_name.as_deref(), s.duration_ms)
+    })).collect_vec();
+    json!({
+        //the api expects single listens to be flagged as such
+        "listen_type": if listens.len() == 1 { "single" } else { "import" },
+        "payload": listens
+    })
+}
@@ -6,39 +6,56 @@ use super::definition::{
     LibraryEntityRequest,
     ListEntitiesRequest,
     FavStateRequest,
+    PreferredSourceRequest,
+    ArtworkRequest,
+    ArtworkResponse,
+    ListGenresRequest,
+    GenreEntitiesRequest,
+    ArtistTracksRequest,
+    ChangesSinceRequest,
+    ListeningHistoryRequest,
+    Genre,
     LibraryEntityResponse,
     SimpleLibraryEntityResponse,
     Blank,
 };
+use crate::artwork::ArtworkStore;
 use crate::db_new;
 use crate::db_new::album::AlbumDb;
 use crate::db_new::artist::ArtistDb;
+use crate::db_new::artist_genre::ArtistGenreDb;
+use crate::db_new::genre::GenreDb;
 use crate::db_new::{DbApi, SetFavedState};
 use crate::db_new::album_artist::AlbumArtistsDb;
-use crate::db_new::models::{Album, Artist, Track};
+use crate::db_new::album_of_week::AlbumOfWeekDb;
+use crate::db_new::charts_of_week::ChartsOfWeekDb;
+use crate::db_new::library_event::LibraryEventDb;
+use crate::db_new::models::{Album, Artist, LibraryEvent, Play, Track};
+use crate::db_new::play::{PlayDb, PlayStats};
 use crate::db_new::track::TrackDb;
 use crate::db_new::track_artist::TrackArtistsDb;
-use crate::model::RequestPage;
+use crate::model::{AlbumType, LibraryEntity, LibraryEventKind, ListOrder, PlaybackSource, RequestPage};
+
+//faved tracks listed with an artist, the rest is paged through by ListArtistTracks
+const FULL_ARTIST_FAVED_TRACKS: i64 = 50;
 
 pub struct LibraryService {
     pub(crate) db: DbApi,
+    pub(crate) artwork: ArtworkStore,
 }
 
 #[tonic::async_trait]
 impl Library for LibraryService {
     async fn get(&self, request: Request<LibraryEntityRequest>) -> Result<Response<LibraryEntityResponse>, Status> {
         use super::definition::library_entity_response::LibraryEntities;
-        use super::definition::{FullArtist, FullTrack};
+        use super::definition::FullTrack;
         let id: i32 = request.get_ref().id;
         match request.get_ref().entity() {
             super::definition::LibraryEntities::Artist => {
-                let api: &dyn ArtistDb = &self.db;
-                match api.find_by_id(id)? {
+                match load_full_artist(&self.db, id)? {
                     Some(artist) => {
-                        let api : &dyn AlbumArtistsDb = &self.db;
-                        let albums = api.load_albums_for_artist(&artist)?;
                         Ok(Response::new(LibraryEntityResponse {
-                            library_entities: Some(LibraryEntities::Artist(FullArtist::from_db(&artist, &albums)))
+                            library_entities: Some(LibraryEntities::Artist(artist))
                         }))
                     },
                     None => Err(Status::not_found("Artist not found!"))
@@ -62,9 +79,12 @@ impl Library for LibraryService {
                         let album = api.load_album_for_track(&track)?;
                         let api : &dyn TrackArtistsDb = &self.db;
                         let artists = api.load_artists_for_track(&track)?;
+                        let stats = self.db.load_play_stats_for_tracks(&[track.track_id])?
+                            .remove(&track.track_id)
+                            .unwrap_or_default();
 
                         Ok(Response::new(LibraryEntityResponse {
-                            library_entities: Some(LibraryEntities::Track(FullTrack::from_db(&track, &album, &artists)))
+                            library_entities: Some(LibraryEntities::Track(FullTrack::from_db(&track, &album, &artists, stats)))
                         }))
                     },
                     None => Err(Status::not_found("Track not found!"))
@@ -80,10 +100,11 @@ impl Library for LibraryService {
         use super::definition::simple_library_entity_response::LibraryEntities;
         let offset = request.get_ref().offset;
         let limit = request.get_ref().limit;
+        let order = ListOrder::from(request.get_ref().order());
         let entities: Vec<SimpleLibraryEntityResponse> = match request.get_ref().entity() {
             super::definition::LibraryEntities::Artist => {
                 let api: &dyn ArtistDb = &self.db;
-                match api.load_artists(&RequestPage::new(offset as i64, limit as i64)) {
+                match api.load_artists(&RequestPage::new(offset as i64, limit as i64), order) {
                     Ok(artists) => {
                         artists.iter()
                             .map(|artist| SimpleLibraryEntityResponse {
@@ -96,7 +117,7 @@ impl Library for LibraryService {
             }
             super::definition::LibraryEntities::Album => {
                 let api: &dyn AlbumDb = &self.db;
-                match api.load_albums(&RequestPage::new(offset as i64, limit as i64)) {
+                match api.load_albums(&RequestPage::new(offset as i64, limit as i64), order) {
                     Ok(albums) => {
                         albums.iter()
                             .map(|albums| SimpleLibraryEntityResponse {
@@ -108,7 +129,7 @@ impl Library for LibraryService {
                 }
             }
             super::definition::LibraryEntities::Track => {
-                load_track_list(&self.db, &RequestPage::new(offset as i64, limit as i64))?
+                load_track_list(&self.db, &RequestPage::new(offset as i64, limit as i64), order)?
                     .iter()
                     .map(|track| SimpleLibraryEntityResponse {
                         library_entities: Some(LibraryEntities::Track(track.clone()))
@@ -152,6 +173,236 @@ impl Library for LibraryService {
             Err(e) => Err(Status::internal(e.to_string()))
         }
     }
+
+    async fn set_preferred_source(&self, request: Request<PreferredSourceRequest>) -> Result<Response<Blank>, Status> {
+        use super::definition::PlaybackSources;
+        let track_id = request.get_ref().track_id;
+        let source = match request.get_ref().source() {
+            PlaybackSources::Unspecified => None,
+            PlaybackSources::Local => Some(PlaybackSource::Local),
+            PlaybackSources::Spotify => Some(PlaybackSource::Spotify)
+        };
+
+        let api: &dyn TrackDb = &self.db;
+        api.set_preferred_source(track_id, source)?;
+        Ok(Response::new(super::definition::Blank {}))
+    }
+
+    async fn get_artwork(&self, request: Request<ArtworkRequest>) -> Result<Response<ArtworkResponse>, Status> {
+        let album_id = request.get_ref().album_id;
+        let size = request.get_ref().size;
+        match self.artwork.get_artwork(album_id, size).await {
+            Ok(Some(artwork)) => Ok(Response::new(ArtworkResponse {
+                data: artwork.data,
+                content_type: artwork.content_type
+            })),
+            Ok(None) => Err(Status::not_found("Artwork not found!")),
+            Err(e) => Err(Status::internal(e.to_string()))
+        }
+    }
+
+    type ListGenresStream = ReceiverStream<Result<Genre, Status>>;
+
+    async fn list_genres(&self, request: Request<ListGenresRequest>) -> Result<Response<Self::ListGenresStream>, Status> {
+        let offset = request.get_ref().offset;
+        let limit = request.get_ref().limit;
+        let api: &dyn GenreDb = &self.db;
+        let genres = api.load_genres(&RequestPage::new(offset as i64, limit as i64))?
+            .iter()
+            .map(Genre::from)
+            .collect_vec();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(async move {
+            for genre in genres {
+                tx.send(Ok(genre)).await.unwrap();
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ListArtistTracksStream = ReceiverStream<Result<super::definition::SimpleTrack, Status>>;
+
+    async fn list_artist_tracks(&self, request: Request<ArtistTracksRequest>) -> Result<Response<Self::ListArtistTracksStream>, Status> {
+        let page = RequestPage::new(request.get_ref().offset as i64, request.get_ref().limit as i64);
+        let api: &dyn ArtistDb = &self.db;
+        let artist = match api.find_by_id(request.get_ref().artist_id)? {
+            Some(artist) => artist,
+            None => return Err(Status::not_found("Artist not found!"))
+        };
+
+        let tracks = if request.get_ref().only_faved {
+            self.db.load_fav_tracks_for_artist(&artist, &page)?
+        } else {
+            self.db.load_track_for_artist(&artist, page)?
+        };
+        let tracks = map_track_list(&self.db, tracks)?;
+
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(async move {
+            for track in tracks {
+                tx.send(Ok(track)).await.unwrap();
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ListByGenreStream = ReceiverStream<Result<SimpleLibraryEntityResponse, Status>>;
+
+    async fn list_by_genre(&self, request: Request<GenreEntitiesRequest>) -> Result<Response<Self::ListByGenreStream>, Status> {
+        use super::definition::simple_library_entity_response::LibraryEntities;
+        let page = RequestPage::new(request.get_ref().offset as i64, request.get_ref().limit as i64);
+        let api: &dyn GenreDb = &self.db;
+        let genre = match api.find_by_id(request.get_ref().genre_id)? {
+            Some(genre) => genre,
+            None => return Err(Status::not_found("Genre not found!"))
+        };
+
+        let api: &dyn ArtistGenreDb = &self.db;
+        let entities: Vec<SimpleLibraryEntityResponse> = match request.get_ref().entity() {
+            super::definition::LibraryEntities::Artist => {
+                api.load_artists_for_genre(&genre, page.offset(), page.limit())?
+                    .iter()
+                    .map(|artist| SimpleLibraryEntityResponse {
+                        library_entities: Some(LibraryEntities::Artist(artist.into()))
+                    }).collect_vec()
+            }
+            super::definition::LibraryEntities::Album => {
+                api.load_albums_for_genre(&genre, &page)?
+                    .iter()
+                    .map(|album| SimpleLibraryEntityResponse {
+                        library_entities: Some(LibraryEntities::Album(album.into()))
+                    }).collect_vec()
+            }
+            super::definition::LibraryEntities::Track => {
+                let tracks = api.load_tracks_for_genre(&genre, &page)?;
+                map_track_list(&self.db, tracks)?
+                    .into_iter()
+                    .map(|track| SimpleLibraryEntityResponse {
+                        library_entities: Some(LibraryEntities::Track(track))
+                    }).collect_vec()
+            }
+            _ => return Err(Status::invalid_argument("Entity not supported!"))
+        };
+
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(async move {
+            for entity in entities {
+                tx.send(Ok(entity)).await.unwrap();
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ChangesSinceStream = ReceiverStream<Result<super::definition::LibraryEvent, Status>>;
+
+    async fn changes_since(&self, request: Request<ChangesSinceRequest>) -> Result<Response<Self::ChangesSinceStream>, Status> {
+        let since = match chrono::DateTime::from_timestamp_millis(request.get_ref().since) {
+            Some(since) => since.naive_utc(),
+            None => return Err(Status::invalid_argument("Timestamp out of range!"))
+        };
+
+        let db = self.db.clone();
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(async move {
+            //page through the log, a full sync may span the whole library
+            let mut page = RequestPage::new(0, 50);
+            loop {
+                let api: &dyn LibraryEventDb = &db;
+                let events = match api.load_library_events_since(since, &page) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        let _ = tx.send(Err(Status::from(e))).await;
+                        return;
+                    }
+                };
+
+                for event in &events {
+                    if tx.send(Ok(event.into())).await.is_err() {
+                        return;
+                    }
+                }
+
+                if (events.len() as i64) < page.limit() {
+                    break;
+                }
+                page = page.next();
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ListeningHistoryStream = ReceiverStream<Result<super::definition::Play, Status>>;
+
+    async fn listening_history(&self, request: Request<ListeningHistoryRequest>) -> Result<Response<Self::ListeningHistoryStream>, Status> {
+        let request = request.get_ref();
+        let page = RequestPage::new(request.offset as i64, request.limit as i64);
+
+        let plays = self.db.load_plays(&page)?;
+        let api: &dyn TrackDb = &self.db;
+        let tracks = api.find_by_ids(plays.iter().map(|p| p.track_id).unique().collect_vec())?;
+        let tracks = map_track_list(&self.db, tracks)?;
+        let entries = plays.iter().map(|play| {
+            let track = tracks.iter().find(|t| t.track_id == play.track_id).cloned();
+            super::definition::Play::from_db(play, track)
+        }).collect_vec();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(async move {
+            for entry in entries {
+                tx.send(Ok(entry)).await.unwrap();
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+fn load_full_artist(db : &DbApi, artist_id : i32) -> Result<Option<super::definition::FullArtist>, db_new::DbError> {
+    use super::definition::{AlbumOfWeekAppearance, ChartAppearance, SimpleAlbum};
+    let api : &dyn ArtistDb = db;
+    let artist = match api.find_by_id(artist_id)? {
+        Some(artist) => artist,
+        None => return Ok(None)
+    };
+
+    let albums = db.load_albums_for_artist(&artist)?;
+    let appears_on = db.load_appears_on_albums_for_artist(&artist)?;
+    let genres = db.load_genres_for_artist(&artist)?;
+    let faved_tracks = map_track_list(db, db.load_fav_tracks_for_artist(&artist, &RequestPage::new(0, FULL_ARTIST_FAVED_TRACKS))?)?;
+    let related_artists = api.load_related_artists(&artist, 20)?;
+
+    //1. chart appearances need their tracks
+    let chart_entries = db.load_chart_entries_for_artist(&artist)?;
+    let api : &dyn TrackDb = db;
+    let chart_tracks = api.find_by_ids(chart_entries.iter().map(|e| e.track_id).unique().collect_vec())?;
+    let chart_tracks = map_track_list(db, chart_tracks)?;
+    let chart_appearances = chart_entries.iter().map(|entry| ChartAppearance {
+        track: chart_tracks.iter().find(|t| t.track_id == entry.track_id).cloned(),
+        source_name: entry.source_name.clone(),
+        year: entry.year,
+        calendar_week: entry.calendar_week,
+        chart_position: entry.chart_position
+    }).collect_vec();
+
+    //2. album of the week history; all albums are part of the artist discography
+    let albums_of_week = db.load_albums_of_week_for_artist(&artist)?.iter().map(|aow| AlbumOfWeekAppearance {
+        album: albums.iter().find(|a| a.album_id == aow.album_id).map(SimpleAlbum::from),
+        source_name: aow.source_name.clone(),
+        year: aow.year,
+        week: aow.week
+    }).collect_vec();
+
+    let mut full_artist = super::definition::FullArtist::from_db(&artist, &albums, &appears_on, &genres);
+    full_artist.faved_tracks = faved_tracks;
+    full_artist.chart_appearances = chart_appearances;
+    full_artist.albums_of_week = albums_of_week;
+    full_artist.related_artists = related_artists.iter().map(super::definition::SimpleArtist::from).collect_vec();
+    Ok(Some(full_artist))
 }
 
 fn load_full_album(db : &DbApi, album_id : i32) -> Result<Option<super::definition::FullAlbum>, db_new::DbError> {
@@ -161,6 +412,7 @@ fn load_full_album(db : &DbApi, album_id : i32) -> Result<Option<super::definiti
             let album_artists = db.load_artists_for_album(&album)?;
             let tracks = db.load_tracks_for_album(&album)?;
             let track_artist_map = db.load_artist_ids_for_tracks(&tracks)?;
+            let play_stats = db.load_play_stats_for_tracks(&tracks.iter().map(|t| t.track_id).collect_vec())?;
 
             //1. collect missing artist ids
             let missing_artists = track_artist_map.values().flatten().unique().filter(|artist_id|{
@@ -194,7 +446,8 @@ fn load_full_album(db : &DbApi, album_id : i32) -> Result<Option<super::definiti
                         .map(super::definition::SimpleArtist::from)
                         .collect_vec();
 
-                    super::definition::SimpleTrack::from_db(track, album, artists)
+                    let stats = play_stats.get(&track.track_id).copied().unwrap_or_default();
+                    super::definition::SimpleTrack::from_db(track, album, artists, stats)
                 }).collect_vec();
 
             Ok(Some(super::definition::FullAlbum::from_db(&album, simple_album_artists, simple_album_tracks)))
@@ -203,9 +456,12 @@ fn load_full_album(db : &DbApi, album_id : i32) -> Result<Option<super::definiti
     }
 }
 
-fn load_track_list(db : &DbApi, page : &RequestPage) -> Result<Vec<super::definition::SimpleTrack>, db_new::DbError> {
-    let tracks = db.load_tracks(page)?;
+fn load_track_list(db : &DbApi, page : &RequestPage, order : ListOrder) -> Result<Vec<super::definition::SimpleTrack>, db_new::DbError> {
+    let tracks = db.load_tracks(page, order)?;
+    map_track_list(db, tracks)
+}
 
+pub(crate) fn map_track_list(db : &DbApi, tracks : Vec<Track>) -> Result<Vec<super::definition::SimpleTrack>, db_new::DbError> {
     let albums = db.load_albums_for_tracks(&tracks)?;
 
     let track_artist_ids = db.load_artist_ids_for_tracks(&tracks)?;
@@ -213,6 +469,7 @@ fn load_track_list(db : &DbApi, page : &RequestPage) -> Result<Vec<super::defini
     let api : &dyn ArtistDb = db;
     let ids_to_load = track_artist_ids.values().flatten().unique().cloned().collect_vec();
     let artists = api.find_by_ids(ids_to_load)?;
+    let play_stats = db.load_play_stats_for_tracks(&tracks.iter().map(|t| t.track_id).collect_vec())?;
 
     let simple_tracks = tracks.iter().map(|track| {
         let album = albums.iter().find(|a| a.album_id == track.album_id).unwrap();
@@ -222,23 +479,44 @@ fn load_track_list(db : &DbApi, page : &RequestPage) -> Result<Vec<super::defini
             .map(super::definition::SimpleArtist::from)
             .collect_vec();
 
-        super::definition::SimpleTrack::from_db(&track, super::definition::SimpleAlbum::from(album), artists)
+        let stats = play_stats.get(&track.track_id).copied().unwrap_or_default();
+        super::definition::SimpleTrack::from_db(track, super::definition::SimpleAlbum::from(album), artists, stats)
     }).collect_vec();
 
     Ok(simple_tracks)
 }
 
 impl super::definition::FullArtist {
-    fn from_db(db_artist: &Artist, db_albums : &[Album]) -> Self {
-        let simple_albums = db_albums
-            .iter()
-            .map(super::definition::SimpleAlbum::from)
+    fn from_db(db_artist: &Artist, db_albums : &[Album], db_appears_on : &[Album], db_genres : &[db_new::models::Genre]) -> Self {
+        let album_groups = AlbumType::ALL.iter()
+            .map(|&album_type| {
+                let mut albums = db_albums.iter()
+                    .filter(|album| AlbumType::from(album.album_type) == album_type)
+                    .collect_vec();
+                //own albums typed as appears on stay next to the ones of other artists
+                if album_type == AlbumType::AppearsOn {
+                    albums.extend(db_appears_on.iter());
+                    albums = albums.into_iter().unique_by(|album| album.album_id).collect_vec();
+                }
+                super::definition::AlbumGroup {
+                    album_type: super::definition::AlbumTypes::from(album_type).into(),
+                    albums: albums.into_iter().map(super::definition::SimpleAlbum::from).collect_vec()
+                }
+            })
+            .filter(|group| !group.albums.is_empty())
             .collect_vec();
         Self {
             artist_id: db_artist.artist_id,
             name: db_artist.name.clone(),
             is_faved: db_artist.is_faved,
-            albums: simple_albums
+            genres: db_genres.iter().map(Genre::from).collect_vec(),
+            albums: album_groups,
+            faved_tracks: vec![],
+            chart_appearances: vec![],
+            albums_of_week: vec![],
+            related_artists: vec![],
+            added_at: to_millis(&db_artist.created_at),
+            faved_at: db_artist.faved_at.as_ref().map(to_millis)
         }
     }
 }
@@ -251,17 +529,19 @@ impl super::definition::FullAlbum {
             name: db_album.name.clone(),
             artists: album_artists,
             tracks: album_tracks,
-            album_type: super::definition::AlbumTypes::Album.into(), //FIXME
+            album_type: super::definition::AlbumTypes::from(AlbumType::from(db_album.album_type)).into(),
             year: db_album.year,
             track_count: db_album.total_tracks,
             is_faved: db_album.is_faved,
             was_album_of_week: db_album.was_aow,
+            added_at: to_millis(&db_album.created_at),
+            faved_at: db_album.faved_at.as_ref().map(to_millis)
         }
     }
 }
 
 impl super::definition::FullTrack {
-    fn from_db(db_track: &Track, db_album : &Album, db_artists : &[Artist]) -> Self {
+    fn from_db(db_track: &Track, db_album : &Album, db_artists : &[Artist], stats : PlayStats) -> Self {
         let simple_artists = db_artists
             .iter()
             .map(super::definition::SimpleArtist::from)
@@ -275,6 +555,15 @@ impl super::definition::FullTrack {
             disc_number: db_track.disc_number,
             duration_ms: db_track.duration_ms,
             is_faved: db_track.is_faved,
+            preferred_source: match PlaybackSource::from_db(db_track.preferred_source) {
+                Some(PlaybackSource::Local) => super::definition::PlaybackSources::Local.into(),
+                Some(PlaybackSource::Spotify) => super::definition::PlaybackSources::Spotify.into(),
+                None => super::definition::PlaybackSources::Unspecified.into()
+            },
+            added_at: to_millis(&db_track.created_at),
+            faved_at: db_track.faved_at.as_ref().map(to_millis),
+            play_count: stats.play_count,
+            last_played_at: stats.last_played.as_ref().map(to_millis)
         }
     }
 }
@@ -288,6 +577,79 @@ impl From<&db_new::models::Artist> for super::definition::SimpleArtist {
     }
 }
 
+impl From<&LibraryEvent> for super::definition::LibraryEvent {
+    fn from(event: &LibraryEvent) -> Self {
+        Self {
+            event_id: event.event_id,
+            entity: super::definition::LibraryEntities::from(LibraryEntity::from(event.entity)).into(),
+            entity_id: event.entity_id,
+            kind: super::definition::LibraryEventKinds::from(LibraryEventKind::from(event.kind)).into(),
+            source: event.source.clone(),
+            timestamp: to_millis(&event.created_at)
+        }
+    }
+}
+
+impl From<LibraryEntity> for super::definition::LibraryEntities {
+    fn from(entity: LibraryEntity) -> Self {
+        match entity {
+            LibraryEntity::Unknown => super::definition::LibraryEntities::Unspecified,
+            LibraryEntity::Artist => super::definition::LibraryEntities::Artist,
+            LibraryEntity::Album => super::definition::LibraryEntities::Album,
+            LibraryEntity::Track => super::definition::LibraryEntities::Track,
+        }
+    }
+}
+
+impl From<LibraryEventKind> for super::definition::LibraryEventKinds {
+    fn from(kind: LibraryEventKind) -> Self {
+        match kind {
+            LibraryEventKind::Unknown => super::definition::LibraryEventKinds::Unspecified,
+            LibraryEventKind::Add => super::definition::LibraryEventKinds::Add,
+            LibraryEventKind::Remove => super::definition::LibraryEventKinds::Remove,
+            LibraryEventKind::Fav => super::definition::LibraryEventKinds::Fav,
+            LibraryEventKind::Unfav => super::definition::LibraryEventKinds::Unfav,
+        }
+    }
+}
+
+impl From<super::definition::ListOrders> for ListOrder {
+    fn from(order: super::definition::ListOrders) -> Self {
+        match order {
+            super::definition::ListOrders::Unspecified => ListOrder::Default,
+            super::definition::ListOrders::RecentlyAdded => ListOrder::RecentlyAdded,
+            super::definition::ListOrders::RecentlyFaved => ListOrder::RecentlyFaved,
+        }
+    }
+}
+
+pub(crate) fn to_millis(timestamp: &chrono::NaiveDateTime) -> i64 {
+    timestamp.and_utc().timestamp_millis()
+}
+
+impl From<AlbumType> for super::definition::AlbumTypes {
+    fn from(album_type: AlbumType) -> Self {
+        match album_type {
+            AlbumType::Unknown => super::definition::AlbumTypes::Unspecified,
+            AlbumType::Single => super::definition::AlbumTypes::Single,
+            AlbumType::Album => super::definition::AlbumTypes::Album,
+            AlbumType::Compilation => super::definition::AlbumTypes::Compilation,
+            AlbumType::AppearsOn => super::definition::AlbumTypes::AppearsOn,
+            AlbumType::EP => super::definition::AlbumTypes::Ep,
+            AlbumType::Live => super::definition::AlbumTypes::Live,
+        }
+    }
+}
+
+impl From<&db_new::models::Genre> for Genre {
+    fn from(db_genre: &db_new::models::Genre) -> Self {
+        Self {
+            genre_id: db_genre.genre_id,
+            name: db_genre.name.clone(),
+        }
+    }
+}
+
 impl From<&db_new::models::Album> for super::definition::SimpleAlbum {
     fn from(db_album: &db_new::models::Album) -> Self {
         Self {
@@ -298,14 +660,33 @@ impl From<&db_new::models::Album> for super::definition::SimpleAlbum {
 }
 
 impl super::definition::SimpleTrack {
-    fn from_db(db_track: &db_new::models::Track, album : super::definition::SimpleAlbum, artists : Vec<super::definition::SimpleArtist>) -> Self {
+    fn from_db(db_track: &db_new::models::Track, album : super::definition::SimpleAlbum, artists : Vec<super::definition::SimpleArtist>, stats : PlayStats) -> Self {
         Self {
             track_id: db_track.track_id,
             title: db_track.title.clone(),
             album : Some(album),
             artists,
             is_faved : db_track.is_faved,
-            duration_ms : db_track.duration_ms
+            duration_ms : db_track.duration_ms,
+            play_count : stats.play_count,
+            last_played_at : stats.last_played.as_ref().map(to_millis)
+        }
+    }
+}
+
+impl super::definition::Play {
+    fn from_db(db_play: &Play, track : Option<super::definition::SimpleTrack>) -> Self {
+        Self {
+            play_id: db_play.play_id,
+            track,
+            started_at: to_millis(&db_play.started_at),
+            played_ms: db_play.played_ms,
+            completed: db_play.completed,
+            source: match PlaybackSource::from_db(Some(db_play.source)) {
+                Some(PlaybackSource::Local) => super::definition::PlaybackSources::Local.into(),
+                Some(PlaybackSource::Spotify) => super::definition::PlaybackSources::Spotify.into(),
+                None => super::definition::PlaybackSources::Unspecified.into()
+            }
         }
     }
 }
@@ -1,9 +1,26 @@
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
 pub mod library;
 pub mod tasks;
 pub mod spotify_auth;
 pub mod proposals;
 pub mod playback;
+pub mod stats;
+pub mod smart_playlists;
 
 pub mod definition {
     tonic::include_proto!("soundbase");
-}
\ No newline at end of file
+}
+/// Streams the already loaded entries, stops once the client went away
+pub(crate) fn stream_entries<T: Send + 'static>(entries: Vec<T>) -> ReceiverStream<Result<T, Status>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    tokio::spawn(async move {
+        for entry in entries {
+            if tx.send(Ok(entry)).await.is_err() {
+                break;
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
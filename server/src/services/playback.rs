@@ -1,29 +1,59 @@
 use std::sync::Arc;
+use std::time::Duration;
 use itertools::Itertools;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio::sync::RwLock;
 use tonic::{Request, Response, Status};
 
-use crate::playback::{LoopingStates, PlaybackController, PlaybackState, PlaybackTrack};
+use crate::db_new::models::SmartPlaylist;
+use crate::db_new::smart_playlist::SmartPlaylistDb;
+use crate::db_new::track::TrackDb;
+use crate::db_new::{DbApi, FindById};
+use crate::model::RequestPage;
+use crate::playback::output::{self, AudioOutputConfig};
+use crate::playback::stream::StreamEncoding;
+use crate::playback::{LoopingStates, PlaybackController, PlaybackQueue, PlaybackState, PlaybackTrack, QueueChange, QueuePosition};
 
+use super::definition::enqueue_tracks_request::Source;
+use super::definition::queue_update::Change;
 use super::definition::{
+    AudioBackend,
+    AudioOutput,
+    AudioOutputsResponse,
+    EnqueueTracksRequest,
+    EnqueueTracksResponse,
     GetQueueRequest,
+    MoveQueueItemRequest,
+    MuteRequest,
     PlaybackBlank,
     PlaybackTrackRequest,
     PlaybackLoopStates,
     PlaybackSeekRequest,
+    PlaybackSetAutoplayRequest,
     PlaybackSetLoopingRequest,
     PlaybackSetShuffleRequest,
     PlaybackStateResponse,
+    QueueChangedResponse,
+    QueueCleared,
+    QueueInsertPositions,
+    QueueItem,
+    QueueItemMoved,
+    QueueItemsInserted,
+    QueueItemsRemoved,
+    QueueUpdate,
     RemoveFromQueueRequest,
+    SetCrossfadeRequest,
+    SetVolumeRequest,
     SimpleTrack,
     SimpleAlbum,
     SimpleArtist,
-    ToQueueRequest
+    ToQueueRequest,
+    VolumeResponse
 };
 use super::definition::playback_controls_server::PlaybackControls;
 
 pub struct PlaybackControlsService {
+    pub(crate) db : DbApi,
     pub(crate) playback : Arc<RwLock<PlaybackController>>
 }
 
@@ -32,11 +62,13 @@ impl PlaybackControls for PlaybackControlsService {
     ///
     /// Queue Control functions
     ///
-    type GetQueueStream = ReceiverStream<Result<SimpleTrack, Status>>;
-    async fn get_queue(&self, _request: Request<GetQueueRequest>) -> Result<Response<Self::GetQueueStream>, Status> {
-        //TODO use offset and limit
-        let tracks = self.playback.read().await.queue().tracks().await.iter()
-            .map(|track| SimpleTrack::from(track))
+    type GetQueueStream = ReceiverStream<Result<QueueItem, Status>>;
+    async fn get_queue(&self, request: Request<GetQueueRequest>) -> Result<Response<Self::GetQueueStream>, Status> {
+        let page = RequestPage::new(request.get_ref().offset as i64, request.get_ref().limit as i64);
+        let (queue_version, tracks) = self.playback.read().await.queue().page(&page).await;
+        let tracks = tracks.iter()
+            .enumerate()
+            .map(|(index, track)| to_queue_item(track, page.offset() as usize + index, queue_version))
             .collect_vec();
         let (tx, rx) = tokio::sync::mpsc::channel(10);
         tokio::spawn(async move {
@@ -66,18 +98,70 @@ impl PlaybackControls for PlaybackControlsService {
     }
 
     async fn remove_from_queue(&self, request: Request<RemoveFromQueueRequest>) -> Result<Response<PlaybackBlank>, Status> {
-        let index = request.get_ref().queue_position;
-        match self.playback.read().await.queue().remove(index as usize).await {
+        let queue = self.playback.read().await.queue();
+        let result = match request.get_ref().item_id {
+            Some(item_id) => queue.remove_item(item_id).await,
+            None => queue.remove(request.get_ref().queue_position as usize).await
+        };
+        match result {
             Ok(_) => Ok(Response::new(PlaybackBlank {})),
             Err(e) => Err(Status::internal(e.to_string()))
         }
     }
 
+    async fn move_queue_item(&self, request: Request<MoveQueueItemRequest>) -> Result<Response<QueueChangedResponse>, Status> {
+        let queue = self.playback.read().await.queue();
+        match queue.move_item(request.get_ref().item_id, request.get_ref().new_position as usize).await {
+            Ok(_) => Ok(Response::new(QueueChangedResponse { queue_version: queue.version().await })),
+            Err(e) => Err(Status::internal(e.to_string()))
+        }
+    }
+
     async fn clear_queue(&self, _request: Request<PlaybackBlank>) -> Result<Response<PlaybackBlank>, Status> {
         self.playback.read().await.queue().clear().await;
         Ok(Response::new(PlaybackBlank {}))
     }
 
+    async fn enqueue_tracks(&self, request: Request<EnqueueTracksRequest>) -> Result<Response<EnqueueTracksResponse>, Status> {
+        let track_ids = match &request.get_ref().source {
+            Some(Source::AlbumId(album_id)) => self.db.load_track_ids_for_album(*album_id)?,
+            Some(Source::ArtistId(artist_id)) => self.db.load_fav_track_ids_for_artist(*artist_id)?,
+            Some(Source::SmartPlaylistId(playlist_id)) => {
+                let api: &dyn FindById<SmartPlaylist> = &self.db;
+                let query = api.find_by_id(*playlist_id)?
+                    .ok_or_else(|| Status::not_found("Smart playlist not found!"))?
+                    .query()?;
+                self.db.evaluate_smart_playlist(&query)?.iter()
+                    .map(|track| track.track_id)
+                    .collect_vec()
+            }
+            Some(Source::TrackIds(list)) => list.track_ids.clone(),
+            None => return Err(Status::invalid_argument("Source required!"))
+        };
+
+        let after_index = request.get_ref().after_index;
+        let position = match request.get_ref().position() {
+            QueueInsertPositions::Unspecified | QueueInsertPositions::End => Some(QueuePosition::End),
+            QueueInsertPositions::Next => Some(QueuePosition::Next),
+            QueueInsertPositions::AfterIndex if after_index >= 0 => Some(QueuePosition::AfterIndex(after_index as usize)),
+            QueueInsertPositions::AfterIndex => return Err(Status::invalid_argument("Invalid queue index!")),
+            QueueInsertPositions::Replace => None
+        };
+
+        let playback = self.playback.read().await;
+        let result = match position {
+            Some(position) => playback.queue().insert_playable(&track_ids, position).await,
+            None => playback.replace_queue_and_play(&track_ids).await
+        };
+        match result {
+            Ok(queued) => Ok(Response::new(EnqueueTracksResponse {
+                queued_tracks: queued as i32,
+                state: Some(PlaybackStateResponse::from(&playback.get_state().await))
+            })),
+            Err(e) => Err(Status::internal(e.to_string()))
+        }
+    }
+
     ///
     /// Playback Control Functions
     ///
@@ -120,11 +204,17 @@ impl PlaybackControls for PlaybackControlsService {
     }
 
     async fn previous(&self, _request: Request<PlaybackBlank>) -> Result<Response<PlaybackStateResponse>, Status> {
-        unimplemented!()
+        match self.playback.read().await.previous_track().await {
+            Ok(_) => Ok(Response::new(PlaybackStateResponse::from(&self.playback.read().await.get_state().await))),
+            Err(e) => Err(Status::internal(e.to_string()))
+        }
     }
 
-    async fn seek(&self, _request: Request<PlaybackSeekRequest>) -> Result<Response<PlaybackStateResponse>, Status> {
-        unimplemented!()
+    async fn seek(&self, request: Request<PlaybackSeekRequest>) -> Result<Response<PlaybackStateResponse>, Status> {
+        match self.playback.read().await.seek_to(request.get_ref().target_position_ms).await {
+            Ok(_) => Ok(Response::new(PlaybackStateResponse::from(&self.playback.read().await.get_state().await))),
+            Err(e) => Err(Status::internal(e.to_string()))
+        }
     }
 
     async fn set_shuffle(&self, _request: Request<PlaybackSetShuffleRequest>) -> Result<Response<PlaybackBlank>, Status> {
@@ -135,6 +225,72 @@ impl PlaybackControls for PlaybackControlsService {
         unimplemented!()
     }
 
+    async fn set_autoplay(&self, request: Request<PlaybackSetAutoplayRequest>) -> Result<Response<PlaybackStateResponse>, Status> {
+        let playback = self.playback.read().await;
+        playback.set_autoplay(request.get_ref().target_state).await;
+        Ok(Response::new(PlaybackStateResponse::from(&playback.get_state().await)))
+    }
+
+    async fn set_volume(&self, request: Request<SetVolumeRequest>) -> Result<Response<PlaybackStateResponse>, Status> {
+        let playback = self.playback.read().await;
+        playback.set_volume(request.get_ref().volume);
+        Ok(Response::new(PlaybackStateResponse::from(&playback.get_state().await)))
+    }
+
+    async fn get_volume(&self, _request: Request<PlaybackBlank>) -> Result<Response<VolumeResponse>, Status> {
+        let mixer = self.playback.read().await.mixer_state();
+        Ok(Response::new(VolumeResponse {
+            volume: mixer.volume,
+            muted: mixer.muted
+        }))
+    }
+
+    async fn mute(&self, request: Request<MuteRequest>) -> Result<Response<PlaybackStateResponse>, Status> {
+        let playback = self.playback.read().await;
+        playback.set_muted(request.get_ref().muted);
+        Ok(Response::new(PlaybackStateResponse::from(&playback.get_state().await)))
+    }
+
+    async fn set_crossfade(&self, request: Request<SetCrossfadeRequest>) -> Result<Response<PlaybackStateResponse>, Status> {
+        let playback = self.playback.read().await;
+        playback.set_crossfade(Duration::from_millis(request.get_ref().duration_ms as u64));
+        Ok(Response::new(PlaybackStateResponse::from(&playback.get_state().await)))
+    }
+
+    async fn list_audio_outputs(&self, _request: Request<PlaybackBlank>) -> Result<Response<AudioOutputsResponse>, Status> {
+        let backends = output::available_backends()
+            .into_iter()
+            .map(|b| AudioBackend {
+                name: b.name,
+                devices: b.devices
+            })
+            .collect();
+        Ok(Response::new(AudioOutputsResponse {
+            backends,
+            formats: output::FORMATS.iter().map(|f| format!("{:?}", f)).collect()
+        }))
+    }
+
+    async fn get_audio_output(&self, _request: Request<PlaybackBlank>) -> Result<Response<AudioOutput>, Status> {
+        let config = self.playback.read().await.audio_output();
+        Ok(Response::new(AudioOutput::from(&config)))
+    }
+
+    async fn set_audio_output(&self, request: Request<AudioOutput>) -> Result<Response<AudioOutput>, Status> {
+        let req = request.into_inner();
+        let config = AudioOutputConfig {
+            backend: req.backend,
+            device: req.device.filter(|d| !d.is_empty()),
+            format: output::parse_format(&req.format).map_err(|e| Status::invalid_argument(e.to_string()))?,
+            sample_rate: if req.sample_rate == 0 { librespot::playback::SAMPLE_RATE } else { req.sample_rate },
+            encoding: req.encoding.parse::<StreamEncoding>().map_err(Status::invalid_argument)?,
+            metadata: req.metadata.filter(|m| !m.is_empty())
+        };
+        let playback = self.playback.read().await;
+        playback.set_audio_output(config).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        Ok(Response::new(AudioOutput::from(&playback.audio_output())))
+    }
+
 
     ///
     /// Playback State Function
@@ -172,6 +328,105 @@ impl PlaybackControls for PlaybackControlsService {
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+
+    type QueueUpdatesStream = ReceiverStream<Result<QueueUpdate, Status>>;
+    async fn queue_updates(&self, _request: Request<PlaybackBlank>) -> Result<Response<Self::QueueUpdatesStream>, Status> {
+        let queue = self.playback.read().await.queue();
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(stream_queue_updates(queue, tx));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Streams the current queue followed by every change to it. A subscriber
+/// falling behind gets the whole queue again instead of the missed changes.
+async fn stream_queue_updates(queue: PlaybackQueue, tx: tokio::sync::mpsc::Sender<Result<QueueUpdate, Status>>) {
+    use tokio::sync::broadcast::error::RecvError;
+
+    //subscribe before taking the snapshot, so no change falls in between
+    let mut updates_rx = queue.subscribe();
+    let mut version = match send_queue_snapshot(&queue, &tx).await {
+        Some(version) => version,
+        None => return
+    };
+    loop {
+        match updates_rx.recv().await {
+            //already part of the snapshot
+            Ok(update) if update.version <= version => {}
+            Ok(update) => {
+                version = update.version;
+                if tx.send(Ok(QueueUpdate::from(&update))).await.is_err() {
+                    break;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                log::info!("Queue update receiver missed {} updates; resending queue", missed);
+                version = match send_queue_snapshot(&queue, &tx).await {
+                    Some(version) => version,
+                    None => break
+                };
+            }
+            Err(RecvError::Closed) => break
+        }
+    }
+    log::info!("Queue update stream closed!");
+}
+
+async fn send_queue_snapshot(queue: &PlaybackQueue, tx: &tokio::sync::mpsc::Sender<Result<QueueUpdate, Status>>) -> Option<u64> {
+    let (version, tracks) = queue.snapshot().await;
+    let cleared = QueueUpdate {
+        queue_version: version,
+        change: Some(Change::Cleared(QueueCleared {}))
+    };
+    let inserted = QueueUpdate {
+        queue_version: version,
+        change: Some(Change::Inserted(QueueItemsInserted {
+            position: 0,
+            items: tracks.iter()
+                .enumerate()
+                .map(|(index, track)| to_queue_item(track, index, version))
+                .collect_vec()
+        }))
+    };
+    tx.send(Ok(cleared)).await.ok()?;
+    tx.send(Ok(inserted)).await.ok()?;
+    Some(version)
+}
+
+fn to_queue_item(track: &PlaybackTrack, position: usize, queue_version: u64) -> QueueItem {
+    QueueItem {
+        track: Some(SimpleTrack::from(track)),
+        autoplayed: track.autoplayed,
+        item_id: track.item_id,
+        position: position as u32,
+        queue_version
+    }
+}
+
+impl From<&crate::playback::QueueUpdate> for QueueUpdate {
+    fn from(update: &crate::playback::QueueUpdate) -> Self {
+        let change = match &update.change {
+            QueueChange::Inserted { position, tracks } => Change::Inserted(QueueItemsInserted {
+                position: *position as u32,
+                items: tracks.iter()
+                    .enumerate()
+                    .map(|(index, track)| to_queue_item(track, position + index, update.version))
+                    .collect_vec()
+            }),
+            QueueChange::Removed { item_ids } => Change::Removed(QueueItemsRemoved {
+                item_ids: item_ids.clone()
+            }),
+            QueueChange::Moved { item_id, new_position } => Change::Moved(QueueItemMoved {
+                item_id: *item_id,
+                new_position: *new_position as u32
+            }),
+            QueueChange::Cleared => Change::Cleared(QueueCleared {})
+        };
+        Self {
+            queue_version: update.version,
+            change: Some(change)
+        }
+    }
 }
 
 fn map_opt_playback_track(opt_track : &Option<PlaybackTrack>) -> Option<SimpleTrack> {
@@ -185,6 +440,8 @@ impl From<&PlaybackTrack> for SimpleTrack {
             title : pb.meta.title.clone(),
             is_faved : pb.meta.is_faved,
             duration_ms : pb.meta.duration_ms,
+            play_count : pb.meta.play_count,
+            last_played_at : pb.meta.last_played.as_ref().map(super::library::to_millis),
             album : Some(SimpleAlbum {
                 album_id : pb.meta.album.album_id,
                 name : pb.meta.album.name.clone()
@@ -197,6 +454,19 @@ impl From<&PlaybackTrack> for SimpleTrack {
     }
 }
 
+impl From<&AudioOutputConfig> for AudioOutput {
+    fn from(config : &AudioOutputConfig) -> Self {
+        AudioOutput {
+            backend : config.backend.clone(),
+            device : config.device.clone(),
+            format : format!("{:?}", config.format),
+            sample_rate : config.sample_rate,
+            encoding : config.encoding.to_string(),
+            metadata : config.metadata.clone()
+        }
+    }
+}
+
 impl From<&PlaybackState> for PlaybackStateResponse {
     fn from(state : &PlaybackState) -> Self {
         Self {
@@ -204,6 +474,12 @@ impl From<&PlaybackState> for PlaybackStateResponse {
             has_previous : state.has_previous,
             has_next : state.has_next,
             loop_state : PlaybackLoopStates::from(&state.looping_state) as i32,
+            autoplay : state.autoplay,
+            queue_version : state.queue_version,
+            volume : state.volume,
+            muted : state.muted,
+            crossfade_ms : state.crossfade.as_millis() as u32,
+            position_ms : state.position_ms,
             playing_track : map_opt_playback_track(&state.current_track)
         }
     }
@@ -1,9 +1,7 @@
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
 use itertools::Itertools;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
-use crate::db_new::album_artist::AlbumArtistsDb;
 use super::definition::track_favourites_server::TrackFavourites;
 use super::definition::{
     NewTrackFavouriteRequest,
@@ -23,12 +21,11 @@ use super::definition::{
 };
 
 use crate::db_new::DbApi;
-use crate::db_new::models::{Artist, NewTrackFavProposal, Track, TrackFavProposal};
+use crate::db_new::models::{NewTrackFavProposal, TrackFavProposal};
 use crate::db_new::track::TrackDb;
-use crate::db_new::track_artist::TrackArtistsDb;
 use crate::db_new::track_fav_proposal::TrackFavProposalDb;
-use crate::model::{RequestPage, UniversalId};
-use crate::spotify::db_utils::{get_or_create_album, get_or_create_artist, get_or_create_track};
+use crate::model::{ChangeSource, RequestPage, UniversalId};
+use crate::spotify::db_utils::insert_track_from_spotify_id;
 use crate::SpotifyApi;
 
 pub struct TrackProposalsService {
@@ -161,7 +158,8 @@ impl TrackFavourites for TrackProposalsService {
             Ok(opt) => {
                 match opt {
                     Some(proposal) => {
-                        let ret = confirm_match(&self.db, &self.spotify, proposal, uni_id).await;
+                        let db = self.db.with_source(ChangeSource::Proposals);
+                        let ret = confirm_match(&db, &self.spotify, proposal, uni_id).await;
                         match ret {
                             Ok(_) => Ok(Response::new(TrackFavouritesBlank{})),
                             Err(e) => Err(Status::internal(e.to_string()))
@@ -182,7 +180,8 @@ impl TrackFavourites for TrackProposalsService {
             Ok(opt) => {
                 match opt {
                     Some(proposal) => {
-                        let ret = discard_proposal_and_unfav_track(&self.db, proposal);
+                        let db = self.db.with_source(ChangeSource::Proposals);
+                        let ret = discard_proposal_and_unfav_track(&db, proposal);
                         match ret {
                             Ok(_) => Ok(Response::new(TrackFavouritesBlank{})),
                             Err(e) => Err(Status::internal(e.to_string()))
@@ -206,7 +205,7 @@ impl TrackFavourites for TrackProposalsService {
         if !resolver.is_excluded(&*source_raw) {
             let proposal = find_on_db(&self.db, &*source_name, &*source_raw)?;
             if let Some(p) = proposal {
-                set_track_to_unfaved(&self.db, &p.track_id)?;
+                set_track_to_unfaved(&self.db.with_source(ChangeSource::Proposals), &p.track_id)?;
                 self.db.delete_track_proposal(p.track_fav_id)?;
             }
         }
@@ -394,54 +393,6 @@ fn try_find_spotify_id_on_db<DB>(db: &DB, prop_match: TrackFavouriteMatch) -> Tr
     }
 }
 
-async fn insert_track_from_spotify_id(db: &DbApi, spotify: &SpotifyApi, spot_id: &str) -> Result<Track, Box<dyn std::error::Error>> {
-    let spotify_track = spotify.get_track_from(spot_id).await?;
-    let spotify_album = spotify.get_album(&spotify_track.album.id.as_ref().clone().unwrap()).await?;
-
-    //lets first add the album
-    let db_album = get_or_create_album(db, &spotify_album)?;
-
-    let album_artist_ids = spotify_album.artists
-        .iter()
-        .map(|simple_artist| simple_artist.id.clone().unwrap())
-        .collect::<HashSet<rspotify::model::ArtistId>>();
-
-
-    //now add the track
-    let db_track = get_or_create_track(db, &db_album, &spotify_track)?;
-
-    let track_artist_ids = spotify_track.artists
-        .iter()
-        .map(|simple_artist| simple_artist.id.clone().unwrap())
-        .collect::<HashSet<rspotify::model::ArtistId>>();
-
-    let mut all_artist_ids = HashSet::new();
-    all_artist_ids.extend(album_artist_ids.clone());
-    all_artist_ids.extend(track_artist_ids.clone());
-
-    let spotify_artists = spotify.get_artists(&all_artist_ids.into_iter().collect::<Vec<rspotify::model::ArtistId>>()).await?;
-    let mut artist_id_to_db_artist : HashMap<String, Artist> = HashMap::new();
-    for spotify_artist in &spotify_artists {
-        let artist = get_or_create_artist(db, spotify_artist)?;
-        artist_id_to_db_artist.insert(spotify_artist.id.to_string(), artist);
-    }
-
-    //now link the artist with album and track
-    let api : &dyn AlbumArtistsDb = db;
-    for artist_id in &album_artist_ids {
-        let db_artist = artist_id_to_db_artist.get(&*artist_id.to_string()).unwrap();
-        let _ = api.new_album_artist_if_missing(db_artist.artist_id, db_album.album_id)?;
-    }
-
-    let api : &dyn TrackArtistsDb = db;
-    for artist_id in &track_artist_ids {
-        let db_artist =artist_id_to_db_artist.get(&*artist_id.to_string()).unwrap();
-        let _ = api.new_track_artist_if_missing(db_track.track_id, db_artist.artist_id)?;
-    }
-
-    Ok(db_track)
-}
-
 struct RockAntenneResolver();
 
 struct FullResolver();
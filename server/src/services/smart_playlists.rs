@@ -0,0 +1,191 @@
+use itertools::Itertools;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+use super::definition::playlist_rule::Rule;
+use super::definition::smart_playlists_server::SmartPlaylists;
+use super::definition::{
+    AlbumOfWeekRule,
+    ChartRule,
+    EnqueueSmartPlaylistResponse,
+    ListSmartPlaylistsRequest,
+    PlaylistRuleGroup,
+    ReleaseYearRule,
+    SimpleTrack,
+    SmartPlaylistBlank,
+    SmartPlaylistRequest,
+};
+use super::library::map_track_list;
+use super::stream_entries;
+use crate::db_new::models;
+use crate::db_new::smart_playlist::SmartPlaylistDb;
+use crate::db_new::{DbApi, FindById};
+use crate::model::smart_playlist::{PlaylistRule, PlaylistSort, SmartPlaylistQuery};
+use crate::model::RequestPage;
+use crate::playback::{PlaybackQueue, QueuePosition};
+
+//about a century, enough to reach back to the first play
+const MAX_RULE_DAYS: i64 = 36500;
+
+pub struct SmartPlaylistsService {
+    pub(crate) db: DbApi,
+    pub(crate) queue: PlaybackQueue,
+}
+
+#[tonic::async_trait]
+impl SmartPlaylists for SmartPlaylistsService {
+    async fn create_smart_playlist(&self, request: Request<super::definition::SmartPlaylist>) -> Result<Response<super::definition::SmartPlaylist>, Status> {
+        let query = parse_query(request.get_ref())
+            .ok_or_else(|| Status::invalid_argument("Invalid playlist definition!"))?;
+        let playlist = self.db.new_smart_playlist(&request.get_ref().name, &query)?;
+        Ok(Response::new(to_api(&playlist, &query)))
+    }
+
+    async fn update_smart_playlist(&self, request: Request<super::definition::SmartPlaylist>) -> Result<Response<super::definition::SmartPlaylist>, Status> {
+        let query = parse_query(request.get_ref())
+            .ok_or_else(|| Status::invalid_argument("Invalid playlist definition!"))?;
+        self.find_playlist(request.get_ref().playlist_id)?
+            .ok_or_else(|| Status::not_found("Smart playlist not found!"))?;
+        let playlist = self.db.update_smart_playlist(request.get_ref().playlist_id, &request.get_ref().name, &query)?;
+        Ok(Response::new(to_api(&playlist, &query)))
+    }
+
+    async fn delete_smart_playlist(&self, request: Request<SmartPlaylistRequest>) -> Result<Response<SmartPlaylistBlank>, Status> {
+        self.find_playlist(request.get_ref().playlist_id)?
+            .ok_or_else(|| Status::not_found("Smart playlist not found!"))?;
+        self.db.delete_smart_playlist(request.get_ref().playlist_id)?;
+        Ok(Response::new(SmartPlaylistBlank {}))
+    }
+
+    type ListSmartPlaylistsStream = ReceiverStream<Result<super::definition::SmartPlaylist, Status>>;
+
+    async fn list_smart_playlists(&self, request: Request<ListSmartPlaylistsRequest>) -> Result<Response<Self::ListSmartPlaylistsStream>, Status> {
+        let page = RequestPage::new(request.get_ref().offset as i64, request.get_ref().limit as i64);
+        let mut playlists = Vec::new();
+        for playlist in self.db.load_smart_playlists(&page)? {
+            playlists.push(to_api(&playlist, &playlist.query()?));
+        }
+        Ok(Response::new(stream_entries(playlists)))
+    }
+
+    type GetSmartPlaylistTracksStream = ReceiverStream<Result<SimpleTrack, Status>>;
+
+    async fn get_smart_playlist_tracks(&self, request: Request<SmartPlaylistRequest>) -> Result<Response<Self::GetSmartPlaylistTracksStream>, Status> {
+        let query = self.find_playlist(request.get_ref().playlist_id)?
+            .ok_or_else(|| Status::not_found("Smart playlist not found!"))?.query()?;
+        let tracks = map_track_list(&self.db, self.db.evaluate_smart_playlist(&query)?)?;
+        Ok(Response::new(stream_entries(tracks)))
+    }
+
+    type PreviewSmartPlaylistStream = ReceiverStream<Result<SimpleTrack, Status>>;
+
+    async fn preview_smart_playlist(&self, request: Request<super::definition::SmartPlaylist>) -> Result<Response<Self::PreviewSmartPlaylistStream>, Status> {
+        let query = parse_query(request.get_ref())
+            .ok_or_else(|| Status::invalid_argument("Invalid playlist definition!"))?;
+        let tracks = map_track_list(&self.db, self.db.evaluate_smart_playlist(&query)?)?;
+        Ok(Response::new(stream_entries(tracks)))
+    }
+
+    async fn enqueue_smart_playlist(&self, request: Request<SmartPlaylistRequest>) -> Result<Response<EnqueueSmartPlaylistResponse>, Status> {
+        let query = self.find_playlist(request.get_ref().playlist_id)?
+            .ok_or_else(|| Status::not_found("Smart playlist not found!"))?.query()?;
+        let track_ids = self.db.evaluate_smart_playlist(&query)?.iter()
+            .map(|track| track.track_id)
+            .collect_vec();
+        match self.queue.insert_playable(&track_ids, QueuePosition::End).await {
+            Ok(queued) => Ok(Response::new(EnqueueSmartPlaylistResponse { queued_tracks: queued as i32 })),
+            Err(e) => Err(Status::internal(e.to_string()))
+        }
+    }
+}
+
+impl SmartPlaylistsService {
+    fn find_playlist(&self, playlist_id: i32) -> Result<Option<models::SmartPlaylist>, crate::db_new::DbError> {
+        let api: &dyn FindById<models::SmartPlaylist> = &self.db;
+        api.find_by_id(playlist_id)
+    }
+}
+
+fn parse_query(playlist: &super::definition::SmartPlaylist) -> Option<SmartPlaylistQuery> {
+    if playlist.name.trim().is_empty() {
+        return None;
+    }
+    let limit = match playlist.limit {
+        Some(limit) if limit <= 0 => return None,
+        limit => limit.map(i64::from)
+    };
+
+    Some(SmartPlaylistQuery {
+        rule: parse_rule(playlist.rule.as_ref()?)?,
+        sort: PlaylistSort::from(playlist.sort),
+        descending: playlist.descending,
+        limit,
+    })
+}
+
+fn parse_rule(rule: &super::definition::PlaylistRule) -> Option<PlaylistRule> {
+    let rule = match rule.rule.as_ref()? {
+        Rule::All(group) => PlaylistRule::All(group.rules.iter().map(parse_rule).collect::<Option<Vec<_>>>()?),
+        Rule::Any(group) => PlaylistRule::Any(group.rules.iter().map(parse_rule).collect::<Option<Vec<_>>>()?),
+        Rule::Not(inner) => PlaylistRule::Not(Box::new(parse_rule(inner)?)),
+        Rule::Faved(faved) => PlaylistRule::Faved(*faved),
+        Rule::AlbumOfWeek(aow) => PlaylistRule::AlbumOfWeek {
+            since_year: aow.since_year,
+            source_name: aow.source_name.clone(),
+        },
+        Rule::ReleaseYear(year) => PlaylistRule::ReleaseYear {
+            from: year.from,
+            to: year.to,
+        },
+        Rule::Charted(chart) => PlaylistRule::Charted {
+            source_name: chart.source_name.clone(),
+            max_position: chart.max_position,
+            since_year: chart.since_year,
+        },
+        Rule::Genre(genre) if !genre.trim().is_empty() => PlaylistRule::Genre(genre.clone()),
+        Rule::Genre(_) => return None,
+        Rule::ArtistId(artist_id) => PlaylistRule::Artist(*artist_id),
+        Rule::NotPlayedForDays(days) if (0..=MAX_RULE_DAYS).contains(days) => PlaylistRule::NotPlayedForDays(*days),
+        Rule::PlayedWithinDays(days) if (0..=MAX_RULE_DAYS).contains(days) => PlaylistRule::PlayedWithinDays(*days),
+        Rule::NotPlayedForDays(_) | Rule::PlayedWithinDays(_) => return None,
+    };
+    Some(rule)
+}
+
+fn to_api(playlist: &models::SmartPlaylist, query: &SmartPlaylistQuery) -> super::definition::SmartPlaylist {
+    super::definition::SmartPlaylist {
+        playlist_id: playlist.playlist_id,
+        name: playlist.name.clone(),
+        rule: Some(to_api_rule(&query.rule)),
+        sort: i32::from(query.sort),
+        descending: query.descending,
+        limit: playlist.max_tracks,
+    }
+}
+
+fn to_api_rule(rule: &PlaylistRule) -> super::definition::PlaylistRule {
+    let rule = match rule {
+        PlaylistRule::All(rules) => Rule::All(PlaylistRuleGroup { rules: rules.iter().map(to_api_rule).collect_vec() }),
+        PlaylistRule::Any(rules) => Rule::Any(PlaylistRuleGroup { rules: rules.iter().map(to_api_rule).collect_vec() }),
+        PlaylistRule::Not(inner) => Rule::Not(Box::new(to_api_rule(inner))),
+        PlaylistRule::Faved(faved) => Rule::Faved(*faved),
+        PlaylistRule::AlbumOfWeek { since_year, source_name } => Rule::AlbumOfWeek(AlbumOfWeekRule {
+            since_year: *since_year,
+            source_name: source_name.clone(),
+        }),
+        PlaylistRule::ReleaseYear { from, to } => Rule::ReleaseYear(ReleaseYearRule {
+            from: *from,
+            to: *to,
+        }),
+        PlaylistRule::Charted { source_name, max_position, since_year } => Rule::Charted(ChartRule {
+            source_name: source_name.clone(),
+            max_position: *max_position,
+            since_year: *since_year,
+        }),
+        PlaylistRule::Genre(genre) => Rule::Genre(genre.clone()),
+        PlaylistRule::Artist(artist_id) => Rule::ArtistId(*artist_id),
+        PlaylistRule::NotPlayedForDays(days) => Rule::NotPlayedForDays(*days),
+        PlaylistRule::PlayedWithinDays(days) => Rule::PlayedWithinDays(*days),
+    };
+    super::definition::PlaylistRule { rule: Some(rule) }
+}
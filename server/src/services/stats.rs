@@ -0,0 +1,207 @@
+use itertools::Itertools;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+use super::definition::stats_server::Stats;
+use super::definition::{
+    FirstListenedEntry,
+    FirstListenedRequest,
+    Genre,
+    ListeningTimeEntry,
+    ListeningTimeRequest,
+    SimpleAlbum,
+    SimpleArtist,
+    StatsBuckets,
+    TopAlbum,
+    TopArtist,
+    TopEntitiesRequest,
+    TopGenre,
+    TopTrack,
+    YearInReviewRequest,
+    YearInReviewResponse,
+};
+use super::library::{map_track_list, to_millis};
+use super::stream_entries;
+use crate::db_new;
+use crate::db_new::album::AlbumDb;
+use crate::db_new::artist::ArtistDb;
+use crate::db_new::genre::GenreDb;
+use crate::db_new::stats::StatsDb;
+use crate::db_new::track::TrackDb;
+use crate::db_new::DbApi;
+use crate::model::{LibraryEntity, RequestPage, StatsBucket, StatsRange};
+
+pub struct StatsService {
+    pub(crate) db: DbApi,
+}
+
+#[tonic::async_trait]
+impl Stats for StatsService {
+    type TopTracksStream = ReceiverStream<Result<TopTrack, Status>>;
+
+    async fn top_tracks(&self, request: Request<TopEntitiesRequest>) -> Result<Response<Self::TopTracksStream>, Status> {
+        let (range, limit) = parse_top_request(request.get_ref())
+            .ok_or_else(|| Status::invalid_argument("Invalid range!"))?;
+        let entries = load_top_tracks(&self.db, &range, limit)?;
+        Ok(Response::new(stream_entries(entries)))
+    }
+
+    type TopArtistsStream = ReceiverStream<Result<TopArtist, Status>>;
+
+    async fn top_artists(&self, request: Request<TopEntitiesRequest>) -> Result<Response<Self::TopArtistsStream>, Status> {
+        let (range, limit) = parse_top_request(request.get_ref())
+            .ok_or_else(|| Status::invalid_argument("Invalid range!"))?;
+        let entries = load_top_artists(&self.db, &range, limit)?;
+        Ok(Response::new(stream_entries(entries)))
+    }
+
+    type TopAlbumsStream = ReceiverStream<Result<TopAlbum, Status>>;
+
+    async fn top_albums(&self, request: Request<TopEntitiesRequest>) -> Result<Response<Self::TopAlbumsStream>, Status> {
+        let (range, limit) = parse_top_request(request.get_ref())
+            .ok_or_else(|| Status::invalid_argument("Invalid range!"))?;
+        let entries = load_top_albums(&self.db, &range, limit)?;
+        Ok(Response::new(stream_entries(entries)))
+    }
+
+    type TopGenresStream = ReceiverStream<Result<TopGenre, Status>>;
+
+    async fn top_genres(&self, request: Request<TopEntitiesRequest>) -> Result<Response<Self::TopGenresStream>, Status> {
+        let (range, limit) = parse_top_request(request.get_ref())
+            .ok_or_else(|| Status::invalid_argument("Invalid range!"))?;
+        let entries = load_top_genres(&self.db, &range, limit)?;
+        Ok(Response::new(stream_entries(entries)))
+    }
+
+    type ListeningTimeStream = ReceiverStream<Result<ListeningTimeEntry, Status>>;
+
+    async fn listening_time(&self, request: Request<ListeningTimeRequest>) -> Result<Response<Self::ListeningTimeStream>, Status> {
+        let range = parse_range(request.get_ref().range.as_ref())
+            .ok_or_else(|| Status::invalid_argument("Invalid range!"))?;
+        let bucket = match request.get_ref().bucket() {
+            StatsBuckets::Unspecified | StatsBuckets::Day => StatsBucket::Day,
+            StatsBuckets::Week => StatsBucket::Week
+        };
+
+        let entries = self.db.listening_time(&range, bucket)?
+            .iter()
+            .filter_map(|(day, listened_ms)| day.and_hms_opt(0, 0, 0).map(|start| ListeningTimeEntry {
+                bucket_start: to_millis(&start),
+                listened_ms: *listened_ms
+            }))
+            .collect_vec();
+        Ok(Response::new(stream_entries(entries)))
+    }
+
+    type FirstListenedStream = ReceiverStream<Result<FirstListenedEntry, Status>>;
+
+    async fn first_listened(&self, request: Request<FirstListenedRequest>) -> Result<Response<Self::FirstListenedStream>, Status> {
+        let entity = match request.get_ref().entity() {
+            super::definition::LibraryEntities::Artist => LibraryEntity::Artist,
+            super::definition::LibraryEntities::Album => LibraryEntity::Album,
+            super::definition::LibraryEntities::Track => LibraryEntity::Track,
+            super::definition::LibraryEntities::Unspecified => return Err(Status::invalid_argument("Entity required!"))
+        };
+
+        let first_listened = self.db.first_listened(entity, &request.get_ref().ids)?;
+        let entries = request.get_ref().ids.iter()
+            .unique()
+            .filter_map(|id| first_listened.get(id).map(|first| FirstListenedEntry {
+                id: *id,
+                first_listened_at: to_millis(first)
+            }))
+            .collect_vec();
+        Ok(Response::new(stream_entries(entries)))
+    }
+
+    async fn year_in_review(&self, request: Request<YearInReviewRequest>) -> Result<Response<YearInReviewResponse>, Status> {
+        let year = request.get_ref().year;
+        let range = match StatsRange::year(year) {
+            Some(range) => range,
+            None => return Err(Status::invalid_argument("Year out of range!"))
+        };
+
+        let year_stats = self.db.load_year_stats(&range, year)?;
+        let api: &dyn AlbumDb = &self.db;
+        let listened_albums_of_week = api.find_by_ids(year_stats.listened_albums_of_week.clone())?
+            .iter()
+            .map(SimpleAlbum::from)
+            .collect_vec();
+
+        Ok(Response::new(YearInReviewResponse {
+            year,
+            listened_ms: year_stats.listened_ms,
+            play_count: year_stats.play_count,
+            distinct_tracks: year_stats.distinct_tracks,
+            top_tracks: load_top_tracks(&self.db, &range, YEAR_IN_REVIEW_LIMIT)?,
+            top_artists: load_top_artists(&self.db, &range, YEAR_IN_REVIEW_LIMIT)?,
+            top_albums: load_top_albums(&self.db, &range, YEAR_IN_REVIEW_LIMIT)?,
+            top_genres: load_top_genres(&self.db, &range, YEAR_IN_REVIEW_LIMIT)?,
+            albums_of_week: year_stats.albums_of_week,
+            listened_albums_of_week,
+            chart_tracks: year_stats.chart_tracks,
+            listened_chart_tracks: year_stats.listened_chart_tracks,
+        }))
+    }
+}
+
+const YEAR_IN_REVIEW_LIMIT: i64 = 10;
+
+//ranges without bounds or with from after to are rejected
+fn parse_range(range: Option<&super::definition::StatsRange>) -> Option<StatsRange> {
+    let range = range?;
+    let from = chrono::DateTime::from_timestamp_millis(range.from)?.naive_utc();
+    let to = chrono::DateTime::from_timestamp_millis(range.to)?.naive_utc();
+    if from < to {
+        Some(StatsRange { from, to })
+    } else {
+        None
+    }
+}
+
+fn parse_top_request(request: &TopEntitiesRequest) -> Option<(StatsRange, i64)> {
+    let range = parse_range(request.range.as_ref())?;
+    //same bounds as for any other list
+    let limit = RequestPage::new(0, request.limit as i64).limit();
+    Some((range, limit))
+}
+
+fn load_top_tracks(db: &DbApi, range: &StatsRange, limit: i64) -> Result<Vec<TopTrack>, db_new::DbError> {
+    let counts = db.top_tracks(range, limit)?;
+    let api: &dyn TrackDb = db;
+    let tracks = map_track_list(db, api.find_by_ids(counts.iter().map(|(id, _)| *id).collect_vec())?)?;
+    Ok(counts.iter().map(|(track_id, play_count)| TopTrack {
+        track: tracks.iter().find(|t| t.track_id == *track_id).cloned(),
+        play_count: *play_count
+    }).collect_vec())
+}
+
+fn load_top_artists(db: &DbApi, range: &StatsRange, limit: i64) -> Result<Vec<TopArtist>, db_new::DbError> {
+    let counts = db.top_artists(range, limit)?;
+    let api: &dyn ArtistDb = db;
+    let artists = api.find_by_ids(counts.iter().map(|(id, _)| *id).collect_vec())?;
+    Ok(counts.iter().map(|(artist_id, play_count)| TopArtist {
+        artist: artists.iter().find(|a| a.artist_id == *artist_id).map(SimpleArtist::from),
+        play_count: *play_count
+    }).collect_vec())
+}
+
+fn load_top_albums(db: &DbApi, range: &StatsRange, limit: i64) -> Result<Vec<TopAlbum>, db_new::DbError> {
+    let counts = db.top_albums(range, limit)?;
+    let api: &dyn AlbumDb = db;
+    let albums = api.find_by_ids(counts.iter().map(|(id, _)| *id).collect_vec())?;
+    Ok(counts.iter().map(|(album_id, play_count)| TopAlbum {
+        album: albums.iter().find(|a| a.album_id == *album_id).map(SimpleAlbum::from),
+        play_count: *play_count
+    }).collect_vec())
+}
+
+fn load_top_genres(db: &DbApi, range: &StatsRange, limit: i64) -> Result<Vec<TopGenre>, db_new::DbError> {
+    let counts = db.top_genres(range, limit)?;
+    let api: &dyn GenreDb = db;
+    let genres = api.find_by_ids(counts.iter().map(|(id, _)| *id).collect_vec())?;
+    Ok(counts.iter().map(|(genre_id, play_count)| TopGenre {
+        genre: genres.iter().find(|g| g.genre_id == *genre_id).map(Genre::from),
+        play_count: *play_count
+    }).collect_vec())
+}
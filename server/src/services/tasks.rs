@@ -1,4 +1,5 @@
 use tonic::{Request, Response, Status};
+use crate::artwork::ArtworkStore;
 use crate::db_new::DbApi;
 use crate::spotify::SpotifyApi;
 
@@ -7,7 +8,8 @@ use super::definition::TasksBlank;
 
 pub struct TasksService {
     pub(crate) db : DbApi,
-    pub(crate) spotify : SpotifyApi
+    pub(crate) spotify : SpotifyApi,
+    pub(crate) artwork : ArtworkStore
 }
 
 #[tonic::async_trait]
@@ -26,4 +28,14 @@ impl Tasks for TasksService {
         crate::tasks::launch_spotify_import(&self.db, &self.spotify);
         Ok(Response::new(TasksBlank{}))
     }
+
+    async fn fetch_artwork(&self, _request : Request<TasksBlank>) -> Result<Response<TasksBlank>, Status> {
+        crate::tasks::launch_fetch_artwork(&self.artwork);
+        Ok(Response::new(TasksBlank{}))
+    }
+
+    async fn analyze_loudness(&self, _request : Request<TasksBlank>) -> Result<Response<TasksBlank>, Status> {
+        crate::tasks::launch_loudness_analysis(&self.db);
+        Ok(Response::new(TasksBlank{}))
+    }
 }
\ No newline at end of file
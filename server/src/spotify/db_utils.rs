@@ -1,16 +1,23 @@
+use std::collections::{HashMap, HashSet};
 use rspotify::model::{FullAlbum, FullArtist, FullTrack};
+use crate::db_new::DbApi;
 use crate::db_new::album::AlbumDb;
+use crate::db_new::album_art::AlbumArtDb;
+use crate::db_new::album_artist::AlbumArtistsDb;
 use crate::db_new::artist::ArtistDb;
-use crate::db_new::models::{Album, Artist, NewAlbum, NewArtist, NewTrack, Track};
+use crate::db_new::artist_genre::ArtistGenreDb;
+use crate::db_new::genre::GenreDb;
+use crate::db_new::models::{Album, Artist, NewAlbum, NewAlbumArt, NewArtist, NewTrack, Track};
 use crate::db_new::track::TrackDb;
+use crate::db_new::track_artist::TrackArtistsDb;
 use crate::model::{AlbumType, UniversalId};
-use super::Result;
+use super::{Result, SpotifyApi, SpotifyApiError};
 
-pub fn get_or_create_album(api : &impl AlbumDb, spotify_album : &FullAlbum) -> Result<Album> {
+pub fn get_or_create_album(api : &(impl AlbumDb + AlbumArtDb), spotify_album : &FullAlbum) -> Result<Album> {
     let id = UniversalId::Spotify(spotify_album.id.to_string());
     let album = match api.find_by_universal_id(&id)? {
         Some(album) => album,
-        None => api.new_full_album(NewAlbum {
+        None => api.new_full_album_if_missing(NewAlbum {
             name: &*spotify_album.name,
             year: spotify_album.release_date[..4].parse::<i32>()?,
             total_tracks: spotify_album.tracks.total as i32,
@@ -19,13 +26,35 @@ pub fn get_or_create_album(api : &impl AlbumDb, spotify_album : &FullAlbum) -> R
             was_aow: Some(false),
             is_faved: Some(false),
             spot_id: Some(spotify_album.id.to_string()),
-            album_type: Some(AlbumType::from(spotify_album.album_type).into()),
+            album_type: Some(AlbumType::from_spotify(spotify_album).into()),
         })?
     };
+    register_album_art(api, &album, spotify_album)?;
     Ok(album)
 }
 
-pub fn get_or_create_artist(api : &impl ArtistDb, spotify_artist : &FullArtist) -> Result<Artist> {
+fn register_album_art(api : &impl AlbumArtDb, album : &Album, spotify_album : &FullAlbum) -> Result<()> {
+    if api.find_album_art(album.album_id, 0)?.is_some() {
+        return Ok(());
+    }
+
+    //spotify lists the images by size, pick the largest as original
+    let largest = spotify_album.images.iter()
+        .max_by_key(|img| img.width.unwrap_or(0));
+    if let Some(image) = largest {
+        let _ = api.new_album_art(NewAlbumArt {
+            album_id: album.album_id,
+            size: 0,
+            source: "spotify",
+            source_url: Some(&*image.url),
+            file_path: None,
+            content_type: None
+        })?;
+    }
+    Ok(())
+}
+
+pub fn get_or_create_artist(api : &(impl ArtistDb + GenreDb + ArtistGenreDb), spotify_artist : &FullArtist) -> Result<Artist> {
     let id = UniversalId::Spotify(spotify_artist.id.to_string());
     let db_artist = match api.find_artist_by_universal_id(&id)? {
         Some(artist) => artist,
@@ -36,14 +65,23 @@ pub fn get_or_create_artist(api : &impl ArtistDb, spotify_artist : &FullArtist)
             is_known_spot: true
         })?
     };
+    link_artist_genres(api, &db_artist, &spotify_artist.genres)?;
     Ok(db_artist)
 }
 
+fn link_artist_genres(api : &(impl GenreDb + ArtistGenreDb), artist : &Artist, genres : &[String]) -> Result<()> {
+    for name in genres {
+        let genre = api.get_or_create_genre(name)?;
+        let _ = api.new_artist_genre_if_missing(artist.artist_id, genre.genre_id)?;
+    }
+    Ok(())
+}
+
 pub fn get_or_create_track(api : &impl TrackDb, db_album : &Album, spotify_track : &FullTrack) -> Result<Track> {
     let id = UniversalId::Spotify(spotify_track.id.clone().unwrap().to_string());
     let db_track = match api.find_track_by_universal_id(&id)? {
         Some(track) => track,
-        None => api.new_full_track(NewTrack {
+        None => api.new_full_track_if_missing(NewTrack {
             title: &*spotify_track.name,
             is_faved: false,
             album_id: db_album.album_id,
@@ -55,4 +93,55 @@ pub fn get_or_create_track(api : &impl TrackDb, db_album : &Album, spotify_track
         })?
     };
     Ok(db_track)
-}
\ No newline at end of file
+}
+
+/// Stores a spotify track with its album and artists unless they are already known
+pub async fn insert_track_from_spotify_id(db: &DbApi, spotify: &SpotifyApi, spot_id: &str) -> Result<Track> {
+    let spotify_track = spotify.get_track_from(spot_id).await?;
+    let album_id = spotify_track.album.id.as_ref()
+        .ok_or_else(|| SpotifyApiError::Internal(format!("Track {} has no album!", spot_id)))?;
+    let spotify_album = spotify.get_album(album_id).await?;
+
+    let album_artist_ids = spotify_album.artists
+        .iter()
+        .map(|simple_artist| simple_artist.id.clone().unwrap())
+        .collect::<HashSet<rspotify::model::ArtistId>>();
+
+    let track_artist_ids = spotify_track.artists
+        .iter()
+        .map(|simple_artist| simple_artist.id.clone().unwrap())
+        .collect::<HashSet<rspotify::model::ArtistId>>();
+
+    let mut all_artist_ids = HashSet::new();
+    all_artist_ids.extend(album_artist_ids.clone());
+    all_artist_ids.extend(track_artist_ids.clone());
+
+    let spotify_artists = spotify.get_artists(&all_artist_ids.into_iter().collect::<Vec<rspotify::model::ArtistId>>()).await?;
+
+    //everything is fetched, now write album, track, artists and their links as a whole
+    db.transaction(|tx| {
+        let db_album = get_or_create_album(tx, &spotify_album)?;
+        let db_track = get_or_create_track(tx, &db_album, &spotify_track)?;
+
+        let mut artist_id_to_db_artist : HashMap<String, Artist> = HashMap::new();
+        for spotify_artist in &spotify_artists {
+            let artist = get_or_create_artist(tx, spotify_artist)?;
+            artist_id_to_db_artist.insert(spotify_artist.id.to_string(), artist);
+        }
+
+        //now link the artist with album and track
+        let api : &dyn AlbumArtistsDb = tx;
+        for artist_id in &album_artist_ids {
+            let db_artist = artist_id_to_db_artist.get(&*artist_id.to_string()).unwrap();
+            let _ = api.new_album_artist_if_missing(db_artist.artist_id, db_album.album_id)?;
+        }
+
+        let api : &dyn TrackArtistsDb = tx;
+        for artist_id in &track_artist_ids {
+            let db_artist = artist_id_to_db_artist.get(&*artist_id.to_string()).unwrap();
+            let _ = api.new_track_artist_if_missing(db_track.track_id, db_artist.artist_id)?;
+        }
+
+        Ok(db_track)
+    })
+}
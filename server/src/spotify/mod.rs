@@ -21,7 +21,7 @@ use thiserror::Error;
 
 use rspotify::{AuthCodeSpotify, Config, Credentials, OAuth, scopes};
 use rspotify::clients::{BaseClient, OAuthClient};
-use rspotify::model::{AlbumId, ArtistId, FullAlbum, FullArtist, FullTrack, SearchResult};
+use rspotify::model::{AlbumId, ArtistId, FullAlbum, FullArtist, FullTrack, RecommendationsAttribute, SearchResult};
 use rspotify::model::{Market, SearchType, TrackId};
 use tokio::sync::RwLock;
 
@@ -85,6 +85,23 @@ impl SpotifyApi {
         self.get_track(&track_id).await
     }
 
+    /// Returns the ids of tracks spotify recommends based on the given one
+    pub async fn get_recommendations(&self, seed_track: &str, limit: u32) -> Result<Vec<String>> {
+        let client = self.0.read().await;
+        let track_id = TrackId::from_str(seed_track)?;
+        let recommendations = client.recommendations(
+            std::iter::empty::<RecommendationsAttribute>(),
+            None::<Vec<&ArtistId>>,
+            None::<Vec<&str>>,
+            Some(vec![&track_id]),
+            Some(&Market::FromToken),
+            Some(limit)
+        ).await?;
+        Ok(recommendations.tracks.iter()
+            .filter_map(|track| track.id.as_ref().map(|id| id.to_string()))
+            .collect::<Vec<String>>())
+    }
+
     pub async fn get_album(&self, album_id: &AlbumId) -> Result<FullAlbum> {
         let client = self.0.read().await;
         Ok(client.album(album_id).await?)
@@ -0,0 +1,184 @@
+use std::sync::Arc;
+
+use futures::StreamExt;
+use hyper::body::Bytes;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Method, Response};
+use librespot::playback::{NUM_CHANNELS, SAMPLE_RATE};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch};
+
+use crate::model::library_models::SimpleTrack;
+use crate::playback::output::AudioOutput;
+use crate::playback::stream::FlacEncoder;
+
+use super::{transcode, ByteStream, Result, StreamFormat, StreamingError};
+
+//audio bytes between two icy metadata blocks
+const ICY_METAINT: usize = 16000;
+
+/// Listen along: streams whatever the output plays right now, like an icecast mount
+pub fn response(output: &AudioOutput, ffmpeg: &str, format: StreamFormat, icy: bool, method: &Method) -> Result<Response<Body>> {
+    if format == StreamFormat::Original {
+        return Err(StreamingError::BadRequest("Listen along needs an encoded format".to_string()));
+    }
+    let mut builder = Response::builder()
+        .header(CONTENT_TYPE, format.content_type())
+        .header(CACHE_CONTROL, "no-cache, no-store")
+        .header("icy-name", "soundbase");
+    if icy {
+        builder = builder.header("icy-metaint", ICY_METAINT);
+    }
+    //a HEAD request only gets the headers, so neither listen in nor start a transcoder
+    if method == Method::HEAD {
+        return builder
+            .body(Body::empty())
+            .map_err(|e| StreamingError::BadRequest(e.to_string()));
+    }
+
+    let samples = output.listen();
+    let stream = match format {
+        StreamFormat::Wav => encoded(samples, wav_header(), s16_bytes),
+        StreamFormat::Flac => {
+            let mut encoder = FlacEncoder::new(SAMPLE_RATE, 16);
+            let header = encoder.stream_header();
+            encoded(samples, header, move |chunk| {
+                let quantized: Vec<i32> = chunk.iter().map(|s| to_s16(*s) as i32).collect();
+                encoder.encode(&quantized)
+            })
+        }
+        //anything else is refused by the transcoder
+        _ => {
+            let (stdin, stream) = transcode::pcm(ffmpeg, SAMPLE_RATE, format)?;
+            feed_transcoder(samples, stdin);
+            stream
+        }
+    };
+    let stream = if icy {
+        with_icy_metadata(stream, output.now_playing())
+    } else {
+        stream
+    };
+    builder
+        .body(Body::wrap_stream(stream))
+        .map_err(|e| StreamingError::BadRequest(e.to_string()))
+}
+
+/// Encodes the chunks in process, starting with the given header
+fn encoded<F>(samples: broadcast::Receiver<Arc<Vec<f64>>>, header: Vec<u8>, mut encode: F) -> ByteStream
+where
+    F: FnMut(&[f64]) -> Vec<u8> + Send + 'static,
+{
+    let body = futures::stream::unfold(samples, |mut samples| async move {
+        let chunk = next_chunk(&mut samples).await?;
+        Some((chunk, samples))
+    })
+    .map(move |chunk| Ok(Bytes::from(encode(&chunk))));
+    futures::stream::once(futures::future::ready(Ok(Bytes::from(header))))
+        .chain(body)
+        .boxed()
+}
+
+fn feed_transcoder(mut samples: broadcast::Receiver<Arc<Vec<f64>>>, mut stdin: tokio::process::ChildStdin) {
+    tokio::spawn(async move {
+        while let Some(chunk) = next_chunk(&mut samples).await {
+            //fails once the listener left and the transcoder was killed
+            if stdin.write_all(&s16_bytes(&chunk)).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+async fn next_chunk(samples: &mut broadcast::Receiver<Arc<Vec<f64>>>) -> Option<Arc<Vec<f64>>> {
+    loop {
+        match samples.recv().await {
+            Ok(chunk) => return Some(chunk),
+            //a slow listener skips ahead
+            Err(RecvError::Lagged(skipped)) => log::debug!("Listen along stream skipped {} chunks", skipped),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+fn to_s16(sample: f64) -> i16 {
+    (sample * i16::MAX as f64).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+fn s16_bytes(chunk: &[f64]) -> Vec<u8> {
+    chunk.iter().flat_map(|s| to_s16(*s).to_le_bytes()).collect()
+}
+
+//16 bit stereo pcm of unknown length
+fn wav_header() -> Vec<u8> {
+    let channels = NUM_CHANNELS as u16;
+    let block_align = channels * 2;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    header.extend_from_slice(&(SAMPLE_RATE * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
+
+/// Inserts a metadata block every ICY_METAINT bytes; it carries the title
+/// whenever the track changed and is empty otherwise
+fn with_icy_metadata(stream: ByteStream, now_playing: watch::Receiver<Option<SimpleTrack>>) -> ByteStream {
+    let mut until_metadata = ICY_METAINT;
+    let mut announced: Option<String> = None;
+    stream
+        .map(move |chunk| {
+            let chunk = chunk?;
+            let mut out = Vec::with_capacity(chunk.len() + 64);
+            let mut rest = &chunk[..];
+            while !rest.is_empty() {
+                let take = rest.len().min(until_metadata);
+                out.extend_from_slice(&rest[..take]);
+                rest = &rest[take..];
+                until_metadata -= take;
+                if until_metadata == 0 {
+                    let title = stream_title(now_playing.borrow().as_ref());
+                    if announced.as_ref() != Some(&title) {
+                        out.extend(icy_block(&title));
+                        announced = Some(title);
+                    } else {
+                        out.push(0);
+                    }
+                    until_metadata = ICY_METAINT;
+                }
+            }
+            Ok(Bytes::from(out))
+        })
+        .boxed()
+}
+
+fn stream_title(track: Option<&SimpleTrack>) -> String {
+    match track {
+        Some(track) => {
+            let artists = track.artists.iter().map(|a| a.name.as_str()).collect::<Vec<_>>().join(", ");
+            format!("{} - {}", artists, track.title)
+        }
+        None => String::new()
+    }
+}
+
+fn icy_block(title: &str) -> Vec<u8> {
+    //quotes would end the value early
+    let mut text = format!("StreamTitle='{}';", title.replace('\'', "’")).into_bytes();
+    //the length byte counts 16 byte units
+    text.truncate(255 * 16);
+    let units = text.len().div_ceil(16);
+    text.resize(units * 16, 0);
+    let mut block = vec![units as u8];
+    block.extend(text);
+    block
+}
@@ -0,0 +1,417 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::Infallible;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use hyper::body::Bytes;
+use hyper::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+use crate::db_new::models::Track;
+use crate::db_new::{DbApi, FindById};
+use crate::playback::output::AudioOutput;
+
+mod live;
+mod transcode;
+
+type Result<T> = std::result::Result<T, StreamingError>;
+
+type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum StreamingError {
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("invalid request: {0}")]
+    BadRequest(String),
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("transcoder unavailable: {0}")]
+    Transcoder(String),
+
+    #[error("database error: {0}")]
+    Database(#[from] crate::db_new::DbError),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("hyper error: {0}")]
+    Hyper(#[from] hyper::Error),
+}
+
+impl StreamingError {
+    fn status(&self) -> StatusCode {
+        match self {
+            StreamingError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            StreamingError::NotFound(_) => StatusCode::NOT_FOUND,
+            StreamingError::Transcoder(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StreamFormat {
+    //the file as it is stored, only for tracks
+    Original,
+    Mp3,
+    //in an ogg container
+    Opus,
+    //only for listen along
+    Flac,
+    Wav,
+}
+
+impl FromStr for StreamFormat {
+    type Err = StreamingError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "original" => Ok(StreamFormat::Original),
+            "mp3" => Ok(StreamFormat::Mp3),
+            "opus" => Ok(StreamFormat::Opus),
+            "flac" => Ok(StreamFormat::Flac),
+            "wav" => Ok(StreamFormat::Wav),
+            _ => Err(StreamingError::BadRequest(format!("Unknown format '{}'", s)))
+        }
+    }
+}
+
+impl StreamFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            StreamFormat::Original => "application/octet-stream",
+            StreamFormat::Mp3 => "audio/mpeg",
+            StreamFormat::Opus => "audio/ogg",
+            StreamFormat::Flac => "audio/flac",
+            StreamFormat::Wav => "audio/wav",
+        }
+    }
+
+    /// The format the file already has, so it can be passed through
+    fn of_file(file: &str) -> Option<Self> {
+        let extension = Path::new(file).extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "mp3" => Some(StreamFormat::Mp3),
+            "opus" => Some(StreamFormat::Opus),
+            "flac" => Some(StreamFormat::Flac),
+            "wav" => Some(StreamFormat::Wav),
+            _ => None
+        }
+    }
+}
+
+/// Serves the audio of local tracks and a listen along stream of the playback over http:
+///  - GET /tracks/{id}?format=original|mp3|opus, original supports range requests
+///  - GET /live?format=mp3|opus|flac|wav, with icecast style metadata on request
+#[derive(Clone)]
+pub struct StreamingServer {
+    db: DbApi,
+    output: AudioOutput,
+    addr: SocketAddr,
+    ffmpeg: String,
+}
+
+impl StreamingServer {
+    /// Returns None unless STREAM_HTTP_ADDR (e.g. 0.0.0.0:50052) is configured;
+    /// FFMPEG points to the binary used for transcoding
+    pub fn from_env(db: DbApi, output: AudioOutput) -> Result<Option<Self>> {
+        let addr = match dotenv::var("STREAM_HTTP_ADDR") {
+            Ok(addr) if !addr.is_empty() => addr,
+            _ => return Ok(None)
+        };
+        let addr = addr.parse::<SocketAddr>()
+            .map_err(|e| StreamingError::Config(format!("STREAM_HTTP_ADDR {}: {}", addr, e)))?;
+        let ffmpeg = match dotenv::var("FFMPEG") {
+            Ok(ffmpeg) => ffmpeg,
+            Err(_) => "ffmpeg".to_string()
+        };
+
+        Ok(Some(Self { db, output, addr, ffmpeg }))
+    }
+
+    /// Spawns the http server
+    pub fn init(&self) {
+        let this = self.clone();
+        let addr = self.addr;
+        tokio::spawn(async move {
+            let service = make_service_fn(move |_| {
+                let this = this.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let this = this.clone();
+                        async move { Ok::<_, Infallible>(this.handle(req).await) }
+                    }))
+                }
+            });
+
+            log::info!("Streaming audio on http://{}", addr);
+            if let Err(e) = Server::bind(&addr).serve(service).await {
+                log::error!("Streaming server failed: {}", e);
+            }
+        });
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        match self.route(&req).await {
+            Ok(mut response) => {
+                if req.method() == Method::HEAD {
+                    *response.body_mut() = Body::empty();
+                }
+                response
+            }
+            Err(e) => {
+                log::warn!("Failed to stream {}: {}", req.uri(), e);
+                let mut response = Response::new(Body::from(e.to_string()));
+                *response.status_mut() = e.status();
+                response
+            }
+        }
+    }
+
+    async fn route(&self, req: &Request<Body>) -> Result<Response<Body>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Err(StreamingError::BadRequest(format!("Unsupported method {}", req.method())));
+        }
+
+        let format = match query_param(req, "format") {
+            Some(format) => Some(format.parse::<StreamFormat>()?),
+            None => None
+        };
+        let segments: Vec<&str> = req.uri().path().split('/').filter(|s| !s.is_empty()).collect();
+        match segments.as_slice() {
+            ["tracks", id] => {
+                let track_id = id.parse::<i32>()
+                    .map_err(|_| StreamingError::BadRequest(format!("Invalid track id '{}'", id)))?;
+                self.track(req, track_id, format.unwrap_or(StreamFormat::Original)).await
+            }
+            ["live"] => {
+                let icy = req.headers().get("icy-metadata").map(|v| v == "1").unwrap_or(false);
+                live::response(&self.output, &self.ffmpeg, format.unwrap_or(StreamFormat::Mp3), icy, req.method())
+            }
+            _ => Err(StreamingError::NotFound(req.uri().path().to_string()))
+        }
+    }
+
+    async fn track(&self, req: &Request<Body>, track_id: i32, format: StreamFormat) -> Result<Response<Body>> {
+        let track: Option<Track> = self.db.find_by_id(track_id)?;
+        let file = track
+            .and_then(|t| t.local_file)
+            .ok_or_else(|| StreamingError::NotFound(format!("Track {} has no local file", track_id)))?;
+
+        let original = StreamFormat::of_file(&file);
+        match format {
+            StreamFormat::Original => serve_file(req, &file).await,
+            _ if original == Some(format) => serve_file(req, &file).await,
+            StreamFormat::Mp3 | StreamFormat::Opus => {
+                //a HEAD request only gets the headers, so don't start a transcoder for it
+                let body = if req.method() == Method::HEAD {
+                    Body::empty()
+                } else {
+                    Body::wrap_stream(transcode::file(&self.ffmpeg, &file, format)?)
+                };
+                Response::builder()
+                    .header(CONTENT_TYPE, format.content_type())
+                    .header(ACCEPT_RANGES, "none")
+                    .body(body)
+                    .map_err(|e| StreamingError::BadRequest(e.to_string()))
+            }
+            _ => Err(StreamingError::BadRequest(format!("Tracks can't be streamed as {:?}", format)))
+        }
+    }
+}
+
+/// Serves the file, or the part of it asked for by a single byte range
+async fn serve_file(req: &Request<Body>, file: &str) -> Result<Response<Body>> {
+    let mut reader = tokio::fs::File::open(file).await?;
+    let len = reader.metadata().await?.len();
+    let content_type = file_content_type(file);
+
+    let range = req.headers().get(RANGE).and_then(|r| r.to_str().ok());
+    let builder = Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(ACCEPT_RANGES, "bytes");
+    let response = match range.map_or(ByteRange::Ignore, |r| parse_range(r, len)) {
+        ByteRange::Range(start, end) => {
+            reader.seek(SeekFrom::Start(start)).await?;
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len))
+                .header(CONTENT_LENGTH, end - start + 1)
+                .body(Body::wrap_stream(read_stream(reader.take(end - start + 1))))
+        }
+        ByteRange::Unsatisfiable => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{}", len))
+            .body(Body::empty()),
+        ByteRange::Ignore => builder
+            .header(CONTENT_LENGTH, len)
+            .body(Body::wrap_stream(read_stream(reader))),
+    };
+    response.map_err(|e| StreamingError::BadRequest(e.to_string()))
+}
+
+fn file_content_type(file: &str) -> &'static str {
+    let extension = Path::new(file).extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    match extension.as_deref() {
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") | Some("oga") | Some("opus") => "audio/ogg",
+        Some("m4a") | Some("mp4") | Some("aac") => "audio/mp4",
+        Some("wav") => "audio/wav",
+        _ => "application/octet-stream"
+    }
+}
+
+/// How a Range header applies to a file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ByteRange {
+    //unsupported or invalid, the whole file is served
+    Ignore,
+    //valid but outside of the file
+    Unsatisfiable,
+    //inclusive offsets
+    Range(u64, u64),
+}
+
+/// Parses a single "bytes=" range; multiple ranges aren't supported and are ignored like invalid ones
+fn parse_range(header: &str, len: u64) -> ByteRange {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return ByteRange::Ignore
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return ByteRange::Ignore
+    };
+    let number = |n: &str| n.parse::<u64>().ok().filter(|_| n.bytes().all(|b| b.is_ascii_digit()));
+
+    match (start, end) {
+        //the last n bytes
+        ("", suffix) => match number(suffix) {
+            None => ByteRange::Ignore,
+            Some(0) => ByteRange::Unsatisfiable,
+            //all of an empty file is nothing to cut a range from
+            Some(_) if len == 0 => ByteRange::Ignore,
+            Some(n) => ByteRange::Range(len - n.min(len), len - 1),
+        },
+        (start, end) => {
+            let first = match number(start) {
+                Some(first) => first,
+                None => return ByteRange::Ignore
+            };
+            let last = match end {
+                "" => None,
+                end => match number(end) {
+                    Some(last) if last >= first => Some(last),
+                    _ => return ByteRange::Ignore
+                }
+            };
+            if first >= len {
+                return ByteRange::Unsatisfiable;
+            }
+            ByteRange::Range(first, last.map_or(len - 1, |last| last.min(len - 1)))
+        }
+    }
+}
+
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    let query = req.uri().query()?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+fn read_stream<R>(reader: R) -> ByteStream
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    futures::stream::unfold(Some(reader), |reader| async move {
+        let mut reader = reader?;
+        let mut buf = vec![0u8; READ_CHUNK_SIZE];
+        match reader.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some(reader)))
+            }
+            //end the stream after reporting the error
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Range(0, 99));
+        assert_eq!(parse_range(" bytes=10-10 ", 1000), ByteRange::Range(10, 10));
+        //open ended
+        assert_eq!(parse_range("bytes=500-", 1000), ByteRange::Range(500, 999));
+        //the end is clamped to the file
+        assert_eq!(parse_range("bytes=900-5000", 1000), ByteRange::Range(900, 999));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Range(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), ByteRange::Range(0, 999));
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn rejects_ranges_outside_of_the_file() {
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=1000-2000", 1000), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_unsupported_and_invalid_ranges() {
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), ByteRange::Ignore);
+        assert_eq!(parse_range("items=0-9", 1000), ByteRange::Ignore);
+        assert_eq!(parse_range("bytes=9-0", 1000), ByteRange::Ignore);
+        assert_eq!(parse_range("bytes=-", 1000), ByteRange::Ignore);
+        assert_eq!(parse_range("bytes=a-b", 1000), ByteRange::Ignore);
+        assert_eq!(parse_range("bytes=+1-2", 1000), ByteRange::Ignore);
+        assert_eq!(parse_range("bytes=10", 1000), ByteRange::Ignore);
+    }
+
+    #[test]
+    fn handles_empty_files() {
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-10", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), ByteRange::Ignore);
+        assert_eq!(parse_range("bytes=-0", 0), ByteRange::Unsatisfiable);
+    }
+}
@@ -0,0 +1,62 @@
+use std::process::Stdio;
+
+use futures::StreamExt;
+use tokio::process::{ChildStdin, Command};
+
+use super::{read_stream, ByteStream, Result, StreamFormat, StreamingError};
+
+/// Transcodes the audio of the file with ffmpeg
+pub fn file(ffmpeg: &str, file: &str, format: StreamFormat) -> Result<ByteStream> {
+    let mut cmd = Command::new(ffmpeg);
+    cmd.args(["-nostdin", "-loglevel", "error", "-i", file, "-map", "0:a:0", "-vn"])
+        .args(codec_args(format)?)
+        .arg("pipe:1")
+        .stdin(Stdio::null());
+    spawn(cmd).map(|(_, stream)| stream)
+}
+
+/// Encodes raw interleaved 16 bit stereo samples written to the returned stdin
+pub fn pcm(ffmpeg: &str, sample_rate: u32, format: StreamFormat) -> Result<(ChildStdin, ByteStream)> {
+    let rate = sample_rate.to_string();
+    let mut cmd = Command::new(ffmpeg);
+    cmd.args(["-loglevel", "error", "-f", "s16le", "-ar", &rate, "-ac", "2", "-i", "pipe:0"])
+        .args(codec_args(format)?)
+        .arg("pipe:1")
+        .stdin(Stdio::piped());
+    spawn(cmd).and_then(|(stdin, stream)| {
+        stdin
+            .map(|stdin| (stdin, stream))
+            .ok_or_else(|| StreamingError::Transcoder("ffmpeg stdin unavailable".to_string()))
+    })
+}
+
+fn codec_args(format: StreamFormat) -> Result<&'static [&'static str]> {
+    match format {
+        StreamFormat::Mp3 => Ok(&["-c:a", "libmp3lame", "-b:a", "192k", "-f", "mp3"]),
+        StreamFormat::Opus => Ok(&["-c:a", "libopus", "-b:a", "128k", "-f", "ogg"]),
+        _ => Err(StreamingError::BadRequest(format!("Can't transcode to {:?}", format)))
+    }
+}
+
+fn spawn(mut cmd: Command) -> Result<(Option<ChildStdin>, ByteStream)> {
+    //the transcoder goes away with the response it feeds
+    cmd.stdout(Stdio::piped()).kill_on_drop(true);
+    let mut child = cmd.spawn().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => StreamingError::Transcoder("ffmpeg not found, install it or set FFMPEG".to_string()),
+        _ => StreamingError::Transcoder(e.to_string()),
+    })?;
+
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take()
+        .ok_or_else(|| StreamingError::Transcoder("ffmpeg stdout unavailable".to_string()))?;
+    //owns the child until its output is read, then reports a failed transcode
+    let exit = futures::stream::once(async move {
+        match child.wait().await {
+            Ok(status) if status.success() => None,
+            Ok(status) => Some(Err(std::io::Error::other(format!("ffmpeg exited with {}", status)))),
+            Err(e) => Some(Err(e)),
+        }
+    })
+    .filter_map(futures::future::ready);
+    Ok((stdin, read_stream(stdout).chain(exit).boxed()))
+}
@@ -21,6 +21,7 @@ use crate::db_new::album::AlbumDb;
 use crate::db_new::album_of_week::AlbumOfWeekDb;
 use crate::db_new::DbApi;
 use crate::db_new::models::{NewAlbum, NewAlbumOfWeek, NewArtist};
+use crate::model::AlbumType;
 use crate::tasks::TasksError;
 use super::Result;
 
@@ -61,7 +62,7 @@ pub async fn fetch_new_rockantenne_album_of_week(api: DbApi) -> Result<()>
                 year: new_aofw_date.year(),
                 spot_id: None,
                 was_aow: None,
-                album_type: None,
+                album_type: Some(AlbumType::classify(AlbumType::Album, &album_name, album_track_count).into()),
                 is_faved: Some(false),
                 total_tracks: album_track_count,
                 is_known_spot: false,
@@ -0,0 +1,242 @@
+/*
+ * Copyright 2022 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fs::File;
+use std::io::BufReader;
+
+use itertools::Itertools;
+use rodio::{Decoder, Source};
+
+use crate::db_new::loudness::LoudnessDb;
+use crate::db_new::DbApi;
+use crate::model::ReplayGain;
+use crate::tasks::TasksError;
+
+use super::Result;
+
+//replay gain 2.0 reference level
+const REFERENCE_LUFS: f64 = -18.0;
+//gates of ITU-R BS.1770 which EBU R128 builds on
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+//blocks are 400ms long and overlap by 75%, so they are built of 100ms segments
+const SEGMENTS_PER_BLOCK: usize = 4;
+const SEGMENTS_PER_SECOND: f64 = 10.0;
+
+//a track id with its local file
+type TrackFile = (i32, String);
+
+/// Measures track and album gain of all albums with local tracks not analysed yet
+pub async fn analyze_missing_loudness(db: DbApi) -> Result<()> {
+    let album_ids = db.load_album_ids_missing_loudness()?;
+    log::info!("Analysing loudness of {} albums ...", album_ids.len());
+
+    for album_id in album_ids {
+        let files = db.load_local_tracks_for_album(album_id)?
+            .into_iter()
+            .filter_map(|t| t.local_file.map(|f| (t.track_id, f)))
+            .collect_vec();
+        //decoding whole albums takes a while, keep it off the runtime threads
+        let (gains, failed) = tokio::task::spawn_blocking(move || measure_album(files))
+            .await
+            .map_err(|e| TasksError::Internal(e.to_string()))?;
+        for (track_id, gain) in gains {
+            db.set_replay_gain(track_id, &gain)?;
+        }
+        //otherwise the whole album would be decoded again on every run
+        for (track_id, file) in failed {
+            db.record_failed_loudness(track_id, &file)?;
+        }
+    }
+    log::info!("Loudness analysis finished");
+    Ok(())
+}
+
+/// The gains of all tracks that could be measured, and the files that couldn't
+fn measure_album(files: Vec<TrackFile>) -> (Vec<(i32, ReplayGain)>, Vec<TrackFile>) {
+    let mut failed = Vec::new();
+    let measured = files.into_iter()
+        .filter_map(|(track_id, file)| match measure_file(&file) {
+            Ok(measurement) => Some((track_id, measurement)),
+            Err(e) => {
+                log::warn!("Failed to measure loudness of '{}' => {:?}", file, e);
+                failed.push((track_id, file));
+                None
+            }
+        })
+        .collect_vec();
+
+    //the album is gated as a whole instead of averaging the track levels
+    let album_blocks = measured.iter()
+        .flat_map(|(_, m)| m.blocks.iter().cloned())
+        .collect_vec();
+    let album_gain = gain_for(&album_blocks);
+    let album_peak = measured.iter()
+        .map(|(_, m)| m.peak)
+        .fold(0.0, f32::max);
+
+    let gains = measured.into_iter()
+        .map(|(track_id, m)| (track_id, ReplayGain {
+            track_gain: Some(gain_for(&m.blocks)),
+            track_peak: Some(m.peak),
+            album_gain: Some(album_gain),
+            album_peak: Some(album_peak),
+        }))
+        .collect_vec();
+    (gains, failed)
+}
+
+struct Measurement {
+    //channel weighted mean square of each block
+    blocks: Vec<f64>,
+    peak: f32,
+}
+
+fn measure_file(file: &str) -> Result<Measurement> {
+    let decoder = Decoder::new(BufReader::new(File::open(file)?))
+        .map_err(|e| TasksError::Internal(e.to_string()))?;
+    let channels = decoder.channels().max(1) as usize;
+    let sample_rate = decoder.sample_rate() as f64;
+    let weights = (0..channels).map(|c| channel_weight(c, channels)).collect_vec();
+    let mut filters = vec![KWeighting::new(sample_rate); channels];
+
+    let segment_frames = (sample_rate / SEGMENTS_PER_SECOND).round() as usize;
+    let mut energy = vec![0.0; channels];
+    let mut frames = 0;
+    let mut segments = Vec::new();
+    let mut peak = 0.0f32;
+    for (i, sample) in decoder.enumerate() {
+        let channel = i % channels;
+        let value = sample as f32 / 32768.0;
+        peak = peak.max(value.abs());
+        let filtered = filters[channel].process(value as f64);
+        energy[channel] += filtered * filtered;
+
+        if channel == channels - 1 {
+            frames += 1;
+            if frames == segment_frames {
+                segments.push(energy.iter()
+                    .zip(&weights)
+                    .map(|(e, w)| w * e / segment_frames as f64)
+                    .sum::<f64>());
+                energy.iter_mut().for_each(|e| *e = 0.0);
+                frames = 0;
+            }
+        }
+    }
+
+    let blocks = segments.windows(SEGMENTS_PER_BLOCK)
+        .map(|w| w.iter().sum::<f64>() / SEGMENTS_PER_BLOCK as f64)
+        .collect_vec();
+    Ok(Measurement { blocks, peak })
+}
+
+//silence has no level, it is left as is
+fn gain_for(blocks: &[f64]) -> f32 {
+    match gated_loudness(blocks) {
+        Some(lufs) => (REFERENCE_LUFS - lufs) as f32,
+        None => 0.0
+    }
+}
+
+fn gated_loudness(blocks: &[f64]) -> Option<f64> {
+    let above_absolute = blocks.iter()
+        .cloned()
+        .filter(|z| loudness(*z) > ABSOLUTE_GATE_LUFS)
+        .collect_vec();
+    if above_absolute.is_empty() {
+        return None;
+    }
+    let threshold = loudness(mean(&above_absolute)) + RELATIVE_GATE_LU;
+    let gated = above_absolute.into_iter()
+        .filter(|z| loudness(*z) > threshold)
+        .collect_vec();
+    Some(loudness(mean(&gated)))
+}
+
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+//surround channels of 5.1 count more, LFE is ignored
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0
+    }
+}
+
+/// The K-weighting pre filter (high shelf followed by a high pass), with the
+/// coefficients derived for the sample rate at hand
+#[derive(Clone)]
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: f64) -> Self {
+        let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+        Self { shelf, high_pass }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+}
+
+//transposed direct form II with a0 normalised to 1
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, z1: 0.0, z2: 0.0 }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z1;
+        self.z1 = self.b[1] * x - self.a[0] * y + self.z2;
+        self.z2 = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
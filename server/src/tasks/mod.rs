@@ -16,18 +16,21 @@
 
 use thiserror::Error;
 
+use crate::artwork::ArtworkStore;
 use crate::db_new::DbApi;
+use crate::model::ChangeSource;
 use crate::spotify::SpotifyApi;
 use crate::tasks::spotify_import::SpotifyImporter;
 
 mod aow_rock_antenne;
+mod loudness;
 mod tow_rock_antenne;
 mod spotify_import;
 
 type Result<T> = std::result::Result<T, TasksError>;
 
 pub fn launch_fetch_albums_of_week(db : &DbApi) {
-    let api = db.clone();
+    let api = db.with_source(ChangeSource::AlbumOfWeek);
     tokio::task::spawn(async move {
         if let Err(e) = aow_rock_antenne::fetch_new_rockantenne_album_of_week(api).await {
             println!("AOW Fetch for Rock Antenne raised an Error! => {:?}", e);
@@ -45,7 +48,7 @@ pub fn launch_fetch_charts(db : &DbApi) {
 }
 
 pub fn launch_spotify_import(db : &DbApi, spotify : &SpotifyApi) {
-    let db = db.clone();
+    let db = db.with_source(ChangeSource::SpotifyImport);
     let spotify = spotify.clone();
     tokio::task::spawn(async move {
         if let Err(e) = SpotifyImporter::new(db, spotify).do_import().await {
@@ -54,6 +57,24 @@ pub fn launch_spotify_import(db : &DbApi, spotify : &SpotifyApi) {
     });
 }
 
+pub fn launch_fetch_artwork(artwork : &ArtworkStore) {
+    let artwork = artwork.clone();
+    tokio::task::spawn(async move {
+        if let Err(e) = artwork.fetch_missing().await {
+            log::error!("Error occured during artwork fetch! => {:?}", e);
+        }
+    });
+}
+
+pub fn launch_loudness_analysis(db : &DbApi) {
+    let db = db.clone();
+    tokio::task::spawn(async move {
+        if let Err(e) = loudness::analyze_missing_loudness(db).await {
+            log::error!("Error occured during loudness analysis! => {:?}", e);
+        }
+    });
+}
+
 fn get_selector(selector: &'static str) -> Result<scraper::Selector> {
     let sel = scraper::Selector::parse(selector);
     match sel {
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use itertools::Itertools;
-use rspotify::model::{ArtistId, FullAlbum, FullArtist, FullTrack};
+use rspotify::model::{ArtistId, FullAlbum, FullArtist, FullTrack, SimplifiedArtist};
 
 use crate::db_new::album::AlbumDb;
 use crate::db_new::album_artist::AlbumArtistsDb;
@@ -144,27 +144,25 @@ impl SpotifyImporter {
                 }
             };
 
-            let db_track = get_or_create_track(&self.db, album, &track)?;
-            let api : &dyn TrackDb = &self.db;
-            let _ = api.set_faved_state(db_track.track_id, true)?;
+            let unknown_artists = self.fetch_unknown_artists(&track.artists).await?;
 
-            let unknown_artists = track.artists.iter()
-                .filter(|&a|!self.known_artists.contains_key(&a.id.as_ref().unwrap().to_string()))
-                .unique_by(|&a|a.id.as_ref().unwrap().to_string())
-                .map(|a|a.id.clone().unwrap())
-                .collect::<Vec<ArtistId>>();
+            //the track, its artists and their links get written as a whole
+            let known_artists = &self.known_artists;
+            let (db_track, new_artists) = self.db.transaction(|tx| -> Result<(Track, HashMap<String, Artist>)> {
+                let db_track = get_or_create_track(tx, album, &track)?;
+                let api : &dyn TrackDb = tx;
+                api.set_faved_state(db_track.track_id, true)?;
 
-            if !unknown_artists.is_empty() {
-                self.add_unknown_artists(&unknown_artists).await?;
-            }
-
-            let track_id = db_track.track_id;
-            let api : &dyn TrackArtistsDb = &self.db;
-            for artist in &track.artists {
-                let artist_id = self.known_artists.get(&artist.id.as_ref().unwrap().to_string()).unwrap().artist_id;
-                let _ = api.new_track_artist_if_missing(track_id, artist_id)?;
-            }
+                let new_artists = create_artists(tx, &unknown_artists)?;
+                let api : &dyn TrackArtistsDb = tx;
+                for artist in &track.artists {
+                    let artist_id = lookup_artist(known_artists, &new_artists, artist).artist_id;
+                    let _ = api.new_track_artist_if_missing(db_track.track_id, artist_id)?;
+                }
+                Ok((db_track, new_artists))
+            })?;
 
+            self.known_artists.extend(new_artists);
             self.known_tracks.insert(track.id.as_ref().unwrap().to_string(), db_track);
         }
         Ok(())
@@ -172,36 +170,53 @@ impl SpotifyImporter {
 
     async fn import_album(&mut self, album : &FullAlbum) -> Result<Album> {
         println!("Adding new album {}", album.name);
-        let dba = get_or_create_album(&self.db, album)?;
-
-        let unknown_artists = album.artists.iter().filter(|&a| {
-            !self.known_artists.contains_key(&a.id.as_ref().unwrap().to_string())
-        }).unique_by(|&a| a.id.as_ref().unwrap().to_string())
-            .map(|a| a.id.as_ref().unwrap().clone())
-            .collect::<Vec<ArtistId>>();
-
-        if !unknown_artists.is_empty() {
-            self.add_unknown_artists(&unknown_artists).await?;
-        }
+        let unknown_artists = self.fetch_unknown_artists(&album.artists).await?;
+
+        //the album, its artists and their links get written as a whole
+        let known_artists = &self.known_artists;
+        let (dba, new_artists) = self.db.transaction(|tx| -> Result<(Album, HashMap<String, Artist>)> {
+            let dba = get_or_create_album(tx, album)?;
+            let new_artists = create_artists(tx, &unknown_artists)?;
+
+            //link albums to artists
+            let api: &dyn AlbumArtistsDb = tx;
+            for artist in &album.artists {
+                let artist_id = lookup_artist(known_artists, &new_artists, artist).artist_id;
+                let _ = api.new_album_artist_if_missing(artist_id, dba.album_id)?;
+            }
+            Ok((dba, new_artists))
+        })?;
 
-        //link albums to artists
-        let album_id = dba.album_id;
-        let api: &dyn AlbumArtistsDb = &self.db;
-        for artist in &album.artists {
-            let artist_id = self.known_artists.get(&artist.id.as_ref().unwrap().to_string()).unwrap().artist_id;
-            let _ = api.new_album_artist_if_missing(artist_id, album_id)?;
-        }
+        self.known_artists.extend(new_artists);
         self.known_albums.insert(album.id.to_string(), dba.clone());
         Ok(dba)
     }
 
-    async fn add_unknown_artists(&mut self, artists : &[ArtistId]) -> Result<()> {
-        let full_unknown_artists = self.spotify.get_artists(artists).await?;
-        for unknown in &full_unknown_artists {
-            println!("Adding new artist {}", unknown.name);
-            let artist = get_or_create_artist(&self.db, unknown)?;
-            self.known_artists.insert(unknown.id.to_string(), artist);
+    async fn fetch_unknown_artists(&self, artists : &[SimplifiedArtist]) -> Result<Vec<FullArtist>> {
+        let unknown_artists = artists.iter()
+            .filter(|&a| !self.known_artists.contains_key(&a.id.as_ref().unwrap().to_string()))
+            .unique_by(|&a| a.id.as_ref().unwrap().to_string())
+            .map(|a| a.id.clone().unwrap())
+            .collect::<Vec<ArtistId>>();
+
+        if unknown_artists.is_empty() {
+            return Ok(Vec::new());
         }
-        Ok(())
+        Ok(self.spotify.get_artists(&unknown_artists).await?)
     }
-}
\ No newline at end of file
+}
+
+fn create_artists(db : &DbApi, artists : &[FullArtist]) -> Result<HashMap<String, Artist>> {
+    let mut created = HashMap::new();
+    for unknown in artists {
+        println!("Adding new artist {}", unknown.name);
+        let artist = get_or_create_artist(db, unknown)?;
+        created.insert(unknown.id.to_string(), artist);
+    }
+    Ok(created)
+}
+
+fn lookup_artist<'a>(known : &'a HashMap<String, Artist>, created : &'a HashMap<String, Artist>, artist : &SimplifiedArtist) -> &'a Artist {
+    let spot_id = artist.id.as_ref().unwrap().to_string();
+    created.get(&spot_id).or_else(|| known.get(&spot_id)).unwrap()
+}